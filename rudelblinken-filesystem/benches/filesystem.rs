@@ -0,0 +1,166 @@
+//! Benchmarks of the common filesystem operations on the host storages
+//!
+//! ```sh
+//! cargo bench --bench filesystem
+//! ```
+//!
+//! Every operation is measured for a few distributions of files. The simulated storage has 16 blocks of
+//! 4096 bytes, so there can be at most 15 files with the default reservation.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rudelblinken_filesystem::storage::dynamic::BoxedStorage;
+use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+use rudelblinken_filesystem::storage::Storage;
+use rudelblinken_filesystem::Filesystem;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Name and sizes of the files of a distribution
+const DISTRIBUTIONS: &[(&str, &[usize])] = &[
+    ("small", &[100; 12]),
+    ("large", &[12000, 12000, 12000]),
+    ("mixed", &[100, 5000, 200, 12000, 1000, 300, 8000]),
+];
+
+/// A storage the benchmarks can run on
+trait BenchStorage: Storage + Send + Sync + 'static {
+    const NAME: &'static str;
+    fn leak() -> &'static Self;
+}
+
+impl BenchStorage for SimulatedStorage {
+    const NAME: &'static str = "simulated";
+    fn leak() -> &'static Self {
+        Box::leak(Box::new(SimulatedStorage::new()))
+    }
+}
+
+impl BenchStorage for BoxedStorage<16, 4096> {
+    const NAME: &'static str = "boxed";
+    fn leak() -> &'static Self {
+        Box::leak(Box::new(
+            BoxedStorage::new(Box::new(SimulatedStorage::new())).unwrap(),
+        ))
+    }
+}
+
+fn name(index: usize) -> String {
+    format!("file{index}")
+}
+
+/// Replace the content of the filesystem with the files of a distribution
+fn fill<T: BenchStorage>(filesystem: &mut Filesystem<T>, sizes: &[usize]) {
+    filesystem.clear().unwrap();
+    for (index, size) in sizes.iter().enumerate() {
+        filesystem
+            .write_file(name(index), &vec![index as u8; *size], &[0; 32])
+            .unwrap();
+    }
+}
+
+fn mount<T: BenchStorage>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("{}/mount", T::NAME));
+    for (distribution, sizes) in DISTRIBUTIONS {
+        let storage = T::leak();
+        fill(&mut Filesystem::new(storage), sizes);
+
+        group.bench_function(BenchmarkId::new("index", distribution), |b| {
+            b.iter(|| black_box(Filesystem::new(storage)))
+        });
+        group.bench_function(BenchmarkId::new("scan", distribution), |b| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iterations {
+                    // A broken index makes mount fall back to scanning. Mount stores a new one.
+                    storage.write_metadata("index", &[]).unwrap();
+                    let start = Instant::now();
+                    black_box(Filesystem::new(storage));
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+fn write_file<T: BenchStorage>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("{}/write_file", T::NAME));
+    for (distribution, sizes) in DISTRIBUTIONS {
+        let mut filesystem = Filesystem::new(T::leak());
+        let contents: Vec<Vec<u8>> = sizes.iter().map(|size| vec![0x42; *size]).collect();
+        group.bench_function(*distribution, |b| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iterations {
+                    filesystem.clear().unwrap();
+                    let start = Instant::now();
+                    for (index, content) in contents.iter().enumerate() {
+                        filesystem
+                            .write_file(name(index), content, &[0; 32])
+                            .unwrap();
+                    }
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+fn read_file<T: BenchStorage>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("{}/read_file", T::NAME));
+    for (distribution, sizes) in DISTRIBUTIONS {
+        let mut filesystem = Filesystem::new(T::leak());
+        fill(&mut filesystem, sizes);
+        let names: Vec<String> = (0..sizes.len()).map(name).collect();
+        group.bench_function(*distribution, |b| {
+            b.iter(|| {
+                for name in &names {
+                    let file = filesystem.read_file(name).unwrap().upgrade().unwrap();
+                    black_box(file.as_ref());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn compact<T: BenchStorage>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("{}/compact", T::NAME));
+    for (distribution, sizes) in DISTRIBUTIONS {
+        let mut filesystem = Filesystem::new(T::leak());
+        group.bench_function(*distribution, |b| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iterations {
+                    // Leave gaps between the remaining files
+                    fill(&mut filesystem, sizes);
+                    for index in (0..sizes.len()).step_by(2) {
+                        filesystem.delete_file(&name(index)).unwrap();
+                    }
+                    let start = Instant::now();
+                    black_box(filesystem.compact().unwrap());
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+fn benches<T: BenchStorage>(c: &mut Criterion) {
+    mount::<T>(c);
+    write_file::<T>(c);
+    read_file::<T>(c);
+    compact::<T>(c);
+}
+
+criterion_group!(
+    filesystem,
+    benches::<SimulatedStorage>,
+    benches::<BoxedStorage<16, 4096>>
+);
+criterion_main!(filesystem);
@@ -0,0 +1,145 @@
+//! Reference server for [TcpStorage](rudelblinken_filesystem::storage::tcp::TcpStorage)
+//!
+//! Serves a storage of 16 blocks of 4096 bytes, the geometry of
+//! [SimulatedStorage](rudelblinken_filesystem::storage::simulated::SimulatedStorage). Clients are
+//! served one after another, and the content is kept between connections, so a device can reboot
+//! and reconnect.
+//!
+//! ```sh
+//! cargo run --example tcp_storage_server --features tcp -- 127.0.0.1:7878 flash.bin
+//! ```
+//!
+//! If an image file is given, it is loaded on start and rewritten after every write or erase, so a
+//! test harness can snapshot and inspect it. Metadata is only kept in memory.
+
+use rudelblinken_filesystem::storage::tcp::{read_frame, write_frame, Request, Response, Status};
+use std::{
+    collections::HashMap,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+};
+
+const BLOCKS: u32 = 16;
+const BLOCK_SIZE: u32 = 4096;
+
+struct Server {
+    flash: Vec<u8>,
+    metadata: HashMap<String, Vec<u8>>,
+    image: Option<PathBuf>,
+}
+
+impl Server {
+    fn handle(&mut self, request: Request) -> Response {
+        let size = self.flash.len();
+        match request {
+            Request::Geometry => {
+                Response::ok([BLOCKS.to_le_bytes(), BLOCK_SIZE.to_le_bytes()].concat())
+            }
+            Request::Read { address, length } => {
+                if address as usize >= size || length as usize > size {
+                    return Response::error(Status::Error, "Read out of bounds");
+                }
+                Response::ok(
+                    (0..length as usize)
+                        .map(|offset| self.flash[(address as usize + offset) % size])
+                        .collect(),
+                )
+            }
+            Request::Write { address, data } => {
+                if address as usize >= size || data.len() > size {
+                    return Response::error(Status::Error, "Write out of bounds");
+                }
+                // Flash can only clear bits
+                for (offset, byte) in data.iter().enumerate() {
+                    self.flash[(address as usize + offset) % size] &= byte;
+                }
+                self.save()
+            }
+            Request::Erase { address, length } => {
+                let end = address as usize + length as usize;
+                if !address.is_multiple_of(BLOCK_SIZE)
+                    || !length.is_multiple_of(BLOCK_SIZE)
+                    || end > size
+                {
+                    return Response::error(Status::Error, "Erase not aligned to blocks");
+                }
+                self.flash[address as usize..end].fill(0xff);
+                self.save()
+            }
+            Request::ReadMetadata { key } => match self.metadata.get(&key) {
+                Some(value) => Response::ok(value.clone()),
+                None => Response::error(Status::NotFound, "No value for that key"),
+            },
+            Request::WriteMetadata { key, value } => {
+                self.metadata.insert(key, value);
+                Response::ok(Vec::new())
+            }
+        }
+    }
+
+    /// Write the image file if there is one
+    fn save(&self) -> Response {
+        if let Some(image) = &self.image {
+            if let Err(error) = std::fs::write(image, &self.flash) {
+                return Response::error(Status::Error, &error.to_string());
+            }
+        }
+        Response::ok(Vec::new())
+    }
+
+    fn serve(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(true)?;
+        loop {
+            let payload = match read_frame(&mut stream) {
+                Ok(payload) => payload,
+                Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(error) => return Err(error),
+            };
+            let response = match Request::decode(&payload) {
+                Ok(request) => self.handle(request),
+                Err(error) => Response::error(Status::Error, &error.to_string()),
+            };
+            write_frame(&mut stream, &response.encode())?;
+        }
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let address = args.next().unwrap_or_else(|| "127.0.0.1:7878".into());
+    let image = args.next().map(PathBuf::from);
+
+    let size = (BLOCKS * BLOCK_SIZE) as usize;
+    let flash = match &image {
+        Some(image) if image.exists() => {
+            let flash = std::fs::read(image)?;
+            if flash.len() != size {
+                eprintln!("The image needs to be exactly {} bytes", size);
+                std::process::exit(1);
+            }
+            flash
+        }
+        _ => vec![0xff; size],
+    };
+    let mut server = Server {
+        flash,
+        metadata: HashMap::new(),
+        image,
+    };
+
+    let listener = TcpListener::bind(&address)?;
+    println!(
+        "Serving {} blocks of {} bytes on {}",
+        BLOCKS, BLOCK_SIZE, address
+    );
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        println!("{} connected", peer);
+        match server.serve(stream) {
+            Ok(()) => println!("{} disconnected", peer),
+            Err(error) => println!("{} disconnected: {}", peer, error),
+        }
+    }
+    Ok(())
+}
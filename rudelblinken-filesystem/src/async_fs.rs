@@ -0,0 +1,158 @@
+//! Async facade over [Filesystem] for host tooling
+//!
+//! All operations are executed on tokio's blocking thread pool, so reading or writing large files does not
+//! block the async runtime. This is only a thin adapter; the actual work is done by the synchronous [Filesystem].
+
+use crate::{
+    file::{File, FileState},
+    lock::RecoverPoisoned,
+    storage::Storage,
+    BlockRun, Filesystem, FilesystemDeleteError, FilesystemWriteError, IntoFileName,
+};
+use std::sync::{Arc, RwLock};
+
+/// A [Filesystem] that can be used from async code
+///
+/// Cloning is cheap and returns a handle to the same filesystem.
+pub struct AsyncFilesystem<T: Storage + 'static + Send + Sync> {
+    filesystem: Arc<RwLock<Filesystem<T>>>,
+}
+
+impl<T: Storage + 'static + Send + Sync> Clone for AsyncFilesystem<T> {
+    fn clone(&self) -> Self {
+        Self {
+            filesystem: self.filesystem.clone(),
+        }
+    }
+}
+
+/// Run a closure on the blocking thread pool and propagate panics
+async fn run_blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+    match ::tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        Err(error) => panic!("Blocking filesystem operation was cancelled: {}", error),
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> AsyncFilesystem<T> {
+    /// Mount the filesystem on the given storage. See [Filesystem::new]
+    pub async fn mount(storage: &'static T) -> Self {
+        let filesystem = run_blocking(move || Filesystem::new(storage)).await;
+        Self {
+            filesystem: Arc::new(RwLock::new(filesystem)),
+        }
+    }
+
+    /// Get the underlying synchronous filesystem
+    pub fn inner(&self) -> &Arc<RwLock<Filesystem<T>>> {
+        &self.filesystem
+    }
+
+    /// Run a closure with shared access to the filesystem on the blocking thread pool
+    pub async fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Filesystem<T>) -> R + Send + 'static,
+    ) -> R {
+        let filesystem = self.filesystem.clone();
+        run_blocking(move || f(&filesystem.read_recovering())).await
+    }
+
+    /// Run a closure with exclusive access to the filesystem on the blocking thread pool
+    pub async fn with_mut<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Filesystem<T>) -> R + Send + 'static,
+    ) -> R {
+        let filesystem = self.filesystem.clone();
+        run_blocking(move || f(&mut filesystem.write_recovering())).await
+    }
+
+    /// Find a file by name. See [Filesystem::read_file]
+    pub async fn read_file(&self, name: &str) -> Option<File<T, { FileState::Weak }>> {
+        let name = name.to_owned();
+        self.with(move |filesystem| filesystem.read_file(&name))
+            .await
+    }
+
+    /// Find a file by hash. See [Filesystem::read_file_by_hash]
+    pub async fn read_file_by_hash(&self, hash: &[u8; 32]) -> Option<File<T, { FileState::Weak }>> {
+        let hash = *hash;
+        self.with(move |filesystem| filesystem.read_file_by_hash(&hash))
+            .await
+    }
+
+    /// Write a file. See [Filesystem::write_file]
+    pub async fn write_file(
+        &self,
+        name: impl IntoFileName,
+        content: Vec<u8>,
+        hash: &[u8; 32],
+    ) -> Result<(), FilesystemWriteError> {
+        let name = name.into_file_name()?;
+        let hash = *hash;
+        self.with_mut(move |filesystem| filesystem.write_file(name, &content, &hash))
+            .await
+    }
+
+    /// Get a writer for a new file. See [Filesystem::get_file_writer]
+    ///
+    /// Writing to the returned file is synchronous, so do larger writes inside [tokio::task::spawn_blocking].
+    pub async fn get_file_writer(
+        &self,
+        name: impl IntoFileName,
+        length: u32,
+        hash: &[u8; 32],
+    ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
+        let name = name.into_file_name()?;
+        let hash = *hash;
+        self.with_mut(move |filesystem| filesystem.get_file_writer(name, length, &hash))
+            .await
+    }
+
+    /// Delete a file. See [Filesystem::delete_file]
+    pub async fn delete_file(&self, name: &str) -> Result<(), FilesystemDeleteError> {
+        let name = name.to_owned();
+        self.with_mut(move |filesystem| filesystem.delete_file(&name))
+            .await
+    }
+
+    /// See [Filesystem::max_writable_file]
+    pub async fn max_writable_file(&self) -> u32 {
+        self.with(|filesystem| filesystem.max_writable_file()).await
+    }
+
+    /// See [Filesystem::free_space]
+    pub async fn free_space(&self) -> u32 {
+        self.with(|filesystem| filesystem.free_space()).await
+    }
+
+    /// See [Filesystem::block_map]
+    pub async fn block_map(&self) -> Vec<BlockRun> {
+        self.with(|filesystem| filesystem.block_map()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::simulated::get_test_storage;
+
+    #[test]
+    fn async_write_and_read_works() {
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let storage = get_test_storage();
+            let filesystem = AsyncFilesystem::mount(storage).await;
+            filesystem
+                .write_file("fancy", vec![1, 2, 3], &[0u8; 32])
+                .await
+                .unwrap();
+            let file = filesystem.read_file("fancy").await.unwrap();
+            assert_eq!(file.upgrade().unwrap().as_ref(), &[1, 2, 3]);
+            filesystem.delete_file("fancy").await.unwrap();
+            assert!(filesystem.read_file("fancy").await.is_none());
+        });
+    }
+}
@@ -0,0 +1,68 @@
+//! Encoding of file attributes
+//!
+//! The attributes of a file are stored as a single metadata value of the storage. The key is derived
+//! from the name of the file, so attributes stay with a file when it is rewritten.
+//!
+//! The value is encoded as `count: u8, (key_length: u8, key, value_length: u16, value)*`. An empty
+//! value means that the file has no attributes.
+
+/// Attributes of a single file
+pub(crate) type Attributes = Vec<(String, Vec<u8>)>;
+
+/// Metadata key of the attributes of the file with the given name
+///
+/// Keys need to be short enough for the NVS (15 characters), so a part of the hash of the name is used.
+pub(crate) fn metadata_key(name: &str) -> String {
+    let hash = blake3::hash(name.as_bytes());
+    hash.as_bytes()[..6]
+        .iter()
+        .fold(String::from("at_"), |mut key, byte| {
+            key.push_str(&format!("{:02x}", byte));
+            key
+        })
+}
+
+/// Encode attributes. Keys and values need to fit their length fields.
+pub(crate) fn encode(attributes: &[(String, Vec<u8>)]) -> Vec<u8> {
+    if attributes.is_empty() {
+        return Vec::new();
+    }
+    let mut encoded = vec![attributes.len() as u8];
+    for (key, value) in attributes {
+        encoded.push(key.len() as u8);
+        encoded.extend_from_slice(key.as_bytes());
+        encoded.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        encoded.extend_from_slice(value);
+    }
+    encoded
+}
+
+/// Decode attributes written by [encode]. Stops at the first broken entry.
+pub(crate) fn decode(bytes: &[u8]) -> Attributes {
+    let mut attributes = Vec::new();
+    let Some((&count, mut rest)) = bytes.split_first() else {
+        return attributes;
+    };
+    for _ in 0..count {
+        let Some((&key_length, after_length)) = rest.split_first() else {
+            break;
+        };
+        let Some((key, after_key)) = after_length.split_at_checked(key_length as usize) else {
+            break;
+        };
+        let Some((value_length, after_value_length)) = after_key.split_first_chunk::<2>() else {
+            break;
+        };
+        let Some((value, after_value)) =
+            after_value_length.split_at_checked(u16::from_le_bytes(*value_length) as usize)
+        else {
+            break;
+        };
+        let Ok(key) = std::str::from_utf8(key) else {
+            break;
+        };
+        attributes.push((key.to_string(), value.to_vec()));
+        rest = after_value;
+    }
+    attributes
+}
@@ -0,0 +1,169 @@
+//! Mount a filesystem with non-default options
+//!
+//! [Filesystem::new] and [Filesystem::mount_readonly] cover the common cases. [FilesystemBuilder]
+//! is for everything else, like checking the content of every file during mount. Mounting with a
+//! report returns a [MountReport] with diagnostics about the mount.
+//!
+//! ```
+//! use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+//! use rudelblinken_filesystem::Filesystem;
+//!
+//! let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+//! let (_filesystem, report) = Filesystem::builder(storage)
+//!     .verify_on_mount(true)
+//!     .mount_with_report();
+//! assert_eq!(report.files_found, 0);
+//! assert!(report.degraded.is_empty());
+//! ```
+
+use crate::{storage::Storage, Filesystem};
+
+/// Options for mounting a [Filesystem]
+///
+/// Created with [Filesystem::builder].
+pub struct FilesystemBuilder<T: Storage + 'static + Send + Sync> {
+    pub(crate) storage: &'static T,
+    pub(crate) read_only: bool,
+    pub(crate) verify_on_mount: bool,
+    pub(crate) reserved_blocks: u32,
+    pub(crate) max_files: Option<usize>,
+    pub(crate) wear_threshold: Option<u32>,
+    #[cfg(feature = "signing")]
+    pub(crate) trusted_keys: Option<Vec<ed25519_dalek::VerifyingKey>>,
+}
+
+impl<T: Storage + 'static + Send + Sync> FilesystemBuilder<T> {
+    pub(crate) fn new(storage: &'static T) -> Self {
+        Self {
+            storage,
+            read_only: false,
+            verify_on_mount: false,
+            reserved_blocks: 1,
+            max_files: None,
+            wear_threshold: None,
+            #[cfg(feature = "signing")]
+            trusted_keys: None,
+        }
+    }
+
+    /// Never write to the storage, see [Filesystem::mount_readonly]
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Recompute the hash of every file during mount
+    ///
+    /// Files whose content does not match the hash in their header are degraded. They can not be read,
+    /// but still occupy their blocks until they are deleted. Their names are returned by
+    /// [Filesystem::degraded_files].
+    ///
+    /// Off by default, as hashing every file slows down booting. Turn it on for tooling and
+    /// filesystem checks.
+    pub fn verify_on_mount(mut self, verify: bool) -> Self {
+        self.verify_on_mount = verify;
+        self
+    }
+
+    /// Keep `blocks` blocks free that new files may not use
+    ///
+    /// Compaction moves files into free space, so it can not make progress on a completely full
+    /// filesystem. The reserved blocks guarantee that it can always move files of that size. Writing,
+    /// truncating and appending files fail with an out of space error instead of using them.
+    /// [Filesystem::free_space] does not include them. Defaults to 1 block.
+    pub fn reserved_blocks(mut self, blocks: u32) -> Self {
+        self.reserved_blocks = blocks;
+        self
+    }
+
+    /// Limit the number of files
+    ///
+    /// The file table is allocated once with space for `max_files` entries, so it does not grow and
+    /// fragment the heap later. Creating more files fails with
+    /// [FilesystemWriteError::TooManyFiles](crate::FilesystemWriteError::TooManyFiles). Files that are
+    /// deleted but still read count until their last reader is dropped.
+    ///
+    /// Mount stops scanning once the table is full. Files after that are not visible until the
+    /// filesystem is mounted with a larger limit.
+    ///
+    /// On the ESP32-C3 every file takes roughly 40 bytes in the table, about 80 bytes of shared state
+    /// on the heap, and its name. Unlimited by default.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Level the wear of blocks that were erased `threshold` times more than the average
+    ///
+    /// Needs a storage that counts erases, see [Storage::erase_count]. When the place a new file would
+    /// normally go contains such a hot block, the file is put on the coldest free blocks instead.
+    /// Compaction moves files off the coldest blocks onto hot free blocks, so blocks that hold files
+    /// that never change get erased too. Both cost extra writes, so a lower threshold levels more
+    /// evenly but wears the flash faster overall. Off by default.
+    pub fn wear_threshold(mut self, threshold: u32) -> Self {
+        self.wear_threshold = Some(threshold);
+        self
+    }
+
+    /// Only allow reading files with a signature of one of the `trusted_keys`
+    ///
+    /// Files need to be written with [Filesystem::write_file_signed]. Their content also needs to match the
+    /// hash in their header. Other files can still be listed in the block map and deleted, but every way
+    /// to read them treats them as missing. [Filesystem::open] fails with
+    /// [FilesystemReadError::UntrustedFile](crate::FilesystemReadError::UntrustedFile).
+    #[cfg(feature = "signing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "signing")))]
+    pub fn verify_signatures(
+        mut self,
+        trusted_keys: impl IntoIterator<Item = ed25519_dalek::VerifyingKey>,
+    ) -> Self {
+        self.trusted_keys = Some(trusted_keys.into_iter().collect());
+        self
+    }
+
+    /// Mount the filesystem with these options
+    pub fn mount(self) -> Filesystem<T> {
+        Filesystem::mount(self).0
+    }
+
+    /// Mount the filesystem and return what happened during mount
+    pub fn mount_with_report(self) -> (Filesystem<T>, MountReport) {
+        Filesystem::mount(self)
+    }
+}
+
+/// What happened while mounting a filesystem
+///
+/// Useful as telemetry to notice devices whose storage slowly degrades.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountReport {
+    /// Number of readable files
+    pub files_found: usize,
+    /// Number of blocks that contained data, but did not belong to a file. They are erased, unless the
+    /// filesystem is mounted read-only.
+    pub corrupt_skipped: u32,
+    /// Number of the blocks in `corrupt_skipped` that could not be erased. Erasing them is tried again
+    /// on the next mount.
+    pub erase_failed: u32,
+    /// Set if an interrupted transaction was completed
+    pub wal_replayed: bool,
+    /// Set if the files were found with the index instead of scanning all blocks
+    pub index_used: bool,
+    /// Set if an index was stored, but it was broken or did not match the storage, so all blocks were
+    /// scanned instead. Not set if there was no index at all.
+    pub scan_fallback: bool,
+    /// Names of the files whose content did not match their hash, see
+    /// [FilesystemBuilder::verify_on_mount]
+    pub degraded: Vec<String>,
+}
+
+/// How the files were found during mount, see [Filesystem::mount_source]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountSource {
+    /// The index stored in the metadata was used
+    Index,
+    /// All blocks were scanned, because the index was missing, damaged or outdated
+    Scan,
+    /// An interrupted transaction was completed, so all blocks were scanned
+    WalReplay,
+}
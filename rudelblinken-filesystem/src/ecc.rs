@@ -0,0 +1,49 @@
+//! Single-bit error correction for file content
+//!
+//! The code is a plain Hamming style code over the whole file: the syndrome is the XOR of the indices
+//! of all set bits and the parity is the number of set bits modulo 2. Both are stored in the file
+//! header when the file is committed.
+//!
+//! Flipping a single bit at index `i` flips the parity and changes the syndrome by `i`, so the
+//! position of the flipped bit is the XOR of the stored and the recomputed syndrome. Two flipped bits
+//! keep the parity intact, but change the syndrome. They can be detected, but not corrected.
+
+/// Result of checking file content against its stored code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EccCheck {
+    /// The content matches the stored code
+    Ok,
+    /// A single bit was flipped. Contains the index of the bit, counted from the start of the content
+    SingleBitError { bit: u32 },
+    /// More than one bit was flipped
+    Uncorrectable,
+}
+
+/// Compute the syndrome and the parity of some content
+pub(crate) fn compute(content: &[u8]) -> (u32, bool) {
+    let mut syndrome: u32 = 0;
+    let mut parity = false;
+    for (byte_index, byte) in content.iter().enumerate() {
+        if *byte == 0 {
+            continue;
+        }
+        parity ^= byte.count_ones() % 2 == 1;
+        for bit in 0..8 {
+            if byte & (1 << bit) != 0 {
+                syndrome ^= (byte_index as u32) * 8 + bit;
+            }
+        }
+    }
+    (syndrome, parity)
+}
+
+/// Check content against the stored syndrome and parity
+pub(crate) fn check(content: &[u8], syndrome: u32, parity: bool) -> EccCheck {
+    let (computed_syndrome, computed_parity) = compute(content);
+    let difference = computed_syndrome ^ syndrome;
+    match (computed_parity == parity, difference) {
+        (true, 0) => EccCheck::Ok,
+        (false, bit) if (bit as usize) < content.len() * 8 => EccCheck::SingleBitError { bit },
+        _ => EccCheck::Uncorrectable,
+    }
+}
@@ -1,6 +1,6 @@
 /// [File] provides a safe interface to read and write files.
 use crate::{
-    file_metadata::{FileMetadata, ReadMetadataError, WriteMetadataError},
+    file_metadata::{FileMetadata, ReadMetadataError, WriteMetadataError, HEADER_SIZE},
     storage::{EraseStorageError, Storage, StorageError},
 };
 use std::{
@@ -8,7 +8,7 @@ use std::{
     io::{SeekFrom, Write},
     ops::Deref,
     ptr::NonNull,
-    sync::RwLock,
+    sync::{OnceLock, RwLock},
 };
 use std::{io::Seek, marker::ConstParamTy};
 use thiserror::Error;
@@ -16,6 +16,7 @@ use zerocopy::IntoBytes;
 
 /// Represents an error that can occur while reading a file.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ReadFileError {
     /// Error occurred in the storage layer.
     #[error(transparent)]
@@ -33,6 +34,7 @@ pub enum ReadFileError {
 
 /// Represents an error that can occur while writing a file.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum WriteFileError {
     /// Error occurred in the storage layer.
     #[error(transparent)]
@@ -53,6 +55,7 @@ pub enum WriteFileError {
 
 /// Represents an error that can occur while reading a file from storage.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ReadFileFromStorageError {
     /// Error occurred while reading metadata.
     #[error(transparent)]
@@ -64,6 +67,7 @@ pub enum ReadFileFromStorageError {
 
 /// Represents an error that can occur while writing a file to storage.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum WriteFileToStorageError {
     /// Error occurred while writing metadata.
     #[error(transparent)]
@@ -74,7 +78,8 @@ pub enum WriteFileToStorageError {
 }
 
 /// Represents an error that can occur while upgrading a file
-#[derive(Error, Debug, Clone)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum UpgradeFileError {
     /// Only weak references and readers can be upgraded.
     #[error("Only weak references and readers can be upgraded.")]
@@ -92,6 +97,7 @@ pub enum UpgradeFileError {
 
 /// Represents an error that can occur while deleting file content.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum DeleteFileContentError {
     /// Error occurred while erasing storage.
     #[error(transparent)]
@@ -100,6 +106,7 @@ pub enum DeleteFileContentError {
 
 /// Represents an error that can occur while committing file content.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum CommitFileContentError {
     /// Error occurred in the storage layer.
     #[error(transparent)]
@@ -135,6 +142,8 @@ struct InnerFile<T: Storage + 'static + Send + Sync> {
     // We need to track this in memory because the flags in memory-mapped flash will be reset when a new file is created in the same place
     /// Set if the file has been deleted.
     has_been_deleted: bool,
+    /// Hash of the content, computed on first request.
+    computed_hash: OnceLock<[u8; 32]>,
 }
 
 impl<T: Storage + 'static + Send + Sync> std::fmt::Debug for InnerFile<T> {
@@ -226,6 +235,7 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
                 current_offset: 0,
                 transition: Box::new(transition),
                 has_been_deleted: false,
+                computed_hash: OnceLock::new(),
             }))),
         };
 
@@ -257,7 +267,7 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
     ) -> Result<Self, ReadFileFromStorageError> {
         let metadata = FileMetadata::from_storage(storage, address)?;
         let content = storage
-            .read(address + size_of::<FileMetadata>() as u32, metadata.length)
+            .read(address + HEADER_SIZE, metadata.length)
             .map_err(ReadFileError::from)?;
         let file_content =
             File::<T, { FileState::Reader }>::new(content, metadata, storage, address, |_| ())?;
@@ -265,12 +275,10 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
         Ok(file_content)
     }
 
-    /// Get the name of the file as a string slice.
-    pub fn name_str(&self) -> &str {
-        self.metadata.name_str()
-    }
-
-    /// Get the hash of the file
+    /// Get the hash of the file as stored in its metadata
+    ///
+    /// This is the hash that was supplied when the file was created. It is not checked against the content,
+    /// so do not trust it for anything security relevant. Use [File::verify_hash] for that.
     pub fn hash(&self) -> &[u8; 32] {
         &self.metadata.hash
     }
@@ -317,6 +325,7 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
                 current_offset: 0,
                 transition: Box::new(transition),
                 has_been_deleted: false,
+                computed_hash: OnceLock::new(),
             }))),
         })
     }
@@ -331,7 +340,7 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
     ) -> Result<Self, WriteFileToStorageError> {
         let metadata = FileMetadata::new_to_storage(storage, address, name, length, &hash)?;
         let content = storage
-            .read(address + size_of::<FileMetadata>() as u32, metadata.length)
+            .read(address + HEADER_SIZE, metadata.length)
             .map_err(WriteFileError::from)?;
         let file_content = File::<T, { FileState::Writer }>::new_writer(
             content,
@@ -354,6 +363,14 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
             assert!(info.reader_count == 0);
             info.writer_count = 0;
             info.reader_count = 1;
+            #[cfg(feature = "ecc")]
+            {
+                let (syndrome, parity) = crate::ecc::compute(self.content);
+                unsafe {
+                    self.metadata
+                        .set_ecc(info.storage, info.storage_address, syndrome, parity)?;
+                }
+            }
             unsafe {
                 self.metadata
                     .set_ready(info.storage, info.storage_address)?;
@@ -461,11 +478,68 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
         self.metadata.important()
     }
 
+    /// Get the index of the block that contains the header of the file.
+    pub fn start_block(&self) -> u32 {
+        let info = unsafe { self.info.as_ref().read().unwrap() };
+        info.storage_address / T::BLOCK_SIZE
+    }
+
+    /// Get the length of the content in bytes, without the header.
+    pub fn length_bytes(&self) -> u32 {
+        self.metadata.length
+    }
+
+    /// Get the number of blocks the file occupies, including the block with the header.
+    ///
+    /// See [Filesystem::blocks_for](crate::Filesystem::blocks_for).
+    pub fn block_span(&self) -> u32 {
+        crate::Filesystem::<T>::blocks_for(self.metadata.length)
+    }
+
+    /// Get the index of the last block of the file.
+    ///
+    /// Files can wrap around the end of the storage, so this is lower than [File::start_block] if the
+    /// file continues at the start of the storage.
+    pub fn end_block(&self) -> u32 {
+        (self.start_block() + self.block_span() - 1) % T::BLOCKS
+    }
+
+    /// Get the name of the file as a string slice.
+    pub fn name_str(&self) -> &str {
+        self.metadata.name_str()
+    }
+
     /// Check the age of the file.
     pub fn age(&self) -> u8 {
         self.metadata.age()
     }
 
+    /// Get the stored error correcting code of the file, if it has one.
+    #[cfg(feature = "ecc")]
+    pub(crate) fn ecc(&self) -> Option<(u32, bool)> {
+        self.metadata.ecc()
+    }
+
+    /// Get when the file was created as `(epoch, counter)`, if it is known.
+    ///
+    /// Compare timestamps as tuples: a file with a higher epoch is always newer, and within an epoch the
+    /// higher counter is newer. The counter restarts in a new epoch, which begins when the counter
+    /// overflows or when mount finds that the stored counter went backwards, for example after the
+    /// metadata storage was cleared. Files written before timestamps were introduced return None.
+    pub fn created_at(&self) -> Option<(u16, u16)> {
+        self.metadata.created_at()
+    }
+
+    /// Store when the file was created
+    pub(crate) fn set_created_at(&self, timestamp: (u16, u16)) -> Result<(), WriteMetadataError> {
+        let info = unsafe { self.info.as_ref().read().unwrap() };
+        unsafe {
+            self.metadata
+                .set_created_at(info.storage, info.storage_address, timestamp)?;
+        }
+        Ok(())
+    }
+
     /// Mark the file as important.
     pub fn set_important(&self) -> Result<(), WriteMetadataError> {
         let info = unsafe { self.info.as_ref().read().unwrap() };
@@ -543,11 +617,11 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
             .map_err(EraseStorageError::from)?;
         info.has_been_deleted = true;
 
-        let full_file_length = self.metadata.length + size_of::<FileMetadata>() as u32;
-        let length = full_file_length.div_ceil(T::BLOCK_SIZE) * T::BLOCK_SIZE;
+        let length = crate::Filesystem::<T>::blocks_for(self.metadata.length) * T::BLOCK_SIZE;
 
         // TODO: Make sure the block with the metadata gets erased last
-        info.storage.erase(info.storage_address, length)?;
+        // The whole span is erased at once, files can wrap around the end of the storage
+        info.storage.erase_wrapping(info.storage_address, length)?;
         Ok(())
     }
 
@@ -594,16 +668,86 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
     }
 }
 
+/// The first bytes of a hash as hex, enough to tell files apart in logs
+fn hash_prefix(hash: &[u8; 32]) -> String {
+    hash[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 impl<T: Storage + 'static + Send + Sync, const STATE: FileState> Debug for File<T, STATE> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("FileContent")
-            // .field("content", &self.content)
-            .field("metadata", &self.metadata)
+        f.debug_struct("File")
+            .field("name", &self.name_str())
+            .field("state", &STATE)
+            .field("length", &self.length_bytes())
+            .field("hash", &hash_prefix(&self.metadata.hash))
+            .field("start_block", &self.start_block())
             .field("info", unsafe { self.info.as_ref() })
             .finish()
     }
 }
 
+impl<T: Storage + 'static + Send + Sync, const STATE: FileState> std::fmt::Display
+    for File<T, STATE>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes at block {}, hash {})",
+            self.name_str(),
+            self.length_bytes(),
+            self.start_block(),
+            hash_prefix(&self.metadata.hash)
+        )
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
+    /// Get the blake3 hash of the content
+    ///
+    /// The hash is computed from the content on the first call and cached for all handles to this file.
+    /// Unlike [File::hash], it is computed from the content, but it does not notice if the content on the
+    /// storage changes afterwards, for example because bits flipped. Use [File::verify_hash] to check the
+    /// content, it always hashes the content again.
+    pub fn computed_hash(&self) -> [u8; 32] {
+        let info = unsafe { self.info.as_ref() };
+        if let Some(hash) = info.read().unwrap().computed_hash.get() {
+            return *hash;
+        }
+        // Hash without holding the lock, as this can take a while for large files
+        let hash: [u8; 32] = blake3::hash(self.content).into();
+        *info.read().unwrap().computed_hash.get_or_init(|| hash)
+    }
+
+    /// Check if the content matches the hash stored in the metadata
+    ///
+    /// The content is hashed on every call, so changes of the content on the storage are noticed.
+    pub fn verify_hash(&self) -> bool {
+        <[u8; 32]>::from(blake3::hash(self.content)) == *self.hash()
+    }
+
+    /// Get the content for executing it in place, without copying it into RAM
+    ///
+    /// Returns None if the file wraps around the end of the storage. The content of such a file is only
+    /// contiguous because the storage is mapped twice in a row, and the part after the end is read
+    /// through the second mapping. Copy these files into RAM before instantiating them.
+    ///
+    /// The slice starts 64 bytes into the first block of the file, so it is aligned to 64 bytes, which is
+    /// enough for WASM modules. It borrows this reader, which keeps the blocks of the file from being
+    /// erased, so it stays valid even if the file is deleted. Drop the instance that uses the slice
+    /// before the reader.
+    pub fn executable_slice(&self) -> Option<&[u8]> {
+        let info = unsafe { self.info.as_ref().read().unwrap() };
+        let end = info.storage_address as u64 + HEADER_SIZE as u64 + self.content.len() as u64;
+        if end > T::BLOCKS as u64 * T::BLOCK_SIZE as u64 {
+            return None;
+        }
+        Some(self.content)
+    }
+}
+
 impl<T: Storage + 'static + Send + Sync> Deref for File<T, { FileState::Reader }> {
     type Target = [u8];
 
@@ -646,13 +790,18 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> Drop for File<T
     fn drop(&mut self) {
         let mut info = unsafe { self.info.as_ref().write().unwrap() };
 
+        // Every handle was counted when it was created. Saturate anyway, an underflow would free
+        // the shared info while other handles still use it.
         if STATE == { FileState::Weak } {
+            debug_assert!(info.weak_count > 0, "weak count underflow");
             info.weak_count = info.weak_count.saturating_sub(1);
         }
         if STATE == { FileState::Writer } {
+            debug_assert!(info.writer_count > 0, "writer count underflow");
             info.writer_count = info.writer_count.saturating_sub(1);
         }
         if STATE == { FileState::Reader } {
+            debug_assert!(info.reader_count > 0, "reader count underflow");
             info.reader_count = info.reader_count.saturating_sub(1);
         }
 
@@ -732,12 +881,19 @@ impl<T: Storage + 'static + Send + Sync> Write for File<T, { FileState::Writer }
         let write_length = std::cmp::min(remaining_length, buf.len() as u32);
 
         let writable_storage = info.storage;
+        // Files can wrap around the end of the storage, so the write may need to be split
+        let storage_size = T::BLOCKS * T::BLOCK_SIZE;
+        let address = (info.storage_address + HEADER_SIZE + current_offset) % storage_size;
+        let (before_end, after_end) = buf[0..write_length as usize]
+            .split_at(std::cmp::min(write_length, storage_size - address) as usize);
         writable_storage
-            .write(
-                info.storage_address + size_of::<FileMetadata>() as u32 + current_offset,
-                &buf[0..write_length as usize],
-            )
+            .write(address, before_end)
             .map_err(std::io::Error::other)?;
+        if !after_end.is_empty() {
+            writable_storage
+                .write(0, after_end)
+                .map_err(std::io::Error::other)?;
+        }
         info.current_offset += write_length;
         Ok(write_length as usize)
     }
@@ -768,9 +924,7 @@ mod tests {
         ))
         .unwrap();
         unsafe { metadata.set_ready(backing_storage, 0) }.unwrap();
-        let content: &'static [u8] = &backing_storage
-            .read(size_of::<FileMetadata>() as u32, 100)
-            .unwrap();
+        let content: &'static [u8] = &backing_storage.read(HEADER_SIZE, 100).unwrap();
         let content_ptr = content.as_ptr() as *mut u8;
         let mut_content: &'static mut [u8] =
             unsafe { std::slice::from_raw_parts_mut(content_ptr, 100) };
@@ -831,6 +985,28 @@ mod tests {
         assert!(File::is_last(&other_content));
     }
 
+    #[test]
+    fn verify_hash_notices_changed_content() {
+        let storage = get_test_storage();
+        let expected_hash: [u8; 32] = blake3::hash(&[0xff; 100]).into();
+        let metadata =
+            FileMetadata::new_to_storage(storage, 0, "toast", 100, &expected_hash).unwrap();
+        unsafe { metadata.set_ready(storage, 0) }.unwrap();
+        let content = storage.read(HEADER_SIZE, 100).unwrap();
+        let content_ptr = content.as_ptr() as *mut u8;
+        let content: &'static mut [u8] =
+            unsafe { std::slice::from_raw_parts_mut(content_ptr, 100) };
+        let file =
+            File::<_, { FileState::Reader }>::new(content, metadata, storage, 0, |_| ()).unwrap();
+        assert_eq!(file.computed_hash(), expected_hash);
+        assert!(file.verify_hash());
+
+        // Changing the content behind the files back is noticed, even though the hash was computed before
+        unsafe { *content_ptr = 17 };
+        assert!(!file.verify_hash());
+        assert!(!file.clone().verify_hash());
+    }
+
     #[test]
     fn downgrading_works() {
         let content = call_new();
@@ -8,15 +8,21 @@ use std::fmt::Formatter;
 
 /// Internal proxy for a file that tracks some metadata in memory
 pub(crate) struct FileInformation<T: Storage + 'static + Send + Sync> {
-    /// Starting address of the file (in flash)
+    /// Byte address of the header of the file in the storage. Always at the start of a block.
     pub address: u32,
-    /// Length of the files content in bytes
+    /// Length of the files content in bytes, without the header
     pub length: u32,
     /// Name of the file
     pub name: String,
     /// Content of the file
     /// Will be None if the file has been deleted
     content: File<T, { FileState::Weak }>,
+    /// Set if the content did not match the hash during mount. Degraded files can not be read.
+    pub degraded: bool,
+    /// Whether the signature of the file is valid for a trusted key, once it was checked. Reset when the
+    /// attributes of the file change.
+    #[cfg(feature = "signing")]
+    pub signature_trusted: std::sync::OnceLock<bool>,
 }
 
 impl<T: Storage + 'static + Send + Sync> Clone for FileInformation<T> {
@@ -26,6 +32,9 @@ impl<T: Storage + 'static + Send + Sync> Clone for FileInformation<T> {
             length: self.length,
             name: self.name.clone(),
             content: self.content.clone(),
+            degraded: self.degraded,
+            #[cfg(feature = "signing")]
+            signature_trusted: self.signature_trusted.clone(),
         }
     }
 }
@@ -37,6 +46,7 @@ impl<T: Storage + 'static + Send + Sync> std::fmt::Debug for FileInformation<T>
             .field("length", &self.length)
             .field("name", &self.name)
             .field("content", &self.content)
+            .field("degraded", &self.degraded)
             .finish()
     }
 }
@@ -44,11 +54,12 @@ impl<T: Storage + 'static + Send + Sync> std::fmt::Debug for FileInformation<T>
 impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
     /// Read a file from storage.
     ///
-    /// address is an address that can be used with storage
+    /// address is the byte address of the first block of the file
     pub fn from_storage(
         storage: &'static T,
         address: u32,
     ) -> Result<FileInformation<T>, ReadFileFromStorageError> {
+        Self::debug_assert_block_address(address);
         let file_content = File::<T, { FileState::Reader }>::from_storage(storage, address)?;
 
         let information = FileInformation {
@@ -56,6 +67,9 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
             length: file_content.len() as u32,
             name: file_content.name_str().into(),
             content: file_content.downgrade(),
+            degraded: false,
+            #[cfg(feature = "signing")]
+            signature_trusted: Default::default(),
         };
 
         Ok(information)
@@ -69,6 +83,7 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
         name: &str,
         hash: &[u8; 32],
     ) -> Result<(Self, File<T, { FileState::Writer }>), WriteFileToStorageError> {
+        Self::debug_assert_block_address(address);
         let file_content =
             File::<T, { FileState::Writer }>::to_storage(storage, address, length, name, hash)?;
 
@@ -77,10 +92,30 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
             length,
             name: name.into(),
             content: file_content.downgrade(),
+            degraded: false,
+            #[cfg(feature = "signing")]
+            signature_trusted: Default::default(),
         };
         Ok((information, file_content))
     }
 
+    /// Catch block indices and unaligned offsets passed as the address of a file
+    fn debug_assert_block_address(address: u32) {
+        debug_assert!(
+            address.is_multiple_of(T::BLOCK_SIZE),
+            "file address {address} is not at the start of a block"
+        );
+        debug_assert!(
+            address / T::BLOCK_SIZE < T::BLOCKS,
+            "file address {address} is outside of the storage"
+        );
+    }
+
+    /// Index of the block that contains the header of the file
+    pub fn start_block(&self) -> u32 {
+        self.address / T::BLOCK_SIZE
+    }
+
     /// Transition to ready by reading content from storage
     pub fn mark_for_deletion(&self) -> Result<(), DeleteFileContentError> {
         self.content.mark_for_deletion()
@@ -98,7 +133,7 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
 
     /// Check if the file is ready to be read
     pub fn valid(&self) -> bool {
-        self.content.ready()
+        self.content.ready() && !self.degraded
     }
 
     /// Check if the file is important
@@ -111,6 +146,11 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
         self.content.age()
     }
 
+    /// Get the creation timestamp of the file
+    pub fn created_at(&self) -> Option<(u16, u16)> {
+        self.content.created_at()
+    }
+
     /// Check if the file is important
     pub fn can_be_deleted(&self) -> bool {
         self.content.can_be_deleted()
@@ -123,6 +163,15 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
         self.content.compare_hash(hash)
     }
 
+    /// Check if the content matches the stored hash
+    ///
+    /// Returns false, if the file can not be read
+    pub fn verify(&self) -> bool {
+        self.content
+            .upgrade()
+            .is_ok_and(|content| content.verify_hash())
+    }
+
     /// Read the file content
     pub fn read(&self) -> File<T, { FileState::Weak }> {
         self.content.clone()
@@ -19,7 +19,10 @@
 //! is located at a specific address in storage. Undefined behavior may occur if these
 //! assumptions are violated. Use these methods with caution and ensure that the metadata
 //! is correctly memory-mapped before calling them.
-use crate::storage::{Storage, StorageError};
+use crate::{
+    storage::{Storage, StorageError},
+    MAX_NAME_LEN,
+};
 use thiserror::Error;
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
@@ -30,6 +33,8 @@ pub enum ReadMetadataError {
     InvalidMarkers,
     #[error("Failed to interpret the storage as metadata: {0}")]
     FailedToInterpretStorageAsMetadata(String),
+    #[error("The file with a length of {0} bytes does not fit into the storage")]
+    LengthTooBig(u32),
     #[error(transparent)]
     StorageError(#[from] StorageError),
 }
@@ -54,8 +59,25 @@ impl FileFlags {
     const DELETED: u16 =             0b0000000001000000;
     /// Important files wont be deleted automatically if space is needed
     const IMPORTANT: u16 =           0b0000000010000000;
+    /// The padding contains the syndrome of the error correcting code
+    #[cfg(feature = "ecc")]
+    const HAS_ECC: u16 =             0b0000000100000000;
+    /// Parity bit of the error correcting code. Cleared if the content has an odd number of set bits
+    #[cfg(feature = "ecc")]
+    const ECC_PARITY: u16 =          0b0000010000000000;
 }
 
+/// Size of the header in front of the content of every file, in bytes
+///
+/// Changes to [FileMetadata] need to keep this size, otherwise existing filesystems can not be mounted
+/// anymore. New fields can use the padding at the end.
+pub const HEADER_SIZE: u32 = 64;
+
+const _: () = assert!(
+    size_of::<FileMetadata>() == HEADER_SIZE as usize,
+    "The fields of FileMetadata need to fit exactly into HEADER_SIZE bytes"
+);
+
 /// Represents a the metadata segment of a file that is memory-mapped into storage.
 ///
 /// Read an existing metadata segment at an address with [from_storage] or place a new one with [new_from_storage]
@@ -72,8 +94,12 @@ pub struct FileMetadata {
     /// SHA3-256 hash of the file
     pub hash: [u8; 32],
     /// Name of the file, null terminated or 16 chars
-    pub name: [u8; 16],
-    /// Reserved space to fill the metadata to 64 byte
+    pub name: [u8; MAX_NAME_LEN],
+    /// Reserved space to fill the metadata to [HEADER_SIZE] bytes
+    ///
+    /// Left erased (0xff) on creation, so it can still be written later. The first four bytes hold
+    /// the syndrome of the error correcting code, if enabled. The last four bytes hold the creation
+    /// timestamp.
     _padding: [u8; 8],
 }
 
@@ -104,8 +130,8 @@ impl FileMetadata {
             age: u16::MAX,
             length,
             hash: *hash,
-            name: [0; 16],
-            _padding: [0; 8],
+            name: [0; MAX_NAME_LEN],
+            _padding: [0xff; 8],
         };
         metadata.set_name(name);
         metadata
@@ -122,13 +148,17 @@ impl FileMetadata {
     }
     /// Convenience function to get the name as a string slice
     pub fn name_str(&self) -> &str {
-        let nul_range_end = self.name.iter().position(|&c| c == b'\0').unwrap_or(16);
+        let nul_range_end = self
+            .name
+            .iter()
+            .position(|&c| c == b'\0')
+            .unwrap_or(MAX_NAME_LEN);
         std::str::from_utf8(&self.name[0..nul_range_end]).unwrap_or_default()
     }
     /// Internal function to set the name from a string slice
     fn set_name(&mut self, name: &str) {
         let name_bytes = name.as_bytes();
-        let name_length = name.len().clamp(0, 16);
+        let name_length = name.len().min(MAX_NAME_LEN);
         self.name[0..name_length].copy_from_slice(&name_bytes[0..name_length]);
     }
 
@@ -201,6 +231,70 @@ impl FileMetadata {
         self.set_flags(storage, address, FileFlags::IMPORTANT)
     }
 
+    /// Store the error correcting code of the content in storage
+    ///
+    /// Assumes that this metadata is located at `address`. Undefined behaviour if it is not or has since been deleted
+    #[cfg(feature = "ecc")]
+    pub unsafe fn set_ecc<T: Storage>(
+        &self,
+        storage: &T,
+        address: u32,
+        syndrome: u32,
+        parity: bool,
+    ) -> Result<(), StorageError> {
+        storage.write(
+            address + std::mem::offset_of!(FileMetadata, _padding) as u32,
+            &syndrome.to_le_bytes(),
+        )?;
+        let mut flags = FileFlags::HAS_ECC;
+        if parity {
+            flags |= FileFlags::ECC_PARITY;
+        }
+        self.set_flags(storage, address, flags)
+    }
+
+    /// Get the syndrome and parity of the error correcting code, if the file has one
+    #[cfg(feature = "ecc")]
+    pub fn ecc(&self) -> Option<(u32, bool)> {
+        if self.flags & FileFlags::HAS_ECC != 0 {
+            return None;
+        }
+        let syndrome = u32::from_le_bytes(self._padding[0..4].try_into().unwrap());
+        Some((syndrome, self.flags & FileFlags::ECC_PARITY == 0))
+    }
+
+    /// Store the creation timestamp in storage
+    ///
+    /// Assumes that this metadata is located at `address`. Undefined behaviour if it is not or has since been deleted
+    pub unsafe fn set_created_at<T: Storage>(
+        &self,
+        storage: &T,
+        address: u32,
+        (epoch, counter): (u16, u16),
+    ) -> Result<(), StorageError> {
+        let mut timestamp = [0u8; 4];
+        timestamp[0..2].copy_from_slice(&epoch.to_le_bytes());
+        timestamp[2..4].copy_from_slice(&counter.to_le_bytes());
+        storage.write(
+            address + std::mem::offset_of!(FileMetadata, _padding) as u32 + 4,
+            &timestamp,
+        )
+    }
+
+    /// Get the creation timestamp, if the file has one
+    ///
+    /// Headers written before timestamps were introduced have zeros in their place, headers of files
+    /// whose timestamp was not stored yet are still erased. Neither is a timestamp, the filesystem never
+    /// hands out `(0, 0)`.
+    pub fn created_at(&self) -> Option<(u16, u16)> {
+        if self._padding[4..8] == [0xff; 4] || self._padding[4..8] == [0; 4] {
+            return None;
+        }
+        let epoch = u16::from_le_bytes(self._padding[4..6].try_into().unwrap());
+        let counter = u16::from_le_bytes(self._padding[6..8].try_into().unwrap());
+        Some((epoch, counter))
+    }
+
     /// Check if the file is ready to be read
     pub fn ready(&self) -> bool {
         self.flags & FileFlags::READY == 0
@@ -248,13 +342,17 @@ impl FileMetadata {
         storage: &T,
         address: u32,
     ) -> Result<&'static Self, ReadMetadataError> {
-        let data = storage.read(address, size_of::<FileMetadata>() as u32)?;
+        let data = storage.read(address, HEADER_SIZE)?;
 
         let metadata = FileMetadata::ref_from_bytes(data)
             .map_err(|e| ReadMetadataError::FailedToInterpretStorageAsMetadata(e.to_string()))?;
         if !metadata.valid_marker() {
             return Err(ReadMetadataError::InvalidMarkers);
         }
+        // A corrupted length would make the file overlap itself after wrapping around
+        if HEADER_SIZE as u64 + metadata.length as u64 > T::BLOCKS as u64 * T::BLOCK_SIZE as u64 {
+            return Err(ReadMetadataError::LengthTooBig(metadata.length));
+        }
         Ok(metadata)
     }
 }
@@ -290,4 +388,45 @@ mod tests {
         assert_eq!(read_metadata.name_str(), "toast");
         assert!(read_metadata.valid_marker());
     }
+
+    #[test]
+    fn headers_with_zero_padding_have_no_creation_time() {
+        let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+        let content = [1u8; 100];
+        let metadata =
+            FileMetadata::new_to_storage(storage, 0, "old", 100, blake3::hash(&content).as_bytes())
+                .unwrap();
+        // Like the headers written before timestamps were introduced
+        let padding = std::mem::offset_of!(FileMetadata, _padding) as u32;
+        storage.write(padding, &[0; 8]).unwrap();
+        storage.write(HEADER_SIZE, &content).unwrap();
+        unsafe { metadata.set_ready(storage, 0) }.unwrap();
+        assert_eq!(metadata.created_at(), None);
+
+        let mut filesystem = crate::Filesystem::new(storage);
+        let old = filesystem.read_file("old").unwrap();
+        assert_eq!(old.created_at(), None);
+        assert!(filesystem.verify_file("old").unwrap());
+        filesystem.write_file("new", &[2], &[0; 32]).unwrap();
+        let new = filesystem.read_file("new").unwrap().created_at();
+        assert!(new.is_some_and(|timestamp| timestamp != (0, 0)));
+    }
+
+    #[test]
+    fn reading_metadata_with_a_length_larger_than_the_storage_fails() {
+        let storage = SimulatedStorage::new();
+        let size = SimulatedStorage::BLOCKS * SimulatedStorage::BLOCK_SIZE;
+        let _ = FileMetadata::new_to_storage(&storage, 0, "toast", size, &[0; 32]).unwrap();
+        assert!(matches!(
+            FileMetadata::from_storage(&storage, 0),
+            Err(ReadMetadataError::LengthTooBig(length)) if length == size
+        ));
+
+        // A file that fills the whole storage is still valid
+        let address = SimulatedStorage::BLOCK_SIZE;
+        let _ =
+            FileMetadata::new_to_storage(&storage, address, "toast", size - HEADER_SIZE, &[0; 32])
+                .unwrap();
+        FileMetadata::from_storage(&storage, address).unwrap();
+    }
 }
@@ -0,0 +1,194 @@
+//! Names of files that are known to fit into the header
+
+use thiserror::Error;
+
+/// Maximum length of the name of a file in bytes
+pub const MAX_NAME_LEN: usize = 16;
+
+/// Errors that can occur when checking the name of a file
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileNameError {
+    /// The name does not fit into the header of a file
+    #[error("The name is {length} bytes long, but only {MAX_NAME_LEN} bytes fit into the header")]
+    NameTooLong {
+        /// Length of the name in bytes
+        length: usize,
+    },
+    /// The name contains a NUL byte, which marks the end of the name in the header
+    #[error("The name contains a NUL byte")]
+    ContainsNul,
+}
+
+/// A name that fits into the header of a file
+///
+/// The name of a file is stored in a slot of [MAX_NAME_LEN] bytes in its header. A [FileName] is checked
+/// once when it is created and can then be passed to every function that creates a file.
+///
+/// [FileName::from_static] is a `const fn`, so names that are known in advance are checked when
+/// compiling:
+///
+/// ```
+/// use rudelblinken_filesystem::FileName;
+///
+/// const MAIN: FileName = FileName::from_static("main");
+/// assert_eq!(MAIN.as_str(), "main");
+/// assert!(FileName::new("a name that is far too long").is_err());
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileName {
+    /// The name padded with zeros, like in the header
+    bytes: [u8; MAX_NAME_LEN],
+    length: u8,
+}
+
+impl FileName {
+    /// Check that `name` fits into the header of a file
+    pub const fn new(name: &str) -> Result<Self, FileNameError> {
+        let source = name.as_bytes();
+        if source.len() > MAX_NAME_LEN {
+            return Err(FileNameError::NameTooLong {
+                length: source.len(),
+            });
+        }
+        let mut bytes = [0; MAX_NAME_LEN];
+        let mut index = 0;
+        while index < source.len() {
+            if source[index] == 0 {
+                return Err(FileNameError::ContainsNul);
+            }
+            bytes[index] = source[index];
+            index += 1;
+        }
+        Ok(Self {
+            bytes,
+            length: source.len() as u8,
+        })
+    }
+
+    /// Create a name from a constant
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is not valid, see [FileName::new]. In a `const`, this is a compile error.
+    pub const fn from_static(name: &'static str) -> Self {
+        match Self::new(name) {
+            Ok(name) => name,
+            Err(_) => panic!("The name does not fit into the header of a file"),
+        }
+    }
+
+    /// The name as a string slice
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.length as usize])
+            .expect("The name was copied from a string slice")
+    }
+}
+
+impl std::ops::Deref for FileName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for FileName {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl std::fmt::Debug for FileName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl std::fmt::Display for FileName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl TryFrom<&str> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: &str) -> Result<Self, FileNameError> {
+        Self::new(name)
+    }
+}
+
+impl PartialEq<str> for FileName {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for FileName {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+/// Everything that can be used as the name of a new file
+///
+/// A [FileName] was already checked. Strings are checked when the file is created, so a name that is
+/// too long fails with [FileNameError] wrapped in the error of the operation.
+pub trait IntoFileName {
+    /// Check the name, see [FileName::new]
+    fn into_file_name(self) -> Result<FileName, FileNameError>;
+}
+
+impl IntoFileName for FileName {
+    fn into_file_name(self) -> Result<FileName, FileNameError> {
+        Ok(self)
+    }
+}
+
+impl IntoFileName for &FileName {
+    fn into_file_name(self) -> Result<FileName, FileNameError> {
+        Ok(*self)
+    }
+}
+
+impl IntoFileName for &str {
+    fn into_file_name(self) -> Result<FileName, FileNameError> {
+        FileName::new(self)
+    }
+}
+
+impl IntoFileName for &String {
+    fn into_file_name(self) -> Result<FileName, FileNameError> {
+        FileName::new(self)
+    }
+}
+
+impl IntoFileName for String {
+    fn into_file_name(self) -> Result<FileName, FileNameError> {
+        FileName::new(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_need_to_fit_into_the_header() {
+        let longest = "0123456789abcdef";
+        assert_eq!(FileName::new(longest).unwrap().as_str(), longest);
+        assert_eq!(FileName::new("").unwrap().as_str(), "");
+        assert_eq!(
+            FileName::new("0123456789abcdefg"),
+            Err(FileNameError::NameTooLong { length: 17 })
+        );
+        // Length is counted in bytes, not characters
+        assert_eq!(
+            FileName::new("üüüüüüüüü"),
+            Err(FileNameError::NameTooLong { length: 18 })
+        );
+        assert_eq!(FileName::new("a\0b"), Err(FileNameError::ContainsNul));
+        assert!(FileName::from_static("main") < FileName::from_static("maintenance"));
+    }
+}
@@ -0,0 +1,88 @@
+//! Bulk edits of the information stored about files
+//!
+//! [Filesystem::update_headers](crate::Filesystem::update_headers) passes a [FileHeaderEdit] for every
+//! file to a closure and stores what was changed. The content of the files is never touched.
+//!
+//! Only the parts that can be changed without rewriting a file are editable. The attributes live in
+//! the metadata of the storage, and flags in the header can only be set, because setting a flag clears
+//! bits in flash. The name is stored in the first block next to the content, so renaming a file would
+//! need a copy of the whole file.
+
+use crate::attributes::Attributes;
+
+/// Editable information of a single file, see [header_edit](self)
+#[derive(Debug)]
+pub struct FileHeaderEdit {
+    name: String,
+    important: bool,
+    attributes: Attributes,
+    pub(crate) important_changed: bool,
+    pub(crate) attributes_changed: bool,
+}
+
+impl FileHeaderEdit {
+    pub(crate) fn new(name: &str, important: bool, attributes: Attributes) -> Self {
+        Self {
+            name: name.to_string(),
+            important,
+            attributes,
+            important_changed: false,
+            attributes_changed: false,
+        }
+    }
+
+    /// Name of the file
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Check if the file is marked as important
+    pub fn important(&self) -> bool {
+        self.important
+    }
+
+    /// Mark the file as important. This can not be undone without rewriting the file.
+    pub fn set_important(&mut self) {
+        self.important_changed |= !self.important;
+        self.important = true;
+    }
+
+    /// All attributes of the file
+    pub fn attrs(&self) -> &[(String, Vec<u8>)] {
+        &self.attributes
+    }
+
+    /// Get a single attribute of the file
+    pub fn get_attr(&self, key: &str) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Set an attribute of the file, replacing the old value
+    pub fn set_attr(&mut self, key: &str, value: &[u8]) {
+        if self.get_attr(key) == Some(value) {
+            return;
+        }
+        match self
+            .attributes
+            .iter_mut()
+            .find(|(existing, _)| existing == key)
+        {
+            Some((_, existing_value)) => *existing_value = value.to_vec(),
+            None => self.attributes.push((key.to_string(), value.to_vec())),
+        }
+        self.attributes_changed = true;
+    }
+
+    /// Remove an attribute of the file and return its value
+    pub fn remove_attr(&mut self, key: &str) -> Option<Vec<u8>> {
+        let position = self
+            .attributes
+            .iter()
+            .position(|(existing, _)| existing == key)?;
+        self.attributes_changed = true;
+        Some(self.attributes.remove(position).1)
+    }
+}
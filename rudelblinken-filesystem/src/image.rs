@@ -0,0 +1,304 @@
+//! Build filesystem images on the host
+//!
+//! [FilesystemImageBuilder] creates the content of a flash partition that already contains a set of
+//! files. The image only depends on the added files and their order, so building it twice with the
+//! same input yields the same bytes. This makes it possible to check the hash of an image in CI.
+//!
+//! The files are placed one after another starting at the first block, in the order they were added.
+//! Files added with [FilesystemImageBuilder::add_file_at] are placed at the requested address instead,
+//! for example to execute them in place at a known offset.
+//! The filesystem metadata (like the first block) is not part of the image. A mount of the image
+//! without metadata starts at the first block, which is where the builder placed the first file.
+//!
+//! ```
+//! use rudelblinken_filesystem::image::FilesystemImageBuilder;
+//!
+//! let image = FilesystemImageBuilder::<16, 4096>::new()
+//!     .add_important_file("main", &[1; 100])
+//!     .add_file("assets", &[2; 100])
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(image.len(), 16 * 4096);
+//! ```
+
+use crate::{
+    file_metadata::WriteMetadataError,
+    storage::{arena::RamArena, EraseStorageError, Storage, StorageError},
+    Filesystem, FilesystemWriteError, WriteOptions,
+};
+use std::{collections::BTreeMap, sync::Mutex};
+use thiserror::Error;
+
+/// Errors that can occur while building an image
+#[derive(Error, Debug)]
+pub enum BuildImageError {
+    /// A file could not be written to the image. Probably the image is too small.
+    #[error("Failed to write {name} to the image: {source}")]
+    WriteFile {
+        /// Name of the file
+        name: String,
+        /// Why the file could not be written
+        source: FilesystemWriteError,
+    },
+    /// A file could not be marked as important
+    #[error(transparent)]
+    WriteMetadataError(#[from] WriteMetadataError),
+}
+
+/// A file that will be written to the image
+struct ImageFile {
+    name: String,
+    content: Vec<u8>,
+    important: bool,
+    /// Address of the header, if the file is pinned
+    address: Option<u32>,
+}
+
+/// Builds a byte identical image for every set of input files
+///
+/// `BLOCKS` and `BLOCK_SIZE` need to match the storage the image will be flashed to.
+pub struct FilesystemImageBuilder<const BLOCKS: u32, const BLOCK_SIZE: u32> {
+    files: Vec<ImageFile>,
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Default
+    for FilesystemImageBuilder<BLOCKS, BLOCK_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> FilesystemImageBuilder<BLOCKS, BLOCK_SIZE> {
+    /// Create a builder for an empty image
+    pub fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// Add a file to the image
+    ///
+    /// The hash of the file is the blake3 hash of its content.
+    pub fn add_file(mut self, name: &str, content: &[u8]) -> Self {
+        self.files.push(ImageFile {
+            name: name.to_string(),
+            content: content.to_vec(),
+            important: false,
+            address: None,
+        });
+        self
+    }
+
+    /// Add a file whose header is placed at `address`
+    ///
+    /// The address needs to be the start of a block. The file does not wrap around the end of the
+    /// image. Building fails if the blocks at the address are not free, see
+    /// [WriteOptions::start_hint]. Add pinned files first, so files without an address do not take
+    /// their blocks.
+    pub fn add_file_at(mut self, name: &str, content: &[u8], address: u32) -> Self {
+        self.files.push(ImageFile {
+            name: name.to_string(),
+            content: content.to_vec(),
+            important: false,
+            address: Some(address),
+        });
+        self
+    }
+
+    /// Add a file that is marked as important, so it is never deleted to make space for new files
+    pub fn add_important_file(mut self, name: &str, content: &[u8]) -> Self {
+        self.files.push(ImageFile {
+            name: name.to_string(),
+            content: content.to_vec(),
+            important: true,
+            address: None,
+        });
+        self
+    }
+
+    /// Build the image
+    ///
+    /// Returns the content of the whole partition, `BLOCKS * BLOCK_SIZE` bytes.
+    pub fn build(self) -> Result<Vec<u8>, BuildImageError> {
+        let storage = ImageStorage::<BLOCKS, BLOCK_SIZE>::new();
+        // The filesystem and all files are dropped before the storage
+        let static_storage = unsafe {
+            std::mem::transmute::<
+                &ImageStorage<BLOCKS, BLOCK_SIZE>,
+                &'static ImageStorage<BLOCKS, BLOCK_SIZE>,
+            >(&storage)
+        };
+        {
+            let mut filesystem = Filesystem::new(static_storage);
+            for file in &self.files {
+                let hash = blake3::hash(&file.content);
+                let options = match file.address {
+                    Some(address) => WriteOptions::default().contiguous(true).start_hint(address),
+                    None => WriteOptions::default(),
+                };
+                filesystem
+                    .write_file_with_options(&file.name, &file.content, hash.as_bytes(), options)
+                    .map_err(|source| BuildImageError::WriteFile {
+                        name: file.name.clone(),
+                        source,
+                    })?;
+                if file.important {
+                    if let Some(written) = filesystem.read_file(&file.name) {
+                        written.set_important()?;
+                    }
+                }
+            }
+        }
+        Ok(storage.arena.read(0, BLOCKS * BLOCK_SIZE).to_vec())
+    }
+}
+
+/// In memory storage with the geometry of the target
+struct ImageStorage<const BLOCKS: u32, const BLOCK_SIZE: u32> {
+    arena: RamArena,
+    metadata: Mutex<BTreeMap<String, Box<[u8]>>>,
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> ImageStorage<BLOCKS, BLOCK_SIZE> {
+    fn new() -> Self {
+        Self {
+            arena: RamArena::new(BLOCKS * BLOCK_SIZE),
+            metadata: Default::default(),
+        }
+    }
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Storage for ImageStorage<BLOCKS, BLOCK_SIZE> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        if address >= BLOCKS * BLOCK_SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        if length > BLOCKS * BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        Ok(self.arena.read(address, length))
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        if address >= BLOCKS * BLOCK_SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        let length = u32::try_from(data.len()).map_err(|_| StorageError::SizeTooBig)?;
+        if length > BLOCKS * BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        self.arena.write(address, data);
+        Ok(())
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        if !address.is_multiple_of(BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseAlongBlockBoundaries);
+        }
+        if !length.is_multiple_of(BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseInBlockSizedChunks);
+        }
+        let end = address
+            .checked_add(length)
+            .ok_or(StorageError::SizeTooBig)?;
+        if end > BLOCKS * BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig.into());
+        }
+        self.arena.erase(address, length);
+        Ok(())
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        self.metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to lock metadata"))?
+            .get(key)
+            .cloned()
+            .ok_or(std::io::ErrorKind::NotFound.into())
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to lock metadata"))?
+            .insert(key.into(), value.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::simulated::{get_test_storage, SimulatedStorage};
+
+    fn example_image() -> Vec<u8> {
+        FilesystemImageBuilder::<16, 4096>::new()
+            .add_important_file("main", &[1; 5000])
+            .add_file("config", &[2; 100])
+            .add_file("assets", &[3; 10000])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn building_twice_yields_identical_images() {
+        assert_eq!(example_image(), example_image());
+    }
+
+    #[test]
+    fn files_are_placed_in_order_from_the_first_block() {
+        let image = example_image();
+        assert_eq!(&image[40..44], b"main");
+        assert_eq!(&image[2 * 4096 + 40..2 * 4096 + 46], b"config");
+        assert_eq!(&image[3 * 4096 + 40..3 * 4096 + 46], b"assets");
+        assert!(image[6 * 4096..].iter().all(|byte| *byte == 0xff));
+    }
+
+    #[test]
+    fn a_flashed_image_can_be_mounted() {
+        let image = example_image();
+        let storage = get_test_storage();
+        storage
+            .write(0, &image[..SimulatedStorage::SIZE as usize - 1])
+            .unwrap();
+        storage
+            .write(
+                SimulatedStorage::SIZE - 1,
+                &image[SimulatedStorage::SIZE as usize - 1..],
+            )
+            .unwrap();
+
+        let filesystem = Filesystem::new(storage);
+        let main = filesystem.read_file("main").unwrap();
+        assert!(main.important());
+        assert!(main.upgrade().unwrap().verify_hash());
+        let assets = filesystem.read_file("assets").unwrap().upgrade().unwrap();
+        assert_eq!(assets.as_ref(), [3; 10000]);
+    }
+
+    #[test]
+    fn files_can_be_pinned_to_an_address() {
+        let image = FilesystemImageBuilder::<16, 4096>::new()
+            .add_file_at("program", &[1; 100], 8 * 4096)
+            .add_file("assets", &[2; 100])
+            .build()
+            .unwrap();
+        assert_eq!(&image[8 * 4096 + 40..8 * 4096 + 47], b"program");
+
+        let result = FilesystemImageBuilder::<16, 4096>::new()
+            .add_file_at("program", &[1; 100], 8 * 4096)
+            .add_file_at("other", &[2; 100], 8 * 4096)
+            .build();
+        assert!(matches!(result, Err(BuildImageError::WriteFile { .. })));
+    }
+
+    #[test]
+    fn building_fails_if_the_files_do_not_fit() {
+        let result = FilesystemImageBuilder::<2, 4096>::new()
+            .add_file("large", &[0; 10000])
+            .build();
+        assert!(matches!(result, Err(BuildImageError::WriteFile { .. })));
+    }
+}
@@ -0,0 +1,95 @@
+//! Encoding of the file index
+//!
+//! Scanning every block during mount reads the whole storage. The index is stored as a metadata value
+//! of the storage and lists where the headers of the files are, so mount only needs to read those
+//! headers and check that the header area of every other block is erased. Leftovers further into a
+//! free block are not noticed, but writing a file there fails instead of mixing the data.
+//!
+//! The value is encoded as `length: u32, crc: u32, (address: u32, length: u32)*`. The first `length`
+//! is the number of bytes of the entries and `crc` is their CRC-32. A torn or corrupted value fails
+//! one of the checks, and the filesystem falls back to scanning all blocks.
+
+/// Metadata key of the index
+pub(crate) const INDEX_KEY: &str = "index";
+
+/// Address of the header and length of the content of a file
+pub(crate) type IndexEntry = (u32, u32);
+
+/// Encode the index
+pub(crate) fn encode(entries: &[IndexEntry]) -> Vec<u8> {
+    let mut encoded_entries = Vec::with_capacity(entries.len() * 8);
+    for (address, length) in entries {
+        encoded_entries.extend_from_slice(&address.to_le_bytes());
+        encoded_entries.extend_from_slice(&length.to_le_bytes());
+    }
+    let mut encoded = Vec::with_capacity(8 + encoded_entries.len());
+    encoded.extend_from_slice(&(encoded_entries.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&crc32(&encoded_entries).to_le_bytes());
+    encoded.extend_from_slice(&encoded_entries);
+    encoded
+}
+
+/// Decode an index written by [encode]. Returns None if the length or checksum do not match.
+pub(crate) fn decode(bytes: &[u8]) -> Option<Vec<IndexEntry>> {
+    let (length, rest) = bytes.split_first_chunk::<4>()?;
+    let (crc, encoded_entries) = rest.split_first_chunk::<4>()?;
+    if u32::from_le_bytes(*length) as usize != encoded_entries.len()
+        || encoded_entries.len() % 8 != 0
+        || u32::from_le_bytes(*crc) != crc32(encoded_entries)
+    {
+        return None;
+    }
+    let entries = encoded_entries
+        .chunks_exact(8)
+        .map(|entry| {
+            let (address, length) = entry.split_at(4);
+            (
+                u32::from_le_bytes(address.try_into().unwrap()),
+                u32::from_le_bytes(length.try_into().unwrap()),
+            )
+        })
+        .collect();
+    Some(entries)
+}
+
+/// CRC-32 as used by zip and ethernet
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn entries_survive_encoding() {
+        let entries = vec![(0, 5000), (8192, 100), (4096 * 15, 0)];
+        assert_eq!(decode(&encode(&entries)), Some(entries));
+        assert_eq!(decode(&encode(&[])), Some(Vec::new()));
+    }
+
+    #[test]
+    fn broken_values_are_rejected() {
+        let encoded = encode(&[(0, 5000), (8192, 100)]);
+        for index in 0..encoded.len() {
+            let mut flipped = encoded.clone();
+            flipped[index] ^= 0x10;
+            assert_eq!(decode(&flipped), None);
+        }
+        assert_eq!(decode(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(decode(&[]), None);
+    }
+}
@@ -20,13 +20,20 @@
 //! ## Autodeleting unimportant files
 // TODO: Improve documentation and move it somewhere else
 //!
-//! When you want to create a new file, but there is not enough space, the filesystem will automatically delete the oldest unimportant files to make space.
+//! When you want to create a new file, but there is not enough space, the filesystem can delete the oldest unimportant files to make space.
+//! This is disabled by default and needs to be enabled with [Filesystem::set_allow_evict_oldest]. The names of the deleted files can be retrieved with [Filesystem::take_evicted].
 //! Unimportant files are files that have not explicitly been marked as important.
 //!
 //! The age of a file is determined by the number of ticks and reboots since it was created. It can be a number between 0 and 15. A file with age 16 has just been created, while a file with age 1 is the oldest file. Every reboot increases the age of all files by 1. You can manually call the tick method to age all files.
 //! Files with age 16 require 1 tick to go to 15. Files with age 15 require 2 ticks to go to 14. Files with age 14 require 3 ticks. The recommended tick rate is once per minute.
+//!
+//! ## Multiple filesystems
+//!
+//! A [Filesystem] only uses the storage it was mounted on, including the metadata of that storage. There
+//! is no global state, so filesystems on different storages can be mounted and used at the same time, for
+//! example one on the internal flash and one on an SD card. Never mount two filesystems on the same
+//! storage. On the esp32, `storage::esp::mount_partition` keeps one filesystem per partition label.
 #![warn(missing_docs)]
-#![allow(static_mut_refs)]
 #![feature(adt_const_params)]
 #![feature(box_as_ptr)]
 #![feature(box_vec_non_null)]
@@ -44,34 +51,66 @@ Create an in-memory filesystem for testing:
 use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
 use rudelblinken_filesystem::Filesystem;
 
-let storage = SimulatedStorage::new();
-// TODO: Improve the interface to allow storages with lifetimes
-let static_storage_ref = unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&storage) };
-let mut filesystem = Filesystem::new(static_storage_ref);
+let mut filesystem = Filesystem::new(SimulatedStorage::new());
 ```
 "##
 )]
-use file::{CommitFileContentError, File, FileState, WriteFileToStorageError};
+use builder::{FilesystemBuilder, MountReport, MountSource};
+use file::{
+    CommitFileContentError, DeleteFileContentError, File, FileState, UpgradeFileError,
+    WriteFileToStorageError,
+};
 use file_information::FileInformation;
-use file_metadata::FileMetadata;
+use file_metadata::WriteMetadataError;
+pub use file_metadata::HEADER_SIZE;
+pub use file_name::{FileName, FileNameError, IntoFileName, MAX_NAME_LEN};
 use std::{
     collections::{BTreeMap, VecDeque},
     io::Write,
-    ops::Bound::Included,
     u16,
 };
-use storage::{EraseStorageError, Storage};
+use storage::{EraseStorageError, IntoStaticStorage, Storage};
 use thiserror::Error;
 
+mod attributes;
+/// Mount options
+pub mod builder;
 /// [file::File] provides a safe interface to read and write files.
 pub mod file;
 mod file_information;
 mod file_metadata;
+mod file_name;
+/// Bulk edits of attributes and flags without touching the content of files
+pub mod header_edit;
+/// Reproducible filesystem images
+#[cfg(feature = "simulated")]
+#[cfg_attr(docsrs, doc(cfg(feature = "simulated")))]
+pub mod image;
+mod index;
+/// Shared access to a filesystem that survives panics
+pub mod lock;
+/// Changes that are kept in RAM until they are committed
+pub mod overlay;
+/// Reading files as a stream
+pub mod reader;
 /// Storage traits and implementations
 pub mod storage;
+/// Multi-file transactions backed by a write-ahead log
+pub mod transaction;
+/// Streaming writes of files with a known size
+pub mod write_session;
+
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+pub mod async_fs;
+#[cfg(feature = "ecc")]
+mod ecc;
+#[cfg(feature = "signing")]
+mod signing;
 
 /// Errors that can occur when finding free space
-#[derive(Error, Debug, Clone)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum FindFreeSpaceError {
     /// Error in filesystem structure
     #[error("Error in filesystem structure")]
@@ -82,10 +121,124 @@ pub enum FindFreeSpaceError {
     /// Not enough space
     #[error("Not enough space")]
     NotEnoughSpace,
+    /// There is no free space for the file and deleting other files is not allowed
+    ///
+    /// All sizes are in bytes of whole blocks. See [FindFreeSpaceError::compaction_would_help].
+    #[error("There is no free space for the file and deleting other files is not allowed")]
+    OutOfSpace {
+        /// Space the file needs, including the blocks that are kept free, see
+        /// [FilesystemBuilder::reserved_blocks]
+        needed: u32,
+        /// See [Filesystem::largest_free_run]
+        largest_free_run: u32,
+        /// How much [Filesystem::compact] would add to the largest free run, see
+        /// [Filesystem::reclaimable_space]
+        reclaimable_by_compaction: u32,
+    },
+    /// The file does not fit into the free blocks at the requested address, see [WriteOptions::start_hint]
+    #[error("The file does not fit into the free blocks at {address:#x}")]
+    HintUnavailable {
+        /// The requested address
+        address: u32,
+    },
+    /// The requested address is not the start of a block of the storage, see [WriteOptions::start_hint]
+    #[error("The address {address:#x} is not the start of a block of the storage")]
+    InvalidHint {
+        /// The requested address
+        address: u32,
+    },
+}
+
+impl FindFreeSpaceError {
+    /// Check if the write that failed would fit after [Filesystem::compact]
+    ///
+    /// Only true for [FindFreeSpaceError::OutOfSpace]. Otherwise the only way to make space is deleting
+    /// files.
+    pub fn compaction_would_help(&self) -> bool {
+        match self {
+            FindFreeSpaceError::OutOfSpace {
+                needed,
+                largest_free_run,
+                reclaimable_by_compaction,
+            } => {
+                *reclaimable_by_compaction > 0
+                    && largest_free_run + reclaimable_by_compaction >= *needed
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Options for creating a file, see [Filesystem::get_file_writer_with_options]
+///
+/// ```
+/// use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+/// use rudelblinken_filesystem::{Filesystem, WriteOptions};
+///
+/// let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+/// let mut filesystem = Filesystem::new(storage);
+/// let options = WriteOptions::default().contiguous(true);
+/// filesystem
+///     .write_file_with_options("main", &[0; 100], &[0; 32], options)
+///     .unwrap();
+/// let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+/// assert!(main.executable_slice().is_some());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WriteOptions {
+    /// Only place the file where it does not wrap around the end of the storage
+    pub contiguous: bool,
+    /// Place the header of the file at this address
+    pub start_hint: Option<u32>,
+}
+
+/// Where a new file would be placed, see [Filesystem::plan_create]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatePlan {
+    /// Index of the block that would hold the header
+    pub start_block: u32,
+    /// Number of blocks the file would occupy, see [Filesystem::blocks_for]
+    pub block_span: u32,
+    /// Set if the file would wrap around the end of the storage
+    pub wraps: bool,
+    /// Names of the unimportant files that would be deleted to make space
+    pub evicted: Vec<String>,
+}
+
+impl CreatePlan {
+    /// Check if the file could be executed in place, see [File::executable_slice]
+    pub fn executable_in_place(&self) -> bool {
+        !self.wraps
+    }
+}
+
+impl WriteOptions {
+    /// Require a location that does not wrap around the end of the storage, so the file can be executed
+    /// in place, see [File::executable_slice].
+    ///
+    /// Other files are never deleted to make space for a contiguous file. If no free run fits, writing
+    /// fails with [FindFreeSpaceError::OutOfSpace].
+    pub fn contiguous(mut self, contiguous: bool) -> Self {
+        self.contiguous = contiguous;
+        self
+    }
+
+    /// Place the header of the file at `address`, for example to execute a program at a known offset
+    ///
+    /// The address needs to be the start of a block, otherwise creating the file fails with
+    /// [FindFreeSpaceError::InvalidHint]. If the blocks of the file starting there are not all free, it
+    /// fails with [FindFreeSpaceError::HintUnavailable] instead of placing the file somewhere else.
+    /// Other files are never deleted to make space at the address.
+    pub fn start_hint(mut self, address: u32) -> Self {
+        self.start_hint = Some(address);
+        self
+    }
 }
 
 /// Errors that can occur when writing a file
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum FilesystemWriteError {
     /// Error while finding free space
     #[error(transparent)]
@@ -102,10 +255,335 @@ pub enum FilesystemWriteError {
     /// There already exists a file with that name. Delete it first
     #[error("There already exists a file with that name. Delete it first")]
     NameAlreadyTaken,
+    /// The name does not fit into the header of a file
+    #[error(transparent)]
+    FileNameError(#[from] FileNameError),
+    /// The file would exceed the quota for its prefix
+    #[error("The file would exceed the quota for the prefix {prefix:?}")]
+    QuotaExceeded {
+        /// Prefix of the quota that would be exceeded
+        prefix: String,
+    },
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+    /// The file table is full, see [FilesystemBuilder::max_files]
+    #[error("There can be at most {max_files} files")]
+    TooManyFiles {
+        /// The configured maximum number of files
+        max_files: usize,
+    },
+    /// The file was written, but storing its signature failed. The file was deleted again
+    #[cfg(feature = "signing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "signing")))]
+    #[error("Failed to store the signature of the file")]
+    SignatureNotStored(#[source] FilesystemAttributeError),
+}
+
+/// Errors that can occur when compacting the filesystem
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemCompactError {
+    /// Error while analyzing the free space
+    #[error(transparent)]
+    FindFreeSpaceError(#[from] FindFreeSpaceError),
+    /// Error while reading the file that should be moved
+    #[error(transparent)]
+    UpgradeFileError(#[from] UpgradeFileError),
+    /// Error while writing the moved file to storage
+    #[error(transparent)]
+    WriteFileToStorageError(#[from] WriteFileToStorageError),
+    /// Some kind of io error
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// Error while copying the flags of the moved file
+    #[error(transparent)]
+    WriteMetadataError(#[from] WriteMetadataError),
+    /// Error while committing the moved file
+    #[error(transparent)]
+    CommitFileContentError(#[from] CommitFileContentError),
+    /// Error while deleting the old copy of the moved file
+    #[error(transparent)]
+    DeleteFileContentError(#[from] DeleteFileContentError),
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+/// Errors that can occur when clearing the filesystem
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemClearError {
+    /// A file is still referenced by a reader or writer
+    #[error("The file {0} is still in use")]
+    FileInUse(String),
+    /// Error while deleting a file
+    #[error(transparent)]
+    DeleteFileContentError(#[from] DeleteFileContentError),
+    /// Error while erasing storage
+    #[error(transparent)]
+    EraseStorageError(#[from] EraseStorageError),
+    /// Error while reading storage
+    #[error(transparent)]
+    StorageError(#[from] storage::StorageError),
+    /// Some kind of io error
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+/// Errors that can occur when accessing the attributes of a file
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemAttributeError {
+    /// The file does not exist
+    #[error("The file does not exist")]
+    FileNotFound,
+    /// The key is longer than 255 bytes
+    #[error("The key is longer than 255 bytes")]
+    KeyTooLong,
+    /// The value is longer than 65535 bytes
+    #[error("The value is longer than 65535 bytes")]
+    ValueTooLong,
+    /// A file can have at most 255 attributes
+    #[error("A file can have at most 255 attributes")]
+    TooManyAttributes,
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+    /// There is no space left in the metadata storage. Remove attributes or files to free some.
+    #[error("There is no space left to store the attributes")]
+    MetadataFull,
+    /// Error while storing the attributes
+    #[error(transparent)]
+    IoError(std::io::Error),
+    /// Error while setting a flag in the header of a file
+    #[error(transparent)]
+    WriteMetadataError(#[from] WriteMetadataError),
+}
+
+impl From<std::io::Error> for FilesystemAttributeError {
+    fn from(error: std::io::Error) -> Self {
+        if storage::StorageError::is_metadata_full(&error) {
+            return FilesystemAttributeError::MetadataFull;
+        }
+        FilesystemAttributeError::IoError(error)
+    }
+}
+
+/// Errors that can occur when reading a file
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemReadError {
+    /// The file does not exist
+    #[error("The file does not exist")]
+    FileNotFound,
+    /// Error while reading the file
+    #[error(transparent)]
+    UpgradeFileError(#[from] UpgradeFileError),
+    /// Error while reading the storage
+    #[error(transparent)]
+    StorageError(#[from] storage::StorageError),
+    /// The file has no valid signature of a trusted key, see [FilesystemBuilder::verify_signatures]
+    #[cfg(feature = "signing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "signing")))]
+    #[error("The file is not signed by a trusted key")]
+    UntrustedFile,
+    /// The content of the file does not match the hash in its header
+    #[error("The content of the file does not match its hash")]
+    Corrupt,
+}
+
+/// Errors that can occur when truncating a file
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemTruncateError {
+    /// The file does not exist
+    #[error("The file does not exist")]
+    FileNotFound,
+    /// The new length is larger than the current length
+    #[error("Can not grow a file from {length} to {new_length} bytes with truncate")]
+    CannotGrowWithTruncate {
+        /// Current length of the file
+        length: u32,
+        /// Requested length
+        new_length: u32,
+    },
+    /// Error while finding space for the truncated file
+    #[error(transparent)]
+    FindFreeSpaceError(#[from] FindFreeSpaceError),
+    /// Error while reading the file
+    #[error(transparent)]
+    UpgradeFileError(#[from] UpgradeFileError),
+    /// Error while writing the truncated file
+    #[error(transparent)]
+    FilesystemCompactError(#[from] FilesystemCompactError),
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+/// Errors that can occur when appending to a file
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemAppendError {
+    /// The file does not exist
+    #[error("The file does not exist")]
+    FileNotFound,
+    /// There is not enough space for the file with the appended data
+    #[error("There is not enough space for the file with the appended data")]
+    OutOfSpace,
+    /// The file would exceed the quota for its prefix
+    #[error("The file would exceed the quota for the prefix {prefix:?}")]
+    QuotaExceeded {
+        /// Prefix of the quota that would be exceeded
+        prefix: String,
+    },
+    /// Error while finding space for the file
+    #[error(transparent)]
+    FindFreeSpaceError(#[from] FindFreeSpaceError),
+    /// Error while reading the file
+    #[error(transparent)]
+    UpgradeFileError(#[from] UpgradeFileError),
+    /// Error while writing the file with the appended data
+    #[error(transparent)]
+    FilesystemCompactError(#[from] FilesystemCompactError),
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+/// Result of [Filesystem::compact]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Number of blocks that were copied to a new location
+    pub blocks_relocated: u32,
+    /// Number of bytes the largest free run has grown by
+    pub bytes_reclaimed: u32,
+    /// Number of files that were moved
+    pub files_moved: u32,
+    /// Number of blocks that were erased
+    pub erases_performed: u32,
+}
+
+/// Result of [Filesystem::compact_step]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactStepReport {
+    /// Number of blocks that were copied to a new location
+    pub blocks_relocated: u32,
+    /// Number of bytes the largest free run has grown by
+    pub bytes_reclaimed: u32,
+    /// Number of files that were moved
+    pub files_moved: u32,
+    /// Number of blocks that were erased
+    pub erases_performed: u32,
+    /// Set if another step could reduce fragmentation further
+    pub more_work: bool,
+}
+
+/// Result of [Filesystem::repair_file]
+#[cfg(feature = "ecc")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ecc")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EccStatus {
+    /// The file was written without error correcting code
+    NoEcc,
+    /// The content matches its error correcting code
+    Ok,
+    /// A single flipped bit was found and corrected
+    Corrected {
+        /// Index of the flipped bit, counted from the start of the content
+        bit: u32,
+    },
+}
+
+/// Errors that can occur when repairing a file
+#[cfg(feature = "ecc")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ecc")))]
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemRepairError {
+    /// The file does not exist
+    #[error("The file does not exist")]
+    FileNotFound,
+    /// More than one bit of the content is wrong. The file can not be repaired
+    #[error("The file is corrupt and can not be repaired")]
+    Corrupt,
+    /// The corrected file needs to be moved, but it is in use
+    #[error("The file needs to be moved for the repair, but it is in use")]
+    FileInUse,
+    /// The corrected file needs to be moved, but there is no free space for it
+    #[error("The file needs to be moved for the repair, but there is no free space")]
+    NoFreeSpace,
+    /// Error while reading the file
+    #[error(transparent)]
+    UpgradeFileError(#[from] UpgradeFileError),
+    /// Error while correcting the file in place
+    #[error(transparent)]
+    StorageError(#[from] storage::StorageError),
+    /// Error while moving the corrected file
+    #[error(transparent)]
+    FilesystemCompactError(#[from] FilesystemCompactError),
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+#[cfg(feature = "ecc")]
+impl From<FindFreeSpaceError> for FilesystemRepairError {
+    fn from(value: FindFreeSpaceError) -> Self {
+        FilesystemCompactError::from(value).into()
+    }
+}
+
+/// Errors that can occur when scrubbing the filesystem
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemScrubError {
+    /// The content does not match its hash and can not be repaired
+    #[error("The file is corrupt and can not be repaired")]
+    Corrupt,
+    /// The file needs to be rewritten, but it is in use
+    #[error("The file needs to be rewritten, but it is in use")]
+    FileInUse,
+    /// The file needs to be rewritten, but there is no free space for it
+    #[error("The file needs to be rewritten, but there is no free space")]
+    NoFreeSpace,
+    /// Error while repairing the file
+    #[cfg(feature = "ecc")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "ecc")))]
+    #[error(transparent)]
+    FilesystemRepairError(#[from] FilesystemRepairError),
+    /// Error while rewriting the file
+    #[error(transparent)]
+    FilesystemCompactError(#[from] FilesystemCompactError),
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+impl From<FindFreeSpaceError> for FilesystemScrubError {
+    fn from(value: FindFreeSpaceError) -> Self {
+        FilesystemCompactError::from(value).into()
+    }
+}
+
+/// Result of [Filesystem::scrub]
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    /// Number of files whose content was compared with their hash
+    pub files_checked: u32,
+    /// Names of the files that were rewritten to fresh blocks
+    pub scrubbed: Vec<String>,
+    /// Names of the files that are corrupt or could not be rewritten, with the reason
+    pub errors: Vec<(String, FilesystemScrubError)>,
 }
 
 /// Errors that can occur when deleting a file
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum FilesystemDeleteError {
     /// Error while erasing storage
     #[error(transparent)]
@@ -116,8 +594,66 @@ pub enum FilesystemDeleteError {
     /// The file does not exist
     #[error("The file does not exist")]
     FileNotFound,
+    /// The filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+    /// The file needs to be deleted securely, but it is still open
+    #[error("The file can not be deleted securely while it is open")]
+    FileInUse,
+}
+
+/// Errors that can occur when reconciling the index with the storage
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemReconcileError {
+    /// Error while storing the rebuilt index
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// The index should be rebuilt, but the filesystem is mounted read-only
+    #[error("The filesystem is mounted read-only")]
+    ReadOnly,
+}
+
+/// Errors that can occur when syncing the filesystem
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemSyncError {
+    /// Error while storing the index, the first block or the creation counter
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// Error while flushing the storage
+    #[error(transparent)]
+    StorageError(#[from] storage::StorageError),
+}
+
+/// Result of [Filesystem::reconcile]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Set if the stored index could be read. A missing or damaged index lists no files.
+    pub index_found: bool,
+    /// Address of the header and name of the files on the storage that are not in the index
+    pub missing_from_index: Vec<(u32, String)>,
+    /// Addresses listed in the index without a matching file on the storage
+    pub missing_from_storage: Vec<u32>,
+    /// Set if the index was replaced with the files found on the storage
+    pub index_rebuilt: bool,
+}
+
+impl ReconcileReport {
+    /// Check if the index matched the storage
+    pub fn consistent(&self) -> bool {
+        self.index_found
+            && self.missing_from_index.is_empty()
+            && self.missing_from_storage.is_empty()
+    }
 }
 
+/// Metadata key of the creation timestamp of the next file
+const CLOCK_KEY: &str = "clock";
+
+/// Attribute that marks a file for secure deletion, see [Filesystem::set_secure_delete]
+pub const SECURE_DELETE_ATTRIBUTE: &str = "secure_delete";
+
 ///  A struct representing the filesystem backed by a generic storage type `T`.
 ///
 /// # Type Parameters
@@ -126,6 +662,118 @@ pub enum FilesystemDeleteError {
 pub struct Filesystem<T: Storage + 'static + Send + Sync> {
     storage: &'static T,
     files: Vec<FileInformation<T>>,
+    /// Maximum number of blocks for files starting with a prefix
+    quotas: Vec<(String, u32)>,
+    /// Set if the filesystem must not write to the storage
+    read_only: bool,
+    /// Set if unimportant files may be deleted to make space
+    allow_evict_oldest: bool,
+    /// Names of the files that were deleted to make space since the last [Filesystem::take_evicted]
+    evicted: Vec<String>,
+    /// Names of the files whose content did not match their hash during mount
+    degraded: Vec<String>,
+    /// Dirty blocks that could not be erased during mount. New files are not placed on them.
+    unusable_blocks: Vec<u32>,
+    /// Number of free blocks that new files may not use, so compaction always has space to move files
+    reserved_blocks: u32,
+    /// Maximum number of entries in the file table
+    max_files: Option<usize>,
+    /// Blocks erased this many times more than the average are avoided
+    wear_threshold: Option<u32>,
+    /// Entries of the index as it is stored, if it is known
+    index: Option<Vec<index::IndexEntry>>,
+    /// How the files were found during mount
+    mount_source: MountSource,
+    /// Creation timestamp of the next file, see [File::created_at]
+    clock: (u16, u16),
+    /// Only files signed by one of these keys can be read
+    #[cfg(feature = "signing")]
+    trusted_keys: Option<Vec<ed25519_dalek::VerifyingKey>>,
+}
+
+/// Usage of a quota, see [Filesystem::set_quota]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Files with names starting with this prefix count towards the quota
+    pub prefix: String,
+    /// Number of blocks used by files with that prefix
+    pub used_blocks: u32,
+    /// Maximum number of blocks files with that prefix may use
+    pub max_blocks: u32,
+}
+
+/// Summary of the space on the storage, see [Filesystem::space_usage]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpaceUsage {
+    /// Number of blocks that belong to files, including files that are marked for deletion, and blocks
+    /// that could not be erased during mount
+    pub used_blocks: u32,
+    /// See [Filesystem::free_space]
+    pub free_space: u32,
+    /// See [Filesystem::largest_free_run]
+    pub largest_free_run: u32,
+    /// See [Filesystem::internal_fragmentation]
+    pub internal_fragmentation: u64,
+    /// See [Filesystem::free_run_count]
+    pub free_run_count: u32,
+}
+
+/// State of a run of blocks in [Filesystem::block_map]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    /// The blocks belong to a file. Contains the index of the file in the file table
+    Used(usize),
+    /// The blocks are free
+    Free,
+    /// The blocks belong to a file that is marked for deletion, but still referenced
+    Tombstone,
+    /// The blocks could not be erased during mount and are not used until the next mount, see
+    /// [MountReport::erase_failed]
+    Unusable,
+}
+
+/// What a single block is used for, see [Filesystem::walk_blocks]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockClass {
+    /// The block starts with the header of a file. Contains the index of the file in the file table
+    HeaderOf(usize),
+    /// The block holds content of a file after its first block. Contains the index of the file in the
+    /// file table
+    DataOf(usize),
+    /// The block is free
+    Free,
+    /// The block belongs to a file that is marked for deletion, but still referenced
+    Tombstone,
+    /// The block could not be erased during mount and is not used until the next mount
+    Unusable,
+}
+
+/// Order of the files returned by [Filesystem::list_files_sorted]
+///
+/// Files that are equal in the chosen key are ordered by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// By name
+    Name,
+    /// Shortest content first
+    LengthAsc,
+    /// Longest content first
+    LengthDesc,
+    /// Oldest file first. Files without a creation timestamp count as the oldest
+    CreatedAsc,
+    /// Newest file first. Files without a creation timestamp count as the oldest
+    CreatedDesc,
+}
+
+/// A run of consecutive blocks with the same state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRun {
+    /// First block of the run
+    pub start: u32,
+    /// Number of blocks in the run. Runs can wrap around the end of the storage.
+    pub len: u32,
+    /// State of the blocks
+    pub state: BlockState,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -151,6 +799,52 @@ struct Range {
     length: u16,
 }
 
+/// What occupies a block, see [Filesystem::block_owners]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockOwner {
+    /// The block belongs to the file with this index in the file table
+    File(usize),
+    /// The block could not be erased during mount
+    Unusable,
+}
+
+/// Outcome of loading the file table from the index during mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IndexLoad {
+    /// There is no index
+    Missing,
+    /// The index is broken or does not match the headers on the storage
+    Rejected,
+    /// The files were added from the index
+    Loaded,
+}
+
+impl<T: Storage + 'static + Send + Sync> std::fmt::Debug for Filesystem<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Filesystem")
+            .field("files", &self.readable_files().len())
+            .field("blocks", &T::BLOCKS)
+            .field("block_size", &T::BLOCK_SIZE)
+            .field("usage", &self.space_usage())
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> std::fmt::Display for Filesystem<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let usage = self.space_usage();
+        write!(
+            f,
+            "files: {}, used blocks: {} of {}, free space: {} bytes",
+            self.readable_files().len(),
+            usage.used_blocks,
+            T::BLOCKS,
+            usage.free_space
+        )
+    }
+}
+
 impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
     /// Retrieves the first block number from the storage metadata.
     fn get_first_block(&self) -> Result<u16, std::io::Error> {
@@ -158,14 +852,102 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
             .storage
             .read_metadata("first_block")?
             .try_into()
-            .unwrap();
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
         Ok(u16::from_le_bytes(*first_block_slice))
     }
+    /// Load the timestamp of the next file and start a new epoch if it went backwards
+    ///
+    /// The counter is stored as metadata, so it restarts when the metadata is cleared while files stay on
+    /// the storage. Any file that is at least as new as the stored counter shows that this happened.
+    fn load_clock(&mut self) {
+        let stored = self
+            .storage
+            .read_metadata(CLOCK_KEY)
+            .ok()
+            .and_then(|value| <[u8; 4]>::try_from(&*value).ok())
+            .map(|value| {
+                (
+                    u16::from_le_bytes([value[0], value[1]]),
+                    u16::from_le_bytes([value[2], value[3]]),
+                )
+            });
+        let newest = self
+            .files
+            .iter()
+            .filter(|file| !file.deleted())
+            .filter_map(|file| file.created_at())
+            .max();
+        self.clock = match (stored, newest) {
+            (Some(stored), Some(newest)) if stored <= newest => {
+                log::info!(
+                    "The creation counter went backwards ({:?} <= {:?}), starting a new epoch",
+                    stored,
+                    newest
+                );
+                (stored.0.max(newest.0).saturating_add(1), 0)
+            }
+            (None, Some(newest)) => {
+                log::info!("The creation counter is missing, starting a new epoch");
+                (newest.0.saturating_add(1), 0)
+            }
+            // (0, 0) can not be told apart from the zeros in headers written before timestamps
+            (stored, _) => stored.filter(|stored| *stored != (0, 0)).unwrap_or((0, 1)),
+        };
+        if Some(self.clock) != stored {
+            self.store_clock();
+        }
+    }
+
+    /// Take the creation timestamp for a new file
+    ///
+    /// The counter is advanced and stored before the timestamp is used, so no two files get the same one.
+    fn next_timestamp(&mut self) -> (u16, u16) {
+        let timestamp = self.clock;
+        self.clock = match self.clock.1.checked_add(1) {
+            Some(counter) => (self.clock.0, counter),
+            None => (self.clock.0.saturating_add(1), 0),
+        };
+        self.store_clock();
+        timestamp
+    }
+
+    /// Store the timestamp of the next file. Failing to store it is logged, the next mount recovers.
+    fn store_clock(&self) {
+        if self.read_only {
+            return;
+        }
+        if let Err(error) = self.storage.write_metadata(CLOCK_KEY, &self.encode_clock()) {
+            log::warn!("Failed to store the creation counter: {}", error);
+        }
+    }
+
+    /// The timestamp of the next file as it is stored
+    fn encode_clock(&self) -> [u8; 4] {
+        let (epoch, counter) = self.clock;
+        let mut value = [0u8; 4];
+        value[0..2].copy_from_slice(&epoch.to_le_bytes());
+        value[2..4].copy_from_slice(&counter.to_le_bytes());
+        value
+    }
+
     /// Sets the first block number in the storage metadata.
+    ///
+    /// The first block is only where the next mount starts scanning, so a full metadata storage is
+    /// logged instead of failing the operation that moved it.
     fn set_first_block(&self, first_block: u16) -> Result<(), std::io::Error> {
-        self.storage
-            .write_metadata("first_block", &first_block.to_le_bytes())?;
-        Ok(())
+        match self
+            .storage
+            .write_metadata("first_block", &first_block.to_le_bytes())
+        {
+            Err(error) if storage::StorageError::is_metadata_full(&error) => {
+                log::error!(
+                    "Failed to store the first block {}, the metadata storage is full",
+                    first_block
+                );
+                Ok(())
+            }
+            result => result,
+        }
     }
 
     /// Creates a new filesystem instance on top of the provided storage.
@@ -181,21 +963,147 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
     ///
     /// # Returns
     /// A new `Filesystem` instance with the reconstructed file list
-    pub fn new(storage: &'static T) -> Self {
+    pub fn new(storage: impl IntoStaticStorage<T>) -> Self {
+        Self::builder(storage).mount()
+    }
+
+    /// Same as [Filesystem::new], but also returns what happened during mount
+    pub fn mount_with_report(storage: impl IntoStaticStorage<T>) -> (Self, MountReport) {
+        Self::builder(storage).mount_with_report()
+    }
+
+    /// Configure how the filesystem is mounted, see [FilesystemBuilder]
+    pub fn builder(storage: impl IntoStaticStorage<T>) -> FilesystemBuilder<T> {
+        FilesystemBuilder::new(storage.into_static())
+    }
+
+    /// Mount the filesystem without ever writing to the storage.
+    ///
+    /// Nothing is repaired during mount and all methods that would modify the storage fail with a
+    /// `ReadOnly` error. Reading files works like on a normal mount. Use this for images that might be
+    /// corrupted or when writing is not safe right now, for example on low battery.
+    ///
+    /// Note that the flags of a file can still be changed through [File::set_important] and [File::increase_age].
+    pub fn mount_readonly(storage: impl IntoStaticStorage<T>) -> Self {
+        Self::builder(storage).read_only(true).mount()
+    }
+
+    /// Check if the filesystem was mounted with [Filesystem::mount_readonly]
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Allow deleting the oldest unimportant files when there is not enough free space for a new file.
+    ///
+    /// Disabled by default, so writing fails with [FindFreeSpaceError::OutOfSpace] instead. The names of
+    /// deleted files are collected until they are retrieved with [Filesystem::take_evicted].
+    pub fn set_allow_evict_oldest(&mut self, allow: bool) {
+        self.allow_evict_oldest = allow;
+    }
+
+    /// Get the names of the files that were deleted to make space since the last call
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// How the files were found during mount
+    ///
+    /// Cheap enough to log on every boot. A device that keeps reporting [MountSource::Scan] can not
+    /// keep its index, which points to a problem with the metadata storage. [Filesystem::reconcile]
+    /// shows how the index differs from the storage.
+    pub fn mount_source(&self) -> MountSource {
+        self.mount_source
+    }
+
+    /// Get the names of the files that were degraded during mount
+    ///
+    /// Only filled if the filesystem was mounted with [FilesystemBuilder::verify_on_mount]. Degraded
+    /// files can not be read. Delete them with [Filesystem::delete_file] to free their blocks.
+    pub fn degraded_files(&self) -> &[String] {
+        &self.degraded
+    }
+
+    fn mount(options: FilesystemBuilder<T>) -> (Self, MountReport) {
+        let FilesystemBuilder {
+            storage,
+            read_only,
+            verify_on_mount,
+            reserved_blocks,
+            max_files,
+            wear_threshold,
+            #[cfg(feature = "signing")]
+            trusted_keys,
+        } = options;
         // Create a fs with an empty files table
         let mut filesystem = Self {
             storage,
-            files: Vec::new(),
+            // Rewriting a file needs one more entry while both versions exist
+            files: max_files.map_or_else(Vec::new, |max_files| Vec::with_capacity(max_files + 1)),
+            quotas: Vec::new(),
+            read_only,
+            allow_evict_oldest: false,
+            evicted: Vec::new(),
+            degraded: Vec::new(),
+            unusable_blocks: Vec::new(),
+            reserved_blocks,
+            max_files,
+            wear_threshold,
+            index: None,
+            mount_source: MountSource::Scan,
+            clock: (0, 0),
+            #[cfg(feature = "signing")]
+            trusted_keys,
         };
+        let mut report = MountReport::default();
+
+        // Finish an interrupted transaction before files that are not ready get erased
+        if !read_only {
+            match transaction::replay(filesystem.storage) {
+                Ok(replayed) => report.wal_replayed = replayed,
+                Err(error) => {
+                    log::warn!("Failed to complete an interrupted transaction: {}", error);
+                }
+            }
+        }
 
         // Find all files
         let first_block = filesystem.get_first_block();
         let first_block = first_block.unwrap_or_else(|_| {
-            filesystem.set_first_block(0).unwrap();
+            if !read_only {
+                if let Err(error) = filesystem.set_first_block(0) {
+                    log::warn!("Failed to initialize the first block: {}", error);
+                }
+            }
             0
         });
+        // The index does not know about the changes of a replayed transaction
+        let index_load = if report.wal_replayed {
+            IndexLoad::Missing
+        } else {
+            filesystem.load_index(verify_on_mount)
+        };
+        report.index_used = index_load == IndexLoad::Loaded;
+        report.scan_fallback = index_load == IndexLoad::Rejected;
+        filesystem.mount_source = if report.wal_replayed {
+            MountSource::WalReplay
+        } else if report.index_used {
+            MountSource::Index
+        } else {
+            MountSource::Scan
+        };
         let mut block_number = 0;
-        while block_number < T::BLOCKS {
+        // Blocks that contain data but no file header. They are only erased after the scan, because
+        // the end of a file that wraps around the end of the storage looks the same.
+        let mut dirty_blocks: Vec<u32> = Vec::new();
+        let mut block_buffer = vec![0u8; T::BLOCK_SIZE as usize];
+        while !report.index_used && block_number < T::BLOCKS {
+            if max_files.is_some_and(|max_files| filesystem.files.len() >= max_files) {
+                log::warn!(
+                    "Stopped mounting after {} files, the remaining blocks are not scanned",
+                    filesystem.files.len()
+                );
+                break;
+            }
             let current_block_number = (block_number + first_block as u32) % T::BLOCKS;
             let file_information = FileInformation::from_storage(
                 filesystem.storage,
@@ -205,725 +1113,5346 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
                 Ok(file_information) => file_information,
                 Err(_) => {
                     block_number += 1;
-                    let Ok(current_block) = filesystem
+                    if filesystem
                         .storage
-                        .read(current_block_number * T::BLOCK_SIZE, T::BLOCK_SIZE)
-                    else {
+                        .read_into(current_block_number * T::BLOCK_SIZE, &mut block_buffer)
+                        .is_err()
+                    {
                         continue;
                     };
-                    if current_block.iter().any(|b| *b != 0xff) {
-                        println!(
-                            "Erasing block {} because it is not zeroed",
-                            current_block_number
-                        );
-                        filesystem
-                            .storage
-                            .erase(current_block_number * T::BLOCK_SIZE, T::BLOCK_SIZE)
-                            .unwrap();
+                    if block_buffer.iter().any(|b| *b != 0xff) {
+                        dirty_blocks.push(current_block_number);
                     };
                     continue;
                 }
             };
-            block_number += ((file_information.length + 64) / T::BLOCK_SIZE) + 1;
-            filesystem.files.push(file_information);
+            block_number += Self::blocks_for(file_information.length);
+            filesystem.add_mounted_file(file_information, verify_on_mount);
+        }
+
+        dirty_blocks.retain(|block| {
+            !filesystem.files.iter().any(|file| {
+                let start_block = file.start_block();
+                let length_in_blocks = Self::blocks_for(file.length);
+                (block + T::BLOCKS - start_block) % T::BLOCKS < length_in_blocks
+            })
+        });
+        report.corrupt_skipped = dirty_blocks.len() as u32;
+        if read_only {
+            dirty_blocks.clear();
+        }
+        // Neighbouring blocks are erased together. The blocks are in scan order, so a run can wrap around.
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for block in dirty_blocks {
+            match runs.last_mut() {
+                Some((start, length)) if (*start + *length) % T::BLOCKS == block => *length += 1,
+                _ => runs.push((block, 1)),
+            }
+        }
+        for (start, length) in runs {
+            log::info!(
+                "Erasing {} blocks starting at {} because they are not zeroed",
+                length,
+                start
+            );
+            if let Err(error) = filesystem
+                .storage
+                .erase_wrapping(start * T::BLOCK_SIZE, length * T::BLOCK_SIZE)
+            {
+                log::warn!(
+                    "Failed to erase {} blocks starting at {}: {}",
+                    length,
+                    start,
+                    error
+                );
+                report.erase_failed += length;
+                filesystem
+                    .unusable_blocks
+                    .extend((start..start + length).map(|block| block % T::BLOCKS));
+            }
         }
 
         unsafe { filesystem.selfcheck() };
+        filesystem.update_index();
+        filesystem.load_clock();
 
-        filesystem
-    }
+        report.files_found = filesystem
+            .files
+            .iter()
+            .filter(|file| file.valid() && !file.marked_for_deletion() && !file.deleted())
+            .count();
+        report.degraded = filesystem.degraded.clone();
+        (filesystem, report)
+    }
 
-    /// Check the filesystem for errors and try to fix them
+    /// Add a file found during mount to the file table
+    fn add_mounted_file(&mut self, mut file_information: FileInformation<T>, verify: bool) {
+        if verify
+            && file_information.valid()
+            && !file_information.marked_for_deletion()
+            && !file_information.verify()
+        {
+            log::warn!(
+                "The content of {} does not match its hash",
+                file_information.name
+            );
+            file_information.degraded = true;
+            self.degraded.push(file_information.name.clone());
+        }
+        self.files.push(file_information);
+    }
+
+    /// Load the file table from the index instead of scanning all blocks
     ///
-    /// Only safe, if none of the files have been read yet. This should only be called in new.
-    unsafe fn selfcheck(&mut self) {
-        // Fix the first block number, if the first file is marked for deletion or deleted
-        if let Some(first_file) = self.files.first() {
-            if first_file.marked_for_deletion() || first_file.deleted() {
-                let new_first_block = self.find_new_first_block();
-                self.set_first_block(new_first_block).unwrap();
+    /// No files are added if the index is missing, broken or does not match the headers on the storage.
+    fn load_index(&mut self, verify_on_mount: bool) -> IndexLoad {
+        let Ok(value) = self.storage.read_metadata(index::INDEX_KEY) else {
+            return IndexLoad::Missing;
+        };
+        let Some(entries) = index::decode(&value) else {
+            log::warn!("The index is corrupted, scanning all blocks");
+            return IndexLoad::Rejected;
+        };
+
+        let mut covered = vec![false; T::BLOCKS as usize];
+        let mut files = Vec::with_capacity(entries.len());
+        for (address, length) in &entries {
+            let file_information =
+                if address % T::BLOCK_SIZE == 0 && *address < T::BLOCKS * T::BLOCK_SIZE {
+                    FileInformation::from_storage(self.storage, *address).ok()
+                } else {
+                    None
+                };
+            let Some(file_information) = file_information.filter(|file| file.length == *length)
+            else {
+                log::info!("The index does not match the storage, scanning all blocks");
+                return IndexLoad::Rejected;
+            };
+            for block in 0..Self::blocks_for(*length) {
+                covered[((address / T::BLOCK_SIZE + block) % T::BLOCKS) as usize] = true;
             }
+            files.push(file_information);
+        }
+        // A file that was created after the index was written, or the leftovers of an interrupted write.
+        // Both would be found and handled by a scan.
+        let mut header = [0u8; HEADER_SIZE as usize];
+        let unexpected_data = (0..T::BLOCKS).any(|block| {
+            !covered[block as usize]
+                && self
+                    .storage
+                    .read_into(block * T::BLOCK_SIZE, &mut header)
+                    .map_or(true, |_| header.iter().any(|byte| *byte != 0xff))
+        });
+        if unexpected_data {
+            log::info!("The index does not match the storage, scanning all blocks");
+            return IndexLoad::Rejected;
         }
 
-        // TODO: Find and remove files with a duplicate name
-
-        // TODO: Cleanup
+        for file_information in files {
+            if self
+                .max_files
+                .is_some_and(|max_files| self.files.len() >= max_files)
+            {
+                log::warn!("Stopped mounting after {} files", self.files.len());
+                break;
+            }
+            self.add_mounted_file(file_information, verify_on_mount);
+        }
+        self.index = Some(entries);
+        IndexLoad::Loaded
     }
 
-    /// Finds a file by name and returns a reference to it.
-    pub fn read_file(&self, name: &str) -> Option<File<T, { FileState::Weak }>> {
-        let file = self.files.iter().find(|file| {
-            file.name == name && !file.marked_for_deletion() && !file.deleted() && file.valid()
-        })?;
-        Some(file.read())
+    /// Store the index, if the file table changed since it was last stored
+    ///
+    /// Failing to store the index is not an error. The next mount notices that it is outdated and scans
+    /// all blocks instead.
+    fn update_index(&mut self) {
+        if self.read_only {
+            return;
+        }
+        let entries = self.index_entries();
+        if self.index.as_ref() == Some(&entries) {
+            return;
+        }
+        match self
+            .storage
+            .write_metadata(index::INDEX_KEY, &index::encode(&entries))
+        {
+            Ok(()) => self.index = Some(entries),
+            Err(error) => log::warn!("Failed to store the index: {}", error),
+        }
     }
 
-    /// Finds a file by name and returns a reference to it.
-    pub fn read_file_by_hash(&self, hash: &[u8; 32]) -> Option<File<T, { FileState::Weak }>> {
-        let file = self.files.iter().find(|file| {
-            file.compare_hash(hash)
-                && !file.marked_for_deletion()
-                && !file.deleted()
-                && file.valid()
-        })?;
-        Some(file.read())
+    /// The index of the current file table
+    fn index_entries(&self) -> Vec<index::IndexEntry> {
+        self.files
+            .iter()
+            .filter(|file| !file.deleted())
+            .map(|file| (file.address, file.length))
+            .collect()
     }
 
-    /// Get information about the free space in the storage
-    fn analyze_free_space(&self) -> Result<BTreeMap<u16, Range>, FindFreeSpaceError> {
-        let mut free_ranges: BTreeMap<u16, Range> = Default::default();
-        free_ranges.insert(
-            0,
-            Range {
-                importance: Importance::Free,
-                length: T::BLOCKS as u16 * 2,
-            },
-        );
-
-        for file in &self.files {
-            let file_importance = if file.important() || !file.can_be_deleted() {
-                Importance::Important
-            } else {
-                Importance::Unimportant { age: file.age() }
-            };
-
-            let start_block = (file.address / T::BLOCK_SIZE) as u16;
-            let length_in_blocks =
-                (file.length + size_of::<FileMetadata>() as u32).div_ceil(T::BLOCK_SIZE) as u16;
-            let end_block = start_block + length_in_blocks;
+    /// Make sure that everything that was done so far survives a power loss
+    ///
+    /// Every operation is only guaranteed to be durable at its own commit point, for example when
+    /// [File::commit](file::File::commit) returns. The index, the first block and the
+    /// creation counter are stored after that, and failing to store them is only logged, because the next
+    /// mount can recover without them. This stores all of them again if the stored values are outdated,
+    /// returns the error instead of logging it and flushes the storage. Once it returns, the next mount
+    /// finds the filesystem as it is now without having to recover anything.
+    ///
+    /// Call this before entering deep sleep or cutting the power on purpose. Values that are already up
+    /// to date are not written again, so calling it often does not wear the metadata storage. On a
+    /// read-only filesystem there is nothing to store, so this only flushes the storage.
+    pub fn sync(&self) -> Result<(), FilesystemSyncError> {
+        if !self.read_only {
+            let index = index::encode(&self.index_entries());
+            self.store_if_changed(index::INDEX_KEY, &index)?;
 
-            let Some((
-                &surrounding_start,
-                &Range {
-                    length: surrounding_length,
-                    importance: surrounding_importance,
-                },
-            )) = free_ranges
-                .range((Included(0), Included(start_block)))
-                .last()
-            else {
-                // There should always be a surrounding free range
-                return Err(FindFreeSpaceError::FilesystemError);
+            // Mount only needs the first block to point at a file that is still in use
+            let first_block = self.get_first_block().ok();
+            let first_file = first_block.and_then(|first_block| {
+                self.files
+                    .iter()
+                    .find(|file| file.start_block() == first_block as u32)
+            });
+            let outdated = match (first_block, first_file) {
+                (None, _) => true,
+                (Some(_), Some(file)) => file.deleted() || file.marked_for_deletion(),
+                (Some(_), None) => false,
             };
+            if outdated {
+                self.storage
+                    .write_metadata("first_block", &self.find_new_first_block().to_le_bytes())?;
+            }
 
-            let space_before = start_block - surrounding_start;
-            let space_after = (surrounding_start + surrounding_length) - (end_block);
+            self.store_if_changed(CLOCK_KEY, &self.encode_clock())?;
+        }
+        self.storage.flush()?;
+        Ok(())
+    }
 
-            if space_before != 0 {
-                free_ranges.insert(
-                    surrounding_start,
-                    Range {
-                        importance: surrounding_importance,
-                        length: space_before,
-                    },
-                );
-            }
-            free_ranges.insert(
-                surrounding_start + space_before,
-                Range {
-                    importance: file_importance,
-                    length: length_in_blocks,
-                },
-            );
-            if space_after != 0 {
-                free_ranges.insert(
-                    surrounding_start + space_before + length_in_blocks,
-                    Range {
-                        importance: surrounding_importance,
-                        length: space_after,
-                    },
-                );
-            }
+    /// Write a metadata value, unless it is already stored
+    fn store_if_changed(&self, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
+        match self.storage.read_metadata(key) {
+            Ok(stored) if *stored == *value => Ok(()),
+            _ => self.storage.write_metadata(key, value),
         }
+    }
 
-        // Remove all trailing free space
-        let last_free_space_start = free_ranges.last_key_value().map_or(0, |(start, _)| *start);
-        let wraparound_length: i64 = last_free_space_start as i64 - T::BLOCKS as i64;
+    /// Compare the stored index with the file headers on the storage
+    ///
+    /// The index can drift from the storage if storing it fails after a file was changed. Mount notices
+    /// that and falls back to [MountSource::Scan], but the drift stays until the file table changes
+    /// again. The headers found by scanning all blocks are authoritative. If `rebuild_index` is set and
+    /// the index does not match them, it is replaced with the scanned files.
+    pub fn reconcile(
+        &mut self,
+        rebuild_index: bool,
+    ) -> Result<ReconcileReport, FilesystemReconcileError> {
+        let stored = self
+            .storage
+            .read_metadata(index::INDEX_KEY)
+            .ok()
+            .and_then(|value| index::decode(&value));
+        let scanned = self.scan_headers();
 
-        // Remove the free space that is occupied by the wraparound from the first block
-        if wraparound_length > 0 {
-            let first_entry = free_ranges.first_key_value().unwrap();
-            if Importance::Free == first_entry.1.importance {
-                panic!("In case of wraparound, the first entry should always be free");
-            }
-            if first_entry.1.length < wraparound_length as u16 {
-                panic!("In case of wraparound, the first entry should always be large enough to accomodate the wraparound");
+        let mut report = ReconcileReport {
+            index_found: stored.is_some(),
+            ..Default::default()
+        };
+        let stored = stored.unwrap_or_default();
+        report.missing_from_index = scanned
+            .iter()
+            .filter(|(address, length, _)| !stored.contains(&(*address, *length)))
+            .map(|(address, _, name)| (*address, name.clone()))
+            .collect();
+        report.missing_from_storage = stored
+            .iter()
+            .filter(|entry| {
+                !scanned
+                    .iter()
+                    .any(|(address, length, _)| (*address, *length) == **entry)
+            })
+            .map(|(address, _)| *address)
+            .collect();
+
+        if rebuild_index && !report.consistent() {
+            if self.read_only {
+                return Err(FilesystemReconcileError::ReadOnly);
             }
-            free_ranges.insert(wraparound_length as u16, first_entry.1.clone());
-            free_ranges.remove(&0);
+            let entries: Vec<index::IndexEntry> = scanned
+                .iter()
+                .map(|(address, length, _)| (*address, *length))
+                .collect();
+            self.storage
+                .write_metadata(index::INDEX_KEY, &index::encode(&entries))?;
+            self.index = Some(entries);
+            report.index_rebuilt = true;
         }
+        Ok(report)
+    }
 
-        // Remove the free space in the end
-        if wraparound_length >= 0 {
-            free_ranges.remove(&last_free_space_start);
-        } else {
-            let end_space = free_ranges.last_key_value().unwrap();
-            free_ranges.insert(
-                *end_space.0,
-                Range {
-                    importance: end_space.1.importance,
-                    length: end_space.1.length - T::BLOCKS as u16,
-                },
-            );
+    /// Replace the file table and the stored index with the files found by scanning all blocks
+    ///
+    /// This is the manual counterpart to the scan mount falls back to. Use it after a tool edited the
+    /// storage directly, or if the index is suspected to have drifted. Unlike [Filesystem::reconcile], the
+    /// index is written even if it looks consistent.
+    ///
+    /// Files that are still on the storage keep their entry, so open readers and writers stay valid.
+    /// Files without a header on the storage are removed from the table, new headers are added like
+    /// during mount.
+    pub fn rebuild_index(&mut self) -> Result<(), FilesystemReconcileError> {
+        if self.read_only {
+            return Err(FilesystemReconcileError::ReadOnly);
         }
-
-        // Now the only overlap is the wrapping section
-
-        // Duplicate all ranges to the back
-        for range in free_ranges.clone().into_iter() {
-            free_ranges.insert(range.0 + T::BLOCKS as u16, range.1);
+        let scanned = self.scan_headers();
+        let on_storage = |file: &FileInformation<T>| {
+            !file.deleted()
+                && scanned
+                    .iter()
+                    .any(|(address, length, _)| (*address, *length) == (file.address, file.length))
+        };
+        self.files.retain(on_storage);
+        for (address, length, _) in &scanned {
+            let known = self
+                .files
+                .iter()
+                .any(|file| (file.address, file.length) == (*address, *length));
+            if known {
+                continue;
+            }
+            match FileInformation::from_storage(self.storage, *address) {
+                Ok(file_information) => self.add_mounted_file(file_information, false),
+                Err(error) => log::warn!("Failed to read the file at {:#x}: {}", address, error),
+            }
         }
 
-        return Ok(free_ranges);
+        let entries = self.index_entries();
+        self.storage
+            .write_metadata(index::INDEX_KEY, &index::encode(&entries))?;
+        self.index = Some(entries);
+        Ok(())
     }
 
-    /// Find a free space in storage of at least the given length.
+    /// Address, length and name of every file header that is not deleted
     ///
-    /// For now the space is guaranteed to start at a block boundary
-    fn find_free_space(&self, length: u32) -> Result<u32, FindFreeSpaceError> {
-        let free_ranges = self.analyze_free_space()?;
-
-        for range in free_ranges.iter() {
-            println!("Free range: {:?}", range);
+    /// Scans like mount, starting at the first block, so the end of a file that wraps around is not
+    /// mistaken for a header. Only reads the headers. Creating files for them would erase files that
+    /// are marked for deletion as soon as the temporary reader is dropped.
+    fn scan_headers(&self) -> Vec<(u32, u32, String)> {
+        let first_block = self.get_first_block().unwrap_or(0) as u32;
+        let mut headers = Vec::new();
+        let mut block = 0;
+        while block < T::BLOCKS {
+            let address = (first_block + block) % T::BLOCKS * T::BLOCK_SIZE;
+            match file_metadata::FileMetadata::from_storage(self.storage, address) {
+                Ok(metadata) if !metadata.deleted() => {
+                    headers.push((address, metadata.length, metadata.name_str().to_string()));
+                    block += Self::blocks_for(metadata.length);
+                }
+                _ => block += 1,
+            }
         }
+        headers
+    }
 
-        let length_in_blocks = length.div_ceil(T::BLOCK_SIZE) as u16;
-
-        if let Some((free_range_start, free_range_length)) = free_ranges
-            .iter()
-            .filter(|(&start, _)| start < T::BLOCKS as u16)
-            .filter(|(_, range)| range.importance == Importance::Free)
-            .filter(|(_, range)| range.length >= (length_in_blocks))
-            .min_by(|(_, range_a), (_, range_b)| range_a.length.cmp(&range_b.length))
-            .map(|(a, b)| (*a as u32, b.length as u32))
-        {
-            // let longest_range_start = longest_range.0 % (T::BLOCKS);
-            println!(
-                "Found free space at {} with length {}",
-                free_range_start, free_range_length
-            );
-            return Ok(free_range_start * T::BLOCK_SIZE);
+    /// Check the filesystem for errors and try to fix them
+    ///
+    /// Only safe, if none of the files have been read yet. This should only be called in new.
+    unsafe fn selfcheck(&mut self) {
+        if self.read_only {
+            return;
         }
-        // println!("No unused free space found");
-
-        let mut cheapest_range: VecDeque<(u16, Range)> = VecDeque::new();
-        let mut cheapest_range_cost: u16 = u16::MAX;
-        let mut current_range: VecDeque<(u16, Range)> = VecDeque::new();
-        let mut current_range_cost: u16 = 0;
-        let mut current_range_length: u16 = 0;
-        for (check_start, check_range) in free_ranges.iter() {
-            let Some(cost) = check_range.importance.get_cost() else {
-                // println!("Skipping important range {:?}", check_range);
+        // Remove duplicate files. They can only be created by an interrupted relocation, so their content is the same.
+        // Interrupted rewrites that change the content are finished by replaying the log before this runs.
+        let mut seen_names: Vec<String> = Vec::new();
+        for file in &self.files {
+            if !file.valid() || file.marked_for_deletion() || file.deleted() {
                 continue;
-            };
-            let current_start = current_range.front().map_or(0, |front| front.0);
-            if current_start + current_range_length != *check_start {
-                current_range.clear();
-                current_range_cost = 0;
-                current_range_length = 0;
             }
-            current_range.push_back((*check_start, *check_range));
-            current_range_cost += cost as u16;
-            current_range_length += check_range.length;
-            // println!(
-            //     "Current range: {:?}, cost: {}, length: {}",
-            //     current_range, current_range_cost, current_range_length
-            // );
-
-            if current_range_length >= length_in_blocks {
-                // Try to remove from start of the current range, until it is shortest
-                loop {
-                    let first_range_length = current_range.front().unwrap().1.length;
-                    if current_range_length - first_range_length < length_in_blocks {
-                        break;
-                    }
-                    let removed = current_range.pop_front().unwrap();
-                    let removed_cost = removed.1.importance.get_cost().unwrap();
-                    current_range_cost -= removed_cost as u16;
-                    current_range_length -= removed.1.length;
-                }
+            if seen_names.contains(&file.name) {
+                log::warn!("Removing duplicate file {}", file.name);
+                let _ = file.mark_for_deletion();
+                continue;
             }
-            if let Some(front) = current_range.front() {
-                if front.0 >= T::BLOCKS as u16 {
-                    break;
+            seen_names.push(file.name.clone());
+        }
+        self.cleanup_files();
+
+        // Fix the first block number, if the first file is marked for deletion or deleted
+        if let Some(first_file) = self.files.first() {
+            if first_file.marked_for_deletion() || first_file.deleted() {
+                let new_first_block = self.find_new_first_block();
+                if let Err(error) = self.set_first_block(new_first_block) {
+                    log::warn!("Failed to update the first block: {}", error);
                 }
             }
+        }
 
-            if current_range_length >= length_in_blocks && current_range_cost < cheapest_range_cost
-            {
-                cheapest_range = current_range.clone();
-                cheapest_range_cost = current_range_cost;
-            }
+        // TODO: Cleanup
+    }
+
+    /// Finds a file by name and returns a reference to it.
+    pub fn read_file(&self, name: &str) -> Option<File<T, { FileState::Weak }>> {
+        self.open(name).ok()
+    }
+
+    /// Finds a file by name like [Filesystem::read_file], but reports why it can not be read.
+    pub fn open(&self, name: &str) -> Result<File<T, { FileState::Weak }>, FilesystemReadError> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| {
+                file.name == name && !file.marked_for_deletion() && !file.deleted() && file.valid()
+            })
+            .ok_or(FilesystemReadError::FileNotFound)?;
+        self.check_trusted(file)?;
+        Ok(file.read())
+    }
+
+    /// Check if a file is signed by a trusted key before it is read, if signatures are verified
+    ///
+    /// The content is hashed again every time, so content that changed on the storage is noticed. The
+    /// signature is only checked once, see [Filesystem::signature_trusted].
+    #[cfg(feature = "signing")]
+    fn check_trusted(&self, file: &FileInformation<T>) -> Result<(), FilesystemReadError> {
+        if self.trusted_keys.is_none() {
+            return Ok(());
         }
+        let content = file.read().upgrade()?;
+        if self.signature_trusted(file, &content) && content.verify_hash() {
+            Ok(())
+        } else {
+            Err(FilesystemReadError::UntrustedFile)
+        }
+    }
 
-        if cheapest_range_cost == u16::MAX {
-            return Err(FindFreeSpaceError::NotEnoughSpace);
+    /// Check if a file is signed by a trusted key before it is listed, if signatures are verified
+    ///
+    /// Listing should not hash every file, so the hash computed on the first check is used, see
+    /// [File::computed_hash]. Reading the file checks the content again.
+    #[cfg(feature = "signing")]
+    fn listed_as_trusted(&self, file: &FileInformation<T>) -> bool {
+        if self.trusted_keys.is_none() {
+            return true;
         }
+        file.read().upgrade().is_ok_and(|content| {
+            self.signature_trusted(file, &content) && content.computed_hash() == *content.hash()
+        })
+    }
 
-        for range in cheapest_range.iter() {
-            println!("Cheapest range: {:?}", range);
-            let matched_file = self
-                .files
-                .iter()
-                .find(|f| f.address == range.0 as u32 * T::BLOCK_SIZE);
+    /// Check if the signature attribute of a file is valid for the hash in its header
+    ///
+    /// Reading the attribute and verifying the signature is slow on the esp32, so the result is cached
+    /// in the file table until the attributes of the file change, see [Filesystem::forget_trust].
+    #[cfg(feature = "signing")]
+    fn signature_trusted(
+        &self,
+        file: &FileInformation<T>,
+        content: &File<T, { FileState::Reader }>,
+    ) -> bool {
+        let Some(trusted_keys) = &self.trusted_keys else {
+            return true;
+        };
+        *file.signature_trusted.get_or_init(|| {
+            self.read_attributes(&file.name)
+                .into_iter()
+                .find(|(key, _)| key == signing::SIGNATURE_ATTRIBUTE)
+                .is_some_and(|(_, signature)| {
+                    signing::verify(trusted_keys, content.hash(), &signature)
+                })
+        })
+    }
 
-            if let Some(file) = matched_file {
-                file.mark_for_deletion().unwrap();
-                if !file.deleted() {
-                    eprintln!("File should have been deleted");
-                    panic!("File should have been deleted");
-                }
-            }
+    /// Drop the cached signature checks of the files with this name, after their attributes changed
+    #[cfg(feature = "signing")]
+    fn forget_trust(&mut self, name: &str) {
+        for file in self.files.iter_mut().filter(|file| file.name == name) {
+            file.signature_trusted = Default::default();
         }
+    }
 
-        let first = cheapest_range.front().unwrap();
-        let start = first.0 as u32 * T::BLOCK_SIZE;
-        println!("Found unimportant space at {}", start);
-        return Ok(start);
+    /// Every file is trusted without the `signing` feature
+    #[cfg(not(feature = "signing"))]
+    fn check_trusted(&self, _file: &FileInformation<T>) -> Result<(), FilesystemReadError> {
+        Ok(())
+    }
 
-        // todo!("Clear cheapest range and return it");
-        // if let Some((free_range_start, free_range_length)) = free_ranges
-        //     .iter()
-        //     .filter(|(_, range)| range.importance != Importance::Important)
-        //     .filter(|(_, range)| range.length >= (length.div_ceil(T::BLOCK_SIZE) as u16))
-        //     .min_by(|(_, range_a), (_, range_b)| range_a.length.cmp(&range_b.length))
-        //     .map(|(a, b)| (*a as u32, b.length as u32))
-        // {
-        //     // let longest_range_start = longest_range.0 % (T::BLOCKS);
-        //     println!(
-        //         "Found free space at {} with length {}",
-        //         free_range_start, free_range_length
-        //     );
-        //     return Ok(free_range_start * T::BLOCK_SIZE);
-        // }
-        // return Err(FindFreeSpaceError::NotEnoughSpace);
+    /// Every file is trusted without the `signing` feature
+    #[cfg(not(feature = "signing"))]
+    fn listed_as_trusted(&self, _file: &FileInformation<T>) -> bool {
+        true
     }
 
-    /// Write a file to storage.
-    pub fn write_file(
-        &mut self,
+    /// Nothing is cached without the `signing` feature
+    #[cfg(not(feature = "signing"))]
+    fn forget_trust(&mut self, _name: &str) {}
+
+    /// Copy part of a file into `buf`, starting at `offset` bytes into the file.
+    ///
+    /// Returns the number of bytes copied. This is less than the length of `buf` if the end of the file
+    /// is reached and 0 if `offset` is at or after the end of the file.
+    pub fn read_file_chunked(
+        &self,
         name: &str,
-        content: &[u8],
-        _hash: &[u8; 32],
-    ) -> Result<(), FilesystemWriteError> {
-        let mut writer = self.get_file_writer(name, content.len() as u32, _hash)?;
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, FilesystemReadError> {
+        let file = self.open(name)?.upgrade()?;
+        let start = (offset as usize).min(file.len());
+        let length = buf.len().min(file.len() - start);
+        buf[..length].copy_from_slice(&file[start..start + length]);
+        Ok(length)
+    }
 
-        writer.write_all(content)?;
-        writer.commit()?;
-        Ok(())
+    /// List all readable files, ordered by name.
+    pub fn list_files(&self) -> impl Iterator<Item = File<T, { FileState::Weak }>> {
+        self.list_files_with_prefix("")
     }
 
-    /// Get a writer that allows writing a file over time.
+    /// List all readable files in the order given by `by`
     ///
-    /// The file can only be read after the content was finished
-    pub fn get_file_writer(
-        &mut self,
-        name: &str,
-        length: u32,
-        hash: &[u8; 32],
-    ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
-        self.cleanup_files();
-        if self
-            .files
-            .iter()
-            .any(|file| !file.deleted() && !file.marked_for_deletion() && file.name == name)
-        {
-            return Err(FilesystemWriteError::NameAlreadyTaken);
+    /// Like [Filesystem::list_files], this skips files that are deleted, degraded or not trusted. Only the
+    /// returned list is sorted, the files stay where they are on the storage.
+    pub fn list_files_sorted(&self, by: SortKey) -> Vec<File<T, { FileState::Weak }>> {
+        // Sorting is stable, so files with the same key stay ordered by name
+        let mut files = self.readable_files();
+        match by {
+            SortKey::Name => {}
+            SortKey::LengthAsc => files.sort_by_key(|file| file.length),
+            SortKey::LengthDesc => files.sort_by_key(|file| std::cmp::Reverse(file.length)),
+            SortKey::CreatedAsc => files.sort_by_key(|file| file.created_at()),
+            SortKey::CreatedDesc => files.sort_by_key(|file| std::cmp::Reverse(file.created_at())),
         }
-        let free_location = self.find_free_space(length + size_of::<FileMetadata>() as u32)?;
+        files.into_iter().map(|file| file.read()).collect()
+    }
 
-        let (file, writer) =
-            FileInformation::to_storage(self.storage, free_location, length, name, hash)?;
-        self.files.push(file);
-        Ok(writer)
+    /// List all readable files with names starting with `prefix`, ordered by name.
+    pub fn list_files_with_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl Iterator<Item = File<T, { FileState::Weak }>> {
+        let mut files: Vec<&FileInformation<T>> = self
+            .files
+            .iter()
+            .filter(|file| {
+                file.name.starts_with(prefix)
+                    && file.valid()
+                    && !file.marked_for_deletion()
+                    && !file.deleted()
+                    && self.listed_as_trusted(file)
+            })
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        files
+            .into_iter()
+            .map(|file| file.read())
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 
-    /// Delete a file
+    /// Iterate over the names and contents of all readable files, ordered by name.
     ///
-    /// The file will only be deleted once there are no strong references to its content left. Strong references can be obtained by calling upgrade on the content of a file
-    pub fn delete_file(&mut self, filename: &str) -> Result<(), FilesystemDeleteError> {
-        let Some((index, _)) = self
+    /// The contents are borrowed from the memory mapped storage without copying. Files can only be
+    /// deleted through a mutable reference to the filesystem, so they stay valid while the iterator is
+    /// borrowed. Filter on the name to only process some files:
+    ///
+    /// ```ignore
+    /// for (name, content) in filesystem.iter_contents().filter(|(name, _)| name.starts_with("app/")) {
+    ///     run_program(name, content);
+    /// }
+    /// ```
+    ///
+    /// The contents can not be kept after a lock around the filesystem is released, see [lock]. Use
+    /// [Filesystem::iter_contents_owned] for that.
+    pub fn iter_contents(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.readable_files().into_iter().filter_map(|file| {
+            let content = self
+                .storage
+                .read(file.address + HEADER_SIZE, file.length)
+                .ok()?;
+            Some((file.name.as_str(), content))
+        })
+    }
+
+    /// Iterate over readers of all readable files, ordered by name.
+    ///
+    /// Unlike [Filesystem::iter_contents], the readers do not borrow the filesystem, so they can be kept
+    /// after a lock around it is released. A reader keeps the content of its file from being erased, even
+    /// if the file is deleted in the meantime.
+    pub fn iter_contents_owned(&self) -> impl Iterator<Item = File<T, { FileState::Reader }>> {
+        let readers: Vec<_> = self
+            .readable_files()
+            .into_iter()
+            .filter_map(|file| file.read().upgrade().ok())
+            .collect();
+        readers.into_iter()
+    }
+
+    /// Name, hash of the content and length of every readable file, ordered by name
+    ///
+    /// The hash is computed from the content, see [File::computed_hash].
+    fn manifest(&self) -> Vec<(String, [u8; 32], u32)> {
+        self.iter_contents_owned()
+            .map(|file| {
+                (
+                    file.name_str().to_string(),
+                    file.computed_hash(),
+                    file.len() as u32,
+                )
+            })
+            .collect()
+    }
+
+    /// Check if two filesystems contain the same files with the same content
+    ///
+    /// Only the names and contents of readable files are compared. Where the files are stored, the
+    /// hashes in their headers, their flags and their attributes do not matter. The filesystems can be
+    /// on different kinds of storage, which makes this useful for checking that an exported image
+    /// contains the same files as the original.
+    pub fn content_eq<U: Storage + 'static + Send + Sync>(&self, other: &Filesystem<U>) -> bool {
+        self.manifest() == other.manifest()
+    }
+
+    /// Get the content of a file without creating a reader
+    ///
+    /// The content is borrowed from the filesystem, so it can not be kept after a lock around the
+    /// filesystem is released. Use [Filesystem::read_file] and [File::upgrade] for that.
+    pub fn read_contents(&self, name: &str) -> Result<&[u8], FilesystemReadError> {
+        let index = self
+            .live_file_index(name)
+            .ok_or(FilesystemReadError::FileNotFound)?;
+        let file = &self.files[index];
+        self.check_trusted(file)?;
+        Ok(self.storage.read(file.address + HEADER_SIZE, file.length)?)
+    }
+
+    /// Get the content of a file and whether it matches the hash in its header
+    ///
+    /// Unlike [Filesystem::read_contents], this also returns files that were degraded during mount, so the
+    /// caller can decide whether to use them. The content is hashed on every call, like in
+    /// [File::verify_hash].
+    pub fn read_file_verified(&self, name: &str) -> Result<(&[u8], bool), FilesystemReadError> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| {
+                file.name == name
+                    && (file.valid() || file.degraded)
+                    && !file.marked_for_deletion()
+                    && !file.deleted()
+            })
+            .ok_or(FilesystemReadError::FileNotFound)?;
+        self.check_trusted(file)?;
+        let verified = file.read().upgrade()?.verify_hash();
+        let content = self.storage.read(file.address + HEADER_SIZE, file.length)?;
+        Ok((content, verified))
+    }
+
+    /// Get the content of a file, failing with [FilesystemReadError::Corrupt] if it does not match its
+    /// hash
+    ///
+    /// See [Filesystem::read_file_verified].
+    pub fn read_file_strict(&self, name: &str) -> Result<&[u8], FilesystemReadError> {
+        match self.read_file_verified(name)? {
+            (content, true) => Ok(content),
+            (_, false) => Err(FilesystemReadError::Corrupt),
+        }
+    }
+
+    /// Get the header of a file exactly as it is stored, for debugging
+    ///
+    /// Also finds files that are not ready or were degraded during mount. Use
+    /// [Filesystem::raw_header_at_block] to look at blocks that do not belong to a known file.
+    pub fn raw_header(
+        &self,
+        name: &str,
+    ) -> Result<[u8; HEADER_SIZE as usize], FilesystemReadError> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.name == name && !file.deleted())
+            .ok_or(FilesystemReadError::FileNotFound)?;
+        self.raw_header_at_block(file.start_block())
+    }
+
+    /// Get the first [HEADER_SIZE] bytes of a block exactly as they are stored, for debugging
+    ///
+    /// The block does not need to contain a valid header.
+    pub fn raw_header_at_block(
+        &self,
+        block: u32,
+    ) -> Result<[u8; HEADER_SIZE as usize], FilesystemReadError> {
+        if block >= T::BLOCKS {
+            return Err(storage::StorageError::AddressTooBig.into());
+        }
+        let mut header = [0; HEADER_SIZE as usize];
+        self.storage.read_into(block * T::BLOCK_SIZE, &mut header)?;
+        Ok(header)
+    }
+
+    /// Files that can be read, ordered by name
+    fn readable_files(&self) -> Vec<&FileInformation<T>> {
+        let mut files: Vec<&FileInformation<T>> = self
+            .files
+            .iter()
+            .filter(|file| {
+                file.valid()
+                    && !file.marked_for_deletion()
+                    && !file.deleted()
+                    && self.listed_as_trusted(file)
+            })
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        files
+    }
+
+    /// Finds a file by hash and returns a reference to it.
+    ///
+    /// This compares the hash stored in the metadata and does not hash any content. See [File::hash].
+    pub fn read_file_by_hash(&self, hash: &[u8; 32]) -> Option<File<T, { FileState::Weak }>> {
+        let file = self.files.iter().find(|file| {
+            file.compare_hash(hash)
+                && !file.marked_for_deletion()
+                && !file.deleted()
+                && file.valid()
+                && self.listed_as_trusted(file)
+        })?;
+        Some(file.read())
+    }
+
+    /// Find the readable file whose header is at the start of `block`.
+    ///
+    /// Useful for tools that work with a map of the blocks, like [Filesystem::block_map]. Returns None if no
+    /// file starts there, for example because the block is in the middle of a file.
+    pub fn file_at_block(&self, block: u32) -> Option<File<T, { FileState::Weak }>> {
+        self.readable_file_at_block(block).map(|file| file.read())
+    }
+
+    /// Get the content of the readable file whose header is at the start of `block`.
+    ///
+    /// The content is borrowed from the memory mapped storage like in [Filesystem::iter_contents].
+    pub fn read_at_block(&self, block: u32) -> Result<&[u8], FilesystemReadError> {
+        let file = self
+            .readable_file_at_block(block)
+            .ok_or(FilesystemReadError::FileNotFound)?;
+        self.check_trusted(file)?;
+        Ok(self.storage.read(file.address + HEADER_SIZE, file.length)?)
+    }
+
+    /// Find the readable file with its header at the start of `block`
+    fn readable_file_at_block(&self, block: u32) -> Option<&FileInformation<T>> {
+        let address = block.checked_mul(T::BLOCK_SIZE)?;
+        self.files.iter().find(|file| {
+            file.address == address
+                && file.valid()
+                && !file.marked_for_deletion()
+                && !file.deleted()
+                && self.listed_as_trusted(file)
+        })
+    }
+
+    /// Get what occupies each block. Deleted files are treated as free space, blocks that could not be
+    /// erased during mount are not.
+    fn block_owners(&self) -> Result<Vec<Option<BlockOwner>>, FindFreeSpaceError> {
+        let mut owners: Vec<Option<BlockOwner>> = vec![None; T::BLOCKS as usize];
+        for (index, file) in self.files.iter().enumerate() {
+            if file.deleted() {
+                continue;
+            }
+            let start_block = file.start_block();
+            let length_in_blocks = Self::blocks_for(file.length);
+            for block in start_block..(start_block + length_in_blocks) {
+                let owner = &mut owners[(block % T::BLOCKS) as usize];
+                if owner.is_some() {
+                    // Files should never overlap
+                    return Err(FindFreeSpaceError::FilesystemError);
+                }
+                *owner = Some(BlockOwner::File(index));
+            }
+        }
+        for block in &self.unusable_blocks {
+            let owner = &mut owners[*block as usize];
+            if owner.is_some() {
+                return Err(FindFreeSpaceError::FilesystemError);
+            }
+            *owner = Some(BlockOwner::Unusable);
+        }
+        Ok(owners)
+    }
+
+    /// Files that should be checked by verify_all
+    fn verifiable_files(&self) -> impl Iterator<Item = &FileInformation<T>> {
+        self.files
+            .iter()
+            .filter(|file| file.valid() && !file.marked_for_deletion() && !file.deleted())
+    }
+
+    /// Recompute the hash of every file and compare it to the stored hash.
+    ///
+    /// Returns the name of every file and whether its content matches its hash.
+    pub fn verify_all(&self) -> Vec<(String, bool)> {
+        self.verifiable_files()
+            .map(|file| (file.name.clone(), file.verify()))
+            .collect()
+    }
+
+    /// Check if the content of a file matches its stored hash.
+    ///
+    /// Returns None, if there is no readable file with that name.
+    pub fn verify_file(&self, name: &str) -> Option<bool> {
+        let file = self.read_file(name)?.upgrade().ok()?;
+        Some(file.verify_hash())
+    }
+
+    /// Same as [Filesystem::verify_all], but hashes the files on multiple threads.
+    #[cfg(feature = "rayon")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rayon")))]
+    pub fn verify_all_parallel(&self) -> Vec<(String, bool)> {
+        use rayon::prelude::*;
+        let files: Vec<&FileInformation<T>> = self.verifiable_files().collect();
+        files
+            .into_par_iter()
+            .map(|file| (file.name.clone(), file.verify()))
+            .collect()
+    }
+
+    /// Get information about the free space in the storage
+    ///
+    /// Returns a map from the first block of a range to the range. Every file gets its own range and
+    /// consecutive free blocks are combined into one range. The ranges are repeated once after the
+    /// end of the storage, so ranges that wrap around the end can be found by looking at consecutive entries.
+    fn analyze_free_space(&self) -> Result<BTreeMap<u16, Range>, FindFreeSpaceError> {
+        let owners = self.block_owners()?;
+
+        let mut free_ranges: BTreeMap<u16, Range> = Default::default();
+        let mut block = 0;
+        while block < T::BLOCKS {
+            match owners[block as usize] {
+                // Unusable blocks can not be freed by deleting files
+                Some(BlockOwner::Unusable) => {
+                    free_ranges.insert(
+                        block as u16,
+                        Range {
+                            importance: Importance::Important,
+                            length: 1,
+                        },
+                    );
+                    block += 1;
+                }
+                Some(BlockOwner::File(index)) => {
+                    let file = &self.files[index];
+                    let start_block = file.start_block();
+                    // Ranges of files that wrap around are inserted at their real start
+                    if start_block == block {
+                        let importance = if file.important() || !file.can_be_deleted() {
+                            Importance::Important
+                        } else {
+                            Importance::Unimportant { age: file.age() }
+                        };
+                        let length = Self::blocks_for(file.length);
+                        free_ranges.insert(
+                            block as u16,
+                            Range {
+                                importance,
+                                length: length as u16,
+                            },
+                        );
+                    }
+                    block += 1;
+                }
+                None => {
+                    let start = block;
+                    while block < T::BLOCKS && owners[block as usize].is_none() {
+                        block += 1;
+                    }
+                    free_ranges.insert(
+                        start as u16,
+                        Range {
+                            importance: Importance::Free,
+                            length: (block - start) as u16,
+                        },
+                    );
+                }
+            }
+        }
+
+        // Duplicate all ranges to the back
+        for range in free_ranges.clone().into_iter() {
+            free_ranges.insert(range.0 + T::BLOCKS as u16, range.1);
+        }
+
+        return Ok(free_ranges);
+    }
+
+    /// Get all runs of free blocks as `(first block, length in blocks)`
+    ///
+    /// Free blocks at the end and at the start of the storage are combined into a single run that wraps around.
+    fn free_runs(free_ranges: &BTreeMap<u16, Range>) -> Vec<(u16, u16)> {
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        let mut current_run: Option<(u16, u16)> = None;
+        for (start, range) in free_ranges.iter() {
+            if range.importance != Importance::Free {
+                runs.extend(current_run.take());
+                continue;
+            }
+            current_run = match current_run {
+                Some((run_start, run_length)) if run_start + run_length == *start => {
+                    Some((run_start, run_length + range.length))
+                }
+                _ => {
+                    runs.extend(current_run.take());
+                    Some((*start, range.length))
+                }
+            };
+        }
+        runs.extend(current_run);
+        // The run at the start is already part of a run that wraps around
+        let wrapping = runs
+            .iter()
+            .any(|(start, length)| *start < T::BLOCKS as u16 && start + length > T::BLOCKS as u16);
+        runs.into_iter()
+            .filter(|(start, _)| *start < T::BLOCKS as u16)
+            .filter(|(start, length)| {
+                !(wrapping && *start == 0 && start + length <= T::BLOCKS as u16)
+            })
+            .map(|(start, length)| (start, length.min(T::BLOCKS as u16)))
+            .collect()
+    }
+
+    /// Get the size of the largest file that can currently be written without deleting other files.
+    ///
+    /// This is the largest run of free blocks minus the size of the file header, limited by the free
+    /// space outside of the reserved blocks. Unimportant files that would be deleted to make space are
+    /// not taken into account.
+    pub fn max_writable_file(&self) -> u32 {
+        self.largest_free_run()
+            .min(self.free_space())
+            .saturating_sub(HEADER_SIZE)
+    }
+
+    /// Get the size of the largest run of free blocks in bytes. Runs can wrap around the end of the storage.
+    pub fn largest_free_run(&self) -> u32 {
+        let Ok(free_ranges) = self.analyze_free_space() else {
+            return 0;
+        };
+        let largest_run = Self::free_runs(&free_ranges)
+            .into_iter()
+            .map(|(_, length)| length as u32)
+            .max()
+            .unwrap_or(0);
+        largest_run * T::BLOCK_SIZE
+    }
+
+    /// Get the number of separate runs of free blocks. Runs can wrap around the end of the storage.
+    ///
+    /// 1 means all free space is in one place and 0 that there is no free space. Every additional run is a
+    /// gap between files that [Filesystem::compact] could merge.
+    pub fn free_run_count(&self) -> u32 {
+        let Ok(free_ranges) = self.analyze_free_space() else {
+            return 0;
+        };
+        Self::free_runs(&free_ranges).len() as u32
+    }
+
+    /// Get the total amount of free space in bytes that can be used for new files.
+    ///
+    /// The free space may be split into multiple runs, so a file of this size does not necessarily fit.
+    /// The reserved blocks are not included, see [FilesystemBuilder::reserved_blocks].
+    pub fn free_space(&self) -> u32 {
+        let Ok(free_blocks) = self.free_blocks() else {
+            return 0;
+        };
+        free_blocks.saturating_sub(self.reserved_blocks) * T::BLOCK_SIZE
+    }
+
+    /// Get the number of bytes that are wasted, because files are rounded up to whole blocks
+    ///
+    /// Every file uses its header and content rounded up to the next block. The rest of its last block can
+    /// not be used by other files. A large value compared to the size of the storage means that many small
+    /// files waste space and combining them would help.
+    pub fn internal_fragmentation(&self) -> u64 {
+        self.files
+            .iter()
+            .filter(|file| !file.deleted())
+            .map(|file| {
+                Self::blocks_for(file.length) as u64 * T::BLOCK_SIZE as u64
+                    - (file.length as u64 + HEADER_SIZE as u64)
+            })
+            .sum()
+    }
+
+    /// Get a summary of the space on the storage
+    pub fn space_usage(&self) -> SpaceUsage {
+        SpaceUsage {
+            used_blocks: T::BLOCKS - self.free_blocks().unwrap_or(T::BLOCKS),
+            free_space: self.free_space(),
+            largest_free_run: self.largest_free_run(),
+            internal_fragmentation: self.internal_fragmentation(),
+            free_run_count: self.free_run_count(),
+        }
+    }
+
+    /// Get the number of blocks that are kept free for compaction
+    pub fn reserved_blocks(&self) -> u32 {
+        self.reserved_blocks
+    }
+
+    /// Count the blocks that do not belong to a file, including the reserved blocks
+    fn free_blocks(&self) -> Result<u32, FindFreeSpaceError> {
+        let owners = self.block_owners()?;
+        Ok(owners.iter().filter(|owner| owner.is_none()).count() as u32)
+    }
+
+    /// Check if a new file of `length_in_blocks` blocks leaves the reserved blocks free
+    fn keeps_reservation(&self, length_in_blocks: u32) -> Result<bool, FindFreeSpaceError> {
+        Ok(self.free_blocks()? >= length_in_blocks + self.reserved_blocks)
+    }
+
+    /// Visit every block in ring order together with the file that owns it
+    ///
+    /// The order starts at the first file, see [Filesystem::block_map]. The visitor gets the block, the
+    /// index of its file in the file table, if any, and whether the block is the first block of that file.
+    /// Nothing is visited if files overlap.
+    fn walk_owners(&self, mut visitor: impl FnMut(u32, Option<BlockOwner>, bool)) {
+        let Ok(owners) = self.block_owners() else {
+            return;
+        };
+        let is_file_start = |block: u32| match owners[block as usize] {
+            Some(BlockOwner::File(index)) => self.files[index].address == block * T::BLOCK_SIZE,
+            _ => false,
+        };
+        let first_block = self.get_first_block().unwrap_or(0) as u32 % T::BLOCKS;
+        let ring_start = (0..T::BLOCKS)
+            .map(|offset| (first_block + offset) % T::BLOCKS)
+            .find(|block| is_file_start(*block))
+            .unwrap_or(0);
+        for offset in 0..T::BLOCKS {
+            let block = (ring_start + offset) % T::BLOCKS;
+            visitor(block, owners[block as usize], is_file_start(block));
+        }
+    }
+
+    /// Call `visitor` for every block in the storage with what the block is used for
+    ///
+    /// Blocks are visited exactly once, in ring order starting at the first file, like in
+    /// [Filesystem::block_map]. Files that wrap around the end of the storage continue at block 0. If the
+    /// file table is inconsistent and files overlap, nothing is visited.
+    pub fn walk_blocks(&self, mut visitor: impl FnMut(u32, BlockClass)) {
+        self.walk_owners(|block, owner, is_file_start| {
+            let class = match owner {
+                None => BlockClass::Free,
+                Some(BlockOwner::Unusable) => BlockClass::Unusable,
+                Some(BlockOwner::File(index)) if self.files[index].marked_for_deletion() => {
+                    BlockClass::Tombstone
+                }
+                Some(BlockOwner::File(index)) if is_file_start => BlockClass::HeaderOf(index),
+                Some(BlockOwner::File(index)) => BlockClass::DataOf(index),
+            };
+            visitor(block, class);
+        });
+    }
+
+    /// Get a map of all blocks in the storage for diagnostics
+    ///
+    /// The runs are in ring order, starting at the first file. Together they cover every block exactly once.
+    /// Free runs that wrap around the end of the storage are reported as a single run, so the map
+    /// is consistent with [Filesystem::largest_free_run]. The reserved blocks are shown as free.
+    pub fn block_map(&self) -> Vec<BlockRun> {
+        let state_of = |owner: Option<BlockOwner>| match owner {
+            None => BlockState::Free,
+            Some(BlockOwner::Unusable) => BlockState::Unusable,
+            Some(BlockOwner::File(index)) if self.files[index].marked_for_deletion() => {
+                BlockState::Tombstone
+            }
+            Some(BlockOwner::File(index)) => BlockState::Used(index),
+        };
+
+        let mut runs: Vec<BlockRun> = Vec::new();
+        self.walk_owners(|block, owner, is_file_start| {
+            let state = state_of(owner);
+            let continues_run = runs
+                .last()
+                .is_some_and(|run: &BlockRun| run.state == state && !is_file_start);
+            match runs.last_mut() {
+                Some(run) if continues_run => run.len += 1,
+                _ => runs.push(BlockRun {
+                    start: block,
+                    len: 1,
+                    state,
+                }),
+            }
+        });
+        runs
+    }
+
+    /// Erase counts of all blocks, if wear leveling is enabled and the storage counts erases
+    fn erase_counts(&self) -> Option<Vec<u32>> {
+        self.wear_threshold?;
+        (0..T::BLOCKS)
+            .map(|block| self.storage.erase_count(block))
+            .collect()
+    }
+
+    /// Blocks erased more often than this are hot, see [FilesystemBuilder::wear_threshold]
+    fn hot_limit(&self, erase_counts: &[u32]) -> u32 {
+        let total: u64 = erase_counts.iter().map(|count| *count as u64).sum();
+        let average = (total / erase_counts.len().max(1) as u64) as u32;
+        average.saturating_add(self.wear_threshold.unwrap_or(u32::MAX))
+    }
+
+    /// Find the first block for a new file of `length_in_blocks` blocks
+    ///
+    /// Uses [Filesystem::find_free_run], unless that run starts with a hot block. Then the coldest
+    /// blocks that can hold the file are used instead.
+    fn place_file(&self, free_ranges: &BTreeMap<u16, Range>, length_in_blocks: u16) -> Option<u32> {
+        let start = Self::find_free_run(free_ranges, length_in_blocks)?;
+        let Some(erase_counts) = self.erase_counts() else {
+            return Some(start);
+        };
+        let limit = self.hot_limit(&erase_counts);
+        let window = |start: u32| {
+            (start..start + length_in_blocks as u32)
+                .map(|block| erase_counts[(block % T::BLOCKS) as usize])
+        };
+        if window(start).all(|count| count <= limit) {
+            return Some(start);
+        }
+        Self::find_coldest_run(free_ranges, length_in_blocks, &erase_counts)
+    }
+
+    /// Find the first block of the free blocks that can hold `length_in_blocks` blocks and were erased
+    /// least often in total
+    fn find_coldest_run(
+        free_ranges: &BTreeMap<u16, Range>,
+        length_in_blocks: u16,
+        erase_counts: &[u32],
+    ) -> Option<u32> {
+        let window = |start: u32| {
+            (start..start + length_in_blocks as u32)
+                .map(|block| erase_counts[(block % T::BLOCKS) as usize])
+        };
+        Self::free_runs(free_ranges)
+            .into_iter()
+            .filter(|(_, length)| *length >= length_in_blocks)
+            .flat_map(|(run_start, run_length)| {
+                (0..=run_length - length_in_blocks)
+                    .map(move |offset| (run_start as u32 + offset as u32) % T::BLOCKS)
+            })
+            .min_by_key(|start| window(*start).map(|count| count as u64).sum::<u64>())
+    }
+
+    /// Number of runs of free blocks. Runs can wrap around the end of the storage.
+    fn count_free_runs(free: &[bool]) -> usize {
+        if free.iter().all(|free| *free) {
+            return 1;
+        }
+        (0..free.len())
+            .filter(|block| free[*block] && !free[(block + free.len() - 1) % free.len()])
+            .count()
+    }
+
+    /// Find a file to move for wear leveling and the block to move it to
+    ///
+    /// Only if some free blocks are hot. The file on the coldest blocks is moved to the hottest free
+    /// blocks, so its old blocks can be used for new files. The target needs to be hotter than every
+    /// block of the file, and the move may not split the free space into more runs.
+    fn wear_relocation(&self, owners: &[Option<BlockOwner>]) -> Option<(usize, u32)> {
+        let erase_counts = self.erase_counts()?;
+        let limit = self.hot_limit(&erase_counts);
+        let free: Vec<bool> = owners.iter().map(Option::is_none).collect();
+        if !(0..T::BLOCKS as usize).any(|block| free[block] && erase_counts[block] > limit) {
+            return None;
+        }
+        let blocks_of_file = |file: &FileInformation<T>| {
+            let start = file.start_block();
+            (start..start + Self::blocks_of(file)).map(|block| (block % T::BLOCKS) as usize)
+        };
+        let (index, file_hottest) = self
             .files
             .iter()
             .enumerate()
-            .find(|(_, file)| file.name == filename)
-        else {
-            return Err(FilesystemDeleteError::FileNotFound);
+            .filter(|(_, file)| Self::movable(file))
+            .map(|(index, file)| {
+                let hottest = blocks_of_file(file).map(|block| erase_counts[block]).max();
+                (index, hottest.unwrap_or(0))
+            })
+            .min_by_key(|(_, hottest)| *hottest)?;
+        let file = &self.files[index];
+        let length = Self::blocks_of(file);
+        let runs_before = Self::count_free_runs(&free).max(1);
+
+        let mut best: Option<(u32, u32)> = None;
+        for start in 0..T::BLOCKS {
+            let window: Vec<usize> = (start..start + length)
+                .map(|block| (block % T::BLOCKS) as usize)
+                .collect();
+            if !window.iter().all(|block| free[*block]) {
+                continue;
+            }
+            let coldest = window.iter().map(|block| erase_counts[*block]).min()?;
+            if coldest <= file_hottest || best.is_some_and(|(best, _)| best >= coldest) {
+                continue;
+            }
+            let mut free_after = free.clone();
+            for block in &window {
+                free_after[*block] = false;
+            }
+            for block in blocks_of_file(file) {
+                free_after[block] = true;
+            }
+            if Self::count_free_runs(&free_after) <= runs_before {
+                best = Some((coldest, start));
+            }
+        }
+        best.map(|(_, start)| (index, start))
+    }
+
+    /// Find the first block of the smallest free run that can hold `length_in_blocks` blocks
+    fn find_free_run(free_ranges: &BTreeMap<u16, Range>, length_in_blocks: u16) -> Option<u32> {
+        Self::free_runs(free_ranges)
+            .into_iter()
+            .filter(|(_, length)| *length >= length_in_blocks)
+            .min_by_key(|(_, length)| *length)
+            .map(|(start, _)| start as u32)
+    }
+
+    /// Find the first block of the smallest free run that can hold `length_in_blocks` blocks without
+    /// wrapping around the end of the storage
+    ///
+    /// A run that wraps around is split at the end of the storage into two runs.
+    fn find_contiguous_run(
+        free_ranges: &BTreeMap<u16, Range>,
+        length_in_blocks: u16,
+    ) -> Option<u32> {
+        let blocks = T::BLOCKS as u16;
+        Self::free_runs(free_ranges)
+            .into_iter()
+            .flat_map(|(start, length)| {
+                let before_end = length.min(blocks - start);
+                [(start, before_end), (0, length - before_end)]
+            })
+            .filter(|(_, length)| *length >= length_in_blocks)
+            .min_by_key(|(_, length)| *length)
+            .map(|(start, _)| start as u32)
+    }
+
+    /// The error for a file of `length_in_blocks` blocks that does not fit
+    fn out_of_space(&self, length_in_blocks: u32) -> FindFreeSpaceError {
+        FindFreeSpaceError::OutOfSpace {
+            needed: (length_in_blocks + self.reserved_blocks) * T::BLOCK_SIZE,
+            largest_free_run: self.largest_free_run(),
+            reclaimable_by_compaction: self.reclaimable_space(),
+        }
+    }
+
+    /// Find free space for a file with `length` bytes of content.
+    ///
+    /// For now the space is guaranteed to start at a block boundary. The reserved blocks are kept free.
+    /// When files are deleted to make space, enough is deleted to fit the file and the reserved blocks
+    /// into one run.
+    ///
+    /// With `contiguous`, only space that does not wrap around the end of the storage is used, and no
+    /// files are deleted.
+    fn find_free_space(
+        &mut self,
+        length: u32,
+        contiguous: bool,
+    ) -> Result<u32, FindFreeSpaceError> {
+        let (start, evict) = self.plan_free_space(length, contiguous)?;
+        for index in evict {
+            let file = &self.files[index];
+            log::info!("Deleting {} to make space", file.name);
+            self.evicted.push(file.name.clone());
+            file.mark_for_deletion().unwrap();
+            if !file.deleted() {
+                eprintln!("File should have been deleted");
+                panic!("File should have been deleted");
+            }
+        }
+        Ok(start)
+    }
+
+    /// Choose the space for [Filesystem::find_free_space] without changing anything
+    ///
+    /// Returns the address of the space and the indices of the files that need to be deleted to make it
+    /// free.
+    fn plan_free_space(
+        &self,
+        length: u32,
+        contiguous: bool,
+    ) -> Result<(u32, Vec<usize>), FindFreeSpaceError> {
+        let free_ranges = self.analyze_free_space()?;
+
+        for range in free_ranges.iter() {
+            println!("Free range: {:?}", range);
+        }
+
+        let length_in_blocks = Self::blocks_for(length) as u16;
+
+        if self.keeps_reservation(length_in_blocks as u32)? {
+            let free_range_start = if contiguous {
+                Self::find_contiguous_run(&free_ranges, length_in_blocks)
+            } else {
+                self.place_file(&free_ranges, length_in_blocks)
+            };
+            if let Some(free_range_start) = free_range_start {
+                println!("Found free space at {}", free_range_start);
+                return Ok((free_range_start * T::BLOCK_SIZE, Vec::new()));
+            }
+        }
+        if contiguous || !self.allow_evict_oldest {
+            return Err(self.out_of_space(length_in_blocks as u32));
+        }
+        let length_in_blocks = length_in_blocks.saturating_add(self.reserved_blocks as u16);
+        // println!("No unused free space found");
+
+        let mut cheapest_range: VecDeque<(u16, Range)> = VecDeque::new();
+        let mut cheapest_range_cost: u16 = u16::MAX;
+        let mut current_range: VecDeque<(u16, Range)> = VecDeque::new();
+        let mut current_range_cost: u16 = 0;
+        let mut current_range_length: u16 = 0;
+        for (check_start, check_range) in free_ranges.iter() {
+            let Some(cost) = check_range.importance.get_cost() else {
+                // println!("Skipping important range {:?}", check_range);
+                continue;
+            };
+            let current_start = current_range.front().map_or(0, |front| front.0);
+            if current_start + current_range_length != *check_start {
+                current_range.clear();
+                current_range_cost = 0;
+                current_range_length = 0;
+            }
+            current_range.push_back((*check_start, *check_range));
+            current_range_cost += cost as u16;
+            current_range_length += check_range.length;
+            // println!(
+            //     "Current range: {:?}, cost: {}, length: {}",
+            //     current_range, current_range_cost, current_range_length
+            // );
+
+            if current_range_length >= length_in_blocks {
+                // Try to remove from start of the current range, until it is shortest
+                loop {
+                    let first_range_length = current_range.front().unwrap().1.length;
+                    if current_range_length - first_range_length < length_in_blocks {
+                        break;
+                    }
+                    let removed = current_range.pop_front().unwrap();
+                    let removed_cost = removed.1.importance.get_cost().unwrap();
+                    current_range_cost -= removed_cost as u16;
+                    current_range_length -= removed.1.length;
+                }
+            }
+            if let Some(front) = current_range.front() {
+                if front.0 >= T::BLOCKS as u16 {
+                    break;
+                }
+            }
+
+            if current_range_length >= length_in_blocks && current_range_cost < cheapest_range_cost
+            {
+                cheapest_range = current_range.clone();
+                cheapest_range_cost = current_range_cost;
+            }
+        }
+
+        if cheapest_range_cost == u16::MAX {
+            return Err(FindFreeSpaceError::NotEnoughSpace);
+        }
+
+        let mut evict = Vec::new();
+        for range in cheapest_range.iter() {
+            println!("Cheapest range: {:?}", range);
+            let matched_file = self
+                .files
+                .iter()
+                .position(|f| f.address == (range.0 as u32 % T::BLOCKS) * T::BLOCK_SIZE);
+            evict.extend(matched_file);
+        }
+
+        let first = cheapest_range.front().unwrap();
+        let start = first.0 as u32 * T::BLOCK_SIZE;
+        println!("Found unimportant space at {}", start);
+        return Ok((start, evict));
+
+        // todo!("Clear cheapest range and return it");
+        // if let Some((free_range_start, free_range_length)) = free_ranges
+        //     .iter()
+        //     .filter(|(_, range)| range.importance != Importance::Important)
+        //     .filter(|(_, range)| range.length >= (length.div_ceil(T::BLOCK_SIZE) as u16))
+        //     .min_by(|(_, range_a), (_, range_b)| range_a.length.cmp(&range_b.length))
+        //     .map(|(a, b)| (*a as u32, b.length as u32))
+        // {
+        //     // let longest_range_start = longest_range.0 % (T::BLOCKS);
+        //     println!(
+        //         "Found free space at {} with length {}",
+        //         free_range_start, free_range_length
+        //     );
+        //     return Ok(free_range_start * T::BLOCK_SIZE);
+        // }
+        // return Err(FindFreeSpaceError::NotEnoughSpace);
+    }
+
+    /// Write a file to storage.
+    ///
+    /// `name` can be a [FileName] or a string that is checked here, see [IntoFileName].
+    pub fn write_file(
+        &mut self,
+        name: impl IntoFileName,
+        content: &[u8],
+        _hash: &[u8; 32],
+    ) -> Result<(), FilesystemWriteError> {
+        self.write_file_with_options(name, content, _hash, WriteOptions::default())
+    }
+
+    /// Write a file with the given options, see [WriteOptions]
+    pub fn write_file_with_options(
+        &mut self,
+        name: impl IntoFileName,
+        content: &[u8],
+        hash: &[u8; 32],
+        options: WriteOptions,
+    ) -> Result<(), FilesystemWriteError> {
+        let mut writer =
+            self.get_file_writer_with_options(name, content.len() as u32, hash, options)?;
+
+        writer.write_all(content)?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Write a file together with an Ed25519 signature over its hash.
+    ///
+    /// The signature is stored as an attribute of the file, see [FilesystemBuilder::verify_signatures].
+    /// It is not checked while writing.
+    #[cfg(feature = "signing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "signing")))]
+    pub fn write_file_signed(
+        &mut self,
+        name: impl IntoFileName,
+        content: &[u8],
+        hash: &[u8; 32],
+        signature: &[u8; 64],
+    ) -> Result<(), FilesystemWriteError> {
+        let name = name.into_file_name()?;
+        let name = name.as_str();
+        self.write_file(name, content, hash)?;
+        if let Err(error) = self.set_attr(name, signing::SIGNATURE_ATTRIBUTE, signature) {
+            if let Err(delete_error) = self.delete_file(name) {
+                log::warn!(
+                    "Failed to delete {} without signature: {}",
+                    name,
+                    delete_error
+                );
+            }
+            return Err(FilesystemWriteError::SignatureNotStored(error));
+        }
+        Ok(())
+    }
+
+    /// Get a writer that allows writing a file over time.
+    ///
+    /// The file can only be read after the content was finished. `length_bytes` is the length of the content
+    /// without the header.
+    ///
+    /// The blocks of the file are allocated and recorded in the table of files before this returns. A shared
+    /// filesystem only needs to be locked for this call, the writer can be used after the lock is released.
+    pub fn get_file_writer(
+        &mut self,
+        name: impl IntoFileName,
+        length_bytes: u32,
+        hash: &[u8; 32],
+    ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
+        self.get_file_writer_with_options(name, length_bytes, hash, WriteOptions::default())
+    }
+
+    /// Get a writer for a file with the given options, see [Filesystem::get_file_writer] and [WriteOptions]
+    pub fn get_file_writer_with_options(
+        &mut self,
+        name: impl IntoFileName,
+        length_bytes: u32,
+        hash: &[u8; 32],
+        options: WriteOptions,
+    ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
+        if self.read_only {
+            return Err(FilesystemWriteError::ReadOnly);
+        }
+        let name = name.into_file_name()?;
+        let name = name.as_str();
+        self.cleanup_files();
+        self.check_new_file(name, length_bytes)?;
+        let free_location = match options.start_hint {
+            Some(address) => self.check_start_hint(address, length_bytes, options.contiguous)?,
+            None => self.find_free_space(length_bytes, options.contiguous)?,
+        };
+        self.create_writer(name, length_bytes, hash, free_location)
+    }
+
+    /// Find out where a new file would be placed, without writing anything
+    ///
+    /// Runs the same checks and chooses the same space as [Filesystem::get_file_writer_with_options], as
+    /// long as nothing changes in between. If unimportant files would be deleted to make space, see
+    /// [Filesystem::set_allow_evict_oldest], they are listed in the plan, but not deleted. Fails with the
+    /// same error as creating the file would, for example [FindFreeSpaceError::OutOfSpace] or
+    /// [FilesystemWriteError::NameAlreadyTaken].
+    pub fn plan_create(
+        &self,
+        name: impl IntoFileName,
+        length_bytes: u32,
+        options: WriteOptions,
+    ) -> Result<CreatePlan, FilesystemWriteError> {
+        if self.read_only {
+            return Err(FilesystemWriteError::ReadOnly);
+        }
+        let name = name.into_file_name()?;
+        self.check_new_file(&name, length_bytes)?;
+        let (address, evict) = match options.start_hint {
+            Some(address) => (
+                self.check_start_hint(address, length_bytes, options.contiguous)?,
+                Vec::new(),
+            ),
+            None => self.plan_free_space(length_bytes, options.contiguous)?,
+        };
+        let start_block = address / T::BLOCK_SIZE;
+        let block_span = Self::blocks_for(length_bytes);
+        Ok(CreatePlan {
+            start_block,
+            block_span,
+            wraps: start_block + block_span > T::BLOCKS,
+            evicted: evict
+                .into_iter()
+                .map(|index| self.files[index].name.clone())
+                .collect(),
+        })
+    }
+
+    /// Check that a file of `length` bytes fits into the free blocks at `address`, see
+    /// [WriteOptions::start_hint]
+    fn check_start_hint(
+        &self,
+        address: u32,
+        length: u32,
+        contiguous: bool,
+    ) -> Result<u32, FindFreeSpaceError> {
+        if !address.is_multiple_of(T::BLOCK_SIZE) || address >= T::BLOCKS * T::BLOCK_SIZE {
+            return Err(FindFreeSpaceError::InvalidHint { address });
+        }
+        let start_block = address / T::BLOCK_SIZE;
+        let length_in_blocks = Self::blocks_for(length);
+        let owners = self.block_owners()?;
+        let free = length_in_blocks <= T::BLOCKS
+            && (0..length_in_blocks)
+                .all(|block| owners[((start_block + block) % T::BLOCKS) as usize].is_none());
+        let wraps = start_block + length_in_blocks > T::BLOCKS;
+        if !free || (contiguous && wraps) {
+            return Err(FindFreeSpaceError::HintUnavailable { address });
+        }
+        if !self.keeps_reservation(length_in_blocks)? {
+            return Err(self.out_of_space(length_in_blocks));
+        }
+        Ok(address)
+    }
+
+    /// Check that a new file with this name and length is allowed, before looking for space
+    fn check_new_file(&self, name: &str, length_bytes: u32) -> Result<(), FilesystemWriteError> {
+        if self
+            .files
+            .iter()
+            .any(|file| !file.deleted() && !file.marked_for_deletion() && file.name == name)
+        {
+            return Err(FilesystemWriteError::NameAlreadyTaken);
+        }
+        self.check_file_count()?;
+        self.check_quotas(name, Self::blocks_for(length_bytes))
+            .map_err(|prefix| FilesystemWriteError::QuotaExceeded { prefix })
+    }
+
+    /// Check if there is space for another entry in the file table
+    fn check_file_count(&self) -> Result<(), FilesystemWriteError> {
+        let entries = self.files.iter().filter(|file| !file.deleted()).count();
+        match self.max_files {
+            Some(max_files) if entries >= max_files => {
+                Err(FilesystemWriteError::TooManyFiles { max_files })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Start writing a file at the given address without any checks
+    fn create_writer(
+        &mut self,
+        name: &str,
+        length: u32,
+        hash: &[u8; 32],
+        address: u32,
+    ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
+        let (file, writer) =
+            FileInformation::to_storage(self.storage, address, length, name, hash)?;
+        // The file is still usable without a timestamp
+        if let Err(error) = writer.set_created_at(self.next_timestamp()) {
+            log::warn!("Failed to store the creation time of {}: {}", name, error);
+        }
+        self.files.push(file);
+        self.update_index();
+        Ok(writer)
+    }
+
+    /// Start a transaction to create and delete several files at once.
+    ///
+    /// Either all changes of the transaction are applied or none of them, even if power is lost
+    /// while committing. See [transaction] for details.
+    pub fn transaction(&mut self) -> transaction::FilesystemTransaction<'_, T> {
+        transaction::FilesystemTransaction::new(self)
+    }
+
+    /// Reserve the blocks for a file of `length_bytes` bytes and write its content in chunks.
+    ///
+    /// The file is only readable after [write_session::WriteSession::commit] checked the content against
+    /// `hash`. See [write_session] for details.
+    pub fn begin_write(
+        &mut self,
+        name: impl IntoFileName,
+        length_bytes: u32,
+        hash: &[u8; 32],
+    ) -> Result<write_session::WriteSession<T>, FilesystemWriteError> {
+        let writer = self.get_file_writer(name, length_bytes, hash)?;
+        Ok(write_session::WriteSession::new(writer, hash))
+    }
+
+    /// Start collecting changes in RAM instead of writing them to the storage.
+    ///
+    /// The overlay shows the current files with the changes on top. See [overlay] for details.
+    pub fn overlay(&self) -> overlay::OverlayFs<T> {
+        overlay::OverlayFs::new(self)
+    }
+
+    /// Delete all files and erase every block that is not erased yet.
+    ///
+    /// Other metadata keys of the storage and the configured quotas are kept. Fails without deleting anything if a
+    /// file is still referenced by a reader or writer.
+    pub fn clear(&mut self) -> Result<(), FilesystemClearError> {
+        self.clear_with_progress(|_| ())
+    }
+
+    /// Same as [Filesystem::clear], but report the number of blocks erased so far to `on_progress`
+    ///
+    /// Clearing a full storage erases every block, which can take long enough to trigger a watchdog. The
+    /// callback is called after every file and after every other block that needed to be erased, see
+    /// [Storage::erase_with_progress]. The blocks of a single file are erased at once.
+    pub fn clear_with_progress(
+        &mut self,
+        mut on_progress: impl FnMut(u32),
+    ) -> Result<(), FilesystemClearError> {
+        if self.read_only {
+            return Err(FilesystemClearError::ReadOnly);
+        }
+        self.cleanup_files();
+        if let Some(file) = self.files.iter().find(|file| !file.can_be_deleted()) {
+            return Err(FilesystemClearError::FileInUse(file.name.clone()));
+        }
+        let mut erased = 0;
+        for file in &self.files {
+            file.mark_for_deletion()?;
+            self.remove_attributes(&file.name)?;
+            // Nothing refers to the file, so marking it erased its blocks
+            erased += Self::blocks_of(file);
+            on_progress(erased);
+        }
+        self.files.clear();
+
+        // Blocks can still contain leftovers of interrupted writes
+        let mut block_buffer = vec![0u8; T::BLOCK_SIZE as usize];
+        let mut dirty = Vec::new();
+        for block in 0..T::BLOCKS {
+            self.storage
+                .read_into(block * T::BLOCK_SIZE, &mut block_buffer)?;
+            dirty.push(block_buffer.iter().any(|b| *b != 0xff));
+        }
+        let mut block = 0;
+        while block < T::BLOCKS {
+            let start = block;
+            while block < T::BLOCKS && dirty[block as usize] {
+                block += 1;
+            }
+            if block == start {
+                block += 1;
+                continue;
+            }
+            self.storage.erase_with_progress(
+                start * T::BLOCK_SIZE,
+                (block - start) * T::BLOCK_SIZE,
+                |done| on_progress(erased + done),
+            )?;
+            erased += block - start;
+        }
+        self.unusable_blocks.clear();
+        self.set_first_block(0)?;
+        self.update_index();
+        Ok(())
+    }
+
+    /// Get all attributes of a file. Attributes are stored as metadata of the storage.
+    pub fn attrs(&self, name: &str) -> Result<Vec<(String, Vec<u8>)>, FilesystemAttributeError> {
+        self.live_file_index(name)
+            .ok_or(FilesystemAttributeError::FileNotFound)?;
+        Ok(self.read_attributes(name))
+    }
+
+    /// Get a single attribute of a file
+    pub fn get_attr(
+        &self,
+        name: &str,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, FilesystemAttributeError> {
+        Ok(self
+            .attrs(name)?
+            .into_iter()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Set an attribute of a file, replacing the old value.
+    ///
+    /// Attributes belong to the name of the file. They are kept when the file is truncated or appended to
+    /// and removed when it is deleted.
+    pub fn set_attr(
+        &mut self,
+        name: &str,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), FilesystemAttributeError> {
+        if self.read_only {
+            return Err(FilesystemAttributeError::ReadOnly);
+        }
+        let mut attributes = self.attrs(name)?;
+        match attributes.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing_value)) => *existing_value = value.to_vec(),
+            None => attributes.push((key.to_string(), value.to_vec())),
+        }
+        Self::check_attributes(&attributes)?;
+        self.forget_trust(name);
+        self.storage.write_metadata(
+            &attributes::metadata_key(name),
+            &attributes::encode(&attributes),
+        )?;
+        Ok(())
+    }
+
+    /// Remove an attribute of a file
+    pub fn remove_attr(&mut self, name: &str, key: &str) -> Result<(), FilesystemAttributeError> {
+        if self.read_only {
+            return Err(FilesystemAttributeError::ReadOnly);
+        }
+        let mut attributes = self.attrs(name)?;
+        let length = attributes.len();
+        attributes.retain(|(existing, _)| existing != key);
+        if attributes.len() != length {
+            self.forget_trust(name);
+            self.storage.write_metadata(
+                &attributes::metadata_key(name),
+                &attributes::encode(&attributes),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Change the attributes and flags of many files at once
+    ///
+    /// `edit` is called for every readable file and can change its attributes or mark it as important,
+    /// see [header_edit]. The content of the files is not touched. All edits are checked before
+    /// anything is stored, and only files that were changed are written. Every file is updated
+    /// atomically, so a power loss leaves each file either with all of its changes or with none.
+    pub fn update_headers(
+        &mut self,
+        mut edit: impl FnMut(&mut header_edit::FileHeaderEdit),
+    ) -> Result<(), FilesystemAttributeError> {
+        if self.read_only {
+            return Err(FilesystemAttributeError::ReadOnly);
+        }
+        let mut edits = Vec::new();
+        for file in &self.files {
+            if !file.valid() || file.marked_for_deletion() || file.deleted() {
+                continue;
+            }
+            let mut header = header_edit::FileHeaderEdit::new(
+                &file.name,
+                file.important(),
+                self.read_attributes(&file.name),
+            );
+            edit(&mut header);
+            if header.attributes_changed {
+                Self::check_attributes(header.attrs())?;
+            }
+            if header.attributes_changed || header.important_changed {
+                edits.push((file.read(), header));
+            }
+        }
+        for (file, header) in edits {
+            if header.attributes_changed {
+                self.forget_trust(header.name());
+                self.storage.write_metadata(
+                    &attributes::metadata_key(header.name()),
+                    &attributes::encode(header.attrs()),
+                )?;
+            }
+            if header.important_changed {
+                file.set_important()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that attributes fit the encoding of [attributes]
+    fn check_attributes(attributes: &[(String, Vec<u8>)]) -> Result<(), FilesystemAttributeError> {
+        if attributes.len() > u8::MAX as usize {
+            return Err(FilesystemAttributeError::TooManyAttributes);
+        }
+        for (key, value) in attributes {
+            if key.len() > u8::MAX as usize {
+                return Err(FilesystemAttributeError::KeyTooLong);
+            }
+            if value.len() > u16::MAX as usize {
+                return Err(FilesystemAttributeError::ValueTooLong);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the attributes of a file name. Missing or unreadable attributes are treated as empty.
+    fn read_attributes(&self, name: &str) -> attributes::Attributes {
+        self.storage
+            .read_metadata(&attributes::metadata_key(name))
+            .map(|value| attributes::decode(&value))
+            .unwrap_or_default()
+    }
+
+    /// Remove all attributes of a file name
+    fn remove_attributes(&self, name: &str) -> Result<(), std::io::Error> {
+        if self.read_attributes(name).is_empty() {
+            return Ok(());
+        }
+        self.storage
+            .write_metadata(&attributes::metadata_key(name), &[])
+    }
+
+    /// Limit the number of blocks that files with names starting with `prefix` can use together.
+    ///
+    /// Creating a file that would exceed the quota fails with [FilesystemWriteError::QuotaExceeded].
+    /// Existing files are not affected. Setting a quota for a prefix again replaces the old one. A
+    /// file counts towards every quota whose prefix matches its name.
+    pub fn set_quota(&mut self, prefix: &str, max_blocks: u32) {
+        match self
+            .quotas
+            .iter_mut()
+            .find(|(existing, _)| existing == prefix)
+        {
+            Some((_, existing_max_blocks)) => *existing_max_blocks = max_blocks,
+            None => self.quotas.push((prefix.to_string(), max_blocks)),
+        }
+    }
+
+    /// Remove the quota for a prefix
+    pub fn remove_quota(&mut self, prefix: &str) {
+        self.quotas.retain(|(existing, _)| existing != prefix);
+    }
+
+    /// Get the usage of every quota
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        self.quotas
+            .iter()
+            .map(|(prefix, max_blocks)| QuotaUsage {
+                prefix: prefix.clone(),
+                used_blocks: self.blocks_with_prefix(prefix),
+                max_blocks: *max_blocks,
+            })
+            .collect()
+    }
+
+    /// Check if a file with the given name can use `additional_blocks` more blocks.
+    ///
+    /// Returns the prefix of the first quota that would be exceeded.
+    fn check_quotas(&self, name: &str, additional_blocks: u32) -> Result<(), String> {
+        for (prefix, max_blocks) in &self.quotas {
+            if name.starts_with(prefix.as_str())
+                && self.blocks_with_prefix(prefix) + additional_blocks > *max_blocks
+            {
+                return Err(prefix.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of blocks used by files with names starting with `prefix`
+    fn blocks_with_prefix(&self, prefix: &str) -> u32 {
+        self.files
+            .iter()
+            .filter(|file| {
+                file.name.starts_with(prefix) && !file.marked_for_deletion() && !file.deleted()
+            })
+            .map(Self::blocks_of)
+            .sum()
+    }
+
+    /// Delete a file
+    ///
+    /// The file will only be deleted once there are no strong references to its content left. Strong references can be obtained by calling upgrade on the content of a file
+    ///
+    /// Files marked with [Filesystem::set_secure_delete] are deleted like with [Filesystem::delete_file_secure].
+    pub fn delete_file(&mut self, filename: &str) -> Result<(), FilesystemDeleteError> {
+        let secure = self
+            .read_attributes(filename)
+            .iter()
+            .any(|(key, _)| key == SECURE_DELETE_ATTRIBUTE);
+        self.delete_file_with(filename, secure)
+    }
+
+    /// Delete a file and erase its blocks right away
+    ///
+    /// [Filesystem::delete_file] only marks a file that is still open, and its content stays in the storage
+    /// until the last strong reference is dropped. This fails with [FilesystemDeleteError::FileInUse]
+    /// instead, and leaves the file untouched. Content that is handed out as a slice can not be erased
+    /// while it is still read.
+    pub fn delete_file_secure(&mut self, filename: &str) -> Result<(), FilesystemDeleteError> {
+        self.delete_file_with(filename, true)
+    }
+
+    /// Mark a file to be deleted securely, even if it is deleted with [Filesystem::delete_file]
+    ///
+    /// The mark is stored as the attribute [SECURE_DELETE_ATTRIBUTE]. Set it right after creating the file.
+    pub fn set_secure_delete(&mut self, name: &str) -> Result<(), FilesystemAttributeError> {
+        self.set_attr(name, SECURE_DELETE_ATTRIBUTE, &[1])
+    }
+
+    fn delete_file_with(
+        &mut self,
+        filename: &str,
+        secure: bool,
+    ) -> Result<(), FilesystemDeleteError> {
+        if self.read_only {
+            return Err(FilesystemDeleteError::ReadOnly);
+        }
+        // Files that are already marked for deletion are gone as far as the caller is concerned, so
+        // deleting a name twice fails instead of finding the tombstone of the first deletion.
+        let Some((index, _)) = self.files.iter().enumerate().find(|(_, file)| {
+            file.name == filename && !file.marked_for_deletion() && !file.deleted()
+        }) else {
+            return Err(FilesystemDeleteError::FileNotFound);
+        };
+        let file = &mut self.files[index];
+        if secure && !file.can_be_deleted() {
+            return Err(FilesystemDeleteError::FileInUse);
+        }
+        file.mark_for_deletion().unwrap();
+        self.remove_attributes(filename)?;
+
+        let file = &self.files[index];
+        let file_block = file.start_block() as u16;
+        let first_block = self.get_first_block().unwrap_or(0);
+        if file.deleted() {
+            self.files.swap_remove(index);
+            self.update_index();
+        }
+
+        if file_block == first_block {
+            let new_first_block = self.find_new_first_block() as u16;
+            if new_first_block != first_block {
+                self.set_first_block(new_first_block)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find_new_first_block(&self) -> u16 {
+        let good_file = self
+            .files
+            .iter()
+            .find(|file| file.valid() && !file.deleted() && !file.marked_for_deletion());
+
+        if let Some(file) = good_file {
+            return file.start_block() as u16;
+        }
+
+        let acceptable_file = self
+            .files
+            .iter()
+            .find(|file| file.valid() && !file.deleted());
+
+        if let Some(file) = acceptable_file {
+            return file.start_block() as u16;
+        }
+
+        let any_file = self.files.iter().find(|file| file.valid());
+
+        if let Some(file) = any_file {
+            return file.start_block() as u16;
+        }
+
+        return 0;
+    }
+
+    /// Number of blocks used by a file
+    fn blocks_of(file: &FileInformation<T>) -> u32 {
+        Self::blocks_for(file.length)
+    }
+
+    /// Number of blocks used by a file with `length_bytes` bytes of content
+    ///
+    /// Every file starts with a header of [HEADER_SIZE] bytes, which is stored in the same block as the
+    /// start of the content. A file occupies all blocks that contain a part of the header or of the
+    /// content, so a file with `BLOCK_SIZE - HEADER_SIZE` bytes fits into a single block, while one more
+    /// byte needs a second block.
+    pub fn blocks_for(length_bytes: u32) -> u32 {
+        (length_bytes as u64 + HEADER_SIZE as u64).div_ceil(T::BLOCK_SIZE as u64) as u32
+    }
+
+    /// Check if a file can be moved to another location
+    fn movable(file: &FileInformation<T>) -> bool {
+        file.valid() && !file.marked_for_deletion() && !file.deleted() && file.can_be_deleted()
+    }
+
+    /// Write a new version of a file to `target_block` and delete the old version.
+    ///
+    /// The content of the new version is the concatenation of `parts`. The important flag and the age
+    /// are copied from the old version. The target blocks need to be free. Readers of the old version
+    /// keep seeing the old content and its blocks are only freed once they are dropped. Weak references
+    /// to the old version will no longer be upgradable afterwards.
+    ///
+    /// The replacement is recorded in the write-ahead log of [transaction] before the new version is
+    /// committed. If power is lost before the old version is marked for deletion, the next mount keeps
+    /// the new version and deletes the old one.
+    fn rewrite(
+        &mut self,
+        index: usize,
+        target_block: u32,
+        hash: &[u8; 32],
+        parts: &[&[u8]],
+    ) -> Result<(), FilesystemCompactError> {
+        debug_assert!(
+            target_block < T::BLOCKS,
+            "target_block {target_block} is not a block index"
+        );
+        let old_file = self.files[index].clone();
+        let length = parts.iter().map(|part| part.len() as u32).sum();
+        let (new_file, mut writer) = FileInformation::to_storage(
+            self.storage,
+            target_block * T::BLOCK_SIZE,
+            length,
+            &old_file.name,
+            hash,
+        )?;
+        for part in parts {
+            writer.write_all(part)?;
+        }
+        if old_file.important() {
+            writer.set_important()?;
+        }
+        if let Some(created_at) = old_file.created_at() {
+            writer.set_created_at(created_at)?;
+        }
+        for _ in old_file.age()..16 {
+            writer.increase_age()?;
+        }
+        // Both versions are on the storage until the old one is marked for deletion. The log makes the
+        // next mount finish the switch if power is lost in between, the content may differ.
+        transaction::log_replacement(
+            self.storage,
+            target_block * T::BLOCK_SIZE,
+            (old_file.address, &old_file.name),
+        )?;
+        writer.commit()?;
+
+        // The old version stays in the table until it is deleted, so its blocks are not reused while it is still read
+        old_file.mark_for_deletion()?;
+        self.files.push(new_file);
+        self.update_index();
+        // The switch is complete, replaying a leftover log on the next mount does not change anything
+        if let Err(error) = transaction::clear_log(self.storage) {
+            log::warn!("Failed to clear the log after a rewrite: {}", error);
+        }
+
+        let first_block = self.get_first_block().unwrap_or(0);
+        if old_file.start_block() == first_block as u32 {
+            self.set_first_block(self.find_new_first_block())?;
+        }
+        Ok(())
+    }
+
+    /// Copy a file to another location and delete the old copy.
+    ///
+    /// The target blocks need to be free. Weak references to the old file will no longer be upgradable afterwards.
+    ///
+    /// `replacement` can be used to change a single byte of the content while copying it.
+    fn relocate(
+        &mut self,
+        index: usize,
+        target_block: u32,
+        replacement: Option<(usize, u8)>,
+    ) -> Result<(), FilesystemCompactError> {
+        let content = self.files[index].read().upgrade()?;
+        let hash = *content.hash();
+        match replacement {
+            Some((offset, value)) => self.rewrite(
+                index,
+                target_block,
+                &hash,
+                &[&content[..offset], &[value], &content[offset + 1..]],
+            ),
+            None => self.rewrite(index, target_block, &hash, &[&content]),
+        }
+    }
+
+    /// Find the index of a file that can be read
+    fn live_file_index(&self, name: &str) -> Option<usize> {
+        self.files.iter().position(|file| {
+            file.name == name && file.valid() && !file.marked_for_deletion() && !file.deleted()
+        })
+    }
+
+    /// Shrink a file to `new_length` bytes.
+    ///
+    /// The length and the hash in the file header can not be changed without erasing, so the shortened
+    /// content is written to new space with an updated hash and the old version is deleted. Its blocks
+    /// are erased right away, or once the last reader of the old version is dropped. Unimportant files
+    /// may be deleted to make space, like when writing a new file.
+    pub fn truncate_file(
+        &mut self,
+        name: &str,
+        new_length: u32,
+    ) -> Result<(), FilesystemTruncateError> {
+        if self.read_only {
+            return Err(FilesystemTruncateError::ReadOnly);
+        }
+        self.cleanup_files();
+        let index = self
+            .live_file_index(name)
+            .ok_or(FilesystemTruncateError::FileNotFound)?;
+        let length = self.files[index].length;
+        if new_length > length {
+            return Err(FilesystemTruncateError::CannotGrowWithTruncate { length, new_length });
+        }
+        if new_length == length {
+            return Ok(());
+        }
+
+        // Holding a reader protects the file from getting deleted to make space
+        let content = self.files[index].read().upgrade()?;
+        let truncated = &content[..new_length as usize];
+        let hash: [u8; 32] = blake3::hash(truncated).into();
+        let free_location = self.find_free_space(new_length, false)?;
+        self.rewrite(index, free_location / T::BLOCK_SIZE, &hash, &[truncated])?;
+        drop(content);
+        self.cleanup_files();
+        Ok(())
+    }
+
+    /// Append data to the end of a file.
+    ///
+    /// The length and the hash in the file header are written once when the file is created and can
+    /// not be changed without erasing the first block. Appending in place is therefore not possible,
+    /// even if the last block of the file still has room for the data. The file is written to new
+    /// space together with the appended data and an updated hash instead, and the old version is
+    /// deleted afterwards. If power is lost in between, the next mount finishes the switch once the
+    /// new version was committed. Readers of the old version keep seeing the old content. Unimportant
+    /// files may be deleted to make space, like when writing a new file.
+    pub fn append_file(&mut self, name: &str, data: &[u8]) -> Result<(), FilesystemAppendError> {
+        if self.read_only {
+            return Err(FilesystemAppendError::ReadOnly);
+        }
+        self.cleanup_files();
+        let index = self
+            .live_file_index(name)
+            .ok_or(FilesystemAppendError::FileNotFound)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let old_length = self.files[index].length;
+        let new_length = u32::try_from(data.len())
+            .ok()
+            .and_then(|length| old_length.checked_add(length))
+            .ok_or(FilesystemAppendError::OutOfSpace)?;
+        let additional_blocks = Self::blocks_for(new_length) - Self::blocks_of(&self.files[index]);
+        self.check_quotas(name, additional_blocks)
+            .map_err(|prefix| FilesystemAppendError::QuotaExceeded { prefix })?;
+
+        // Holding a reader protects the file from getting deleted to make space
+        let content = self.files[index].read().upgrade()?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&content);
+        hasher.update(data);
+        let hash: [u8; 32] = hasher.finalize().into();
+        let free_location =
+            self.find_free_space(new_length, false)
+                .map_err(|error| match error {
+                    FindFreeSpaceError::FilesystemError => error.into(),
+                    _ => FilesystemAppendError::OutOfSpace,
+                })?;
+        self.rewrite(
+            index,
+            free_location / T::BLOCK_SIZE,
+            &hash,
+            &[&content, data],
+        )?;
+        drop(content);
+        self.cleanup_files();
+        Ok(())
+    }
+
+    /// Move a single file to reduce fragmentation of the free space.
+    ///
+    /// A file next to a free run is moved to the other end of that run, so the space it used becomes
+    /// part of the run. Files that are currently referenced by readers or writers are not moved.
+    /// Weak references to moved files can not be upgraded anymore, so look the files up again afterwards.
+    pub fn compact_step(&mut self) -> Result<CompactStepReport, FilesystemCompactError> {
+        if self.read_only {
+            return Err(FilesystemCompactError::ReadOnly);
+        }
+        self.cleanup_files();
+        let largest_run_before = self.largest_free_run();
+        let owners = self.block_owners()?;
+        let mut runs = Self::free_runs(&self.analyze_free_space()?);
+        runs.sort_by_key(|(start, length)| (std::cmp::Reverse(*length), *start));
+        if runs.len() <= 1 {
+            runs.clear();
+        }
+
+        let mut relocation: Option<(usize, u32)> = None;
+        for (run_start, run_length) in runs {
+            let (run_start, run_length) = (run_start as u32, run_length as u32);
+            // Try moving the file after the run to the start of the run
+            let after = owners[((run_start + run_length) % T::BLOCKS) as usize];
+            if let Some(BlockOwner::File(index)) = after {
+                let file = &self.files[index];
+                if Self::movable(file) && Self::blocks_of(file) <= run_length {
+                    relocation = Some((index, run_start));
+                    break;
+                }
+            }
+            // Try moving the file before the run to the end of the run
+            let before = owners[((run_start + T::BLOCKS - 1) % T::BLOCKS) as usize];
+            if let Some(BlockOwner::File(index)) = before {
+                let file = &self.files[index];
+                if Self::movable(file) && Self::blocks_of(file) <= run_length {
+                    let target = (run_start + run_length - Self::blocks_of(file)) % T::BLOCKS;
+                    relocation = Some((index, target));
+                    break;
+                }
+            }
+        }
+        // Level the wear once the free space is not fragmented anymore
+        let wear_leveling = relocation.is_none();
+        let Some((index, target_block)) = relocation.or_else(|| self.wear_relocation(&owners))
+        else {
+            return Ok(CompactStepReport::default());
+        };
+
+        let blocks = Self::blocks_of(&self.files[index]);
+        self.relocate(index, target_block, None)?;
+        self.cleanup_files();
+
+        let more_work = !wear_leveling && Self::free_runs(&self.analyze_free_space()?).len() > 1;
+        Ok(CompactStepReport {
+            blocks_relocated: blocks,
+            bytes_reclaimed: self.largest_free_run().saturating_sub(largest_run_before),
+            files_moved: 1,
+            erases_performed: blocks,
+            more_work,
+        })
+    }
+
+    /// Move files until the free space is in a single run or no more files can be moved.
+    ///
+    /// See [Filesystem::compact_step] for details.
+    pub fn compact(&mut self) -> Result<CompactReport, FilesystemCompactError> {
+        let mut report = CompactReport::default();
+        // Guard against moving files back and forth forever
+        let max_steps = self.files.len() * T::BLOCKS as usize;
+        for _ in 0..max_steps {
+            let step = self.compact_step()?;
+            report.blocks_relocated += step.blocks_relocated;
+            report.bytes_reclaimed += step.bytes_reclaimed;
+            report.files_moved += step.files_moved;
+            report.erases_performed += step.erases_performed;
+            if !step.more_work {
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Get the number of free bytes that are not part of the largest free run
+    ///
+    /// This is how much [Filesystem::compact] could add to the largest free run, based on the
+    /// [Filesystem::block_map]. Tombstones are not counted, their blocks are freed when the last reader is
+    /// dropped and compacting does not change that.
+    pub fn reclaimable_space(&self) -> u32 {
+        let free_runs = self
+            .block_map()
+            .into_iter()
+            .filter(|run| run.state == BlockState::Free)
+            .map(|run| run.len);
+        let (total, largest) = free_runs.fold((0, 0), |(total, largest), len| {
+            (total + len, std::cmp::max(largest, len))
+        });
+        (total - largest) * T::BLOCK_SIZE
+    }
+
+    /// Compact only if at least `min_reclaimable` bytes can be reclaimed, see [Filesystem::reclaimable_space]
+    ///
+    /// Returns None without moving any files if compacting is not worth the wear. That is always the case
+    /// if the free space is not split, see [Filesystem::free_run_count]. This can be called regularly, for
+    /// example when the device is idle.
+    pub fn compact_if_needed(
+        &mut self,
+        min_reclaimable: u32,
+    ) -> Result<Option<CompactReport>, FilesystemCompactError> {
+        if self.read_only {
+            return Err(FilesystemCompactError::ReadOnly);
+        }
+        self.cleanup_files();
+        if self.free_run_count() <= 1 {
+            return Ok(None);
+        }
+        let reclaimable = self.reclaimable_space();
+        if reclaimable == 0 || reclaimable < min_reclaimable {
+            return Ok(None);
+        }
+        self.compact().map(Some)
+    }
+
+    /// Check a file against its error correcting code and fix a single flipped bit.
+    ///
+    /// Flash can only clear bits without erasing. If the flipped bit needs to be cleared, it is fixed
+    /// in place. Otherwise the corrected content is written to free space and the old copy is deleted.
+    /// Moving fails if the file is in use. Weak references to moved files can not be upgraded anymore.
+    #[cfg(feature = "ecc")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "ecc")))]
+    pub fn repair_file(&mut self, name: &str) -> Result<EccStatus, FilesystemRepairError> {
+        if self.read_only {
+            return Err(FilesystemRepairError::ReadOnly);
+        }
+        self.cleanup_files();
+        let index = self
+            .live_file_index(name)
+            .ok_or(FilesystemRepairError::FileNotFound)?;
+        let file = self.files[index].clone();
+        let content = file.read().upgrade()?;
+        let Some((syndrome, parity)) = content.ecc() else {
+            return Ok(EccStatus::NoEcc);
+        };
+        let bit = match ecc::check(&content, syndrome, parity) {
+            ecc::EccCheck::Ok => return Ok(EccStatus::Ok),
+            ecc::EccCheck::Uncorrectable => return Err(FilesystemRepairError::Corrupt),
+            ecc::EccCheck::SingleBitError { bit } => bit,
+        };
+
+        let offset = (bit / 8) as usize;
+        let stored = content[offset];
+        let corrected = stored ^ (1 << (bit % 8));
+        drop(content);
+        if corrected & !stored == 0 {
+            // Only a bit needs to be cleared, that can be done without erasing
+            let address =
+                (file.address + HEADER_SIZE + offset as u32) % (T::BLOCKS * T::BLOCK_SIZE);
+            self.storage.write(address, &[corrected])?;
+            return Ok(EccStatus::Corrected { bit });
+        }
+
+        if !Self::movable(&file) {
+            return Err(FilesystemRepairError::FileInUse);
+        }
+        let target_block = self
+            .place_file(&self.analyze_free_space()?, Self::blocks_of(&file) as u16)
+            .ok_or(FilesystemRepairError::NoFreeSpace)?;
+        self.relocate(index, target_block, Some((offset, corrected)))?;
+        self.cleanup_files();
+        Ok(EccStatus::Corrected { bit })
+    }
+
+    /// Run [Filesystem::repair_file] on every file.
+    ///
+    /// Returns the name of every file and the result of the repair.
+    #[cfg(feature = "ecc")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "ecc")))]
+    pub fn repair_all(&mut self) -> Vec<(String, Result<EccStatus, FilesystemRepairError>)> {
+        let names: Vec<String> = self
+            .verifiable_files()
+            .map(|file| file.name.clone())
+            .collect();
+        names
+            .into_iter()
+            .map(|name| {
+                let result = self.repair_file(&name);
+                (name, result)
+            })
+            .collect()
+    }
+
+    /// Check every file and rewrite it to fresh blocks to refresh the charge of the flash cells
+    ///
+    /// Flash cells slowly lose their charge, so data that is never rewritten may become unreadable after
+    /// years. The content of every file is compared with its hash first. A file that matches is only
+    /// rewritten if `force` is set, because a read can not tell how much charge is left. A file that does
+    /// not match has already lost data. With the `ecc` feature, a single flipped bit is corrected with
+    /// [Filesystem::repair_file] and the file is rewritten. Otherwise it is reported as
+    /// [FilesystemScrubError::Corrupt] and left alone, as rewriting would only refresh the wrong content.
+    ///
+    /// Files are rewritten like when compacting, so a power loss leaves either the old or the new copy.
+    /// With wear leveling, see [FilesystemBuilder::wear_threshold], every file is written to the free
+    /// blocks that were erased least often. Files that are in use or do not fit into the free space are
+    /// reported and skipped. Weak references to rewritten files can not be upgraded anymore.
+    ///
+    /// This erases every block that holds a file once per forced scrub. Once a year is plenty for flash
+    /// that retains data for decades.
+    pub fn scrub(&mut self, force: bool) -> Result<ScrubReport, FilesystemScrubError> {
+        if self.read_only {
+            return Err(FilesystemScrubError::ReadOnly);
+        }
+        self.cleanup_files();
+        let names: Vec<String> = self
+            .verifiable_files()
+            .map(|file| file.name.clone())
+            .collect();
+        let mut report = ScrubReport::default();
+        for name in names {
+            report.files_checked += 1;
+            match self.scrub_file(&name, force) {
+                Ok(true) => report.scrubbed.push(name),
+                Ok(false) => {}
+                Err(error) => report.errors.push((name, error)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Scrub a single file, see [Filesystem::scrub]. Returns whether the file was rewritten.
+    fn scrub_file(&mut self, name: &str, force: bool) -> Result<bool, FilesystemScrubError> {
+        let Some(index) = self.live_file_index(name) else {
+            return Ok(false);
+        };
+        let content = self.files[index]
+            .read()
+            .upgrade()
+            .map_err(FilesystemCompactError::from)?;
+        let intact = content.verify_hash();
+        drop(content);
+        if !intact {
+            #[cfg(not(feature = "ecc"))]
+            return Err(FilesystemScrubError::Corrupt);
+            #[cfg(feature = "ecc")]
+            {
+                let address = self.files[index].address;
+                match self.repair_file(name)? {
+                    EccStatus::Corrected { .. } => {}
+                    EccStatus::NoEcc | EccStatus::Ok => return Err(FilesystemScrubError::Corrupt),
+                }
+                let Some(index) = self.live_file_index(name) else {
+                    return Ok(false);
+                };
+                if self.files[index].address != address {
+                    // Moving the corrected file already rewrote it
+                    return Ok(true);
+                }
+            }
+        } else if !force {
+            return Ok(false);
+        }
+
+        let Some(index) = self.live_file_index(name) else {
+            return Ok(false);
+        };
+        let file = &self.files[index];
+        if !Self::movable(file) {
+            return Err(FilesystemScrubError::FileInUse);
+        }
+        let length_in_blocks = Self::blocks_of(file) as u16;
+        let free_ranges = self.analyze_free_space()?;
+        let target_block = match self.erase_counts() {
+            Some(erase_counts) => {
+                Self::find_coldest_run(&free_ranges, length_in_blocks, &erase_counts)
+            }
+            None => Self::find_free_run(&free_ranges, length_in_blocks),
+        }
+        .ok_or(FilesystemScrubError::NoFreeSpace)?;
+        self.relocate(index, target_block, None)?;
+        self.cleanup_files();
+        Ok(true)
+    }
+
+    /// Remove all files with no remaining strong pointers
+    fn cleanup_files(&mut self) {
+        let mut remove_indices: Vec<usize> = Vec::new();
+        for index in 0..self.files.len() {
+            if self.files[index].deleted() {
+                remove_indices.push(index);
+            }
+        }
+        let removed_files = !remove_indices.is_empty();
+        for index in remove_indices.into_iter().rev() {
+            self.files.swap_remove(index);
+        }
+        if removed_files {
+            self.update_index();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::simulated::{get_test_storage, SimulatedStorage};
+
+    use super::*;
+
+    /// Mount without reserved blocks, so the tests can use the whole storage
+    fn mount_unreserved(storage: &'static SimulatedStorage) -> Filesystem<SimulatedStorage> {
+        Filesystem::builder(storage).reserved_blocks(0).mount()
+    }
+
+    #[test]
+    fn writing_and_reading_a_simple_file_works() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.read_file("fancy").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn can_read_a_file_from_an_old_storage() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let filesystem = Filesystem::new(storage);
+        let result = filesystem.read_file("fancy").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn can_read_a_file_by_hash() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        filesystem.write_file("fancy2", &file, &[5u8; 32]).unwrap();
+        filesystem.read_file_by_hash(&[0u8; 32]).unwrap();
+        assert!(filesystem.read_file_by_hash(&[3u8; 32]).is_none());
+        filesystem.read_file_by_hash(&[5u8; 32]).unwrap();
+    }
+
+    #[test]
+    fn writing_multiple_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.read_file("fancy").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        let result = filesystem.read_file("fancy2").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn a_filesystem_can_own_its_storage() {
+        let mut filesystem = Filesystem::new(SimulatedStorage::new());
+        filesystem
+            .write_file("main", &[1; 100], &[0u8; 32])
+            .unwrap();
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        drop(filesystem);
+        // The storage outlives the filesystem, so files can still be read
+        assert_eq!(main.as_ref(), [1; 100]);
+    }
+
+    #[test]
+    fn blocks_for_counts_the_header_and_partial_blocks() {
+        type Fs = Filesystem<SimulatedStorage>;
+        let block_size = SimulatedStorage::BLOCK_SIZE;
+        assert_eq!(Fs::blocks_for(0), 1);
+        assert_eq!(Fs::blocks_for(block_size - 64), 1);
+        assert_eq!(Fs::blocks_for(block_size - 63), 2);
+        assert_eq!(Fs::blocks_for(block_size), 2);
+        assert_eq!(Fs::blocks_for(block_size + 1), 2);
+        assert_eq!(Fs::blocks_for(2 * block_size - 64), 2);
+        assert_eq!(Fs::blocks_for(2 * block_size - 63), 3);
+        // Does not overflow when adding the header
+        assert_eq!(Fs::blocks_for(u32::MAX), 1_048_577);
+    }
+
+    #[test]
+    fn files_know_which_blocks_they_span() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let block_size = SimulatedStorage::BLOCK_SIZE as usize;
+        filesystem
+            .write_file("start", &vec![1; 3 * block_size - 64], &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("middle", &vec![2; 9 * block_size - 63], &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("end", &vec![3; 3 * block_size - 64], &[0u8; 32])
+            .unwrap();
+        let span = |filesystem: &Filesystem<SimulatedStorage>, name: &str| {
+            let file = filesystem.read_file(name).unwrap().upgrade().unwrap();
+            (file.start_block(), file.block_span(), file.end_block())
+        };
+        assert_eq!(span(&filesystem, "start"), (0, 3, 2));
+        assert_eq!(span(&filesystem, "middle"), (3, 10, 12));
+        assert_eq!(span(&filesystem, "end"), (13, 3, 15));
+
+        filesystem.delete_file("start").unwrap();
+        filesystem.delete_file("end").unwrap();
+        filesystem
+            .write_file("wrapped", &vec![4; 6 * block_size - 64], &[0u8; 32])
+            .unwrap();
+        assert_eq!(span(&filesystem, "wrapped"), (13, 6, 2));
+    }
+
+    #[test]
+    fn a_file_of_blocks_for_blocks_fills_them_exactly() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let length = 2 * SimulatedStorage::BLOCK_SIZE - HEADER_SIZE;
+        filesystem
+            .write_file("exact", &vec![1; length as usize], &[0u8; 32])
+            .unwrap();
+        filesystem.write_file("next", &[2; 10], &[0u8; 32]).unwrap();
+        let next = filesystem.read_file("next").unwrap();
+        assert_eq!(
+            filesystem
+                .files
+                .iter()
+                .find(|file| file.name == "next")
+                .unwrap()
+                .address,
+            Filesystem::<SimulatedStorage>::blocks_for(length) * SimulatedStorage::BLOCK_SIZE
+        );
+        assert_eq!(next.upgrade().unwrap().as_ref(), [2; 10]);
+    }
+
+    #[test]
+    fn unimportant_files_get_deleted() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.set_allow_evict_oldest(true);
+        // A bit bigger than half the storage size
+        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+        assert!(filesystem.read_file("fancy").is_none());
+        let result = filesystem.read_file("fancy2").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        assert_eq!(filesystem.take_evicted(), ["fancy"]);
+        assert!(filesystem.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn files_are_not_deleted_to_make_space_by_default() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        // A bit bigger than half the storage size
+        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.write_file("fancy2", &file, &[0u8; 32]);
+        assert!(matches!(
+            result,
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::OutOfSpace { .. }
+            ))
+        ));
+        assert!(filesystem.read_file("fancy").is_some());
+        assert!(filesystem.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn planning_a_file_does_not_change_anything() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let blocks = |count: usize| vec![0u8; SimulatedStorage::BLOCK_SIZE as usize * count - 64];
+        filesystem
+            .write_file("start", &blocks(3), &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("middle", &blocks(10), &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("end", &blocks(3), &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("start").unwrap();
+        filesystem.delete_file("end").unwrap();
+        let length = SimulatedStorage::BLOCK_SIZE * 6 - HEADER_SIZE;
+
+        let plan = filesystem
+            .plan_create("wrapped", length, WriteOptions::default())
+            .unwrap();
+        assert_eq!(
+            plan,
+            CreatePlan {
+                start_block: 13,
+                block_span: 6,
+                wraps: true,
+                evicted: Vec::new(),
+            }
+        );
+        assert!(!plan.executable_in_place());
+        let contiguous = WriteOptions::default().contiguous(true);
+        assert!(matches!(
+            filesystem.plan_create("wrapped", length, contiguous),
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::OutOfSpace { .. }
+            ))
+        ));
+        assert!(matches!(
+            filesystem.plan_create("middle", 10, WriteOptions::default()),
+            Err(FilesystemWriteError::NameAlreadyTaken)
+        ));
+        let plan = filesystem.plan_create("small", 10, contiguous).unwrap();
+        assert!(plan.executable_in_place());
+
+        filesystem
+            .write_file("wrapped", &vec![1; length as usize], &[0u8; 32])
+            .unwrap();
+        let wrapped = filesystem.read_file("wrapped").unwrap();
+        assert_eq!(wrapped.start_block(), 13);
+
+        // Files that would be evicted are only listed
+        filesystem.set_allow_evict_oldest(true);
+        let plan = filesystem
+            .plan_create("new", 10 * 4096 - 64, WriteOptions::default())
+            .unwrap();
+        assert_eq!(plan.evicted, ["middle"]);
+        assert!(filesystem.read_file("middle").is_some());
+        filesystem
+            .write_file("new", &blocks(10), &[0u8; 32])
+            .unwrap();
+        assert_eq!(filesystem.take_evicted(), plan.evicted);
+        assert_eq!(
+            filesystem.read_file("new").unwrap().start_block(),
+            plan.start_block
+        );
+    }
+
+    #[test]
+    fn files_can_be_pinned_to_an_address() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let block = SimulatedStorage::BLOCK_SIZE;
+        let at = |address| WriteOptions::default().start_hint(address);
+        filesystem
+            .write_file_with_options("other", &[1; 100], &[0u8; 32], at(4 * block))
+            .unwrap();
+        filesystem
+            .write_file_with_options("pinned", &[2; 5000], &[0u8; 32], at(8 * block))
+            .unwrap();
+        assert_eq!(filesystem.read_file("pinned").unwrap().start_block(), 8);
+
+        let plan = filesystem.plan_create("next", 100, at(10 * block)).unwrap();
+        assert_eq!((plan.start_block, plan.block_span), (10, 1));
+        // The file would overlap "pinned", so it is not moved somewhere else
+        assert!(matches!(
+            filesystem.plan_create("next", 100, at(9 * block)),
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::HintUnavailable { address }
+            )) if address == 9 * block
+        ));
+        assert!(matches!(
+            filesystem.plan_create("next", 100, at(block + 1)),
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::InvalidHint { .. }
+            ))
+        ));
+        assert!(matches!(
+            filesystem.plan_create("next", 100, at(SimulatedStorage::SIZE)),
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::InvalidHint { .. }
+            ))
+        ));
+
+        // A file at the last block wraps around, unless it needs to be contiguous
+        let wrapping = filesystem
+            .plan_create("next", 2 * block, at(15 * block))
+            .unwrap();
+        assert!(wrapping.wraps);
+        assert!(matches!(
+            filesystem.plan_create("next", 2 * block, at(15 * block).contiguous(true)),
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::HintUnavailable { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn out_of_space_errors_tell_if_compacting_would_help() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let blocks = |count: usize| vec![0u8; SimulatedStorage::BLOCK_SIZE as usize * count - 64];
+        for name in ["a", "b", "c", "d"] {
+            filesystem.write_file(name, &blocks(4), &[0u8; 32]).unwrap();
+        }
+        filesystem.delete_file("a").unwrap();
+        filesystem.delete_file("c").unwrap();
+
+        let Err(FilesystemWriteError::FindFreeSpaceError(error)) =
+            filesystem.write_file("big", &blocks(6), &[0u8; 32])
+        else {
+            panic!("Expected an out of space error");
+        };
+        assert_eq!(
+            error,
+            FindFreeSpaceError::OutOfSpace {
+                needed: 6 * 4096,
+                largest_free_run: 4 * 4096,
+                reclaimable_by_compaction: 4 * 4096,
+            }
+        );
+        assert!(error.compaction_would_help());
+
+        let Err(FilesystemWriteError::FindFreeSpaceError(error)) =
+            filesystem.write_file("huge", &blocks(9), &[0u8; 32])
+        else {
+            panic!("Expected an out of space error");
+        };
+        assert!(!error.compaction_would_help());
+
+        filesystem.compact().unwrap();
+        filesystem
+            .write_file("big", &blocks(6), &[0u8; 32])
+            .unwrap();
+    }
+
+    #[test]
+    fn writing_to_a_full_ring_fails_cleanly() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        for i in 0..SimulatedStorage::BLOCKS {
+            filesystem
+                .write_file(format!("small_file_{}", i), &[0; 32], &[0u8; 32])
+                .unwrap();
+        }
+        let result = filesystem.write_file("one_more", &[0; 32], &[0u8; 32]);
+        assert!(matches!(
+            result,
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::OutOfSpace { .. }
+            ))
+        ));
+        assert_eq!(
+            filesystem.list_files().count(),
+            SimulatedStorage::BLOCKS as usize
+        );
+        let filesystem = mount_unreserved(storage);
+        assert_eq!(
+            filesystem.list_files().count(),
+            SimulatedStorage::BLOCKS as usize
+        );
+    }
+
+    #[test]
+    fn compaction_works_on_a_filesystem_filled_to_capacity() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        assert_eq!(filesystem.reserved_blocks(), 1);
+        let mut count = 0;
+        while filesystem
+            .write_file(format!("file_{}", count), &[1; 100], &[0u8; 32])
+            .is_ok()
+        {
+            count += 1;
+        }
+        assert_eq!(count, SimulatedStorage::BLOCKS - 1);
+        assert_eq!(filesystem.free_space(), 0);
+        assert_eq!(filesystem.max_writable_file(), 0);
+        assert_eq!(filesystem.largest_free_run(), SimulatedStorage::BLOCK_SIZE);
+
+        filesystem.delete_file("file_3").unwrap();
+        filesystem.delete_file("file_8").unwrap();
+        let report = filesystem.compact().unwrap();
+        assert!(report.files_moved > 0);
+        assert_eq!(
+            filesystem.largest_free_run(),
+            SimulatedStorage::BLOCK_SIZE * 3
+        );
+        assert_eq!(filesystem.free_space(), SimulatedStorage::BLOCK_SIZE * 2);
+        assert_eq!(
+            filesystem.max_writable_file(),
+            SimulatedStorage::BLOCK_SIZE * 2 - HEADER_SIZE
+        );
+        let two_blocks = [2u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("large", &two_blocks, &[0u8; 32])
+            .unwrap();
+        assert!(filesystem
+            .write_file("one_more", &[1; 100], &[0u8; 32])
+            .is_err());
+    }
+
+    #[test]
+    fn the_reservation_can_be_configured() {
+        let storage = get_test_storage();
+        let filesystem = Filesystem::builder(storage).reserved_blocks(4).mount();
+        assert_eq!(
+            filesystem.free_space(),
+            SimulatedStorage::BLOCK_SIZE * (SimulatedStorage::BLOCKS - 4)
+        );
+        assert_eq!(
+            filesystem.max_writable_file(),
+            SimulatedStorage::BLOCK_SIZE * (SimulatedStorage::BLOCKS - 4) - HEADER_SIZE
+        );
+    }
+
+    #[test]
+    fn important_files_dont_get_deleted() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.set_allow_evict_oldest(true);
+        // A bit bigger than half the storage size
+        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.read_file("fancy").unwrap();
+        result.set_important().unwrap();
+
+        filesystem
+            .write_file("fancy2", &file, &[0u8; 32])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn open_reader_protects_files_from_being_deleted() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.set_allow_evict_oldest(true);
+        // A bit bigger than half the storage size
+        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.read_file("fancy").unwrap();
+        let _strong_ref = result.upgrade().unwrap();
+
+        filesystem
+            .write_file("fancy2", &file, &[0u8; 32])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn deleting_a_file_works() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        let None = filesystem.read_file("fancy") else {
+            panic!("Should not be able to read a deleted file");
+        };
+    }
+
+    #[test]
+    fn deleting_a_file_actually_works() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        let None = filesystem.read_file("fancy") else {
+            panic!("Should not be able to read a deleted file");
+        };
+
+        let filesystem = Filesystem::new(storage);
+        let None = filesystem.read_file("fancy") else {
+            panic!("Should not be able to read a deleted file");
+        };
+    }
+
+    #[test]
+    fn deleting_a_small_file_wears_a_whole_block() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("small", &[1; 100], &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("large", &[2; 5000], &[0u8; 32])
+            .unwrap();
+        let blocks = |name: &str| {
+            let file = filesystem.read_file(name).unwrap();
+            (file.start_block(), file.block_span())
+        };
+        let (small, large) = (blocks("small"), blocks("large"));
+        let erase_count = |block: u32| storage.erase_count(block).unwrap();
+        let before: Vec<u32> = (0..SimulatedStorage::BLOCKS).map(erase_count).collect();
+
+        storage.reset_stats();
+        filesystem.delete_file("small").unwrap();
+        filesystem.delete_file("large").unwrap();
+        assert_eq!(storage.stats().blocks_erased, 3);
+        for (start, span) in [small, large] {
+            for block in start..start + span {
+                assert_eq!(erase_count(block), before[block as usize] + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn deleting_a_file_twice_fails_cleanly() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1; 100], &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        assert!(matches!(
+            filesystem.delete_file("fancy"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+        assert!(matches!(
+            filesystem.delete_file_secure("fancy"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn deleting_a_file_with_other_handles_twice_fails_cleanly() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1; 100], &[0u8; 32])
+            .unwrap();
+        let weak = filesystem.read_file("fancy").unwrap();
+        let reader = weak.upgrade().unwrap();
+        let second_reader = reader.clone();
+
+        // The file stays in the table while it is read, the second delete must not find it
+        filesystem.delete_file("fancy").unwrap();
+        assert!(matches!(
+            filesystem.delete_file("fancy"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+        drop(reader);
+        assert!(matches!(
+            filesystem.delete_file("fancy"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+        assert_eq!(second_reader.as_ref(), [1; 100]);
+        drop(second_reader);
+        assert!(weak.upgrade().is_err());
+        drop(weak);
+        assert!(matches!(
+            filesystem.delete_file("fancy"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+
+        // A new file with the same name is deleted, not the tombstone of the old one
+        filesystem
+            .write_file("fancy", &[2; 100], &[0u8; 32])
+            .unwrap();
+        let reader = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        filesystem
+            .write_file("fancy", &[3; 100], &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        assert!(filesystem.read_file("fancy").is_none());
+        assert_eq!(reader.as_ref(), [2; 100]);
+        drop(reader);
+        assert!(matches!(
+            filesystem.delete_file("fancy"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn file_cant_be_upgraded_if_it_has_been_deleted_and_there_are_only_weak_references() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let content = vec![0; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
+        let file = filesystem.read_file("fancy").unwrap();
+        let weak_ref = file;
+        filesystem.delete_file("fancy").unwrap();
+        let Err(_) = weak_ref.upgrade() else {
+            panic!("Should not be able to upgrade deleted file");
+        };
+        let None = filesystem.read_file("fancy") else {
+            panic!("Should not be able to read a deleted file");
+        };
+    }
+
+    #[test]
+    fn no_new_references_can_be_created_to_a_file_marked_for_deletion() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let content = vec![0; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
+        let file = filesystem.read_file("fancy").unwrap();
+        let strong_ref = file.upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        let None = filesystem.read_file("fancy") else {
+            panic!(
+                "Should not be able to create a new reference to a file marked for deletion file"
+            );
+        };
+        // Strong ref still has the same correct content
+        assert_eq!(strong_ref.as_ref(), content);
+    }
+
+    #[test]
+    fn writing_a_maximum_size_file_works() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.read_file("fancy").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn deleting_a_file_makes_space_for_a_new_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+        let result = filesystem.read_file("fancy2").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn can_write_a_big_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+
+        let file = [42u8; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem
+            .write_file("big_file", &file, &[0u8; 32])
+            .unwrap();
+        let result = filesystem.read_file("big_file").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn multiple_unimportant_files_can_get_overwritten() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        filesystem.set_allow_evict_oldest(true);
+
+        for i in 0..SimulatedStorage::BLOCKS {
+            filesystem
+                .write_file(format!("small_file_{}", i), &[0; 32], &[0u8; 32])
+                .unwrap();
+        }
+
+        let file = [42u8; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem
+            .write_file("big_file", &file, &[0u8; 32])
+            .unwrap();
+        let result = filesystem.read_file("big_file").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn deleting_a_file_does_not_make_space_for_a_new_file_if_there_are_still_strong_references_to_its_content(
+    ) {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize - HEADER_SIZE as usize];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let fancy_file = filesystem.read_file("fancy").unwrap();
+        let strong_ref = fancy_file.upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        let Err(_) = filesystem.write_file("fancy2", &file, &[0u8; 32]) else {
+            panic!("Should fail because the file was not yet deleted");
+        };
+        assert_eq!(strong_ref.as_ref(), file);
+        drop(strong_ref);
+        // Should work now, because we dropped the last strong reference
+        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+    }
+
+    #[test]
+    fn writing_a_file_thats_too_big_fails() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize + 1];
+        let Err(_) = filesystem.write_file("fancy", &file, &[0u8; 32]) else {
+            panic!("Should fail when there is not enough space");
+        };
+    }
+
+    #[test]
+    fn deleting_the_first_file_moves_the_marker() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize
+            - HEADER_SIZE as usize
+            - SimulatedStorage::BLOCK_SIZE as usize * 2];
+        filesystem.write_file("first", &file, &[0u8; 32]).unwrap();
+        assert_eq!(filesystem.get_first_block().unwrap(), 0);
+        let file2 = [0u8; SimulatedStorage::BLOCK_SIZE as usize - HEADER_SIZE as usize];
+        filesystem.write_file("second", &file2, &[0u8; 32]).unwrap();
+        assert_eq!(filesystem.get_first_block().unwrap(), 0);
+        filesystem.delete_file("first").unwrap();
+        assert_ne!(filesystem.get_first_block().unwrap(), 0);
+        // drop(filesystem);
+        // let mut filesystem = Filesystem::new(storage);
+    }
+
+    #[test]
+    fn can_not_create_two_files_with_the_same_name() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::BLOCK_SIZE as usize - HEADER_SIZE as usize];
+        filesystem.write_file("cool", &file, &[0u8; 32]).unwrap();
+        filesystem
+            .write_file("cool", &file, &[0u8; 32])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn names_that_do_not_fit_into_the_header_are_rejected() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        assert!(matches!(
+            filesystem.write_file("a_name_that_is_too_long", &[0; 100], &[0u8; 32]),
+            Err(FilesystemWriteError::FileNameError(
+                FileNameError::NameTooLong { length: 23 }
+            ))
+        ));
+        assert!(filesystem.get_file_writer("a\0b", 100, &[0u8; 32]).is_err());
+        assert_eq!(filesystem.list_files().count(), 0);
+
+        const NAME: FileName = FileName::from_static("0123456789abcdef");
+        filesystem.write_file(NAME, &[0; 100], &[0u8; 32]).unwrap();
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file(&NAME).is_some());
+    }
+
+    #[test]
+    fn max_writable_file_of_an_empty_filesystem_is_the_whole_storage() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let filesystem = mount_unreserved(storage);
+        assert_eq!(
+            filesystem.max_writable_file(),
+            SimulatedStorage::SIZE - HEADER_SIZE
+        );
+    }
+
+    #[test]
+    fn max_writable_file_shrinks_when_writing_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let file = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 2];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        assert_eq!(
+            filesystem.max_writable_file(),
+            SimulatedStorage::SIZE - SimulatedStorage::BLOCK_SIZE * 3 - HEADER_SIZE
+        );
+    }
+
+    #[test]
+    fn max_writable_file_considers_free_space_that_wraps_around() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let three_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 3 - HEADER_SIZE as usize];
+        let ten_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 10 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("start", &three_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("middle", &ten_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("end", &three_blocks, &[0u8; 32])
+            .unwrap();
+        assert_eq!(filesystem.max_writable_file(), 0);
+        filesystem.delete_file("start").unwrap();
+        filesystem.delete_file("end").unwrap();
+
+        let max_size = filesystem.max_writable_file();
+        assert_eq!(max_size, SimulatedStorage::BLOCK_SIZE * 6 - HEADER_SIZE);
+
+        let file: Vec<u8> = (0..max_size).map(|i| i as u8).collect();
+        filesystem.write_file("wrapped", &file, &[0u8; 32]).unwrap();
+        assert_eq!(filesystem.max_writable_file(), 0);
+        let result = filesystem.read_file("wrapped").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        assert!(result.upgrade().unwrap().executable_slice().is_none());
+        let middle = filesystem.read_file("middle").unwrap().upgrade().unwrap();
+        let slice = middle.executable_slice().unwrap();
+        assert_eq!(slice, ten_blocks);
+        assert!((slice.as_ptr() as usize).is_multiple_of(64));
+        drop(middle);
+
+        // Mounting again should not erase the part of the file after the wraparound
+        drop(result);
+        drop(filesystem);
+        let mut filesystem = mount_unreserved(storage);
+        let result = filesystem.read_file("wrapped").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        drop(result);
+
+        filesystem.delete_file("wrapped").unwrap();
+        assert_eq!(filesystem.max_writable_file(), max_size);
+    }
+
+    #[test]
+    fn contiguous_files_are_not_placed_where_they_wrap_around() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let three_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 3 - HEADER_SIZE as usize];
+        let ten_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 10 - HEADER_SIZE as usize];
+        let five_blocks = [1u8; SimulatedStorage::BLOCK_SIZE as usize * 5 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("start", &three_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("middle", &ten_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("end", &three_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("start").unwrap();
+        filesystem.delete_file("end").unwrap();
+
+        // The only free run that fits wraps around
+        let contiguous = WriteOptions::default().contiguous(true);
+        assert!(matches!(
+            filesystem.write_file_with_options("main", &five_blocks, &[0u8; 32], contiguous),
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::OutOfSpace { .. }
+            ))
+        ));
+        assert!(filesystem.read_file("middle").is_some());
+
+        // Without the option the file wraps around
+        filesystem
+            .write_file("main", &five_blocks, &[0u8; 32])
+            .unwrap();
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert!(main.executable_slice().is_none());
+        drop(main);
+
+        filesystem.delete_file("main").unwrap();
+        filesystem.delete_file("middle").unwrap();
+        filesystem
+            .write_file_with_options("main", &five_blocks, &[0u8; 32], contiguous)
+            .unwrap();
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.executable_slice().unwrap(), five_blocks);
+    }
+
+    #[test]
+    fn freed_blocks_are_erased_in_contiguous_runs() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let three_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 3 - HEADER_SIZE as usize];
+        let ten_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 10 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("start", &three_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("middle", &ten_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("end", &three_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("start").unwrap();
+        filesystem.delete_file("end").unwrap();
+        let wrapped = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 6 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("wrapped", &wrapped, &[0u8; 32])
+            .unwrap();
+
+        storage.reset_stats();
+        filesystem.delete_file("middle").unwrap();
+        assert_eq!(storage.stats().erase_calls, 1);
+
+        // Erase can not wrap around, so the two parts are erased separately
+        storage.reset_stats();
+        filesystem.delete_file("wrapped").unwrap();
+        assert_eq!(storage.stats().erase_calls, 2);
+
+        filesystem
+            .write_file("truncated", &ten_blocks, &[0u8; 32])
+            .unwrap();
+        storage.reset_stats();
+        filesystem.truncate_file("truncated", 100).unwrap();
+        assert_eq!(storage.stats().erase_calls, 1);
+    }
+
+    #[test]
+    fn a_header_with_a_length_larger_than_the_storage_is_ignored() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("good", &[1; 100], &[0u8; 32])
+            .unwrap();
+        drop(filesystem);
+        let address = 5 * SimulatedStorage::BLOCK_SIZE;
+        let metadata = file_metadata::FileMetadata::new_to_storage(
+            storage,
+            address,
+            "corrupt",
+            SimulatedStorage::SIZE,
+            &[0; 32],
+        )
+        .unwrap();
+        unsafe { metadata.set_ready(storage, address) }.unwrap();
+
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("corrupt").is_none());
+        assert!(filesystem.read_file("good").is_some());
+        assert_eq!(storage.read(address, 1).unwrap(), [0xff]);
+    }
+
+    #[test]
+    fn files_with_a_mismatched_hash_are_degraded_when_verifying_on_mount() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [1; 100];
+        let hash: [u8; 32] = blake3::hash(&content).into();
+        filesystem.write_file("bad", &content, &hash).unwrap();
+        filesystem.write_file("good", &content, &hash).unwrap();
+        drop(filesystem);
+        // Flip some bits in the content of the first file
+        storage.write(HEADER_SIZE, &[0]).unwrap();
+
+        // Without verification the corruption goes unnoticed
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("bad").is_some());
+        assert!(filesystem.degraded_files().is_empty());
+        drop(filesystem);
+
+        let (mut filesystem, report) = Filesystem::builder(storage)
+            .verify_on_mount(true)
+            .mount_with_report();
+        assert_eq!(report.degraded, ["bad"]);
+        assert_eq!(report.files_found, 1);
+        assert_eq!(filesystem.degraded_files(), ["bad"]);
+        assert!(filesystem.read_file("bad").is_none());
+        assert!(filesystem.read_file("good").is_some());
+        assert_eq!(filesystem.verify_all(), [("good".to_string(), true)]);
+        // The blocks of the degraded file are not reused until it is deleted
+        let used_blocks: u32 = filesystem
+            .block_map()
+            .iter()
+            .filter(|run| matches!(run.state, BlockState::Used(_)))
+            .map(|run| run.len)
+            .sum();
+        assert_eq!(used_blocks, 2);
+        filesystem.delete_file("bad").unwrap();
+        drop(filesystem);
+
+        let filesystem = Filesystem::builder(storage).verify_on_mount(true).mount();
+        assert!(filesystem.degraded_files().is_empty());
+        assert!(filesystem.read_file("good").is_some());
+    }
+
+    #[test]
+    fn raw_headers_are_returned_verbatim() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("first", &[1; 5000], &[7; 32])
+            .unwrap();
+        filesystem
+            .write_file("second", &[2; 100], &[8; 32])
+            .unwrap();
+        let address = filesystem.files[1].address;
+
+        let header = filesystem.raw_header("second").unwrap();
+        assert_eq!(header, storage.read(address, HEADER_SIZE).unwrap());
+        assert_eq!(&header[8..40], &[8; 32]);
+        assert_eq!(&header[40..46], b"second");
+        assert_eq!(
+            filesystem
+                .raw_header_at_block(address / SimulatedStorage::BLOCK_SIZE)
+                .unwrap(),
+            header
+        );
+
+        // The second block of the first file and free blocks have no header
+        assert_eq!(filesystem.raw_header_at_block(1).unwrap(), [0x01; 64]);
+        assert_eq!(filesystem.raw_header_at_block(15).unwrap(), [0xff; 64]);
+        assert!(matches!(
+            filesystem.raw_header_at_block(SimulatedStorage::BLOCKS),
+            Err(FilesystemReadError::StorageError(
+                storage::StorageError::AddressTooBig
+            ))
+        ));
+        assert!(matches!(
+            filesystem.raw_header("missing"),
+            Err(FilesystemReadError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn verified_reads_report_content_that_does_not_match_the_hash() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [3u8; 5000];
+        let hash: [u8; 32] = blake3::hash(&content).into();
+        filesystem.write_file("good", &content, &hash).unwrap();
+        filesystem.write_file("bad", &content, &[0; 32]).unwrap();
+
+        assert_eq!(
+            filesystem.read_file_verified("good").unwrap(),
+            (&content[..], true)
+        );
+        assert_eq!(filesystem.read_file_strict("good").unwrap(), content);
+        assert_eq!(
+            filesystem.read_file_verified("bad").unwrap(),
+            (&content[..], false)
+        );
+        assert!(matches!(
+            filesystem.read_file_strict("bad"),
+            Err(FilesystemReadError::Corrupt)
+        ));
+        assert!(matches!(
+            filesystem.read_file_verified("missing"),
+            Err(FilesystemReadError::FileNotFound)
+        ));
+        drop(filesystem);
+
+        // Degraded files can only be read this way
+        let filesystem = Filesystem::builder(storage).verify_on_mount(true).mount();
+        assert_eq!(filesystem.degraded_files(), ["bad"]);
+        assert!(filesystem.read_contents("bad").is_err());
+        assert_eq!(
+            filesystem.read_file_verified("bad").unwrap(),
+            (&content[..], false)
+        );
+    }
+
+    #[test]
+    fn dirty_blocks_are_erased_in_contiguous_runs_on_mount() {
+        let storage = get_test_storage();
+        for block in [3, 4, 5, 9] {
+            storage
+                .write(block * SimulatedStorage::BLOCK_SIZE + 100, &[0])
+                .unwrap();
+        }
+        storage.reset_stats();
+        let (_, report) = Filesystem::mount_with_report(storage);
+        assert_eq!(storage.stats().erase_calls, 2);
+        assert_eq!(report.corrupt_skipped, 4);
+        assert_eq!(report.erase_failed, 0);
+        assert!(!report.wal_replayed);
+        for block in [3, 4, 5, 9] {
+            assert_eq!(
+                storage
+                    .read(block * SimulatedStorage::BLOCK_SIZE + 100, 1)
+                    .unwrap(),
+                [0xff]
+            );
+        }
+    }
+
+    #[test]
+    fn dirty_blocks_that_can_not_be_erased_are_skipped_on_mount() {
+        let storage = get_test_storage();
+        for block in [3, 4, 9] {
+            storage
+                .write(block * SimulatedStorage::BLOCK_SIZE + 100, &[0])
+                .unwrap();
+        }
+        storage.wear_out(4);
+        let (mut filesystem, report) = Filesystem::builder(storage)
+            .reserved_blocks(0)
+            .mount_with_report();
+        assert_eq!(report.corrupt_skipped, 3);
+        assert_eq!(report.erase_failed, 2);
+        assert!(filesystem.block_map().contains(&BlockRun {
+            start: 3,
+            len: 2,
+            state: BlockState::Unusable
+        }));
+        assert_eq!(
+            filesystem.free_space(),
+            SimulatedStorage::BLOCK_SIZE * (SimulatedStorage::BLOCKS - 2)
+        );
+
+        // Every other block can be filled, the blocks that could not be erased are never written
+        let one_block = [1u8; SimulatedStorage::BLOCK_SIZE as usize - HEADER_SIZE as usize];
+        for index in 0..SimulatedStorage::BLOCKS - 2 {
+            let name = format!("f{index}");
+            filesystem
+                .write_file(&name, &one_block, &[0u8; 32])
+                .unwrap();
+            let start_block = filesystem.read_file(&name).unwrap().start_block();
+            assert!(![3, 4].contains(&start_block));
+        }
+        let result = filesystem.write_file("full", &[1], &[0u8; 32]);
+        assert!(matches!(
+            result,
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::OutOfSpace { .. }
+            ))
+        ));
+        for block in [3, 4] {
+            let content = storage
+                .read(block * SimulatedStorage::BLOCK_SIZE, 101)
+                .unwrap();
+            assert!(content[..100].iter().all(|byte| *byte == 0xff));
+            assert_eq!(content[100], 0x00);
+        }
+    }
+
+    #[test]
+    fn block_map_covers_all_blocks() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let two_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("first", &two_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("second", &two_blocks, &[0u8; 32])
+            .unwrap();
+
+        let map = filesystem.block_map();
+        assert_eq!(
+            map,
+            vec![
+                BlockRun {
+                    start: 0,
+                    len: 2,
+                    state: BlockState::Used(0)
+                },
+                BlockRun {
+                    start: 2,
+                    len: 2,
+                    state: BlockState::Used(1)
+                },
+                BlockRun {
+                    start: 4,
+                    len: SimulatedStorage::BLOCKS - 4,
+                    state: BlockState::Free
+                },
+            ]
+        );
+        assert_eq!(
+            filesystem.free_space(),
+            (SimulatedStorage::BLOCKS - 4) * SimulatedStorage::BLOCK_SIZE
+        );
+        assert_eq!(filesystem.largest_free_run(), filesystem.free_space());
+    }
+
+    #[test]
+    fn walking_blocks_visits_every_block_once_in_ring_order() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let blocks = |count: usize| vec![0u8; SimulatedStorage::BLOCK_SIZE as usize * count - 64];
+        filesystem.write_file("a", &blocks(2), &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &blocks(12), &[0u8; 32]).unwrap();
+        filesystem.delete_file("a").unwrap();
+        // Only fits into the free run that wraps around the end
+        filesystem.write_file("c", &blocks(4), &[0u8; 32]).unwrap();
+        let b = filesystem.read_file("b").unwrap().upgrade().unwrap();
+        filesystem.delete_file("b").unwrap();
+        let c = filesystem.live_file_index("c").unwrap();
+
+        let mut visited = Vec::new();
+        filesystem.walk_blocks(|block, class| visited.push((block, class)));
+        // "c" became the first file when "a" was deleted
+        let mut expected = vec![(14, BlockClass::HeaderOf(c))];
+        expected.extend([15, 0, 1].map(|block| (block, BlockClass::DataOf(c))));
+        expected.extend((2..14).map(|block| (block, BlockClass::Tombstone)));
+        assert_eq!(visited, expected);
+
+        drop(b);
+        let mut free = 0;
+        filesystem.walk_blocks(|_, class| free += (class == BlockClass::Free) as u32);
+        assert_eq!(free * SimulatedStorage::BLOCK_SIZE, filesystem.free_space());
+    }
+
+    #[test]
+    fn block_map_shows_tombstones() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = mount_unreserved(storage);
+        let two_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+        let twelve_blocks =
+            [0u8; SimulatedStorage::BLOCK_SIZE as usize * 12 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("start", &two_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("middle", &two_blocks, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("end", &twelve_blocks, &[0u8; 32])
+            .unwrap();
+        let middle = filesystem.read_file("middle").unwrap().upgrade().unwrap();
+        filesystem.delete_file("middle").unwrap();
+        filesystem.delete_file("start").unwrap();
+
+        let map = filesystem.block_map();
+        assert_eq!(
+            map.iter().map(|run| run.len).sum::<u32>(),
+            SimulatedStorage::BLOCKS
+        );
+        assert!(map.contains(&BlockRun {
+            start: 2,
+            len: 2,
+            state: BlockState::Tombstone
+        }));
+        let free_blocks: u32 = map
+            .iter()
+            .filter(|run| run.state == BlockState::Free)
+            .map(|run| run.len)
+            .sum();
+        assert_eq!(
+            free_blocks * SimulatedStorage::BLOCK_SIZE,
+            filesystem.free_space()
+        );
+        assert_eq!(filesystem.free_space(), SimulatedStorage::BLOCK_SIZE * 2);
+        drop(middle);
+    }
+
+    #[test]
+    fn verify_all_detects_files_with_wrong_hashes() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [1u8, 2, 3, 4];
+        let hash = blake3::hash(&content);
+        filesystem
+            .write_file("good", &content, hash.as_bytes())
+            .unwrap();
+        filesystem.write_file("bad", &content, &[0u8; 32]).unwrap();
+
+        let mut results = filesystem.verify_all();
+        results.sort();
+        assert_eq!(
+            results,
+            vec![("bad".to_string(), false), ("good".to_string(), true)]
+        );
+
+        #[cfg(feature = "rayon")]
+        {
+            let mut parallel_results = filesystem.verify_all_parallel();
+            parallel_results.sort();
+            assert_eq!(parallel_results, results);
+        }
+    }
+
+    #[test]
+    fn verifying_notices_content_that_changed_after_it_was_hashed() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [1u8, 2, 3, 4];
+        filesystem
+            .write_file("a", &content, blake3::hash(&content).as_bytes())
+            .unwrap();
+        assert_eq!(filesystem.verify_file("a"), Some(true));
+        assert_eq!(filesystem.read_file_strict("a").unwrap(), content);
+
+        let address =
+            filesystem.read_file("a").unwrap().start_block() * SimulatedStorage::BLOCK_SIZE;
+        storage.write(address + HEADER_SIZE, &[0]).unwrap();
+        assert_eq!(filesystem.verify_file("a"), Some(false));
+        assert_eq!(filesystem.verify_all(), vec![("a".to_string(), false)]);
+        assert!(matches!(
+            filesystem.read_file_strict("a"),
+            Err(FilesystemReadError::Corrupt)
+        ));
+    }
+
+    #[test]
+    fn compacting_merges_free_space() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let two_blocks = [7u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+        for name in ["a", "b", "c", "d"] {
+            filesystem
+                .write_file(name, &two_blocks, &[0u8; 32])
+                .unwrap();
+        }
+        filesystem.read_file("b").unwrap().set_important().unwrap();
+        filesystem.delete_file("a").unwrap();
+        filesystem.delete_file("c").unwrap();
+        assert_eq!(
+            filesystem.largest_free_run(),
+            SimulatedStorage::BLOCK_SIZE * 10
+        );
+
+        let report = filesystem.compact().unwrap();
+        assert_eq!(report.files_moved, 1);
+        assert_eq!(report.blocks_relocated, 2);
+        assert_eq!(report.erases_performed, 2);
+        assert_eq!(report.bytes_reclaimed, SimulatedStorage::BLOCK_SIZE * 2);
+        assert_eq!(
+            filesystem.largest_free_run(),
+            SimulatedStorage::BLOCK_SIZE * 12
+        );
+        assert_eq!(filesystem.largest_free_run(), filesystem.free_space());
+
+        for name in ["b", "d"] {
+            let file = filesystem.read_file(name).unwrap().upgrade().unwrap();
+            assert_eq!(file.as_ref(), two_blocks);
+        }
+        assert!(filesystem.read_file("b").unwrap().important());
+
+        // The moved files are still there after mounting again
+        drop(filesystem);
+        let filesystem = mount_unreserved(storage);
+        assert!(filesystem.read_file("b").is_some());
+        assert!(filesystem.read_file("d").is_some());
+        assert_eq!(filesystem.largest_free_run(), filesystem.free_space());
+    }
+
+    #[test]
+    fn compacting_only_when_needed_skips_small_gains() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        let two_blocks = [7u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+        for name in ["a", "b", "c", "d"] {
+            filesystem
+                .write_file(name, &two_blocks, &[0u8; 32])
+                .unwrap();
+        }
+        assert_eq!(filesystem.reclaimable_space(), 0);
+        assert_eq!(filesystem.free_run_count(), 1);
+        assert_eq!(filesystem.compact_if_needed(0).unwrap(), None);
+        filesystem.delete_file("a").unwrap();
+        filesystem.delete_file("c").unwrap();
+        let reclaimable = SimulatedStorage::BLOCK_SIZE * 2;
+        assert_eq!(filesystem.reclaimable_space(), reclaimable);
+        // The space of "a" joins the free run at the end of the storage
+        assert_eq!(filesystem.free_run_count(), 2);
+        assert_eq!(filesystem.space_usage().free_run_count, 2);
+
+        storage.reset_stats();
+        assert_eq!(filesystem.compact_if_needed(reclaimable + 1).unwrap(), None);
+        assert_eq!(storage.stats().erase_calls, 0);
+        assert!(filesystem.read_file("b").unwrap().upgrade().is_ok());
+
+        let report = filesystem.compact_if_needed(reclaimable).unwrap().unwrap();
+        assert_eq!(report.bytes_reclaimed, reclaimable);
+        assert_eq!(filesystem.reclaimable_space(), 0);
+        assert_eq!(filesystem.free_run_count(), 1);
+    }
+
+    #[test]
+    fn filesystems_on_different_storages_are_independent() {
+        let first = get_test_storage();
+        let second = get_test_storage();
+        std::thread::scope(|scope| {
+            for (storage, name) in [(first, "first"), (second, "second")] {
+                scope.spawn(move || {
+                    let mut filesystem = Filesystem::new(storage);
+                    for index in 0..5 {
+                        let content = vec![index; 3000];
+                        filesystem
+                            .write_file(format!("{name}{index}"), &content, &[0; 32])
+                            .unwrap();
+                        filesystem
+                            .set_attr(&format!("{name}{index}"), "owner", name.as_bytes())
+                            .unwrap();
+                    }
+                    filesystem.delete_file(&format!("{name}0")).unwrap();
+                });
+            }
+        });
+
+        let first = Filesystem::new(first);
+        let second = Filesystem::new(second);
+        for (filesystem, name) in [(&first, "first"), (&second, "second")] {
+            let names: Vec<String> = filesystem
+                .list_files()
+                .map(|file| file.name_str().to_string())
+                .collect();
+            assert_eq!(
+                names,
+                (1..5)
+                    .map(|index| format!("{name}{index}"))
+                    .collect::<Vec<_>>()
+            );
+            for index in 1..5u8 {
+                let file_name = format!("{name}{index}");
+                let file = filesystem.read_file(&file_name).unwrap().upgrade().unwrap();
+                assert_eq!(file.as_ref(), [index; 3000]);
+                assert_eq!(
+                    filesystem.get_attr(&file_name, "owner").unwrap().as_deref(),
+                    Some(name.as_bytes())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn debug_output_is_a_short_summary() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("main", &[1; 100], &[0xab; 32])
+            .unwrap();
+        filesystem.write_file("data", &[2; 5000], &[0; 32]).unwrap();
+
+        assert_eq!(
+            filesystem.to_string(),
+            "files: 2, used blocks: 3 of 16, free space: 49152 bytes"
+        );
+        let debug = format!("{:?}", filesystem);
+        assert!(debug.starts_with("Filesystem { files: 2, blocks: 16, block_size: 4096,"));
+
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        let block = main.start_block();
+        assert_eq!(
+            main.to_string(),
+            format!("main (100 bytes at block {block}, hash abababab)")
+        );
+        let debug = format!("{:?}", main);
+        assert!(debug.contains(r#"name: "main""#));
+        assert!(debug.contains(r#"hash: "abababab""#));
+        // Neither the content nor the rest of the hash is printed
+        assert!(!debug.contains("[1, 1"));
+        assert!(!debug.contains("ababababab"));
+    }
+
+    #[test]
+    fn compacting_does_not_move_referenced_files() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let two_blocks = [7u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+        for name in ["a", "b", "c", "d"] {
+            filesystem
+                .write_file(name, &two_blocks, &[0u8; 32])
+                .unwrap();
+        }
+        filesystem.delete_file("a").unwrap();
+        filesystem.delete_file("c").unwrap();
+        let reader = filesystem.read_file("b").unwrap().upgrade().unwrap();
+
+        // b would be the first choice, but it is referenced, so d gets moved instead
+        let report = filesystem.compact_step().unwrap();
+        assert_eq!(report.files_moved, 1);
+        assert!(!report.more_work);
+        assert_eq!(reader.as_ref(), two_blocks);
+        assert!(filesystem.read_file("d").is_some());
+        assert_eq!(
+            filesystem.largest_free_run(),
+            SimulatedStorage::BLOCK_SIZE * 12
+        );
+    }
+
+    /// Keep a static file and rewrite small files many times. Returns the erase counts of all blocks.
+    fn churn(wear_threshold: Option<u32>) -> Vec<u32> {
+        let storage = get_test_storage();
+        let mut builder = Filesystem::builder(storage);
+        if let Some(threshold) = wear_threshold {
+            builder = builder.wear_threshold(threshold);
+        }
+        let mut filesystem = builder.mount();
+        let four_blocks = [7u8; SimulatedStorage::BLOCK_SIZE as usize * 4 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("static", &four_blocks, &[0u8; 32])
+            .unwrap();
+        for round in 0..2000 {
+            let name = format!("config{}", round % 3);
+            let _ = filesystem.delete_file(&name);
+            filesystem.write_file(&name, &[1; 100], &[0u8; 32]).unwrap();
+            if round % 20 == 0 {
+                filesystem.compact().unwrap();
+            }
+        }
+        assert!(filesystem.read_file("static").is_some());
+        (0..SimulatedStorage::BLOCKS)
+            .map(|block| storage.erase_count(block).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn wear_leveling_moves_static_files_off_cold_blocks() {
+        let spread = |counts: &[u32]| counts.iter().max().unwrap() - counts.iter().min().unwrap();
+        let without = churn(None);
+        let with = churn(Some(8));
+        assert!(spread(&with) < spread(&without));
+        assert!(spread(&with) <= 32, "{with:?}");
+    }
+
+    /// Flip a bit in the content of the first file behind the back of the filesystem
+    #[cfg(feature = "ecc")]
+    fn flip_bit(filesystem: &Filesystem<SimulatedStorage>, bit: u32) {
+        let address = filesystem.files[0].address + HEADER_SIZE + bit / 8;
+        let byte = filesystem.storage.read(address, 1).unwrap().as_ptr() as *mut u8;
+        unsafe { *byte ^= 1 << (bit % 8) };
+    }
+
+    #[cfg(feature = "ecc")]
+    #[test]
+    fn repairing_an_intact_file_does_nothing() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        assert_eq!(filesystem.repair_file("fancy").unwrap(), EccStatus::Ok);
+    }
+
+    #[cfg(feature = "ecc")]
+    #[test]
+    fn repairing_corrects_a_bit_in_place() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [0u8; 100];
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
+        let address = filesystem.files[0].address;
+        flip_bit(&filesystem, 83);
+
+        let status = filesystem.repair_file("fancy").unwrap();
+        assert_eq!(status, EccStatus::Corrected { bit: 83 });
+        assert_eq!(filesystem.files[0].address, address);
+        let file = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), content);
+    }
+
+    #[cfg(feature = "ecc")]
+    #[test]
+    fn repairing_moves_the_file_if_a_bit_needs_to_be_set() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [0xffu8; 100];
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
+        flip_bit(&filesystem, 0);
+        flip_bit(&filesystem, 0);
+        flip_bit(&filesystem, 799);
+
+        let status = filesystem.repair_file("fancy").unwrap();
+        assert_eq!(status, EccStatus::Corrected { bit: 799 });
+        let file = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), content);
+        drop(file);
+        assert_eq!(filesystem.repair_file("fancy").unwrap(), EccStatus::Ok);
+
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        let file = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), content);
+    }
+
+    #[test]
+    fn scrubbing_rewrites_files_to_fresh_blocks() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        for (name, length) in [("a", 100), ("b", 5000), ("c", 10)] {
+            let content = vec![0xff; length];
+            let hash: [u8; 32] = blake3::hash(&content).into();
+            filesystem.write_file(name, &content, &hash).unwrap();
+        }
+        let addresses = |filesystem: &Filesystem<SimulatedStorage>| -> Vec<u32> {
+            ["a", "b", "c"]
+                .iter()
+                .map(|name| filesystem.files[filesystem.live_file_index(name).unwrap()].address)
+                .collect()
+        };
+        let before = addresses(&filesystem);
+
+        let report = filesystem.scrub(false).unwrap();
+        assert_eq!(report.files_checked, 3);
+        assert!(report.scrubbed.is_empty());
+        assert!(report.errors.is_empty());
+        assert_eq!(addresses(&filesystem), before);
+
+        // A file that is read can not be moved
+        let c = filesystem.read_file("c").unwrap().upgrade().unwrap();
+        let report = filesystem.scrub(true).unwrap();
+        assert_eq!(report.scrubbed, ["a", "b"]);
+        assert!(matches!(
+            report.errors.as_slice(),
+            [(name, FilesystemScrubError::FileInUse)] if name == "c"
+        ));
+        drop(c);
+        let after = addresses(&filesystem);
+        assert_ne!(after[0], before[0]);
+        assert_ne!(after[1], before[1]);
+        assert!(filesystem.verify_all().iter().all(|(_, ok)| *ok));
+
+        // Clear a bit of "a" behind the back of the filesystem
+        let address = after[0] + HEADER_SIZE + 10;
+        storage.write(address, &[0xfe]).unwrap();
+        let report = filesystem.scrub(false).unwrap();
+        #[cfg(not(feature = "ecc"))]
+        {
+            assert!(report.scrubbed.is_empty());
+            assert!(matches!(
+                report.errors.as_slice(),
+                [(name, FilesystemScrubError::Corrupt)] if name == "a"
+            ));
+        }
+        #[cfg(feature = "ecc")]
+        {
+            assert_eq!(report.scrubbed, ["a"]);
+            assert!(report.errors.is_empty());
+            assert!(filesystem.verify_file("a").unwrap());
+        }
+
+        let mut filesystem = Filesystem::mount_readonly(storage);
+        assert!(matches!(
+            filesystem.scrub(true),
+            Err(FilesystemScrubError::ReadOnly)
+        ));
+    }
+
+    #[cfg(feature = "ecc")]
+    #[test]
+    fn two_flipped_bits_are_reported_as_corrupt() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[0x55; 100], &[0u8; 32])
+            .unwrap();
+        flip_bit(&filesystem, 3);
+        flip_bit(&filesystem, 500);
+
+        let result = filesystem.repair_file("fancy");
+        assert!(matches!(result, Err(FilesystemRepairError::Corrupt)));
+    }
+
+    #[test]
+    fn quotas_are_enforced_per_prefix() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.set_quota("ota/", 3);
+        filesystem.set_quota("app/", 2);
+        let one_block = [0u8; SimulatedStorage::BLOCK_SIZE as usize - HEADER_SIZE as usize];
+        let two_blocks = [0u8; SimulatedStorage::BLOCK_SIZE as usize * 2 - HEADER_SIZE as usize];
+
+        filesystem
+            .write_file("ota/a", &two_blocks, &[0u8; 32])
+            .unwrap();
+        let result = filesystem.write_file("ota/b", &two_blocks, &[0u8; 32]);
+        assert!(
+            matches!(result, Err(FilesystemWriteError::QuotaExceeded { prefix }) if prefix == "ota/")
+        );
+        filesystem
+            .write_file("ota/b", &one_block, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("app/a", &two_blocks, &[0u8; 32])
+            .unwrap();
+        // Files without a quota are not limited
+        filesystem
+            .write_file("other", &two_blocks, &[0u8; 32])
+            .unwrap();
+
+        assert_eq!(
+            filesystem.usage(),
+            vec![
+                QuotaUsage {
+                    prefix: "ota/".into(),
+                    used_blocks: 3,
+                    max_blocks: 3
+                },
+                QuotaUsage {
+                    prefix: "app/".into(),
+                    used_blocks: 2,
+                    max_blocks: 2
+                }
+            ]
+        );
+
+        // Deleting a file frees its part of the quota
+        filesystem.delete_file("ota/a").unwrap();
+        filesystem
+            .write_file("ota/c", &two_blocks, &[0u8; 32])
+            .unwrap();
+    }
+
+    #[test]
+    fn truncating_a_file_frees_its_trailing_blocks() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content: Vec<u8> = (0..SimulatedStorage::BLOCK_SIZE * 3)
+            .map(|i| i as u8)
+            .collect();
+        filesystem.write_file("log", &content, &[0u8; 32]).unwrap();
+        let free_before = filesystem.free_space();
+        let old_reader = filesystem.read_file("log").unwrap().upgrade().unwrap();
+
+        filesystem.truncate_file("log", 10).unwrap();
+        let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), &content[..10]);
+        assert_eq!(file.hash(), blake3::hash(&content[..10]).as_bytes());
+        // The old version is still readable until the reader is dropped
+        assert_eq!(old_reader.as_ref(), content);
+        drop(old_reader);
+        drop(file);
+        assert_eq!(
+            filesystem.free_space(),
+            free_before + SimulatedStorage::BLOCK_SIZE * 3
+        );
+
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), &content[..10]);
+    }
+
+    #[test]
+    fn a_truncate_that_reached_the_storage_is_not_undone() {
+        let content: Vec<u8> = (0..SimulatedStorage::BLOCK_SIZE * 3)
+            .map(|i| i as u8)
+            .collect();
+        let setup = || {
+            let storage = get_test_storage();
+            let mut filesystem = Filesystem::new(storage);
+            filesystem.write_file("log", &content, &[0u8; 32]).unwrap();
+            (storage, filesystem)
         };
-        let file = &mut self.files[index];
-        if !file.marked_for_deletion() {
-            file.mark_for_deletion().unwrap();
-        }
+        let (_, mut filesystem) = setup();
+        filesystem.truncate_file("log", 10).unwrap();
+        let new_address =
+            filesystem.read_file("log").unwrap().start_block() * SimulatedStorage::BLOCK_SIZE;
+        drop(filesystem);
 
-        let file = &self.files[index];
-        let file_block = (file.address / T::BLOCK_SIZE) as u16;
-        let first_block = self.get_first_block().unwrap_or(0);
-        if file.deleted() {
-            self.files.swap_remove(index);
+        for operations in 0..40 {
+            let (storage, mut filesystem) = setup();
+            storage.cut_power_after(operations);
+            let _ = filesystem.truncate_file("log", 10);
+            drop(filesystem);
+            storage.restore_power();
+            // The truncate is durable once it is in the log or the new version is marked as ready. Both
+            // versions are on the storage until the old one is marked for deletion.
+            let logged = storage
+                .read_metadata("wal")
+                .is_ok_and(|log| !log.is_empty());
+            let committed = logged
+                || file_metadata::FileMetadata::from_storage(storage, new_address)
+                    .is_ok_and(|metadata| metadata.ready());
+
+            let filesystem = Filesystem::new(storage);
+            let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+            let expected = if committed { &content[..10] } else { &content };
+            assert!(
+                file.as_ref() == expected,
+                "power lost after {operations} operations"
+            );
         }
+    }
 
-        if file_block == first_block {
-            let new_first_block = self.find_new_first_block() as u16;
-            if new_first_block != first_block {
-                self.set_first_block(new_first_block)?;
-            }
+    #[test]
+    fn truncate_can_not_grow_a_file() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("log", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let result = filesystem.truncate_file("log", 4);
+        assert!(matches!(
+            result,
+            Err(FilesystemTruncateError::CannotGrowWithTruncate {
+                length: 3,
+                new_length: 4
+            })
+        ));
+        assert!(matches!(
+            filesystem.truncate_file("missing", 0),
+            Err(FilesystemTruncateError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn appending_to_a_file_works() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        filesystem
+            .write_file("log", b"first\n", &[0u8; 32])
+            .unwrap();
+        filesystem.append_file("log", b"second\n").unwrap();
+        filesystem.append_file("log", b"third\n").unwrap();
+
+        let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), b"first\nsecond\nthird\n");
+        assert_eq!(
+            file.hash(),
+            blake3::hash(b"first\nsecond\nthird\n").as_bytes()
+        );
+        drop(file);
+        assert_eq!(
+            filesystem.free_space(),
+            SimulatedStorage::BLOCK_SIZE * (SimulatedStorage::BLOCKS - 1)
+        );
+
+        drop(filesystem);
+        let filesystem = mount_unreserved(storage);
+        let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), b"first\nsecond\nthird\n");
+    }
+
+    #[test]
+    fn appending_moves_the_file_even_if_its_last_block_has_room() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        filesystem
+            .write_file("log", b"first\n", &[0u8; 32])
+            .unwrap();
+        let old_block = filesystem.read_file("log").unwrap().start_block();
+
+        filesystem.append_file("log", b"second\n").unwrap();
+        let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+        assert_ne!(file.start_block(), old_block);
+        assert_eq!(file.as_ref(), b"first\nsecond\n");
+        drop(file);
+        assert_eq!(
+            filesystem.free_space(),
+            SimulatedStorage::BLOCK_SIZE * (SimulatedStorage::BLOCKS - 1)
+        );
+    }
+
+    #[test]
+    fn an_append_that_reached_the_storage_is_not_undone() {
+        let setup = || {
+            let storage = get_test_storage();
+            let mut filesystem = Filesystem::new(storage);
+            filesystem
+                .write_file("log", b"first\n", &[0u8; 32])
+                .unwrap();
+            (storage, filesystem)
+        };
+        let (_, mut filesystem) = setup();
+        filesystem.append_file("log", b"second\n").unwrap();
+        let new_address =
+            filesystem.read_file("log").unwrap().start_block() * SimulatedStorage::BLOCK_SIZE;
+        drop(filesystem);
+
+        for operations in 0..40 {
+            let (storage, mut filesystem) = setup();
+            storage.cut_power_after(operations);
+            let _ = filesystem.append_file("log", b"second\n");
+            drop(filesystem);
+            storage.restore_power();
+            let logged = storage
+                .read_metadata("wal")
+                .is_ok_and(|log| !log.is_empty());
+            let committed = logged
+                || file_metadata::FileMetadata::from_storage(storage, new_address)
+                    .is_ok_and(|metadata| metadata.ready());
+
+            let filesystem = Filesystem::new(storage);
+            let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+            let expected: &[u8] = if committed {
+                b"first\nsecond\n"
+            } else {
+                b"first\n"
+            };
+            assert!(
+                file.as_ref() == expected,
+                "power lost after {operations} operations"
+            );
         }
+    }
 
-        Ok(())
+    #[test]
+    fn appending_fails_without_space_for_the_larger_file() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let ten_blocks =
+            vec![0u8; SimulatedStorage::BLOCK_SIZE as usize * 10 - HEADER_SIZE as usize];
+        filesystem
+            .write_file("log", &ten_blocks, &[0u8; 32])
+            .unwrap();
+
+        let result = filesystem.append_file("log", &[1]);
+        assert!(matches!(result, Err(FilesystemAppendError::OutOfSpace)));
+        let file = filesystem.read_file("log").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), ten_blocks);
     }
 
-    fn find_new_first_block(&self) -> u16 {
-        let good_file = self
-            .files
-            .iter()
-            .find(|file| file.valid() && !file.deleted() && !file.marked_for_deletion());
+    #[test]
+    fn reading_a_file_in_chunks_works() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content: Vec<u8> = (0..100).collect();
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
 
-        if let Some(file) = good_file {
-            return (file.address / T::BLOCK_SIZE) as u16;
+        let mut buffer = [0u8; 40];
+        let mut read = Vec::new();
+        loop {
+            let length = filesystem
+                .read_file_chunked("fancy", read.len() as u32, &mut buffer)
+                .unwrap();
+            if length == 0 {
+                break;
+            }
+            read.extend_from_slice(&buffer[..length]);
         }
+        assert_eq!(read, content);
+        assert_eq!(
+            filesystem
+                .read_file_chunked("fancy", 1000, &mut buffer)
+                .unwrap(),
+            0
+        );
+        assert!(matches!(
+            filesystem.read_file_chunked("missing", 0, &mut buffer),
+            Err(FilesystemReadError::FileNotFound)
+        ));
+    }
 
-        let acceptable_file = self
-            .files
+    #[test]
+    fn clearing_removes_all_files() {
+        let storage = get_test_storage();
+        let mut filesystem = mount_unreserved(storage);
+        filesystem.set_quota("app/", 4);
+        filesystem
+            .write_file("app/a", &[1; 5000], &[0u8; 32])
+            .unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        // Leftover of an interrupted write
+        storage
+            .write(SimulatedStorage::BLOCK_SIZE * 10, &[0])
+            .unwrap();
+        let weak = filesystem.read_file("b").unwrap();
+
+        let mut progress = Vec::new();
+        filesystem
+            .clear_with_progress(|blocks| progress.push(blocks))
+            .unwrap();
+        // Two blocks of "app/a", one of "b" and the leftover
+        assert_eq!(progress, [2, 3, 4]);
+        assert!(filesystem.read_file("app/a").is_none());
+        assert!(weak.upgrade().is_err());
+        assert_eq!(filesystem.usage()[0].used_blocks, 0);
+        assert_eq!(filesystem.free_space(), SimulatedStorage::SIZE);
+        assert!(storage
+            .read(0, SimulatedStorage::SIZE - 1)
+            .unwrap()
             .iter()
-            .find(|file| file.valid() && !file.deleted());
+            .all(|b| *b == 0xff));
 
-        if let Some(file) = acceptable_file {
-            return (file.address / T::BLOCK_SIZE) as u16;
-        }
+        drop(filesystem);
+        let filesystem = mount_unreserved(storage);
+        assert!(filesystem.read_file("b").is_none());
+    }
 
-        let any_file = self.files.iter().find(|file| file.valid());
+    #[test]
+    fn clearing_fails_while_a_file_is_read() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        let reader = filesystem.read_file("a").unwrap().upgrade().unwrap();
+        assert!(matches!(
+            filesystem.clear(),
+            Err(FilesystemClearError::FileInUse(name)) if name == "a"
+        ));
+        assert_eq!(reader.as_ref(), [1; 100]);
+    }
 
-        if let Some(file) = any_file {
-            return (file.address / T::BLOCK_SIZE) as u16;
-        }
+    #[test]
+    fn read_only_mount_does_not_write() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        drop(filesystem);
+        // Garbage that a normal mount would erase
+        storage
+            .write(SimulatedStorage::BLOCK_SIZE * 8, &[0])
+            .unwrap();
 
-        return 0;
+        let mut filesystem = Filesystem::mount_readonly(storage);
+        assert!(filesystem.read_only());
+        let file = filesystem.read_file("a").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), [1; 100]);
+        assert!(matches!(
+            filesystem.write_file("b", &[2; 100], &[0u8; 32]),
+            Err(FilesystemWriteError::ReadOnly)
+        ));
+        assert!(matches!(
+            filesystem.delete_file("a"),
+            Err(FilesystemDeleteError::ReadOnly)
+        ));
+        assert!(matches!(
+            filesystem.compact(),
+            Err(FilesystemCompactError::ReadOnly)
+        ));
+        assert!(matches!(
+            filesystem.clear(),
+            Err(FilesystemClearError::ReadOnly)
+        ));
+        assert_eq!(
+            storage.read(SimulatedStorage::BLOCK_SIZE * 8, 1).unwrap(),
+            [0]
+        );
     }
 
-    /// Remove all files with no remaining strong pointers
-    fn cleanup_files(&mut self) {
-        let mut remove_indices: Vec<usize> = Vec::new();
-        for index in 0..self.files.len() {
-            if self.files[index].deleted() {
-                remove_indices.push(index);
-            }
+    #[test]
+    fn attributes_can_be_set_and_read() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("app/a", &[1; 100], &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("app/b", &[2; 100], &[0u8; 32])
+            .unwrap();
+        filesystem.set_attr("app/a", "autostart", b"true").unwrap();
+        filesystem.set_attr("app/a", "priority", &[3]).unwrap();
+        filesystem.set_attr("app/a", "priority", &[4]).unwrap();
+
+        assert_eq!(
+            filesystem.get_attr("app/a", "autostart").unwrap(),
+            Some(b"true".to_vec())
+        );
+        assert_eq!(
+            filesystem.attrs("app/a").unwrap(),
+            vec![
+                ("autostart".to_string(), b"true".to_vec()),
+                ("priority".to_string(), vec![4])
+            ]
+        );
+        assert!(filesystem.attrs("app/b").unwrap().is_empty());
+        assert!(matches!(
+            filesystem.set_attr("missing", "autostart", b"true"),
+            Err(FilesystemAttributeError::FileNotFound)
+        ));
+
+        // Attributes are kept across mounts and when the file is rewritten
+        drop(filesystem);
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.append_file("app/a", &[1]).unwrap();
+        assert_eq!(
+            filesystem.get_attr("app/a", "priority").unwrap(),
+            Some(vec![4])
+        );
+        filesystem.remove_attr("app/a", "priority").unwrap();
+        assert_eq!(filesystem.get_attr("app/a", "priority").unwrap(), None);
+
+        // Attributes are removed with the file
+        filesystem.delete_file("app/a").unwrap();
+        filesystem
+            .write_file("app/a", &[1; 100], &[0u8; 32])
+            .unwrap();
+        assert!(filesystem.attrs("app/a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn headers_can_be_edited_without_touching_the_content() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let names: Vec<String> = (0..10).map(|index| format!("app/{index}")).collect();
+        for (index, name) in names.iter().enumerate() {
+            let content = [index as u8; 1000];
+            let hash: [u8; 32] = blake3::hash(&content).into();
+            filesystem.write_file(name, &content, &hash).unwrap();
+            filesystem.set_attr(name, "autostart", b"true").unwrap();
+            filesystem.set_attr(name, "version", b"1").unwrap();
         }
-        for index in remove_indices.into_iter().rev() {
-            self.files.swap_remove(index);
+        storage.reset_stats();
+
+        filesystem
+            .update_headers(|header| {
+                header.remove_attr("autostart");
+                if header.name() == "app/0" {
+                    header.set_important();
+                }
+            })
+            .unwrap();
+
+        assert_eq!(storage.stats().erase_calls, 0);
+        for name in &names {
+            assert_eq!(
+                filesystem.attrs(name).unwrap(),
+                [("version".to_string(), b"1".to_vec())]
+            );
+            let file = filesystem.read_file(name).unwrap().upgrade().unwrap();
+            assert!(file.verify_hash());
+            assert_eq!(file.important(), name == "app/0");
         }
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.verify_all().iter().all(|(_, ok)| *ok));
+        assert!(filesystem.read_file("app/0").unwrap().important());
+        assert_eq!(filesystem.get_attr("app/9", "autostart").unwrap(), None);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::storage::simulated::SimulatedStorage;
+    #[test]
+    fn invalid_header_edits_change_nothing() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        for name in ["a", "b"] {
+            filesystem.write_file(name, &[1; 100], &[0u8; 32]).unwrap();
+        }
+        let long_key = "k".repeat(256);
+        assert!(matches!(
+            filesystem.update_headers(|header| {
+                let key = if header.name() == "b" {
+                    &long_key
+                } else {
+                    "ok"
+                };
+                header.set_attr(key, b"1");
+            }),
+            Err(FilesystemAttributeError::KeyTooLong)
+        ));
+        assert!(filesystem.attrs("a").unwrap().is_empty());
+    }
 
-    use super::*;
+    #[test]
+    fn a_full_metadata_storage_is_reported() {
+        let storage = get_test_storage();
+        storage.set_metadata_capacity(0);
+        // Mounting still works without a stored first block
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("first", &[1; 100], &[0u8; 32])
+            .unwrap();
+        assert!(matches!(
+            filesystem.set_attr("first", "autostart", b"true"),
+            Err(FilesystemAttributeError::MetadataFull)
+        ));
+
+        // Freeing space makes the attributes writable again
+        storage.set_metadata_capacity(usize::MAX);
+        filesystem.set_attr("first", "autostart", b"true").unwrap();
+        storage.set_metadata_capacity(0);
+        filesystem.delete_file("first").unwrap();
+        filesystem
+            .write_file("second", &[2; 100], &[0u8; 32])
+            .unwrap();
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("first").is_none());
+        assert!(filesystem.read_file("second").is_some());
+    }
 
     #[test]
-    fn writing_and_reading_a_simple_file_works() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn iterating_contents_skips_deleted_and_degraded_files() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let result = filesystem.read_file("fancy").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        for (name, content) in [
+            ("corrupt", &[3; 100][..]),
+            ("app/b", &[2; 100]),
+            ("app/a", &[1; 5000]),
+        ] {
+            let hash: [u8; 32] = blake3::hash(content).into();
+            filesystem.write_file(name, content, &hash).unwrap();
+        }
+        filesystem
+            .write_file("deleted", &[4; 10], &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("deleted").unwrap();
+        drop(filesystem);
+        storage.write(HEADER_SIZE, &[0]).unwrap();
+
+        let filesystem = Filesystem::builder(storage).verify_on_mount(true).mount();
+        let contents: Vec<(&str, &[u8])> = filesystem.iter_contents().collect();
+        assert_eq!(
+            contents,
+            [("app/a", &[1; 5000][..]), ("app/b", &[2; 100][..])]
+        );
+        let apps = filesystem
+            .iter_contents()
+            .filter(|(name, _)| name.starts_with("app/b"))
+            .count();
+        assert_eq!(apps, 1);
     }
 
     #[test]
-    fn can_read_a_file_from_an_old_storage() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+    #[cfg(feature = "simulated")]
+    fn exported_images_have_the_same_content() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let filesystem = Filesystem::new(storage);
-        let result = filesystem.read_file("fancy").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        let files: [(&str, &[u8]); 3] = [("b", &[2; 100]), ("a", &[1; 5000]), ("c", &[3; 9000])];
+        for (name, content) in files {
+            filesystem.write_file(name, content, &[0u8; 32]).unwrap();
+        }
+
+        // Export the blocks and import them shifted by a few blocks, without the metadata
+        let imported = get_test_storage();
+        for block in 0..SimulatedStorage::BLOCKS {
+            let target = (block + 5) % SimulatedStorage::BLOCKS;
+            imported
+                .write_block(target, storage.read_block(block).unwrap())
+                .unwrap();
+        }
+        let imported_filesystem = Filesystem::mount_readonly(imported);
+        assert!(filesystem.content_eq(&imported_filesystem));
+
+        // An image built in a different order with the real hashes has the same content
+        let mut builder = image::FilesystemImageBuilder::<16, 4096>::new();
+        for (name, content) in files.iter().rev() {
+            builder = builder.add_file(name, content);
+        }
+        let rom: &'static storage::rom::RomStorage<16, 4096> = Box::leak(Box::new(
+            storage::rom::RomStorage::from_static(builder.build().unwrap().leak()),
+        ));
+        let built = Filesystem::mount_readonly(rom);
+        assert!(built.content_eq(&filesystem));
+
+        filesystem.delete_file("c").unwrap();
+        filesystem.write_file("c", &[4; 9000], &[0u8; 32]).unwrap();
+        assert!(!filesystem.content_eq(&built));
+        filesystem.delete_file("c").unwrap();
+        assert!(!filesystem.content_eq(&built));
     }
 
     #[test]
-    fn can_read_a_file_by_hash() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+    fn listing_files_by_prefix_works() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        filesystem.write_file("fancy2", &file, &[5u8; 32]).unwrap();
-        filesystem.read_file_by_hash(&[0u8; 32]).unwrap();
-        assert!(filesystem.read_file_by_hash(&[3u8; 32]).is_none());
-        filesystem.read_file_by_hash(&[5u8; 32]).unwrap();
+        for name in ["ota/b", "app/b", "ota/a", "app/a", "other"] {
+            filesystem.write_file(name, &[1; 10], &[0u8; 32]).unwrap();
+        }
+        filesystem.delete_file("app/b").unwrap();
+
+        let names = |files: Vec<File<SimulatedStorage, { FileState::Weak }>>| -> Vec<String> {
+            files
+                .iter()
+                .map(|file| file.name_str().to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(filesystem.list_files_with_prefix("ota/").collect()),
+            ["ota/a", "ota/b"]
+        );
+        assert_eq!(
+            names(filesystem.list_files_with_prefix("app/").collect()),
+            ["app/a"]
+        );
+        assert_eq!(
+            names(filesystem.list_files().collect()),
+            ["app/a", "ota/a", "ota/b", "other"]
+        );
     }
 
     #[test]
-    fn writing_multiple_files() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn listing_files_sorted_by_length_and_age() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
-        let result = filesystem.read_file("fancy").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
-        let result = filesystem.read_file("fancy2").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        for (name, length) in [("b", 300), ("a", 100), ("gone", 50), ("d", 300), ("c", 200)] {
+            filesystem
+                .write_file(name, &vec![1; length], &[0u8; 32])
+                .unwrap();
+        }
+        filesystem.delete_file("gone").unwrap();
+        let stored = |filesystem: &Filesystem<SimulatedStorage>| -> Vec<u32> {
+            filesystem.files.iter().map(|file| file.address).collect()
+        };
+        let stored_before = stored(&filesystem);
+
+        let names = |by: SortKey| -> Vec<String> {
+            filesystem
+                .list_files_sorted(by)
+                .iter()
+                .map(|file| file.name_str().to_string())
+                .collect()
+        };
+        assert_eq!(names(SortKey::Name), ["a", "b", "c", "d"]);
+        assert_eq!(names(SortKey::LengthAsc), ["a", "c", "b", "d"]);
+        assert_eq!(names(SortKey::LengthDesc), ["b", "d", "c", "a"]);
+        assert_eq!(names(SortKey::CreatedAsc), ["b", "a", "d", "c"]);
+        assert_eq!(names(SortKey::CreatedDesc), ["c", "d", "a", "b"]);
+        // The file table is not reordered
+        assert_eq!(stored(&filesystem), stored_before);
+    }
+
+    #[test]
+    fn the_number_of_files_can_be_limited() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::builder(storage).max_files(2).mount();
+        filesystem.write_file("a", &[1; 10], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 10], &[0u8; 32]).unwrap();
+        let result = filesystem.write_file("c", &[3; 10], &[0u8; 32]);
+        assert!(matches!(
+            result,
+            Err(FilesystemWriteError::TooManyFiles { max_files: 2 })
+        ));
+
+        filesystem.delete_file("a").unwrap();
+        filesystem.write_file("c", &[3; 10], &[0u8; 32]).unwrap();
+    }
+
+    #[test]
+    fn mount_stops_after_the_maximum_number_of_files() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        for name in ["a", "b", "c"] {
+            filesystem.write_file(name, &[1; 10], &[0u8; 32]).unwrap();
+        }
+        drop(filesystem);
+
+        let (filesystem, report) = Filesystem::builder(storage)
+            .max_files(2)
+            .mount_with_report();
+        assert_eq!(report.files_found, 2);
+        assert_eq!(filesystem.list_files().count(), 2);
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(filesystem.list_files().count(), 3);
     }
 
     #[test]
-    fn unimportant_files_get_deleted() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn mount_uses_the_index() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        // A bit bigger than half the storage size
-        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
-        assert!(filesystem.read_file("fancy").is_none());
-        let result = filesystem.read_file("fancy2").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        filesystem.write_file("a", &[1; 5000], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        filesystem.write_file("c", &[3; 100], &[0u8; 32]).unwrap();
+        filesystem.delete_file("b").unwrap();
+        drop(filesystem);
+
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(report.index_used);
+        assert!(!report.scan_fallback);
+        assert_eq!(filesystem.mount_source(), MountSource::Index);
+        assert_eq!(report.files_found, 2);
+        let main = filesystem.read_file("a").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 5000]);
+        assert!(filesystem.read_file("b").is_none());
     }
 
     #[test]
-    fn important_files_dont_get_deleted() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn a_corrupted_index_falls_back_to_scanning() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        // A bit bigger than half the storage size
-        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let result = filesystem.read_file("fancy").unwrap();
-        result.set_important().unwrap();
+        filesystem.write_file("a", &[1; 5000], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        drop(filesystem);
 
-        filesystem
-            .write_file("fancy2", &file, &[0u8; 32])
-            .unwrap_err();
+        let mut index = storage.read_metadata("index").unwrap();
+        index[9] ^= 0x01;
+        storage.write_metadata("index", &index).unwrap();
+
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(!report.index_used);
+        assert!(report.scan_fallback);
+        assert_eq!(filesystem.mount_source(), MountSource::Scan);
+        let names: Vec<String> = filesystem
+            .list_files()
+            .map(|file| file.name_str().to_string())
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        drop(filesystem);
+
+        // The scan stored a fresh index
+        let (_, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(report.index_used);
+        assert!(!report.scan_fallback);
     }
 
     #[test]
-    fn open_reader_protects_files_from_being_deleted() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn a_missing_index_is_not_a_scan_fallback() {
+        let storage = get_test_storage();
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(!report.index_used);
+        assert!(!report.scan_fallback);
+        assert_eq!(filesystem.mount_source(), MountSource::Scan);
+    }
+
+    #[test]
+    fn an_outdated_index_falls_back_to_scanning() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        // A bit bigger than half the storage size
-        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let result = filesystem.read_file("fancy").unwrap();
-        let _strong_ref = result.upgrade().unwrap();
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        let outdated_index = storage.read_metadata("index").unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        drop(filesystem);
+        storage.write_metadata("index", &outdated_index).unwrap();
 
-        filesystem
-            .write_file("fancy2", &file, &[0u8; 32])
-            .unwrap_err();
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(!report.index_used);
+        assert!(report.scan_fallback);
+        assert!(filesystem.read_file("b").is_some());
     }
 
     #[test]
-    fn deleting_a_file_works() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn reconciling_reports_and_repairs_a_drifted_index() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        filesystem.delete_file("fancy").unwrap();
-        let None = filesystem.read_file("fancy") else {
-            panic!("Should not be able to read a deleted file");
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        let address_of = |filesystem: &Filesystem<SimulatedStorage>, name: &str| {
+            filesystem
+                .files
+                .iter()
+                .find(|f| f.name == name)
+                .unwrap()
+                .address
         };
+        let (a, b) = (address_of(&filesystem, "a"), address_of(&filesystem, "b"));
+        assert!(filesystem.reconcile(false).unwrap().consistent());
+
+        // Storing the index failed after "b" was written, and it still lists a deleted file
+        let ghost = 10 * SimulatedStorage::BLOCK_SIZE;
+        storage
+            .write_metadata("index", &index::encode(&[(a, 100), (ghost, 100)]))
+            .unwrap();
+        let report = filesystem.reconcile(false).unwrap();
+        assert!(report.index_found);
+        assert_eq!(report.missing_from_index, [(b, "b".to_string())]);
+        assert_eq!(report.missing_from_storage, [ghost]);
+        assert!(!report.index_rebuilt);
+        assert!(!report.consistent());
+
+        let report = filesystem.reconcile(true).unwrap();
+        assert!(report.index_rebuilt);
+        assert!(filesystem.reconcile(false).unwrap().consistent());
+        drop(filesystem);
+
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(report.index_used);
+        assert_eq!(report.files_found, 2);
+        drop(filesystem);
+
+        // A missing index is rebuilt as well, but not on a read-only filesystem
+        storage.write_metadata("index", &[]).unwrap();
+        let mut filesystem = Filesystem::mount_readonly(storage);
+        assert!(!filesystem.reconcile(false).unwrap().index_found);
+        assert!(matches!(
+            filesystem.reconcile(true),
+            Err(FilesystemReconcileError::ReadOnly)
+        ));
+        drop(filesystem);
+        let mut filesystem = Filesystem::new(storage);
+        assert!(filesystem.reconcile(false).unwrap().consistent());
     }
 
     #[test]
-    fn deleting_a_file_actually_works() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn rebuilding_the_index_picks_up_changes_to_the_storage() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        filesystem.delete_file("fancy").unwrap();
-        let None = filesystem.read_file("fancy") else {
-            panic!("Should not be able to read a deleted file");
-        };
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        let reader = filesystem.read_file("a").unwrap().upgrade().unwrap();
 
-        let filesystem = Filesystem::new(storage);
-        let None = filesystem.read_file("fancy") else {
-            panic!("Should not be able to read a deleted file");
-        };
+        // A tool adds a file behind the back of the filesystem and damages the index
+        let mut tool = Filesystem::new(storage);
+        tool.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        drop(tool);
+        storage.write_metadata("index", &[1, 2, 3]).unwrap();
+        assert!(filesystem.read_file("b").is_none());
+
+        filesystem.rebuild_index().unwrap();
+        let b = filesystem.read_file("b").unwrap().upgrade().unwrap();
+        assert_eq!(b.as_ref(), [2; 100]);
+        assert_eq!(reader.as_ref(), [1; 100]);
+        let stored = index::decode(&storage.read_metadata("index").unwrap()).unwrap();
+        assert_eq!(stored, filesystem.index_entries());
+        assert_eq!(stored.len(), 2);
+        drop((reader, b, filesystem));
+
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(report.index_used);
+        assert_eq!(report.files_found, 2);
+        drop(filesystem);
+
+        let mut filesystem = Filesystem::mount_readonly(storage);
+        assert!(matches!(
+            filesystem.rebuild_index(),
+            Err(FilesystemReconcileError::ReadOnly)
+        ));
     }
 
     #[test]
-    fn file_cant_be_upgraded_if_it_has_been_deleted_and_there_are_only_weak_references() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn sync_stores_outdated_metadata() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let content = vec![0; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem
-            .write_file("fancy", &content, &[0u8; 32])
-            .unwrap();
-        let file = filesystem.read_file("fancy").unwrap();
-        let weak_ref = file;
-        filesystem.delete_file("fancy").unwrap();
-        let Err(_) = weak_ref.upgrade() else {
-            panic!("Should not be able to upgrade deleted file");
-        };
-        let None = filesystem.read_file("fancy") else {
-            panic!("Should not be able to read a deleted file");
-        };
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        let outdated_index = storage.read_metadata("index").unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        let first_block = filesystem.get_first_block().unwrap();
+
+        // Storing the metadata failed after "b" was written
+        storage.write_metadata("index", &outdated_index).unwrap();
+        storage.write_metadata(CLOCK_KEY, &[0; 4]).unwrap();
+        storage.write_metadata("first_block", &[]).unwrap();
+        filesystem.sync().unwrap();
+        assert_eq!(filesystem.get_first_block().unwrap(), first_block);
+        assert_eq!(
+            *storage.read_metadata(CLOCK_KEY).unwrap(),
+            filesystem.encode_clock()
+        );
+        drop(filesystem);
+
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(report.index_used);
+        assert_eq!(report.files_found, 2);
+        filesystem.sync().unwrap();
+        drop(filesystem);
+
+        // Nothing to store on a read-only filesystem
+        storage.write_metadata("index", &[]).unwrap();
+        let filesystem = Filesystem::mount_readonly(storage);
+        filesystem.sync().unwrap();
+        assert!(storage.read_metadata("index").unwrap().is_empty());
     }
 
     #[test]
-    fn no_new_references_can_be_created_to_a_file_marked_for_deletion() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn creation_times_stay_ordered_when_the_counter_is_reset() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let content = vec![0; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem
-            .write_file("fancy", &content, &[0u8; 32])
-            .unwrap();
-        let file = filesystem.read_file("fancy").unwrap();
-        let strong_ref = file.upgrade().unwrap();
-        filesystem.delete_file("fancy").unwrap();
-        let None = filesystem.read_file("fancy") else {
-            panic!(
-                "Should not be able to create a new reference to a file marked for deletion file"
-            );
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        let created_at = |filesystem: &Filesystem<SimulatedStorage>, name: &str| {
+            filesystem.read_file(name).unwrap().created_at().unwrap()
         };
-        // Strong ref still has the same correct content
-        assert_eq!(strong_ref.as_ref(), content);
+        assert_eq!(created_at(&filesystem, "a"), (0, 1));
+        assert_eq!(created_at(&filesystem, "b"), (0, 2));
+        drop(filesystem);
+
+        // The metadata was cleared, but the files are still there
+        storage.write_metadata("clock", &[]).unwrap();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("c", &[3; 100], &[0u8; 32]).unwrap();
+        assert_eq!(created_at(&filesystem, "c"), (1, 0));
+        drop(filesystem);
+
+        // An older counter was restored
+        storage.write_metadata("clock", &[0, 0, 5, 0]).unwrap();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("d", &[4; 100], &[0u8; 32]).unwrap();
+        assert_eq!(created_at(&filesystem, "d"), (2, 0));
+
+        // Moving a file keeps its creation time
+        filesystem.delete_file("a").unwrap();
+        filesystem.compact().unwrap();
+        let mut names: Vec<String> = filesystem
+            .list_files()
+            .map(|file| file.name_str().to_string())
+            .collect();
+        names.sort_by_key(|name| created_at(&filesystem, name));
+        assert_eq!(names, ["b", "c", "d"]);
     }
 
     #[test]
-    fn writing_a_maximum_size_file_works() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn the_epoch_advances_when_the_counter_overflows() {
+        let storage = get_test_storage();
+        storage
+            .write_metadata("clock", &[3, 0, 0xff, 0xff])
+            .unwrap();
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let result = filesystem.read_file("fancy").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        let a = filesystem.read_file("a").unwrap().created_at().unwrap();
+        let b = filesystem.read_file("b").unwrap().created_at().unwrap();
+        assert_eq!((a, b), ((3, u16::MAX), (4, 0)));
+        assert!(a < b);
     }
 
     #[test]
-    fn deleting_a_file_makes_space_for_a_new_file() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn files_can_be_read_by_their_first_block() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        filesystem.delete_file("fancy").unwrap();
-        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
-        let result = filesystem.read_file("fancy2").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        filesystem.write_file("a", &[1; 5000], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 100], &[0u8; 32]).unwrap();
+        let blocks: Vec<u32> = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let file = filesystem.files.iter().find(|file| file.name == *name);
+                file.unwrap().start_block()
+            })
+            .collect();
+
+        let file = filesystem.file_at_block(blocks[1]).unwrap();
+        assert_eq!(file.name_str(), "b");
+        assert_eq!(file.start_block(), blocks[1]);
+        assert_eq!(file.length_bytes(), 100);
+        assert_eq!(filesystem.read_at_block(blocks[0]).unwrap(), [1; 5000]);
+
+        // The second block of "a"
+        assert!(filesystem.file_at_block(blocks[0] + 1).is_none());
+        assert!(matches!(
+            filesystem.read_at_block(blocks[0] + 1),
+            Err(FilesystemReadError::FileNotFound)
+        ));
+        assert!(filesystem.file_at_block(u32::MAX).is_none());
+
+        filesystem.delete_file("b").unwrap();
+        assert!(filesystem.file_at_block(blocks[1]).is_none());
     }
 
     #[test]
-    fn can_write_a_big_file() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn internal_fragmentation_counts_the_unused_rest_of_the_last_block() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-
-        let file = [42u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
+        assert_eq!(filesystem.internal_fragmentation(), 0);
+        let block_size = SimulatedStorage::BLOCK_SIZE as u64;
+        filesystem.write_file("a", &[1; 100], &[0u8; 32]).unwrap();
+        filesystem.write_file("b", &[2; 1000], &[0u8; 32]).unwrap();
+        // Fills exactly two blocks
+        let exact = 2 * SimulatedStorage::BLOCK_SIZE - HEADER_SIZE;
         filesystem
-            .write_file("big_file", &file, &[0u8; 32])
+            .write_file("c", &vec![3; exact as usize], &[0u8; 32])
             .unwrap();
-        let result = filesystem.read_file("big_file").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        let expected = (block_size - 100 - 64) + (block_size - 1000 - 64);
+        assert_eq!(filesystem.internal_fragmentation(), expected);
+
+        let usage = filesystem.space_usage();
+        assert_eq!(usage.used_blocks, 4);
+        assert_eq!(usage.internal_fragmentation, expected);
+        assert_eq!(usage.free_space, filesystem.free_space());
+
+        filesystem.delete_file("a").unwrap();
+        assert_eq!(filesystem.internal_fragmentation(), block_size - 1000 - 64);
     }
 
     #[test]
-    fn multiple_unimportant_files_can_get_overwritten() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
-        let mut filesystem = Filesystem::new(storage);
-
-        for i in 0..SimulatedStorage::BLOCKS {
-            filesystem
-                .write_file(&format!("small_file_{}", i), &[0; 32], &[0u8; 32])
-                .unwrap();
-        }
+    fn errors_can_be_used_as_trait_objects() {
+        fn assert_error<E: std::error::Error + Send + Sync + 'static>() {}
+        assert_error::<FilesystemWriteError>();
+        assert_error::<FilesystemReadError>();
+        assert_error::<FilesystemDeleteError>();
+        assert_error::<transaction::FilesystemTransactionError>();
 
-        let file = [42u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem
-            .write_file("big_file", &file, &[0u8; 32])
+        // The underlying io error can be found by following the sources
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(FilesystemReadError::from(storage::StorageError::from(
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "flash timeout"),
+            )));
+        let io_error =
+            std::iter::successors(Some(error.as_ref() as &dyn std::error::Error), |error| {
+                error.source()
+            })
+            .find_map(|error| error.downcast_ref::<std::io::Error>())
             .unwrap();
-        let result = filesystem.read_file("big_file").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        assert_eq!(io_error.kind(), std::io::ErrorKind::TimedOut);
     }
 
     #[test]
-    fn deleting_a_file_does_not_make_space_for_a_new_file_if_there_are_still_strong_references_to_its_content(
-    ) {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn errors_without_storage_errors_can_be_compared() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let fancy_file = filesystem.read_file("fancy").unwrap();
-        let strong_ref = fancy_file.upgrade().unwrap();
-        filesystem.delete_file("fancy").unwrap();
-        let Err(_) = filesystem.write_file("fancy2", &file, &[0u8; 32]) else {
-            panic!("Should fail because the file was not yet deleted");
+        let result = filesystem.write_file("huge", &[0; 17 * 4096], &[0u8; 32]);
+        let Err(FilesystemWriteError::FindFreeSpaceError(error)) = result else {
+            panic!("Expected an out of space error");
         };
-        assert_eq!(strong_ref.as_ref(), file);
-        drop(strong_ref);
-        // Should work now, because we dropped the last strong reference
-        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+        assert_eq!(
+            error,
+            FindFreeSpaceError::OutOfSpace {
+                needed: 19 * 4096,
+                largest_free_run: 16 * 4096,
+                reclaimable_by_compaction: 0,
+            }
+        );
+
+        filesystem.write_file("a", &[1; 10], &[0u8; 32]).unwrap();
+        let file = filesystem.read_file("a").unwrap();
+        filesystem.delete_file("a").unwrap();
+        assert_eq!(
+            file.upgrade().unwrap_err(),
+            UpgradeFileError::FileHasBeenDeleted
+        );
     }
 
     #[test]
-    fn writing_a_file_thats_too_big_fails() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn storages_with_large_blocks_can_be_mounted() {
+        use crate::storage::simulated::SizedSimulatedStorage;
+        type LargeBlocks = SizedSimulatedStorage<4, 65536>;
+
+        let storage: &'static LargeBlocks = Box::leak(Box::new(LargeBlocks::new()));
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize + 1];
-        let Err(_) = filesystem.write_file("fancy", &file, &[0u8; 32]) else {
-            panic!("Should fail when there is not enough space");
-        };
+        let content: Vec<u8> = (0..100_000).map(|index| index as u8).collect();
+        filesystem
+            .write_file("large", &content, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("small", &[1; 100], &[0u8; 32])
+            .unwrap();
+        assert_eq!(filesystem.free_space(), 0);
+        for file in &filesystem.files {
+            assert!(file.address.is_multiple_of(65536));
+        }
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        let file = filesystem.read_file("large").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), content.as_slice());
+        assert!(filesystem.read_file("small").is_some());
     }
 
     #[test]
-    fn deleting_the_first_file_moves_the_marker() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn secure_files_are_erased_when_they_are_deleted() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize
-            - size_of::<FileMetadata>()
-            - SimulatedStorage::BLOCK_SIZE as usize * 2];
-        filesystem.write_file("first", &file, &[0u8; 32]).unwrap();
-        assert_eq!(filesystem.get_first_block().unwrap(), 0);
-        let file2 = [0u8; SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("second", &file2, &[0u8; 32]).unwrap();
-        assert_eq!(filesystem.get_first_block().unwrap(), 0);
-        filesystem.delete_file("first").unwrap();
-        assert_ne!(filesystem.get_first_block().unwrap(), 0);
-        // drop(filesystem);
-        // let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("secret", &[0x42; 5000], &[0u8; 32])
+            .unwrap();
+        filesystem.set_secure_delete("secret").unwrap();
+        let address = filesystem
+            .files
+            .iter()
+            .find(|file| file.name == "secret")
+            .unwrap()
+            .address;
+
+        let reader = filesystem.read_file("secret").unwrap().upgrade().unwrap();
+        assert!(matches!(
+            filesystem.delete_file("secret"),
+            Err(FilesystemDeleteError::FileInUse)
+        ));
+        assert_eq!(reader.as_ref(), &[0x42; 5000]);
+        drop(reader);
+
+        filesystem.delete_file("secret").unwrap();
+        assert!(filesystem.read_file("secret").is_none());
+        assert!(storage
+            .read(address, 2 * 4096)
+            .unwrap()
+            .iter()
+            .all(|byte| *byte == 0xff));
     }
 
     #[test]
-    fn can_not_create_two_files_with_the_same_name() {
-        let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+    fn any_file_can_be_deleted_securely() {
+        let storage = get_test_storage();
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("cool", &file, &[0u8; 32]).unwrap();
         filesystem
-            .write_file("cool", &file, &[0u8; 32])
-            .unwrap_err();
+            .write_file("plain", &[0x42; 100], &[0u8; 32])
+            .unwrap();
+        let reader = filesystem.read_file("plain").unwrap().upgrade().unwrap();
+        assert!(matches!(
+            filesystem.delete_file_secure("plain"),
+            Err(FilesystemDeleteError::FileInUse)
+        ));
+        drop(reader);
+        filesystem.delete_file_secure("plain").unwrap();
+        assert!(filesystem.read_file("plain").is_none());
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn trust_is_checked_again_when_the_signature_changes() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let storage = get_test_storage();
+        let trusted = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let content = [7u8; 100];
+        let hash: [u8; 32] = blake3::hash(&content).into();
+        let mut filesystem = Filesystem::builder(storage)
+            .verify_signatures([trusted.verifying_key()])
+            .mount();
+        let signature = trusted.sign(&hash).to_bytes();
+        filesystem
+            .write_file_signed("main", &content, &hash, &signature)
+            .unwrap();
+        assert_eq!(filesystem.list_files().count(), 1);
+
+        let foreign_signature = other.sign(&hash).to_bytes();
+        filesystem
+            .set_attr("main", "signature", &foreign_signature)
+            .unwrap();
+        assert_eq!(filesystem.list_files().count(), 0);
+        assert!(matches!(
+            filesystem.open("main"),
+            Err(FilesystemReadError::UntrustedFile)
+        ));
+
+        filesystem
+            .update_headers(|header| header.set_attr("signature", &signature))
+            .unwrap();
+        assert_eq!(filesystem.list_files().count(), 1);
+        assert!(filesystem.open("main").is_ok());
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn only_files_signed_by_a_trusted_key_can_be_read() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let storage = get_test_storage();
+        let trusted = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let content = [7u8; 3000];
+        let hash: [u8; 32] = blake3::hash(&content).into();
+        {
+            let mut filesystem = Filesystem::new(storage);
+            let signature = trusted.sign(&hash).to_bytes();
+            filesystem
+                .write_file_signed("signed", &content, &hash, &signature)
+                .unwrap();
+            let signature = other.sign(&hash).to_bytes();
+            filesystem
+                .write_file_signed("foreign", &content, &hash, &signature)
+                .unwrap();
+            filesystem.write_file("unsigned", &content, &hash).unwrap();
+            let wrong_hash = [0u8; 32];
+            let signature = trusted.sign(&wrong_hash).to_bytes();
+            filesystem
+                .write_file_signed("tampered", &content, &wrong_hash, &signature)
+                .unwrap();
+        }
+
+        let filesystem = Filesystem::builder(storage)
+            .verify_signatures([trusted.verifying_key()])
+            .mount();
+        let file = filesystem.open("signed").unwrap().upgrade().unwrap();
+        assert_eq!(file.as_ref(), &content);
+        for name in ["foreign", "unsigned", "tampered"] {
+            assert!(matches!(
+                filesystem.open(name),
+                Err(FilesystemReadError::UntrustedFile)
+            ));
+            assert!(filesystem.read_file(name).is_none());
+        }
+        assert_eq!(
+            filesystem
+                .list_files_with_prefix("")
+                .map(|file| file.name_str().to_string())
+                .collect::<Vec<_>>(),
+            vec!["signed"]
+        );
     }
 }
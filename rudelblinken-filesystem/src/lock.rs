@@ -0,0 +1,182 @@
+//! Recover a shared [Filesystem] after a panic
+//!
+//! A [Filesystem] that is shared between threads usually lives in a [RwLock]. If a thread panics while
+//! holding the lock, the lock is poisoned and every later `read().unwrap()` panics as well. One failed
+//! operation would make the filesystem unusable until the next reboot.
+//!
+//! The accessors of [RecoverPoisoned] log a warning, clear the poison and return the guard anyway.
+//! This is safe for the data on the storage, because every operation leaves the storage in a state that
+//! can be mounted, just like after a power loss. The table of files in memory may be missing the changes
+//! of the interrupted operation. They show up again after the next mount.
+//!
+//! # Keeping file contents after unlocking
+//!
+//! [Filesystem::iter_contents] and [Filesystem::read_contents] borrow the contents from the filesystem,
+//! so they can only be used while the guard is held. Once it is dropped, a writer could delete a file
+//! and erase its blocks. The borrow checker rejects keeping the contents longer:
+//!
+//! ```compile_fail
+//! # use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+//! # use rudelblinken_filesystem::Filesystem;
+//! # use std::sync::RwLock;
+//! # let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+//! let filesystem = RwLock::new(Filesystem::new(storage));
+//! let guard = filesystem.read().unwrap();
+//! let content = guard.read_contents("main").unwrap();
+//! drop(guard);
+//! println!("{:?}", content);
+//! ```
+//!
+//! Readers are not borrowed from the filesystem. Take them with [Filesystem::read_file] or
+//! [Filesystem::iter_contents_owned] to keep the contents after unlocking. A reader keeps the blocks of
+//! its file from being erased until it is dropped, even if the file is deleted meanwhile.
+//!
+//! ```
+//! # use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+//! # use rudelblinken_filesystem::Filesystem;
+//! # use std::sync::RwLock;
+//! # let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+//! let filesystem = RwLock::new(Filesystem::new(storage));
+//! filesystem.write().unwrap().write_file("main", &[1; 100], &[0; 32]).unwrap();
+//!
+//! let readers: Vec<_> = filesystem.read().unwrap().iter_contents_owned().collect();
+//! filesystem.write().unwrap().delete_file("main").unwrap();
+//! assert_eq!(readers[0].as_ref(), [1; 100]);
+//! ```
+
+use crate::{storage::Storage, Filesystem};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Lock a shared [Filesystem] without failing on a poisoned lock
+pub trait RecoverPoisoned<T: Storage + 'static + Send + Sync> {
+    /// Acquire shared access. Clears the poison if a previous holder panicked.
+    fn read_recovering(&self) -> RwLockReadGuard<'_, Filesystem<T>>;
+    /// Acquire exclusive access. Clears the poison if a previous holder panicked.
+    fn write_recovering(&self) -> RwLockWriteGuard<'_, Filesystem<T>>;
+}
+
+impl<T: Storage + 'static + Send + Sync> RecoverPoisoned<T> for RwLock<Filesystem<T>> {
+    fn read_recovering(&self) -> RwLockReadGuard<'_, Filesystem<T>> {
+        self.read().unwrap_or_else(|poisoned| {
+            log::warn!("Recovering the filesystem lock after a panic");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    fn write_recovering(&self) -> RwLockWriteGuard<'_, Filesystem<T>> {
+        self.write().unwrap_or_else(|poisoned| {
+            log::warn!("Recovering the filesystem lock after a panic");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::simulated::get_test_storage;
+    use std::{io::Write, sync::Arc};
+
+    #[test]
+    fn a_panic_while_writing_does_not_lock_out_other_threads() {
+        let filesystem = Arc::new(RwLock::new(Filesystem::new(get_test_storage())));
+        let panicking = filesystem.clone();
+        std::thread::spawn(move || {
+            let mut filesystem = panicking.write().unwrap();
+            filesystem.write_file("before", &[1; 10], &[0; 32]).unwrap();
+            panic!("Simulated panic while holding the lock");
+        })
+        .join()
+        .unwrap_err();
+        assert!(filesystem.is_poisoned());
+
+        let reader = filesystem.read_recovering();
+        assert!(reader.read_file("before").is_some());
+        drop(reader);
+        assert!(!filesystem.is_poisoned());
+        filesystem
+            .write_recovering()
+            .write_file("after", &[2; 10], &[0; 32])
+            .unwrap();
+    }
+
+    #[test]
+    fn owned_contents_outlive_the_guard() {
+        let filesystem = Arc::new(RwLock::new(Filesystem::new(get_test_storage())));
+        for name in ["b", "a"] {
+            filesystem
+                .write()
+                .unwrap()
+                .write_file(name, name.as_bytes(), &[0; 32])
+                .unwrap();
+        }
+        let readers: Vec<_> = filesystem.read_recovering().iter_contents_owned().collect();
+
+        let writer = filesystem.clone();
+        std::thread::spawn(move || {
+            let mut filesystem = writer.write_recovering();
+            filesystem.delete_file("a").unwrap();
+            filesystem.write_file("c", &[3; 5000], &[0; 32]).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let contents: Vec<(&str, &[u8])> = readers
+            .iter()
+            .map(|reader| (reader.name_str(), reader.as_ref()))
+            .collect();
+        assert_eq!(contents, [("a", &b"a"[..]), ("b", &b"b"[..])]);
+        let filesystem = filesystem.read_recovering();
+        assert!(matches!(
+            filesystem.read_contents("a"),
+            Err(crate::FilesystemReadError::FileNotFound)
+        ));
+        assert_eq!(filesystem.read_contents("c").unwrap(), [3; 5000]);
+    }
+
+    #[test]
+    fn files_created_from_several_threads_do_not_overlap() {
+        let storage = get_test_storage();
+        let filesystem = Arc::new(RwLock::new(Filesystem::new(storage)));
+        let threads: Vec<_> = (0..2u8)
+            .map(|thread| {
+                let filesystem = filesystem.clone();
+                std::thread::spawn(move || {
+                    for file in 0..3u8 {
+                        let name = format!("{}-{}", thread, file);
+                        let content = [thread * 16 + file; 5000];
+                        // Only the allocation holds the lock, the content is written afterwards
+                        let mut writer = filesystem
+                            .write_recovering()
+                            .get_file_writer(&name, content.len() as u32, &[0; 32])
+                            .unwrap();
+                        writer.write_all(&content).unwrap();
+                        writer.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let check = |filesystem: &Filesystem<_>| {
+            for thread in 0..2u8 {
+                for file in 0..3u8 {
+                    let name = format!("{}-{}", thread, file);
+                    let content = filesystem.read_file(&name).unwrap().upgrade().unwrap();
+                    assert_eq!(content.as_ref(), [thread * 16 + file; 5000]);
+                }
+            }
+        };
+        check(&filesystem.read_recovering());
+        drop(filesystem);
+
+        let (filesystem, report) = Filesystem::builder(storage).mount_with_report();
+        assert!(report.index_used);
+        assert_eq!(report.files_found, 6);
+        check(&filesystem);
+    }
+}
@@ -0,0 +1,272 @@
+//! Pending changes kept in RAM on top of a filesystem
+//!
+//! An [OverlayFs] shows the files of a filesystem together with writes and deletions that have not
+//! been applied yet. Nothing is written to the storage until [OverlayFs::commit], so a complete set of
+//! files can be checked before any of them replaces the installed ones. Dropping the overlay or calling
+//! [OverlayFs::discard] forgets the changes.
+//!
+//! The overlay does not borrow the filesystem. It keeps weak references to the files that existed when
+//! it was created, so the filesystem can be used in the meantime. The changes are applied in a single
+//! [transaction](crate::transaction), so either all of them end up in the storage or none.
+
+use crate::{
+    file::{File, FileState},
+    storage::Storage,
+    transaction::FilesystemTransactionError,
+    Filesystem, FilesystemDeleteError, FilesystemWriteError,
+};
+use std::ops::Deref;
+use thiserror::Error;
+
+/// Errors that can occur when committing an overlay
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemOverlayError {
+    /// Error while writing a file of the overlay
+    #[error(transparent)]
+    WriteError(#[from] FilesystemWriteError),
+    /// Error while deleting a file that was deleted or replaced in the overlay
+    #[error(transparent)]
+    DeleteError(#[from] FilesystemDeleteError),
+    /// Error while committing the changes
+    #[error(transparent)]
+    TransactionError(#[from] FilesystemTransactionError),
+}
+
+/// A change to a file name
+enum Change {
+    Write { content: Vec<u8>, hash: [u8; 32] },
+    Delete,
+}
+
+/// Files of a filesystem with pending changes on top, see [overlay](self)
+///
+/// ```
+/// use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+/// use rudelblinken_filesystem::Filesystem;
+///
+/// let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+/// let mut filesystem = Filesystem::new(storage);
+/// filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+///
+/// let mut overlay = filesystem.overlay();
+/// overlay.write_file("main", &[2; 100], &[0; 32]);
+/// assert_eq!(&*overlay.read_file("main").unwrap(), [2; 100]);
+/// // The storage still has the old file
+/// assert_eq!(filesystem.read_file("main").unwrap().upgrade().unwrap().as_ref(), [1; 100]);
+///
+/// overlay.commit(&mut filesystem).unwrap();
+/// assert_eq!(filesystem.read_file("main").unwrap().upgrade().unwrap().as_ref(), [2; 100]);
+/// ```
+pub struct OverlayFs<T: Storage + 'static + Send + Sync> {
+    /// Files of the filesystem when the overlay was created
+    base: Vec<File<T, { FileState::Weak }>>,
+    /// Changes by file name, in the order they were made
+    changes: Vec<(String, Change)>,
+}
+
+/// Content of a file in an [OverlayFs]
+pub enum OverlayFile<'a, T: Storage + 'static + Send + Sync> {
+    /// The file is stored in the filesystem
+    Stored(File<T, { FileState::Reader }>),
+    /// The file was written in the overlay
+    Pending(&'a [u8]),
+}
+
+impl<T: Storage + 'static + Send + Sync> Deref for OverlayFile<'_, T> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            OverlayFile::Stored(file) => file,
+            OverlayFile::Pending(content) => content,
+        }
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> OverlayFs<T> {
+    pub(crate) fn new(filesystem: &Filesystem<T>) -> Self {
+        Self {
+            base: filesystem.list_files().collect(),
+            changes: Vec::new(),
+        }
+    }
+
+    fn change(&self, name: &str) -> Option<&Change> {
+        self.changes
+            .iter()
+            .find(|(changed, _)| changed == name)
+            .map(|(_, change)| change)
+    }
+
+    fn set_change(&mut self, name: &str, change: Change) {
+        self.changes.retain(|(changed, _)| changed != name);
+        self.changes.push((name.to_string(), change));
+    }
+
+    /// Find a stored file that can still be read
+    fn base_file(&self, name: &str) -> Option<File<T, { FileState::Reader }>> {
+        self.base
+            .iter()
+            .filter(|file| file.name_str() == name)
+            .find_map(|file| file.upgrade().ok())
+    }
+
+    /// Read a file. Files written in the overlay shadow the stored ones.
+    pub fn read_file(&self, name: &str) -> Option<OverlayFile<'_, T>> {
+        match self.change(name) {
+            Some(Change::Write { content, .. }) => Some(OverlayFile::Pending(content)),
+            Some(Change::Delete) => None,
+            None => self.base_file(name).map(OverlayFile::Stored),
+        }
+    }
+
+    /// Names of all files, stored files first
+    pub fn list_files(&self) -> impl Iterator<Item = &str> {
+        let stored = self
+            .base
+            .iter()
+            .map(|file| file.name_str())
+            .filter(|name| self.change(name).is_none() && self.base_file(name).is_some());
+        let pending = self
+            .changes
+            .iter()
+            .filter_map(|(name, change)| match change {
+                Change::Write { .. } => Some(name.as_str()),
+                Change::Delete => None,
+            });
+        stored.chain(pending)
+    }
+
+    /// Write a file in the overlay, replacing a file with the same name
+    pub fn write_file(&mut self, name: &str, content: &[u8], hash: &[u8; 32]) {
+        self.set_change(
+            name,
+            Change::Write {
+                content: content.to_vec(),
+                hash: *hash,
+            },
+        );
+    }
+
+    /// Delete a file in the overlay
+    pub fn delete_file(&mut self, name: &str) -> Result<(), FilesystemDeleteError> {
+        if self.read_file(name).is_none() {
+            return Err(FilesystemDeleteError::FileNotFound);
+        }
+        self.set_change(name, Change::Delete);
+        Ok(())
+    }
+
+    /// Check if there are changes that have not been committed
+    pub fn has_changes(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    /// Apply the changes to `filesystem` in a single transaction
+    ///
+    /// Use the filesystem the overlay was created from. Stored files that are replaced or deleted in the
+    /// overlay need to still exist. If anything fails, no changes are applied.
+    pub fn commit(self, filesystem: &mut Filesystem<T>) -> Result<(), FilesystemOverlayError> {
+        let mut transaction = filesystem.transaction();
+        for (name, change) in &self.changes {
+            let stored = self.base.iter().any(|file| file.name_str() == name);
+            if stored {
+                match transaction.delete_file(name) {
+                    // A file written in the overlay may have been deleted from the filesystem meanwhile
+                    Err(FilesystemDeleteError::FileNotFound)
+                        if matches!(change, Change::Write { .. }) => {}
+                    result => result?,
+                }
+            }
+            if let Change::Write { content, hash } = change {
+                transaction.write_file(name, content, hash)?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Drop all changes. Same as dropping the overlay.
+    pub fn discard(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::simulated::get_test_storage;
+    use crate::{Filesystem, FilesystemDeleteError};
+
+    #[test]
+    fn the_overlay_shadows_the_stored_files() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+        filesystem.write_file("old", &[2; 100], &[0; 32]).unwrap();
+
+        let mut overlay = filesystem.overlay();
+        overlay.write_file("main", &[3; 5000], &[0; 32]);
+        overlay.write_file("new", &[4; 100], &[0; 32]);
+        overlay.delete_file("old").unwrap();
+        assert!(matches!(
+            overlay.delete_file("missing"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+
+        assert_eq!(&*overlay.read_file("main").unwrap(), [3; 5000]);
+        assert_eq!(&*overlay.read_file("new").unwrap(), [4; 100]);
+        assert!(overlay.read_file("old").is_none());
+        assert_eq!(overlay.list_files().collect::<Vec<_>>(), ["main", "new"]);
+
+        // Nothing reached the storage
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 100]);
+        assert!(filesystem.read_file("new").is_none());
+        assert!(filesystem.read_file("old").is_some());
+        drop(main);
+
+        overlay.discard();
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        let names: Vec<String> = filesystem
+            .list_files()
+            .map(|file| file.name_str().to_string())
+            .collect();
+        assert_eq!(names, ["main", "old"]);
+    }
+
+    #[test]
+    fn committing_applies_all_changes() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+        filesystem.write_file("old", &[2; 100], &[0; 32]).unwrap();
+
+        let mut overlay = filesystem.overlay();
+        overlay.write_file("main", &[3; 5000], &[0; 32]);
+        overlay.write_file("new", &[4; 100], &[0; 32]);
+        overlay.delete_file("old").unwrap();
+        overlay.commit(&mut filesystem).unwrap();
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [3; 5000]);
+        assert!(filesystem.read_file("new").is_some());
+        assert!(filesystem.read_file("old").is_none());
+    }
+
+    #[test]
+    fn a_failed_commit_changes_nothing() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+
+        let mut overlay = filesystem.overlay();
+        overlay.write_file("first", &[2; 100], &[0; 32]);
+        overlay.write_file("huge", &[3; 17 * 4096], &[0; 32]);
+        assert!(overlay.commit(&mut filesystem).is_err());
+
+        assert!(filesystem.read_file("first").is_none());
+        assert_eq!(filesystem.list_files().count(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+//! Reading files as a stream
+//!
+//! A reader of a [File] derefs to its content, so most code can use the slice directly. [FileReader]
+//! wraps a reader in [std::io::Read], [std::io::Seek] and [std::io::BufRead] for code that expects a
+//! stream, for example to read a config file line by line:
+//!
+//! ```
+//! use rudelblinken_filesystem::reader::FileReader;
+//! use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+//! use rudelblinken_filesystem::Filesystem;
+//! use std::io::BufRead;
+//!
+//! let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+//! let mut filesystem = Filesystem::new(storage);
+//! filesystem.write_file("config", b"name=rudel\nleds=4\n", &[0; 32]).unwrap();
+//!
+//! let config = filesystem.read_file("config").unwrap().upgrade().unwrap();
+//! let lines: Vec<String> = FileReader::new(config).lines().map(Result::unwrap).collect();
+//! assert_eq!(lines, ["name=rudel", "leds=4"]);
+//! ```
+//!
+//! The content is memory-mapped, so the buffer of [BufRead::fill_buf] is a window into the mapping
+//! instead of a copy in RAM. Nothing is allocated and only the parts of the file that are read are
+//! loaded from the flash. The size of the window is set with [FileReader::buffer_size].
+//!
+//! With the `embedded-io` feature, [FileReader] also implements the `Read`, `BufRead` and `Seek` traits
+//! of `embedded-io`.
+
+use crate::{
+    file::{File, FileState},
+    storage::Storage,
+};
+use std::io::{BufRead, Read, Seek, SeekFrom};
+
+/// Default size of the window returned by [BufRead::fill_buf]
+pub const DEFAULT_BUFFER_SIZE: usize = 512;
+
+/// A stream over the content of a file, see [reader](self)
+///
+/// The reader keeps the file open, so its blocks are not erased while it is read, even if the file is
+/// deleted.
+pub struct FileReader<T: Storage + 'static + Send + Sync> {
+    file: File<T, { FileState::Reader }>,
+    position: usize,
+    buffer_size: usize,
+}
+
+impl<T: Storage + 'static + Send + Sync> FileReader<T> {
+    /// Read `file` from the start
+    pub fn new(file: File<T, { FileState::Reader }>) -> Self {
+        Self {
+            file,
+            position: 0,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+
+    /// Set the maximum number of bytes returned by [BufRead::fill_buf]
+    ///
+    /// A buffer size of 0 is treated as 1, so an empty buffer always means the end of the file.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// Current position in the content
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The file that is read
+    pub fn file(&self) -> &File<T, { FileState::Reader }> {
+        &self.file
+    }
+
+    /// Stop reading and return the file
+    pub fn into_inner(self) -> File<T, { FileState::Reader }> {
+        self.file
+    }
+
+    /// The window into the content at the current position
+    fn window(&self) -> &[u8] {
+        let end = self
+            .position
+            .saturating_add(self.buffer_size)
+            .min(self.file.len());
+        &self.file[self.position..end]
+    }
+
+    /// Copy from the current position into `buf`
+    fn copy_to(&mut self, buf: &mut [u8]) -> usize {
+        let remaining = &self.file[self.position..];
+        let length = remaining.len().min(buf.len());
+        buf[..length].copy_from_slice(&remaining[..length]);
+        self.position += length;
+        length
+    }
+
+    /// Move the position, clamped to the content like seeking in a writer
+    fn seek_to(&mut self, pos: SeekFrom) -> u64 {
+        let length = self.file.len() as u64;
+        let target = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => length.saturating_add_signed(offset),
+            SeekFrom::Current(offset) => (self.position as u64).saturating_add_signed(offset),
+        };
+        self.position = target.min(length) as usize;
+        self.position as u64
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> Read for FileReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(self.copy_to(buf))
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> BufRead for FileReader<T> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        Ok(self.window())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = self.position.saturating_add(amt).min(self.file.len());
+    }
+}
+
+/// Seeking past either end of the content stops at that end, like seeking in a writer
+impl<T: Storage + 'static + Send + Sync> Seek for FileReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        Ok(self.seek_to(pos))
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: Storage + 'static + Send + Sync> embedded_io::ErrorType for FileReader<T> {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: Storage + 'static + Send + Sync> embedded_io::Read for FileReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.copy_to(buf))
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: Storage + 'static + Send + Sync> embedded_io::BufRead for FileReader<T> {
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        Ok(self.window())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        BufRead::consume(self, amt)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: Storage + 'static + Send + Sync> embedded_io::Seek for FileReader<T> {
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, Self::Error> {
+        Ok(self.seek_to(pos.into()))
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::simulated::{get_test_storage, SimulatedStorage},
+        Filesystem,
+    };
+
+    fn config_reader(content: &[u8]) -> FileReader<SimulatedStorage> {
+        let mut filesystem = Filesystem::new(get_test_storage());
+        filesystem.write_file("config", content, &[0; 32]).unwrap();
+        let config = filesystem.read_file("config").unwrap().upgrade().unwrap();
+        FileReader::new(config)
+    }
+
+    #[test]
+    fn lines_can_be_longer_than_the_buffer() {
+        let reader = config_reader(b"first=1\nsecond=22\n\nlast").buffer_size(3);
+        let lines: Vec<String> = reader.lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["first=1", "second=22", "", "last"]);
+
+        let mut reader = config_reader(b"abc").buffer_size(0);
+        assert_eq!(reader.fill_buf().unwrap(), b"a");
+        reader.consume(10);
+        assert_eq!(reader.fill_buf().unwrap(), b"");
+    }
+
+    #[test]
+    fn seeking_stops_at_the_ends_of_the_content() {
+        let mut reader = config_reader(b"0123456789");
+        let mut buf = [0; 4];
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 6);
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"6789");
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(reader.seek(SeekFrom::Current(-20)).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::Start(20)).unwrap(), 10);
+        assert_eq!(reader.seek(SeekFrom::Start(2)).unwrap(), 2);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "23456789");
+        assert_eq!(reader.position(), 10);
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn embedded_io_reads_the_same_content() {
+        use embedded_io::{BufRead, Read, Seek};
+
+        let mut reader = config_reader(b"key=value\n").buffer_size(4);
+        assert_eq!(BufRead::fill_buf(&mut reader).unwrap(), b"key=");
+        BufRead::consume(&mut reader, 4);
+        let mut buf = [0; 16];
+        assert_eq!(Read::read(&mut reader, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"value\n");
+        assert_eq!(
+            Seek::seek(&mut reader, embedded_io::SeekFrom::Start(0)).unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Signatures over the hash of a file
+//!
+//! A file can carry an Ed25519 signature over the hash in its header. The header has no space left for
+//! it, so it is stored as the attribute [SIGNATURE_ATTRIBUTE] of the file. The signature only covers
+//! the hash, so the content needs to be checked against the hash as well before it can be trusted.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+/// Name of the attribute that holds the signature
+pub(crate) const SIGNATURE_ATTRIBUTE: &str = "signature";
+
+/// Check if `signature` was made over `hash` by one of the `trusted_keys`
+pub(crate) fn verify(trusted_keys: &[VerifyingKey], hash: &[u8; 32], signature: &[u8]) -> bool {
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    trusted_keys
+        .iter()
+        .any(|key| key.verify_strict(hash, &signature).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn only_signatures_of_trusted_keys_are_accepted() {
+        let trusted = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let hash = [3; 32];
+        let keys = [other.verifying_key(), trusted.verifying_key()];
+
+        let signature = trusted.sign(&hash).to_bytes();
+        assert!(verify(&keys, &hash, &signature));
+        assert!(!verify(&keys[..1], &hash, &signature));
+        assert!(!verify(&keys, &[4; 32], &signature));
+        assert!(!verify(&keys, &hash, &signature[..63]));
+    }
+}
@@ -4,16 +4,49 @@
 
 use thiserror::Error;
 
+#[cfg(any(
+    test,
+    feature = "simulated",
+    feature = "sdmmc",
+    feature = "psram",
+    feature = "tcp"
+))]
+pub(crate) mod arena;
+
 #[cfg(any(test, feature = "simulated"))]
 #[cfg_attr(docsrs, doc(cfg(feature = "simulated")))]
 pub mod simulated;
 
+#[cfg(feature = "sdmmc")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sdmmc")))]
+pub mod sdmmc;
+
 #[cfg(feature = "esp")]
 #[cfg_attr(docsrs, doc(cfg(feature = "esp")))]
 pub mod esp;
 
+#[cfg(feature = "psram")]
+#[cfg_attr(docsrs, doc(cfg(feature = "psram")))]
+pub mod psram;
+
+#[cfg(feature = "tcp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tcp")))]
+pub mod tcp;
+
+pub mod dual;
+pub mod dynamic;
+pub mod provisioning;
+pub mod retry;
+pub mod rom;
+
+#[cfg(any(test, feature = "esp"))]
+mod dump;
+#[cfg(any(test, feature = "esp"))]
+mod wrapping_map;
+
 /// Some kind of error that can occur during a storage operation
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum StorageError {
     /// Failed to write to flash. Maybe the pages are not erased.
     #[error("Failed to write to flash. Maybe the pages are not erased.")]
@@ -30,10 +63,135 @@ pub enum StorageError {
     /// Other error occurred during a storage operation
     #[error("{0}")]
     Other(String),
+    /// There is no space left to store metadata
+    #[error("There is no space left to store metadata")]
+    NvsFull,
+    /// esp-idf ran out of memory (`ESP_ERR_NO_MEM`)
+    #[error("Out of memory")]
+    OutOfMemory,
+    /// esp-idf rejected the size of a read or write (`ESP_ERR_INVALID_SIZE`)
+    #[error("Invalid size")]
+    InvalidSize,
+    /// The flash operation timed out (`ESP_ERR_TIMEOUT` or `ESP_ERR_FLASH_OP_TIMEOUT`). Trying again
+    /// usually works.
+    #[error("The flash operation timed out")]
+    Timeout,
+    /// The metadata key does not exist (`ESP_ERR_NVS_NOT_FOUND`)
+    #[error("The metadata key does not exist")]
+    MetadataNotFound,
+    /// An esp-idf function failed with a code that has no own variant, see [classify_esp_err]
+    #[error("{name} ({code:#x})")]
+    Esp {
+        /// The `esp_err_t` that was returned
+        code: i32,
+        /// Name of the error as returned by `esp_err_to_name`
+        name: String,
+    },
+    /// Only returned by write_block
+    #[error("Data needs to be exactly one block long")]
+    NotABlock,
+    /// The write would need to set bits of a byte that was not erased
+    ///
+    /// Only returned by storages that check writes, like the simulated storage in strict mode.
+    #[error("Writing at {offset:#x} would need an erase first")]
+    WriteOverDirty {
+        /// Address of the first byte that was not erased
+        offset: u32,
+    },
+    /// The storage can not be written, see [rom::RomStorage]
+    #[error("The storage is read-only")]
+    ReadOnly,
+}
+
+impl StorageError {
+    /// The error [Storage::write_metadata] should return if there is no space left for the value
+    pub fn metadata_full() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::StorageFull, StorageError::NvsFull)
+    }
+
+    /// Check if an error returned by [Storage::write_metadata] means that there is no space left
+    pub fn is_metadata_full(error: &std::io::Error) -> bool {
+        error.kind() == std::io::ErrorKind::StorageFull
+    }
+
+    /// Check if the operation may succeed when it is tried again
+    ///
+    /// Only timeouts and busy flash errors of esp-idf are transient. Errors of other backends never are.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            StorageError::Timeout => true,
+            StorageError::Esp { code, .. } => retry::TRANSIENT_ESP_ERRORS.contains(code),
+            _ => false,
+        }
+    }
+}
+
+/// Convert an `esp_err_t` returned by the flash or NVS functions of esp-idf into a [StorageError]
+///
+/// The codes these functions commonly return get their own variant, so they can be matched. All other
+/// codes become [StorageError::Esp] with the name from `esp_err_to_name`. The values are spelled out, so
+/// errors can be classified without the `esp` feature. `ESP_ERR_NVS_NOT_ENOUGH_SPACE` becomes
+/// [StorageError::NvsFull].
+pub fn classify_esp_err(code: i32) -> StorageError {
+    match code {
+        0x101 => StorageError::OutOfMemory,
+        0x104 => StorageError::InvalidSize,
+        0x107 | 0x6002 => StorageError::Timeout,
+        0x1102 => StorageError::MetadataNotFound,
+        0x1105 => StorageError::NvsFull,
+        code => StorageError::Esp {
+            code,
+            name: esp_err_name(code),
+        },
+    }
+}
+
+/// Name of the NVS namespace used for the metadata of the flash partition with the given label
+///
+/// The partition named `storage` uses `filesystem1` for compatibility. NVS namespaces are limited to
+/// 15 characters, so all other partitions use `fs_` followed by a part of the hash of their label.
+/// Cutting the label short instead would give labels with a common prefix, like `programs_a` and
+/// `programs_b`, the same namespace.
+pub fn nvs_namespace(label: &str) -> String {
+    if label == "storage" {
+        return String::from("filesystem1");
+    }
+    let hash = blake3::hash(label.as_bytes());
+    hash.as_bytes()[..6]
+        .iter()
+        .fold(String::from("fs_"), |mut namespace, byte| {
+            namespace.push_str(&format!("{:02x}", byte));
+            namespace
+        })
+}
+
+/// End of the `length` bytes starting at `address`, if they fit into a storage of `size` bytes
+///
+/// Fails with [StorageError::SizeTooBig] if the end overflows a `u32` or lies past the end of the
+/// storage. Backends that do not wrap around use this to check writes and erases.
+pub(crate) fn range_end(address: u32, length: u32, size: u32) -> Result<u32, StorageError> {
+    address
+        .checked_add(length)
+        .filter(|end| *end <= size)
+        .ok_or(StorageError::SizeTooBig)
+}
+
+/// Name of an `esp_err_t`, like `ESP_ERR_INVALID_ARG`
+#[cfg(feature = "esp")]
+fn esp_err_name(code: i32) -> String {
+    let name = unsafe { std::ffi::CStr::from_ptr(esp_idf_sys::esp_err_to_name(code)) };
+    name.to_string_lossy().into()
+}
+
+/// Name of an `esp_err_t`. Without esp-idf, only the number is known.
+#[cfg(not(feature = "esp"))]
+fn esp_err_name(code: i32) -> String {
+    format!("esp_err_t {code:#x}")
 }
 
 #[derive(Error, Debug)]
 /// Errors that can occur during the erase operation of the storage.
+#[non_exhaustive]
 pub enum EraseStorageError {
     /// Failed during storage operation
     #[error(transparent)]
@@ -69,7 +227,21 @@ pub trait Storage {
     /// Address must be inside the storage size. length must be lower or equal to the storage size. If address + length go over the bounds of the storage the storage needs to wrap around there. You should use an MMU for this
     ///
     /// This function is expected to return a slice that points into memory mapped storage. This means that the data is not copied and the data is directly read from the storage. This way no copy operations are needed to read data from the storage.
+    ///
+    /// Files are handed out as slices returned by this function, so every storage needs to provide it.
+    /// Backends without a memory mapping have to keep a copy of the storage in RAM.
     fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError>;
+    /// Copy data from a specific location into a buffer
+    ///
+    /// Same bounds and wraparound rules as [Storage::read]. The filesystem uses this wherever it only
+    /// needs a copy of the data, for example while scanning the storage during mount. Backends that can
+    /// read without going through their mapping should override this. The default implementation copies
+    /// from [Storage::read].
+    fn read_into(&self, address: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        let length = u32::try_from(buf.len()).map_err(|_| StorageError::SizeTooBig)?;
+        buf.copy_from_slice(self.read(address, length)?);
+        Ok(())
+    }
     /// Write at a specific location
     ///
     /// address must be inside the storage size. length must be lower or equal to the storage size.
@@ -80,11 +252,101 @@ pub trait Storage {
     ///
     /// address must be inside the storage size. length must be lower or equal to the storage size. address must be block aligned. length must be a multiple of block size
     fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError>;
+    /// Reset a range of blocks that may wrap around the end of the storage
+    ///
+    /// Same rules as [Storage::erase], except that address + length may go over the end of the storage.
+    /// The range is erased with one call to [Storage::erase], or two if it wraps around.
+    fn erase_wrapping(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        let storage_size = Self::BLOCKS * Self::BLOCK_SIZE;
+        let length_before_end = std::cmp::min(length, storage_size - address);
+        self.erase(address, length_before_end)?;
+        if length_before_end < length {
+            self.erase(0, length - length_before_end)?;
+        }
+        Ok(())
+    }
+
+    /// Reset a range of blocks one block at a time and report the progress
+    ///
+    /// Same rules as [Storage::erase_wrapping]. Erasing many blocks at once can take long enough to
+    /// trigger a watchdog. `on_progress` is called with the number of blocks erased so far after every
+    /// block, so the caller can feed the watchdog or update a progress bar. The default implementation
+    /// calls [Storage::erase] once per block.
+    fn erase_with_progress(
+        &self,
+        address: u32,
+        length: u32,
+        mut on_progress: impl FnMut(u32),
+    ) -> Result<(), EraseStorageError> {
+        let storage_size = Self::BLOCKS * Self::BLOCK_SIZE;
+        for block in 0..length.div_ceil(Self::BLOCK_SIZE) {
+            let block_address = (address + block * Self::BLOCK_SIZE) % storage_size;
+            self.erase(block_address, Self::BLOCK_SIZE)?;
+            on_progress(block + 1);
+        }
+        Ok(())
+    }
+
+    /// Read a whole block
+    ///
+    /// `index` is the number of the block, not its address. It must be lower than [Storage::BLOCKS].
+    fn read_block(&self, index: u32) -> Result<&'static [u8], StorageError> {
+        if index >= Self::BLOCKS {
+            return Err(StorageError::AddressTooBig);
+        }
+        self.read(index * Self::BLOCK_SIZE, Self::BLOCK_SIZE)
+    }
+    /// Write a whole block
+    ///
+    /// `index` is the number of the block, not its address. It must be lower than [Storage::BLOCKS].
+    /// `data` must be exactly [Storage::BLOCK_SIZE] bytes long. Like [Storage::write], this can only
+    /// clear bits, so the block usually needs to be erased first.
+    fn write_block(&self, index: u32, data: &[u8]) -> Result<(), StorageError> {
+        if index >= Self::BLOCKS {
+            return Err(StorageError::AddressTooBig);
+        }
+        if data.len() != Self::BLOCK_SIZE as usize {
+            return Err(StorageError::NotABlock);
+        }
+        self.write(index * Self::BLOCK_SIZE, data)
+    }
+
+    /// Number of times a block has been erased, if the storage keeps track of it
+    ///
+    /// Used for wear leveling, see [FilesystemBuilder::wear_threshold](crate::builder::FilesystemBuilder::wear_threshold).
+    /// The default implementation returns None.
+    fn erase_count(&self, _block: u32) -> Option<u32> {
+        None
+    }
+
+    /// Wait until all previous writes and erases are stored persistently
+    ///
+    /// Used by [Filesystem::sync](crate::Filesystem::sync). Backends that buffer writes need to override
+    /// this. The default implementation does nothing, which is correct for backends that store every
+    /// write before returning from it.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
 
     /// Read a metadata key from persistent storage
     fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>>;
     /// Write a metadata key from persistent storage
+    ///
+    /// The value is written directly from the slice. Implementations should avoid copying it if the backend allows.
+    ///
+    /// If there is no space left for the value, return [StorageError::metadata_full], so the filesystem can
+    /// tell that error apart from others.
     fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()>;
+    /// Read a metadata key into a caller provided buffer
+    ///
+    /// The buffer is cleared and grown as needed. Reusing the same buffer for multiple reads avoids allocating
+    /// every time, which is useful for large values. The default implementation copies the result of [Storage::read_metadata].
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        let value = self.read_metadata(key)?;
+        buf.clear();
+        buf.extend_from_slice(&value);
+        Ok(())
+    }
 
     /// Write metadata and return a memorymapped slice to the metadata
     fn write_readback(&self, address: u32, data: &[u8]) -> Result<&'static [u8], StorageError> {
@@ -100,4 +362,249 @@ pub trait Storage {
         }
         Ok(read_data)
     }
+    /// Overwrite a region with arbitrary data, erasing first if needed
+    ///
+    /// If `data` only clears bits of the current content, it is written directly. Otherwise the blocks
+    /// covering the region are read, erased and written again with `data` merged in, so the rest of
+    /// those blocks keeps its content.
+    ///
+    /// The second case erases every affected block once per call, which wears the flash like any other
+    /// erase. Collect changes and call this once instead of updating a block byte by byte. It is also not
+    /// power-safe: if power is lost between the erase and the write, the whole blocks are lost, not just
+    /// the region. The region may not wrap around the end of the storage.
+    fn rmw(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        let length = u32::try_from(data.len()).map_err(|_| StorageError::SizeTooBig)?;
+        let storage_size = Self::BLOCKS * Self::BLOCK_SIZE;
+        if address >= storage_size {
+            return Err(StorageError::AddressTooBig);
+        }
+        if length > storage_size - address {
+            return Err(StorageError::SizeTooBig);
+        }
+        if length == 0 {
+            return Ok(());
+        }
+        let current = self.read(address, length)?;
+        if current == data {
+            return Ok(());
+        }
+        if current.iter().zip(data).all(|(old, new)| old & new == *new) {
+            return self.write(address, data);
+        }
+
+        let first_block = address / Self::BLOCK_SIZE;
+        let last_block = (address + length - 1) / Self::BLOCK_SIZE;
+        let start = first_block * Self::BLOCK_SIZE;
+        let span = (last_block - first_block + 1) * Self::BLOCK_SIZE;
+        let mut merged = self.read(start, span)?.to_vec();
+        let offset = (address - start) as usize;
+        merged[offset..offset + data.len()].copy_from_slice(data);
+        self.erase(start, span).map_err(|error| match error {
+            EraseStorageError::StorageError(error) => error,
+            error => StorageError::Other(error.to_string()),
+        })?;
+        self.write(start, &merged)
+    }
+}
+
+/// A storage that a [Filesystem](crate::Filesystem) can be mounted on
+///
+/// Files hand out slices that point directly into the storage, and they can outlive the filesystem. So
+/// the storage needs to live for the rest of the program. A `&'static` reference is used as it is. An
+/// owned storage is moved to the heap and leaked, so a filesystem can be created without a `static` or
+/// a [Box::leak] at the call site:
+///
+/// ```
+/// use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+/// use rudelblinken_filesystem::Filesystem;
+///
+/// let mut filesystem = Filesystem::new(SimulatedStorage::new());
+/// filesystem.write_file("main", &[1, 2, 3], &[0; 32]).unwrap();
+/// ```
+///
+/// The memory of a leaked storage is never freed, even after the filesystem is dropped. Create a
+/// reference once and mount it again instead of creating a new storage every time.
+pub trait IntoStaticStorage<T: Storage> {
+    /// Get a reference to the storage that lives for the rest of the program
+    fn into_static(self) -> &'static T;
+}
+
+impl<T: Storage> IntoStaticStorage<T> for &'static T {
+    fn into_static(self) -> &'static T {
+        self
+    }
+}
+
+impl<T: Storage> IntoStaticStorage<T> for T {
+    fn into_static(self) -> &'static T {
+        Box::leak(Box::new(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::simulated::SimulatedStorage;
+
+    #[test]
+    fn partitions_with_a_common_prefix_get_different_namespaces() {
+        assert_eq!(nvs_namespace("storage"), "filesystem1");
+        let a = nvs_namespace("programs_slot_a");
+        let b = nvs_namespace("programs_slot_b");
+        assert_ne!(a, b);
+        assert!(a.len() <= 15 && b.len() <= 15);
+        assert_eq!(a, nvs_namespace("programs_slot_a"));
+    }
+
+    #[test]
+    fn range_end_accepts_ranges_inside_the_storage() {
+        assert_eq!(range_end(0, 4096, 4096).unwrap(), 4096);
+        assert_eq!(range_end(4096, 0, 4096).unwrap(), 4096);
+    }
+
+    #[test]
+    fn range_end_rejects_ranges_past_the_storage() {
+        assert!(matches!(
+            range_end(4095, 2, 4096),
+            Err(StorageError::SizeTooBig)
+        ));
+        assert!(matches!(
+            range_end(1, u32::MAX, 4096),
+            Err(StorageError::SizeTooBig)
+        ));
+    }
+
+    #[test]
+    fn common_esp_errors_get_their_own_variant() {
+        assert!(matches!(classify_esp_err(0x101), StorageError::OutOfMemory));
+        assert!(matches!(classify_esp_err(0x104), StorageError::InvalidSize));
+        assert!(matches!(
+            classify_esp_err(0x1102),
+            StorageError::MetadataNotFound
+        ));
+        assert!(matches!(classify_esp_err(0x1105), StorageError::NvsFull));
+        for code in [0x107, 0x6002] {
+            let error = classify_esp_err(code);
+            assert!(matches!(error, StorageError::Timeout));
+            assert!(error.is_transient());
+        }
+
+        // ESP_ERR_NOT_FINISHED
+        let error = classify_esp_err(0x10c);
+        assert!(matches!(error, StorageError::Esp { code: 0x10c, .. }));
+        assert!(error.is_transient());
+        // ESP_ERR_FLASH_OP_FAIL
+        assert!(!classify_esp_err(0x6001).is_transient());
+    }
+
+    #[test]
+    fn erasing_with_progress_reports_every_block() {
+        let storage = SimulatedStorage::new();
+        for block in 0..16 {
+            storage.write(block * 4096, &[0; 4096]).unwrap();
+        }
+        storage.reset_stats();
+        let mut progress = Vec::new();
+        // Wraps around the end of the storage
+        storage
+            .erase_with_progress(14 * 4096, 4 * 4096, |blocks| progress.push(blocks))
+            .unwrap();
+        assert_eq!(progress, [1, 2, 3, 4]);
+        assert_eq!(storage.stats().erase_calls, 4);
+        assert_eq!(storage.stats().blocks_erased, 4);
+        let erased = |block: u32| {
+            storage
+                .read_block(block)
+                .unwrap()
+                .iter()
+                .all(|b| *b == 0xff)
+        };
+        assert!([14, 15, 0, 1].into_iter().all(erased));
+        assert!(!erased(2) && !erased(13));
+    }
+
+    #[test]
+    fn rmw_writes_directly_if_only_bits_are_cleared() {
+        let storage = SimulatedStorage::new();
+        storage.write(4096, &[0xf0; 100]).unwrap();
+        storage.reset_stats();
+        storage.rmw(4096 + 10, &[0x30, 0x00]).unwrap();
+        assert_eq!(storage.stats().erase_calls, 0);
+        assert_eq!(
+            storage.read(4096 + 8, 5).unwrap(),
+            [0xf0, 0xf0, 0x30, 0x00, 0xf0]
+        );
+    }
+
+    #[test]
+    fn rmw_keeps_the_rest_of_the_erased_blocks() {
+        let storage = SimulatedStorage::new();
+        storage.write(0, &[0x11; 3 * 4096]).unwrap();
+        storage.reset_stats();
+        // Crosses the boundary between the first and second block and sets bits
+        storage.rmw(4096 - 2, &[0xff, 0x22, 0x33, 0xff]).unwrap();
+        assert_eq!(storage.stats().erase_calls, 1);
+        assert_eq!(
+            storage.read(4096 - 3, 6).unwrap(),
+            [0x11, 0xff, 0x22, 0x33, 0xff, 0x11]
+        );
+        assert!(storage
+            .read(0, 4096 - 2)
+            .unwrap()
+            .iter()
+            .all(|byte| *byte == 0x11));
+        assert!(storage
+            .read(4096 + 2, 2 * 4096 - 2)
+            .unwrap()
+            .iter()
+            .all(|byte| *byte == 0x11));
+    }
+
+    #[test]
+    fn blocks_can_be_accessed_by_index() {
+        let storage = SimulatedStorage::new();
+        let last = SimulatedStorage::BLOCKS - 1;
+        let block = [0x42; SimulatedStorage::BLOCK_SIZE as usize];
+        storage.write_block(0, &block).unwrap();
+        storage.write_block(last, &block).unwrap();
+        assert_eq!(storage.read_block(0).unwrap(), block);
+        assert_eq!(storage.read_block(last).unwrap(), block);
+        assert_eq!(
+            storage
+                .read(last * SimulatedStorage::BLOCK_SIZE - 1, 2)
+                .unwrap(),
+            [0xff, 0x42]
+        );
+
+        assert!(matches!(
+            storage.read_block(SimulatedStorage::BLOCKS),
+            Err(StorageError::AddressTooBig)
+        ));
+        assert!(matches!(
+            storage.write_block(SimulatedStorage::BLOCKS, &block),
+            Err(StorageError::AddressTooBig)
+        ));
+        assert!(matches!(
+            storage.write_block(1, &block[1..]),
+            Err(StorageError::NotABlock)
+        ));
+        assert!(matches!(
+            storage.write_block(1, &[0x42; SimulatedStorage::BLOCK_SIZE as usize + 1]),
+            Err(StorageError::NotABlock)
+        ));
+        assert_eq!(storage.read_block(1).unwrap(), [0xff; 4096]);
+    }
+
+    #[test]
+    fn rmw_rejects_regions_outside_of_the_storage() {
+        let storage = SimulatedStorage::new();
+        assert!(matches!(
+            storage.rmw(SimulatedStorage::SIZE - 1, &[0, 0]),
+            Err(StorageError::SizeTooBig)
+        ));
+        assert!(matches!(
+            storage.rmw(SimulatedStorage::SIZE, &[0]),
+            Err(StorageError::AddressTooBig)
+        ));
+    }
 }
@@ -0,0 +1,139 @@
+//! A heap allocated buffer that behaves like memory mapped NOR flash
+//!
+//! The buffer is twice as large as the storage it represents. The second half mirrors the first
+//! half, so reads that go over the end of the storage wrap around without copying, just like the
+//! double mapping used by the esp backend.
+
+use std::{alloc::Layout, ptr::NonNull};
+
+/// Alignment of the arena. Chosen to match the flash block size so that every block is aligned.
+const ARENA_ALIGNMENT: usize = 4096;
+
+/// RAM backed storage area with NOR flash semantics and wraparound
+pub(crate) struct RamArena {
+    buffer: NonNull<u8>,
+    /// Size of the represented storage. The buffer is twice as large.
+    size: u32,
+    /// Frees the buffer if it was not allocated with the global allocator
+    free: Option<unsafe fn(NonNull<u8>)>,
+}
+
+unsafe impl Send for RamArena {}
+unsafe impl Sync for RamArena {}
+
+impl std::fmt::Debug for RamArena {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RamArena")
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl RamArena {
+    /// Allocate a new erased arena for a storage of `size` bytes
+    pub fn new(size: u32) -> Self {
+        let layout = Self::layout(size);
+        let buffer = unsafe { std::alloc::alloc(layout) };
+        let Some(buffer) = NonNull::new(buffer) else {
+            std::alloc::handle_alloc_error(layout);
+        };
+        unsafe { std::ptr::write_bytes(buffer.as_ptr(), 0xff, layout.size()) };
+        Self {
+            buffer,
+            size,
+            free: None,
+        }
+    }
+
+    /// Use an externally allocated buffer for a storage of `size` bytes and erase it
+    ///
+    /// # Safety
+    ///
+    /// `buffer` needs to be valid for `2 * size` bytes and aligned to 64 bytes. It is freed with `free`
+    /// when the arena is dropped.
+    #[cfg_attr(not(feature = "psram"), allow(dead_code))]
+    pub unsafe fn from_buffer(
+        buffer: NonNull<u8>,
+        size: u32,
+        free: unsafe fn(NonNull<u8>),
+    ) -> Self {
+        std::ptr::write_bytes(buffer.as_ptr(), 0xff, size as usize * 2);
+        Self {
+            buffer,
+            size,
+            free: Some(free),
+        }
+    }
+
+    fn layout(size: u32) -> Layout {
+        Layout::from_size_align(size as usize * 2, ARENA_ALIGNMENT)
+            .expect("Arena size should not overflow isize")
+    }
+
+    /// Get a slice into the arena. The slice may reach into the mirrored half.
+    ///
+    /// The caller needs to make sure that `address < size` and `length <= size`.
+    pub fn read(&self, address: u32, length: u32) -> &'static [u8] {
+        debug_assert!(address < self.size && length <= self.size);
+        unsafe {
+            std::slice::from_raw_parts(self.buffer.as_ptr().add(address as usize), length as usize)
+        }
+    }
+
+    /// Apply `f` to every byte in the range in both halves of the arena
+    fn update(&self, address: u32, length: u32, mut f: impl FnMut(&mut u8, usize)) {
+        debug_assert!(address < self.size && length <= self.size);
+        let buffer =
+            unsafe { std::slice::from_raw_parts_mut(self.buffer.as_ptr(), self.size as usize * 2) };
+        for index in 0..length as usize {
+            let position = (address as usize + index) % self.size as usize;
+            f(&mut buffer[position], index);
+            f(&mut buffer[position + self.size as usize], index);
+        }
+    }
+
+    /// Write with NOR flash semantics. Only bits that are 0 in `data` are cleared.
+    pub fn write(&self, address: u32, data: &[u8]) {
+        self.update(address, data.len() as u32, |byte, index| {
+            *byte &= data[index]
+        });
+    }
+
+    /// Find the first byte where writing `data` would need to set a bit, relative to `address`
+    ///
+    /// NOR flash can only clear bits, so writing such a byte would store something else than `data`.
+    #[cfg_attr(not(any(test, feature = "simulated")), allow(dead_code))]
+    pub fn find_dirty(&self, address: u32, data: &[u8]) -> Option<u32> {
+        debug_assert!(address < self.size && data.len() as u32 <= self.size);
+        let current = self.read(address, data.len() as u32);
+        current
+            .iter()
+            .zip(data)
+            .position(|(old, new)| old & new != *new)
+            .map(|offset| offset as u32)
+    }
+
+    /// Replace the content of a range, ignoring NOR flash semantics
+    ///
+    /// Useful when the arena is used as a cache for some other medium.
+    #[cfg_attr(not(feature = "sdmmc"), allow(dead_code))]
+    pub fn load(&self, address: u32, data: &[u8]) {
+        self.update(address, data.len() as u32, |byte, index| {
+            *byte = data[index]
+        });
+    }
+
+    /// Reset a range to 0xff
+    pub fn erase(&self, address: u32, length: u32) {
+        self.update(address, length, |byte, _| *byte = 0xff);
+    }
+}
+
+impl Drop for RamArena {
+    fn drop(&mut self) {
+        match self.free {
+            Some(free) => unsafe { free(self.buffer) },
+            None => unsafe { std::alloc::dealloc(self.buffer.as_ptr(), Self::layout(self.size)) },
+        }
+    }
+}
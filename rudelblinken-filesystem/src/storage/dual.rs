@@ -0,0 +1,145 @@
+//! A/B storage for atomic updates of the whole filesystem content
+//!
+//! [DualStorage] wraps two storages, for example two flash partitions. One of them is active and
+//! gets mounted, the other one is inactive and can be filled with a new set of files. When the new set
+//! is complete, [DualStorage::commit_switch] flips the active storage by writing a single metadata key.
+//!
+//! As writing a metadata key is atomic, a power loss during the update always leaves the device with
+//! either the old or the new set of files.
+//!
+//! The flag is stored in the metadata of the first storage. Both storages need their own metadata
+//! namespace, as the filesystem keeps its state there. That state includes the attributes of the
+//! files, so the inactive storage is cleared through a mounted [Filesystem] with
+//! [DualStorage::clear_inactive] instead of erasing its blocks.
+
+use super::Storage;
+use crate::{Filesystem, FilesystemClearError};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Metadata key of the active storage flag
+const ACTIVE_KEY: &str = "active_storage";
+
+/// Two storages of which one is active
+///
+/// ```
+/// use rudelblinken_filesystem::storage::{dual::DualStorage, simulated::SimulatedStorage};
+/// use rudelblinken_filesystem::Filesystem;
+///
+/// let storage: &'static DualStorage<SimulatedStorage> = Box::leak(Box::new(DualStorage::new(
+///     SimulatedStorage::new(),
+///     SimulatedStorage::new(),
+/// )));
+/// // Write the new files to the inactive storage
+/// let mut update = storage.clear_inactive().unwrap();
+/// update.write_file("main", &[0; 100], &[0; 32]).unwrap();
+/// drop(update);
+/// // Switch over. The next mount will use the new files
+/// storage.commit_switch().unwrap();
+/// let filesystem = Filesystem::new(storage.active());
+/// assert!(filesystem.read_file("main").is_some());
+/// ```
+pub struct DualStorage<T: Storage> {
+    storages: [T; 2],
+    /// Index of the active storage
+    active: AtomicU8,
+}
+
+impl<T: Storage> DualStorage<T> {
+    /// Combine two storages. The active one is selected by the flag stored in the first storage.
+    ///
+    /// If the flag was never written, the first storage is active.
+    pub fn new(first: T, second: T) -> Self {
+        let active = match first.read_metadata(ACTIVE_KEY).as_deref() {
+            Ok([1]) => 1,
+            _ => 0,
+        };
+        Self {
+            storages: [first, second],
+            active: AtomicU8::new(active),
+        }
+    }
+
+    /// Index of the active storage (0 or 1)
+    pub fn active_index(&self) -> u8 {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// The storage that should be mounted
+    pub fn active(&self) -> &T {
+        &self.storages[self.active_index() as usize]
+    }
+
+    /// The storage that can be used to prepare an update
+    pub fn inactive(&self) -> &T {
+        &self.storages[1 - self.active_index() as usize]
+    }
+
+    /// Mount the inactive storage and delete all of its files, so a new set of files can be written
+    ///
+    /// Erasing the blocks alone would keep the metadata of the old files, like their attributes, which
+    /// new files with the same names would pick up. [Filesystem::clear] removes them as well. Make sure
+    /// that there is no other filesystem using the inactive storage while doing this.
+    pub fn clear_inactive(&'static self) -> Result<Filesystem<T>, FilesystemClearError>
+    where
+        T: Send + Sync,
+    {
+        let mut filesystem = Filesystem::new(self.inactive());
+        filesystem.clear()?;
+        Ok(filesystem)
+    }
+
+    /// Make the inactive storage the active one.
+    ///
+    /// The switch is persisted before this function returns. Filesystems that are already mounted keep
+    /// using their storage, so remount to pick up the new files.
+    pub fn commit_switch(&self) -> std::io::Result<()> {
+        let new_active = 1 - self.active_index();
+        self.storages[0].write_metadata(ACTIVE_KEY, &[new_active])?;
+        self.active.store(new_active, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::simulated::SimulatedStorage;
+
+    #[test]
+    fn switching_flips_active_and_inactive() {
+        let storage = DualStorage::new(SimulatedStorage::new(), SimulatedStorage::new());
+        assert_eq!(storage.active_index(), 0);
+        storage.inactive().write(0, &[0]).unwrap();
+        storage.commit_switch().unwrap();
+        assert_eq!(storage.active_index(), 1);
+        assert_eq!(storage.active().read(0, 1).unwrap(), &[0]);
+        assert_eq!(storage.inactive().read(0, 1).unwrap(), &[0xff]);
+        storage.commit_switch().unwrap();
+        assert_eq!(storage.active_index(), 0);
+    }
+
+    #[test]
+    fn clearing_the_inactive_storage_removes_the_attributes_of_old_files() {
+        let storage: &'static DualStorage<SimulatedStorage> = Box::leak(Box::new(
+            DualStorage::new(SimulatedStorage::new(), SimulatedStorage::new()),
+        ));
+        let mut old = Filesystem::new(storage.inactive());
+        old.write_file("main", &[1], &[0; 32]).unwrap();
+        old.set_attr("main", "version", b"old").unwrap();
+        drop(old);
+
+        let mut update = storage.clear_inactive().unwrap();
+        assert!(update.read_file("main").is_none());
+        update.write_file("main", &[2], &[0; 32]).unwrap();
+        assert_eq!(update.get_attr("main", "version").unwrap(), None);
+    }
+
+    #[test]
+    fn active_storage_is_persisted() {
+        let storage = DualStorage::new(SimulatedStorage::new(), SimulatedStorage::new());
+        storage.commit_switch().unwrap();
+        let [first, second] = storage.storages;
+        let storage = DualStorage::new(first, second);
+        assert_eq!(storage.active_index(), 1);
+    }
+}
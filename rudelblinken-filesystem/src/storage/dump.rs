@@ -0,0 +1,84 @@
+//! Copy the raw content of a storage to and from a stream
+//!
+//! The dump contains the blocks of the storage in order, `BLOCKS * BLOCK_SIZE` bytes without any
+//! header. The metadata of the storage is not included, so a restored storage starts scanning at the
+//! first block like after a fresh flash.
+
+use super::Storage;
+use std::io::{Read, Write};
+
+/// Write the whole content of the storage to `writer`, one block at a time
+pub(crate) fn dump_to<T: Storage>(storage: &T, writer: &mut impl Write) -> std::io::Result<()> {
+    for block in 0..T::BLOCKS {
+        let data = storage.read_block(block).map_err(std::io::Error::other)?;
+        writer.write_all(data)?;
+    }
+    writer.flush()
+}
+
+/// Replace the whole content of the storage with a dump read from `reader`
+///
+/// Every block is erased right before it is written. If the reader fails or ends early, the blocks
+/// before that point already contain the new content.
+pub(crate) fn restore_from<T: Storage>(storage: &T, reader: &mut impl Read) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; T::BLOCK_SIZE as usize];
+    for block in 0..T::BLOCKS {
+        reader.read_exact(&mut buffer)?;
+        let address = block * T::BLOCK_SIZE;
+        storage
+            .erase(address, T::BLOCK_SIZE)
+            .map_err(std::io::Error::other)?;
+        // Erased flash already reads as 0xff, skipping those writes saves time
+        if buffer.iter().all(|byte| *byte == 0xff) {
+            continue;
+        }
+        storage
+            .write_block(block, &buffer)
+            .map_err(std::io::Error::other)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::simulated::{get_test_storage, SimulatedStorage},
+        Filesystem,
+    };
+
+    #[test]
+    fn a_restored_dump_contains_the_same_files() {
+        let source = get_test_storage();
+        let mut filesystem = Filesystem::new(source);
+        filesystem.write_file("main", &[1; 5000], &[0; 32]).unwrap();
+        filesystem
+            .write_file("config", &[2; 100], &[0; 32])
+            .unwrap();
+        drop(filesystem);
+
+        let mut dump = Vec::new();
+        dump_to(source, &mut dump).unwrap();
+        assert_eq!(dump.len(), SimulatedStorage::SIZE as usize);
+
+        let target = get_test_storage();
+        Filesystem::new(target)
+            .write_file("old", &[3; 100], &[0; 32])
+            .unwrap();
+        restore_from(target, &mut dump.as_slice()).unwrap();
+
+        let filesystem = Filesystem::new(target);
+        assert!(filesystem.read_file("old").is_none());
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 5000]);
+        assert!(filesystem.read_file("config").is_some());
+    }
+
+    #[test]
+    fn restoring_a_truncated_dump_fails() {
+        let storage = get_test_storage();
+        let dump = vec![0xffu8; SimulatedStorage::BLOCK_SIZE as usize];
+        let error = restore_from(storage, &mut dump.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,208 @@
+//! Select the storage backend at runtime
+//!
+//! [Storage] describes its geometry with associated constants, so it can not be used as a trait object.
+//! [DynStorage] offers the same operations with methods for the geometry instead and is implemented for
+//! every [Storage].
+//!
+//! The filesystem itself still needs the geometry at compile time. [BoxedStorage] bridges the two: it
+//! wraps any boxed [DynStorage] and checks once that its geometry matches the constants.
+//!
+//! ```
+//! use rudelblinken_filesystem::storage::dynamic::{BoxedStorage, DynStorage};
+//! use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+//! use rudelblinken_filesystem::Filesystem;
+//!
+//! // Decided at boot, for example by checking if an SD card is present
+//! let backend: Box<dyn DynStorage + Send + Sync> = Box::new(SimulatedStorage::new());
+//! let storage: &'static BoxedStorage<16, 4096> =
+//!     Box::leak(Box::new(BoxedStorage::new(backend).unwrap()));
+//! let mut filesystem = Filesystem::new(storage);
+//! filesystem.write_file("main", &[0; 100], &[0; 32]).unwrap();
+//! ```
+
+use super::{EraseStorageError, Storage, StorageError};
+use thiserror::Error;
+
+/// Object safe version of [Storage]
+///
+/// See [Storage] for the meaning of the methods. There is a blanket implementation for every [Storage].
+pub trait DynStorage {
+    /// Total number of blocks
+    fn block_count(&self) -> u32;
+    /// Size in which blocks can be erased
+    fn block_size(&self) -> u32;
+    /// See [Storage::read]
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError>;
+    /// See [Storage::read_into]
+    fn read_into(&self, address: u32, buf: &mut [u8]) -> Result<(), StorageError>;
+    /// See [Storage::write]
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError>;
+    /// See [Storage::erase]
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError>;
+    /// See [Storage::erase_count]
+    fn erase_count(&self, block: u32) -> Option<u32>;
+    /// See [Storage::flush]
+    fn flush(&self) -> Result<(), StorageError>;
+    /// See [Storage::read_metadata]
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>>;
+    /// See [Storage::write_metadata]
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()>;
+    /// See [Storage::read_metadata_into]
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()>;
+}
+
+impl<S: Storage> DynStorage for S {
+    fn block_count(&self) -> u32 {
+        S::BLOCKS
+    }
+
+    fn block_size(&self) -> u32 {
+        S::BLOCK_SIZE
+    }
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        Storage::read(self, address, length)
+    }
+
+    fn read_into(&self, address: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        Storage::read_into(self, address, buf)
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        Storage::write(self, address, data)
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        Storage::erase(self, address, length)
+    }
+
+    fn erase_count(&self, block: u32) -> Option<u32> {
+        Storage::erase_count(self, block)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Storage::flush(self)
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        Storage::read_metadata(self, key)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        Storage::write_metadata(self, key, value)
+    }
+
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        Storage::read_metadata_into(self, key, buf)
+    }
+}
+
+/// An error while wrapping a [DynStorage]
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStorageError {
+    /// The storage does not have the expected number or size of blocks
+    #[error("The storage has {blocks} blocks of {block_size} bytes, which does not match the expected geometry")]
+    GeometryMismatch {
+        /// Number of blocks of the storage
+        blocks: u32,
+        /// Block size of the storage
+        block_size: u32,
+    },
+}
+
+/// A [Storage] that forwards to a boxed [DynStorage] of the same geometry
+pub struct BoxedStorage<const BLOCKS: u32, const BLOCK_SIZE: u32> {
+    inner: Box<dyn DynStorage + Send + Sync>,
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> BoxedStorage<BLOCKS, BLOCK_SIZE> {
+    /// Wrap a storage. Fails if it does not have `BLOCKS` blocks of `BLOCK_SIZE` bytes.
+    pub fn new(inner: Box<dyn DynStorage + Send + Sync>) -> Result<Self, BoxStorageError> {
+        if inner.block_count() != BLOCKS || inner.block_size() != BLOCK_SIZE {
+            return Err(BoxStorageError::GeometryMismatch {
+                blocks: inner.block_count(),
+                block_size: inner.block_size(),
+            });
+        }
+        Ok(Self { inner })
+    }
+
+    /// The wrapped storage
+    pub fn inner(&self) -> &(dyn DynStorage + Send + Sync) {
+        self.inner.as_ref()
+    }
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Storage for BoxedStorage<BLOCKS, BLOCK_SIZE> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        self.inner.read(address, length)
+    }
+
+    fn read_into(&self, address: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        self.inner.read_into(address, buf)
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.inner.write(address, data)
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        self.inner.erase(address, length)
+    }
+
+    fn erase_count(&self, block: u32) -> Option<u32> {
+        self.inner.erase_count(block)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        self.inner.read_metadata(key)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.inner.write_metadata(key, value)
+    }
+
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        self.inner.read_metadata_into(key, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::simulated::SimulatedStorage, Filesystem};
+
+    #[test]
+    fn a_boxed_storage_can_be_mounted() {
+        let backend: Box<dyn DynStorage + Send + Sync> = Box::new(SimulatedStorage::new());
+        assert_eq!(backend.block_count(), SimulatedStorage::BLOCKS);
+        let storage: &'static BoxedStorage<16, 4096> =
+            Box::leak(Box::new(BoxedStorage::new(backend).unwrap()));
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 100]);
+    }
+
+    #[test]
+    fn a_storage_with_a_different_geometry_is_rejected() {
+        let result = BoxedStorage::<32, 4096>::new(Box::new(SimulatedStorage::new()));
+        assert!(matches!(
+            result,
+            Err(BoxStorageError::GeometryMismatch {
+                blocks: 16,
+                block_size: 4096
+            })
+        ));
+    }
+}
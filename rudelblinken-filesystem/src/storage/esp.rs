@@ -1,15 +1,20 @@
 /// Storage implementation backed by esp32-c3 flash
 // TODO: Write better module level docs
 use crate::{
-    storage::{EraseStorageError, Storage, StorageError},
+    storage::{
+        classify_esp_err, dump, nvs_namespace, range_end,
+        wrapping_map::{map_wrapping, WrappingMapError},
+        EraseStorageError, Storage, StorageError,
+    },
     Filesystem,
 };
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, EspNvsPartition, NvsDefault};
 use esp_idf_sys::{
-    esp_err_to_name, esp_partition_erase_range, esp_partition_find, esp_partition_get,
-    esp_partition_mmap, esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA, esp_partition_next,
+    esp_err_t, esp_partition_erase_range, esp_partition_find, esp_partition_get,
+    esp_partition_mmap, esp_partition_mmap_handle_t,
+    esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA, esp_partition_next, esp_partition_read,
     esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
-    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_UNDEFINED,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_UNDEFINED, esp_partition_t,
     esp_partition_type_t_ESP_PARTITION_TYPE_ANY, esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
     esp_partition_write_raw, ESP_OK,
 };
@@ -20,175 +25,549 @@ use std::{
 use thiserror::Error;
 
 /// A storage implementation that stores data in the flash of the ESP32-C3
-pub struct FlashStorage {
+pub type FlashStorage = SizedFlashStorage<256, 4096>;
+
+/// A [FlashStorage] with `BLOCKS` blocks of `BLOCK_SIZE` bytes
+///
+/// `BLOCK_SIZE` needs to match the erase size of the partition. Use this for flash chips with larger
+/// erase blocks, for example `SizedFlashStorage<16, 65536>`.
+pub struct SizedFlashStorage<const BLOCKS: u32, const BLOCK_SIZE: u32> {
     partition: *const esp_idf_sys::esp_partition_t,
     nvs: Mutex<EspNvs<NvsDefault>>,
 
     storage_arena: *mut u8,
+    /// Number of bytes mapped at `storage_arena`. Twice the size of the storage, unless it fits into a
+    /// single MMU page and is only mapped once.
+    mapped_length: u32,
+    /// Handles of the mappings of the partition. They are never released.
+    _mmap_handles: Vec<esp_partition_mmap_handle_t>,
 }
 
-unsafe impl Sync for FlashStorage {}
-unsafe impl Send for FlashStorage {}
+unsafe impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Sync
+    for SizedFlashStorage<BLOCKS, BLOCK_SIZE>
+{
+}
+unsafe impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Send
+    for SizedFlashStorage<BLOCKS, BLOCK_SIZE>
+{
+}
 
-/// Log information about the available partitions
-pub fn print_partitions() {
+/// Convert an error code of the NVS functions into the error the metadata methods of [Storage] return
+fn metadata_error(code: esp_err_t) -> std::io::Error {
+    match classify_esp_err(code) {
+        StorageError::NvsFull => StorageError::metadata_full(),
+        error @ StorageError::MetadataNotFound => {
+            std::io::Error::new(std::io::ErrorKind::NotFound, error)
+        }
+        error => std::io::Error::other(error),
+    }
+}
+
+/// An entry of the partition table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Label of the partition
+    pub label: String,
+    /// Type of the partition, `1` for data partitions
+    pub partition_type: u32,
+    /// Subtype of the partition
+    pub subtype: u32,
+    /// Offset of the partition in flash
+    pub address: u32,
+    /// Size of the partition in bytes
+    pub size: u32,
+}
+
+/// Get all entries of the partition table
+pub fn list_partitions() -> Vec<PartitionInfo> {
+    let mut partitions = Vec::new();
     unsafe {
         let mut partition_iterator = esp_partition_find(
             esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
             esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
             std::ptr::null_mut(),
         );
-        if partition_iterator == std::ptr::null_mut() {
-            panic!("No partitions found!");
-        }
-        // println!("type, subtype, label, address, name");
-
-        while partition_iterator != std::ptr::null_mut() {
-            let partition = *esp_partition_get(partition_iterator);
-            let label = String::from_utf8(std::mem::transmute(partition.label.to_vec()));
-            // label.copy_from_slice(&partition.label.);
-            println!(
-                "{}, {}, {:?}, {:0x}, {}",
-                partition.type_, partition.subtype, label, partition.address, partition.size
-            );
+        // The iterator is released by esp_partition_next when it reaches the end
+        while !partition_iterator.is_null() {
+            let partition = &*esp_partition_get(partition_iterator);
+            let label = std::ffi::CStr::from_ptr(partition.label.as_ptr());
+            partitions.push(PartitionInfo {
+                label: label.to_string_lossy().into(),
+                partition_type: partition.type_ as u32,
+                subtype: partition.subtype as u32,
+                address: partition.address,
+                size: partition.size,
+            });
             partition_iterator = esp_partition_next(partition_iterator);
         }
     }
+    partitions
+}
+
+/// Find the data partition with the given label and subtype
+///
+/// If there is a partition with that label but a different type or subtype, the error says so.
+/// Otherwise it lists the labels of all data partitions.
+pub(crate) fn find_data_partition(
+    label: &str,
+    subtype: esp_idf_sys::esp_partition_subtype_t,
+) -> Result<*const esp_partition_t, CreateStorageError> {
+    // A NUL byte would silently cut the label short
+    let c_label = std::ffi::CString::new(label)
+        .map_err(|_| CreateStorageError::InvalidLabel(label.to_string()))?;
+    unsafe {
+        let partition_iterator = esp_partition_find(
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            subtype,
+            c_label.as_ptr(),
+        );
+        if partition_iterator.is_null() {
+            let partitions = list_partitions();
+            if let Some(partition) = partitions.iter().find(|partition| partition.label == label) {
+                return Err(CreateStorageError::PartitionWrongType {
+                    label: label.to_string(),
+                    found_type: partition.partition_type,
+                    found_subtype: partition.subtype,
+                    expected_subtype: (subtype
+                        != esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY)
+                        .then_some(subtype as u32),
+                });
+            }
+            let found = partitions
+                .into_iter()
+                .filter(|partition| {
+                    partition.partition_type == esp_partition_type_t_ESP_PARTITION_TYPE_DATA as u32
+                })
+                .map(|partition| partition.label)
+                .collect();
+            return Err(CreateStorageError::NoPartitionFound {
+                expected: label.to_string(),
+                found,
+            });
+        }
+        let partition = esp_partition_get(partition_iterator);
+        esp_idf_sys::esp_partition_iterator_release(partition_iterator);
+        Ok(partition)
+    }
+}
+
+/// Map `length` bytes of a partition starting at `offset` into the data address space
+///
+/// Returns the pointer to the mapped bytes and the handle of the mapping.
+///
+/// # Safety
+///
+/// `partition` needs to point to a valid partition that contains the range.
+pub(crate) unsafe fn map_partition(
+    partition: *const esp_partition_t,
+    offset: usize,
+    length: usize,
+) -> Result<(*const u8, esp_partition_mmap_handle_t), esp_err_t> {
+    let mut pointer: *const c_void = std::ptr::null_mut();
+    let mut handle: esp_partition_mmap_handle_t = 0;
+    let err = esp_partition_mmap(
+        partition,
+        offset,
+        length,
+        esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA,
+        std::ptr::addr_of_mut!(pointer),
+        std::ptr::addr_of_mut!(handle),
+    );
+    if err != ESP_OK {
+        return Err(err);
+    }
+    Ok((pointer as *const u8, handle))
+}
+
+/// Log information about the available partitions
+pub fn print_partitions() {
+    let partitions = list_partitions();
+    if partitions.is_empty() {
+        panic!("No partitions found!");
+    }
+    for partition in partitions {
+        println!(
+            "{}, {}, {:?}, {:0x}, {}",
+            partition.partition_type,
+            partition.subtype,
+            partition.label,
+            partition.address,
+            partition.size
+        );
+    }
 }
 
 #[derive(Error, Debug, Clone)]
 /// An error while opening an esp32 storage
 pub enum CreateStorageError {
-    /// Failed to find a storage partition. (type: data, subtype: undefined, name: storage)
-    #[error("Failed to find a storage partition. (type: data, subtype: undefined, name: storage)")]
-    NoPartitionFound,
+    /// Failed to find a storage partition. (type: data, subtype: undefined, name: storage or the requested label)
+    #[error("Failed to find a data partition with subtype undefined labeled {expected} (found: {})", .found.join(", "))]
+    NoPartitionFound {
+        /// The requested label
+        expected: String,
+        /// Labels of the data partitions in the partition table
+        found: Vec<String>,
+    },
+    /// A partition with the label exists, but it is not a data partition or has a different subtype
+    #[error(
+        "The partition {label} has type {found_type:#04x} and subtype {found_subtype:#04x}, but a data partition (type 0x01){} is required",
+        .expected_subtype.map(|subtype| format!(" with subtype {subtype:#04x}")).unwrap_or_default()
+    )]
+    PartitionWrongType {
+        /// The requested label
+        label: String,
+        /// Type of the partition in the partition table
+        found_type: u32,
+        /// Subtype of the partition in the partition table
+        found_subtype: u32,
+        /// The required subtype. None if any subtype is accepted.
+        expected_subtype: Option<u32>,
+    },
+    /// The label contains a NUL byte, so it can not be passed to esp-idf
+    #[error("The partition label {0:?} contains a NUL byte")]
+    InvalidLabel(String),
     /// Failed to memorymap the secrets
     #[error("Failed to memorymap the secrets")]
     FailedToMmapSecrets,
+    /// Failed to map a storage that fits into a single MMU page
+    #[error("Failed to map the partition ({0})")]
+    FailedToMapPartition(PartitionGeometry),
     /// Failed to find the default nvs partition
     #[error("Failed to find the default nvs partition")]
     NoNvsPartitionFound,
-    /// Failed to open filesystem1 nvs namespace
-    #[error("Failed to open filesystem1 nvs namespace")]
+    /// Failed to open the nvs namespace of the partition
+    #[error("Failed to open the nvs namespace of the partition")]
     FailedToOpenNvsNamespace,
     /// The erase size of the underlying flash does not match the static block size
-    #[error("The erase size of the underlying flash does not match the static block size")]
-    EraseSizeDoesNotMatchBlockSize,
+    #[error("The erase size of the flash ({erase_size:#x}) does not match the block size of the storage ({block_size:#x})")]
+    EraseSizeDoesNotMatchBlockSize {
+        /// Erase size of the partition
+        erase_size: u32,
+        /// Block size of the storage
+        block_size: u32,
+    },
+    /// The partition does not start at a MMU page boundary, so it can not be mapped contiguously
+    #[error("The partition does not start at a MMU page boundary ({0})")]
+    PartitionNotAligned(PartitionGeometry),
+    /// The partition is smaller than the storage
+    #[error("The partition is too small, the storage needs {required:#x} bytes ({geometry})")]
+    PartitionTooSmall {
+        /// Geometry of the partition
+        geometry: PartitionGeometry,
+        /// Number of bytes required by the storage
+        required: u32,
+    },
+    /// The mapped pages of the partition did not end up next to each other
+    #[error("The partition could not be mapped twice in a row ({0})")]
+    MappingNotContiguous(PartitionGeometry),
+}
+
+/// Location and size of a flash partition, used in errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionGeometry {
+    /// Offset of the partition in flash
+    pub address: u32,
+    /// Size of the partition in bytes
+    pub size: u32,
+    /// Size of a MMU page in bytes
+    pub page_size: u32,
+}
+
+impl std::fmt::Display for PartitionGeometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "address: {:#x}, size: {:#x}, MMU page size: {:#x}",
+            self.address, self.size, self.page_size
+        )
+    }
+}
+
+impl PartitionGeometry {
+    /// Number of bytes of the partition that get mapped for a storage of `storage_size` bytes.
+    ///
+    /// Only the part used by the storage is mapped. The double mapping only works if the mapped part
+    /// starts at a page boundary and covers whole pages, otherwise the second copy would not start
+    /// directly after the first one.
+    fn map_length(&self, storage_size: u32) -> Result<u32, CreateStorageError> {
+        if self.address % self.page_size != 0 {
+            return Err(CreateStorageError::PartitionNotAligned(*self));
+        }
+        if self.size < storage_size {
+            return Err(CreateStorageError::PartitionTooSmall {
+                geometry: *self,
+                required: storage_size,
+            });
+        }
+        let map_length = storage_size.next_multiple_of(self.page_size);
+        if map_length != storage_size {
+            // The storage ends in the middle of a page, so the second copy would start too late
+            return Err(CreateStorageError::PartitionNotAligned(*self));
+        }
+        Ok(map_length)
+    }
 }
 
-impl FlashStorage {
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> std::fmt::Debug
+    for SizedFlashStorage<BLOCKS, BLOCK_SIZE>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = unsafe { std::ffi::CStr::from_ptr((*self.partition).label.as_ptr()) };
+        f.debug_struct("FlashStorage")
+            .field("label", &label.to_string_lossy())
+            .field("geometry", &self.geometry())
+            .finish()
+    }
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> std::fmt::Display
+    for SizedFlashStorage<BLOCKS, BLOCK_SIZE>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let geometry = self.geometry();
+        write!(
+            f,
+            "{} blocks of {:#x} bytes at {:#x}",
+            geometry.block_count, geometry.block_size, geometry.start_address
+        )
+    }
+}
+
+/// Layout of the partition used by a [FlashStorage]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageGeometry {
+    /// Offset of the partition in flash
+    pub start_address: u32,
+    /// Size of the partition in bytes. Can be larger than the part used by the storage.
+    pub size: u32,
+    /// Smallest unit of the flash that can be erased, in bytes
+    pub erase_size: u32,
+    /// Size of a block of the storage in bytes
+    pub block_size: u32,
+    /// Number of blocks used by the storage
+    pub block_count: u32,
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> SizedFlashStorage<BLOCKS, BLOCK_SIZE> {
     /// Find the partition named storage and load a filesystem from it.
     ///
     /// Note that this is only safe if nothing else is writing to that storage until the device is reset
-    pub fn new() -> Result<FlashStorage, CreateStorageError> {
-        // TODO: Make sure that there is only one flash storage instance.
-        let mut label: Vec<i8> = String::from("storage")
-            .bytes()
-            .into_iter()
-            .map(|c| c as i8)
-            .collect();
-        label.push(0);
+    pub fn new() -> Result<Self, CreateStorageError> {
+        let nvs_default_partition: EspNvsPartition<NvsDefault> =
+            EspDefaultNvsPartition::take().or(Err(CreateStorageError::NoNvsPartitionFound))?;
+        Self::with_label("storage", nvs_default_partition)
+    }
+
+    /// Find the data partition with the given label and use it as storage.
+    ///
+    /// Every partition gets its own NVS namespace for its metadata. The partition named `storage` uses
+    /// `filesystem1` for compatibility, all other partitions use `fs_` followed by a part of the hash of
+    /// their label. The default NVS partition can only be taken once, so pass a clone of it when opening
+    /// more than one partition.
+    ///
+    /// The partition needs to start at a MMU page boundary and be at least `BLOCKS * BLOCK_SIZE` bytes
+    /// large. Only that part of the partition is used, any remaining space at the end is ignored.
+    ///
+    /// A storage that fits into a single MMU page can not be mapped twice in a row. It is mapped once
+    /// instead, and reading a range that wraps around its end through [Storage::read] fails with
+    /// [StorageError::SizeTooBig]. Write files with
+    /// [WriteOptions::contiguous](crate::WriteOptions::contiguous) on such a storage.
+    ///
+    /// Note that this is only safe if nothing else is writing to that storage until the device is reset
+    pub fn with_label(
+        label: &str,
+        nvs_partition: EspNvsPartition<NvsDefault>,
+    ) -> Result<Self, CreateStorageError> {
+        let namespace = nvs_namespace(label);
 
         // Find the partition
-        let partition;
+        let partition = find_data_partition(
+            label,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_UNDEFINED,
+        )?;
         unsafe {
-            let partition_iterator = esp_partition_find(
-                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
-                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_UNDEFINED,
-                label.as_mut_ptr(),
-            );
-            if partition_iterator == std::ptr::null_mut() {
-                return Err(CreateStorageError::NoPartitionFound);
-            }
-            partition = esp_partition_get(partition_iterator);
             if (*partition).erase_size as u32 != Self::BLOCK_SIZE {
-                return Err(CreateStorageError::EraseSizeDoesNotMatchBlockSize);
+                return Err(CreateStorageError::EraseSizeDoesNotMatchBlockSize {
+                    erase_size: (*partition).erase_size as u32,
+                    block_size: Self::BLOCK_SIZE,
+                });
             }
         }
 
-        // Memorymap the partition
-        let memory_mapped_flash: *mut u8;
-        let mut storage_handle_a: u32 = 0;
-        let mut storage_handle_b: u32 = 0;
-        let mut storage_handle_c: u32 = 0;
-        unsafe {
-            let mut first_pointer: *const c_void = std::ptr::null_mut();
-            // Mount first mmu page
-            let err = esp_partition_mmap(
-                partition,
-                0,
-                esp_idf_sys::CONFIG_MMU_PAGE_SIZE as usize,
-                esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA,
-                std::ptr::addr_of_mut!(first_pointer),
-                std::ptr::addr_of_mut!(storage_handle_a),
-            );
-            if err != 0 {
-                return Err(CreateStorageError::FailedToMmapSecrets);
-            }
-            let mut idk_pointer: *const c_void = std::ptr::null_mut();
-            // Mount the remaining pages
-            let err = esp_partition_mmap(
-                partition,
-                esp_idf_sys::CONFIG_MMU_PAGE_SIZE as usize,
-                (*partition).size as usize - esp_idf_sys::CONFIG_MMU_PAGE_SIZE as usize,
-                esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA,
-                std::ptr::addr_of_mut!(idk_pointer),
-                std::ptr::addr_of_mut!(storage_handle_b),
-            );
-            if err != 0 {
-                return Err(CreateStorageError::FailedToMmapSecrets);
+        let geometry = unsafe {
+            PartitionGeometry {
+                address: (*partition).address,
+                size: (*partition).size,
+                page_size: esp_idf_sys::CONFIG_MMU_PAGE_SIZE as u32,
             }
-            // If we now mmap the whole partition, will get a pointer to the memory mapped partition directly after the first a partition.
-            // If we would have mounted the whole partition in one step previously, we would have got the same pointer again
-            let err = esp_partition_mmap(
-                partition,
-                0,
-                (*partition).size as usize,
-                esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA,
-                std::ptr::addr_of_mut!(idk_pointer),
-                std::ptr::addr_of_mut!(storage_handle_c),
-            );
-            if err != 0 {
-                // println!("Errorcode: {}", err);
-                // let error: &std::ffi::CStr = std::ffi::CStr::from_ptr(esp_err_to_name(err));
-                // println!("Error description: {}", error.to_string_lossy());
-                return Err(CreateStorageError::FailedToMmapSecrets);
-            }
-
-            // println!("Got out_ptr: {:0x?}", first_pointer);
-            memory_mapped_flash = first_pointer as _;
+        };
+        let storage_size = Self::BLOCKS * Self::BLOCK_SIZE;
+        // A single page can not be split into the parts needed for the double mapping
+        let fits_into_one_page = storage_size <= geometry.page_size;
+        let (memory_mapped_flash, mapped_length, mmap_handles) = if fits_into_one_page {
+            let (pointer, handle) = unsafe { Self::map_partition_once(partition, geometry)? };
+            (pointer, storage_size, vec![handle])
+        } else {
+            let (pointer, handles) = unsafe { Self::map_partition_wrapping(partition, geometry)? };
+            (pointer, 2 * storage_size, handles.to_vec())
+        };
 
-            let nvs_default_partition: EspNvsPartition<NvsDefault> =
-                EspDefaultNvsPartition::take().or(Err(CreateStorageError::NoNvsPartitionFound))?;
-            let nvs = EspNvs::new(nvs_default_partition, "filesystem1", true)
-                .or(Err(CreateStorageError::FailedToOpenNvsNamespace))?;
+        let nvs = EspNvs::new(nvs_partition, &namespace, true)
+            .or(Err(CreateStorageError::FailedToOpenNvsNamespace))?;
 
-            return Ok(FlashStorage {
-                partition: partition,
-                nvs: Mutex::new(nvs),
+        Ok(Self {
+            partition,
+            nvs: Mutex::new(nvs),
+            storage_arena: memory_mapped_flash,
+            mapped_length,
+            _mmap_handles: mmap_handles,
+        })
+    }
 
-                // size: (*partition).size as usize,
-                storage_arena: memory_mapped_flash,
-                // storage_handle_a,
-                // storage_handle_b,
-                // storage_handle_c,
+    /// Map the used part of a partition that fits into a single MMU page once
+    ///
+    /// # Safety
+    ///
+    /// `partition` needs to point to a valid partition described by `geometry`.
+    unsafe fn map_partition_once(
+        partition: *const esp_partition_t,
+        geometry: PartitionGeometry,
+    ) -> Result<(*mut u8, esp_partition_mmap_handle_t), CreateStorageError> {
+        let storage_size = Self::BLOCKS * Self::BLOCK_SIZE;
+        if geometry.size < storage_size {
+            return Err(CreateStorageError::PartitionTooSmall {
+                geometry,
+                required: storage_size,
             });
         }
+        let (pointer, handle) = map_partition(partition, 0, storage_size as usize)
+            .map_err(|_| CreateStorageError::FailedToMapPartition(geometry))?;
+        Ok((pointer as *mut u8, handle))
+    }
+
+    /// Map the used part of the partition twice in a row, see [wrapping_map](super::wrapping_map) for how this works.
+    ///
+    /// Returns the pointer to the first copy and the handles of the three mappings. The mappings are never
+    /// released, the storage lives until the device is reset.
+    ///
+    /// # Safety
+    ///
+    /// `partition` needs to point to a valid partition described by `geometry`. Nothing else may map flash
+    /// while this runs.
+    unsafe fn map_partition_wrapping(
+        partition: *const esp_partition_t,
+        geometry: PartitionGeometry,
+    ) -> Result<(*mut u8, [esp_partition_mmap_handle_t; 3]), CreateStorageError> {
+        let map_length = geometry.map_length(Self::BLOCKS * Self::BLOCK_SIZE)? as usize;
+        let result = map_wrapping(map_length, geometry.page_size as usize, |offset, length| {
+            let (pointer, handle) = map_partition(partition, offset, length)
+                .map_err(|_| WrappingMapError::MapFailed)?;
+            Ok((pointer as usize, handle))
+        });
+        match result {
+            Ok((address, handles)) => Ok((address as *mut u8, handles)),
+            Err(WrappingMapError::MapFailed) => Err(CreateStorageError::FailedToMmapSecrets),
+            Err(WrappingMapError::NotContiguous) => {
+                Err(CreateStorageError::MappingNotContiguous(geometry))
+            }
+        }
+    }
+
+    /// Get the raw pointer to the memory mapped storage and its length in bytes.
+    ///
+    /// This is meant for use cases that need to hand the mapped flash to something else, for example
+    /// executing code directly from flash. Prefer [Storage::read] for everything else.
+    ///
+    /// The storage is mapped twice in a row, so the returned length is `2 * BLOCKS * BLOCK_SIZE`. The
+    /// byte at `pointer + BLOCKS * BLOCK_SIZE + n` is the same flash byte as the one at `pointer + n`.
+    /// This allows accessing data that wraps around the end of the storage as one contiguous slice.
+    /// A storage that fits into a single MMU page is only mapped once, then the length is
+    /// `BLOCKS * BLOCK_SIZE`.
+    ///
+    /// # Safety
+    ///
+    /// - The mapping is read-only. Writing through the pointer is undefined behaviour.
+    /// - Writes and erases through [Storage::write] and [Storage::erase] change the mapped memory.
+    ///   Any slice built from the pointer must not be used while the filesystem could write to or
+    ///   erase the range it covers. Reading a region while it gets erased returns garbage, and the
+    ///   filesystem may reuse the blocks of a deleted file at any time. Hold a reader to the file
+    ///   you are accessing to keep its blocks from being reused.
+    /// - Content that wraps around the end of the storage must be accessed through the second copy,
+    ///   never by reading past `pointer + 2 * BLOCKS * BLOCK_SIZE`.
+    /// - The pointer stays valid as long as this storage exists.
+    pub unsafe fn raw_arena(&self) -> (*const u8, usize) {
+        (self.storage_arena as *const u8, self.mapped_length as usize)
+    }
+
+    /// Get the location and layout of the partition
+    ///
+    /// Tools that create images for this storage need to use the same block size and block count.
+    pub fn geometry(&self) -> StorageGeometry {
+        let partition = unsafe { &*self.partition };
+        StorageGeometry {
+            start_address: partition.address,
+            size: partition.size,
+            erase_size: partition.erase_size,
+            block_size: Self::BLOCK_SIZE,
+            block_count: Self::BLOCKS,
+        }
+    }
+
+    /// Size of the storage in bytes
+    pub fn capacity(&self) -> u32 {
+        Self::BLOCKS * Self::BLOCK_SIZE
+    }
+
+    /// CRC-32 over the raw content of the storage
+    ///
+    /// This is a fingerprint of the image, not of the files. It covers every byte of the storage,
+    /// including free and erased blocks and the headers of deleted files, but not the metadata in the
+    /// NVS. So it changes whenever the filesystem writes or erases anything, even if the content of all
+    /// files stays the same. Compare it across reboots to notice unexpected changes to the flash without
+    /// mounting a filesystem. Use [Filesystem::content_eq] to compare the files instead.
+    ///
+    /// The checksum is the same as the one of zip, so it matches the CRC-32 of a dump created with
+    /// [FlashStorage::dump_to]. The storage is read through its mapping in one pass.
+    pub fn partition_crc(&self) -> Result<u32, StorageError> {
+        let image = self.read(0, Self::BLOCKS * Self::BLOCK_SIZE)?;
+        Ok(crate::index::crc32(image))
+    }
+
+    /// Stream the raw content of the storage to `writer`
+    ///
+    /// Writes [FlashStorage::capacity] bytes, one block at a time. The metadata in the NVS is not
+    /// included. Use this to pull the storage of a device for debugging.
+    pub fn dump_to(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        dump::dump_to(self, writer)
+    }
+
+    /// Replace the raw content of the storage with a dump created by [FlashStorage::dump_to]
+    ///
+    /// Every block is erased and written in order. If `reader` fails or ends early, the storage is left
+    /// with a mix of old and new blocks.
+    ///
+    /// This requires exclusive access to the storage. No filesystem may be mounted on it while
+    /// restoring, and existing [Filesystem] instances do not see the new content. Mount it again
+    /// afterwards.
+    pub fn restore_from(&self, reader: &mut impl std::io::Read) -> std::io::Result<()> {
+        dump::restore_from(self, reader)
     }
 }
 
-impl Storage for FlashStorage {
-    const BLOCKS: u32 = 256;
-    const BLOCK_SIZE: u32 = 4096;
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Storage for SizedFlashStorage<BLOCKS, BLOCK_SIZE> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
 
     fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
-        // TODO: Make this actually safe
-        if (address) > Self::BLOCKS * Self::BLOCK_SIZE {
-            return Err(StorageError::AddressTooBig.into());
+        // The storage is mapped twice in a row when it is created and the mappings are never released.
+        // A range that starts in the first copy and is at most as long as the storage ends inside the
+        // second copy, so every range that passes these checks is mapped. A storage that is only mapped
+        // once can not hand out ranges that wrap around.
+        if address >= Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::AddressTooBig);
         }
-        if (address + length) > Self::BLOCKS * Self::BLOCK_SIZE * 2 {
-            // TODO: Support erase with wraparound
-            return Err(StorageError::SizeTooBig.into());
+        if length > Self::BLOCKS * Self::BLOCK_SIZE || address + length > self.mapped_length {
+            return Err(StorageError::SizeTooBig);
         }
         let thing: &[u8];
         unsafe {
@@ -202,8 +581,40 @@ impl Storage for FlashStorage {
         return Ok(thing);
     }
 
+    fn read_into(&self, address: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        if address >= Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        let length = u32::try_from(buf.len()).map_err(|_| StorageError::SizeTooBig)?;
+        if length > Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        // Split the read where it wraps around the end of the partition
+        let first_length = (Self::BLOCKS * Self::BLOCK_SIZE - address).min(length) as usize;
+        let (first, second) = buf.split_at_mut(first_length);
+        for (address, part) in [(address, first), (0, second)] {
+            if part.is_empty() {
+                continue;
+            }
+            unsafe {
+                let error_code = esp_partition_read(
+                    self.partition,
+                    address as usize,
+                    part.as_mut_ptr() as *mut c_void,
+                    part.len(),
+                );
+                if error_code != ESP_OK {
+                    return Err(classify_esp_err(error_code));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
-        // TODO: Make this actually safe
+        let length = u32::try_from(data.len()).map_err(|_| StorageError::SizeTooBig)?;
+        // TODO: Support write with wraparound
+        range_end(address, length, Self::BLOCKS * Self::BLOCK_SIZE)?;
         let data_ptr = data.as_ptr() as *const c_void;
         // println!(
         //     "STORAGE: {:0x?}, INPUT: {:0x?}",
@@ -217,9 +628,7 @@ impl Storage for FlashStorage {
                 esp_partition_write_raw(self.partition, address as usize, data_ptr, data.len());
             if error_code != ESP_OK {
                 // println!("Failed to write to flash with code {}", error_code);
-                let error: &std::ffi::CStr = std::ffi::CStr::from_ptr(esp_err_to_name(error_code));
-                // println!("Description: {}", error.to_string_lossy());
-                return Err(StorageError::Other(error.to_string_lossy().into()));
+                return Err(classify_esp_err(error_code));
             }
         };
         // unsafe {
@@ -242,10 +651,8 @@ impl Storage for FlashStorage {
         if (address) > Self::BLOCKS * Self::BLOCK_SIZE {
             return Err(StorageError::AddressTooBig.into());
         }
-        if (address + length) > Self::BLOCKS * Self::BLOCK_SIZE {
-            // TODO: Support erase with wraparound
-            return Err(StorageError::SizeTooBig.into());
-        }
+        // TODO: Support erase with wraparound
+        range_end(address, length, Self::BLOCKS * Self::BLOCK_SIZE)?;
 
         unsafe {
             // println!(
@@ -257,25 +664,36 @@ impl Storage for FlashStorage {
                 esp_partition_erase_range(self.partition, address as usize, length as usize);
             if error_code != ESP_OK {
                 // println!("Failed to erase flash with code {}", error_code);
-                let error: &std::ffi::CStr = std::ffi::CStr::from_ptr(esp_err_to_name(error_code));
-                // println!("Description: {}", error.to_string_lossy());
-                return Err(StorageError::Other(error.to_string_lossy().into()).into());
+                return Err(classify_esp_err(error_code).into());
             }
         }
         return Ok(());
     }
 
     fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
-        let mut read_buffer = [0u8; 256];
-        let buffer = self
+        let mut buffer = Vec::new();
+        self.read_metadata_into(key, &mut buffer)?;
+        return Ok(buffer.into_boxed_slice());
+    }
+
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        let nvs = self
             .nvs
             .lock()
-            .map_err(|_| std::io::Error::other("Failed to obtain lock to nvs"))?
-            .get_raw(key, &mut read_buffer)
-            .map_err(|_| std::io::Error::other("Failed to read value from nvs"))?
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to nvs"))?;
+        let length = nvs
+            .blob_len(key)
+            .map_err(|error| metadata_error(error.code()))?
             .ok_or(std::io::ErrorKind::NotFound)?;
-        let boxed_result: Box<[u8]> = buffer.iter().cloned().collect();
-        return Ok(boxed_result);
+        buf.clear();
+        buf.resize(length, 0);
+        let read_length = nvs
+            .get_raw(key, buf)
+            .map_err(|error| metadata_error(error.code()))?
+            .ok_or(std::io::ErrorKind::NotFound)?
+            .len();
+        buf.truncate(read_length);
+        return Ok(());
     }
 
     fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
@@ -283,15 +701,19 @@ impl Storage for FlashStorage {
             .lock()
             .map_err(|_| std::io::Error::other("Failed to obtain lock to nvs"))?
             .set_raw(key, value)
-            .map_err(|_| std::io::Error::other("Failed to write value to nvs"))?;
+            .map_err(|error| metadata_error(error.code()))?;
         return Ok(());
     }
 }
 
-static mut STORAGE_SINGLETON: Option<FlashStorage> = None;
-static mut FILESYSTEM_SINGLETON: Option<RwLock<Filesystem<FlashStorage>>> = None;
+/// Filesystems mounted with [mount_partition], by the label of their partition
+static MOUNTED_FILESYSTEMS: Mutex<Vec<(String, &'static RwLock<Filesystem<FlashStorage>>)>> =
+    Mutex::new(Vec::new());
+/// The default NVS partition can only be taken once, so all partitions mounted with [mount_partition]
+/// share it
+static NVS_PARTITION: Mutex<Option<EspDefaultNvsPartition>> = Mutex::new(None);
 
-/// An error occurred while initializing the global storage singleton
+/// An error occurred while mounting a partition with [mount_partition]
 #[derive(Error, Debug, Clone)]
 pub enum SetupStorageError {
     /// Storage is already initialized
@@ -300,28 +722,53 @@ pub enum SetupStorageError {
     /// An error while creating the storage
     #[error(transparent)]
     CreateStorageError(#[from] CreateStorageError),
+    /// The lock of the filesystem was poisoned by a panicking thread
+    #[error("The lock of the filesystem was poisoned")]
+    LockPoisoned,
 }
 
-/// Setup the global storage singleton
-fn setup_storage() -> Result<(), SetupStorageError> {
-    unsafe {
-        STORAGE_SINGLETON = Some(FlashStorage::new()?);
-        FILESYSTEM_SINGLETON = Some(RwLock::new(Filesystem::new(
-            STORAGE_SINGLETON.as_ref().unwrap(),
-        )));
-        dbg!(&FILESYSTEM_SINGLETON.as_ref().unwrap().read().unwrap().files);
+/// Get the filesystem on the data partition with the given label, mounting it on first use
+///
+/// This is a convenience for firmware that needs a filesystem from many places. Each partition is mounted
+/// once and shared by all callers, and different labels give independent filesystems. The storages and
+/// filesystems are never dropped.
+///
+/// Nothing else depends on this. A [SizedFlashStorage] can also be created directly and passed to
+/// [Filesystem::new], as long as the same partition is not mounted twice. This takes the default NVS
+/// partition on first use, so do not combine it with [SizedFlashStorage::new].
+pub fn mount_partition(
+    label: &str,
+) -> Result<&'static RwLock<Filesystem<FlashStorage>>, SetupStorageError> {
+    // Held while mounting, so a partition can not be mounted twice
+    let mut mounted = MOUNTED_FILESYSTEMS
+        .lock()
+        .map_err(|_| SetupStorageError::LockPoisoned)?;
+    if let Some((_, filesystem)) = mounted
+        .iter()
+        .find(|(mounted_label, _)| mounted_label == label)
+    {
+        return Ok(filesystem);
     }
-    return Ok(());
-}
 
-/// Get the global filesystem singleton
-fn get_filesystem() -> Result<&'static RwLock<Filesystem<FlashStorage>>, SetupStorageError> {
-    unsafe {
-        if FILESYSTEM_SINGLETON.is_none() {
-            setup_storage()?;
+    let nvs_partition = {
+        let mut nvs_partition = NVS_PARTITION
+            .lock()
+            .map_err(|_| SetupStorageError::LockPoisoned)?;
+        if nvs_partition.is_none() {
+            *nvs_partition = Some(
+                EspDefaultNvsPartition::take().or(Err(CreateStorageError::NoNvsPartitionFound))?,
+            );
         }
-        Ok(FILESYSTEM_SINGLETON.as_ref().unwrap())
-    }
+        nvs_partition.clone().unwrap()
+    };
+    let storage: &'static FlashStorage =
+        Box::leak(Box::new(FlashStorage::with_label(label, nvs_partition)?));
+    let filesystem = Filesystem::new(storage);
+    log::debug!("Mounted filesystem with {} on {}", filesystem, storage);
+    let filesystem: &'static RwLock<Filesystem<FlashStorage>> =
+        Box::leak(Box::new(RwLock::new(filesystem)));
+    mounted.push((label.to_string(), filesystem));
+    Ok(filesystem)
 }
 
 // fn get_first_block() -> u16 {
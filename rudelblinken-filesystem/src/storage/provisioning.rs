@@ -0,0 +1,155 @@
+//! Read-only access to factory provisioned data
+//!
+//! Devices get values like their id or keys written to a separate data partition during production.
+//! With the `esp` feature, [ProvisioningStorage] maps such a partition and gives access to its bytes, so
+//! firmware does not need its own unsafe partition code.
+//!
+//! The content is usually a list of entries encoded as `tag: u8, length: u16, value`, with the length
+//! in little endian. [parse_tlv] decodes that format. The list ends at the end of the data or at a
+//! tag of `0xff`, which is what erased flash reads as.
+//!
+//! ```
+//! use rudelblinken_filesystem::storage::provisioning::parse_tlv;
+//!
+//! let data = [0x01, 0x02, 0x00, 0xab, 0xcd, 0xff, 0xff, 0xff];
+//! assert_eq!(parse_tlv(&data).unwrap(), [(0x01, &[0xab, 0xcd][..])]);
+//! ```
+
+use thiserror::Error;
+
+/// Tag that ends a list of entries, as read from erased flash
+const END_TAG: u8 = 0xff;
+
+/// Errors that can occur when parsing a list of entries
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseTlvError {
+    /// An entry continues past the end of the data
+    #[error("The entry at offset {offset} is truncated")]
+    Truncated {
+        /// Offset of the tag of the truncated entry
+        offset: usize,
+    },
+}
+
+/// Decode a list of `tag: u8, length: u16, value` entries, see [provisioning](self)
+pub fn parse_tlv(data: &[u8]) -> Result<Vec<(u8, &[u8])>, ParseTlvError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(&tag) = data.get(offset) {
+        if tag == END_TAG {
+            break;
+        }
+        let truncated = ParseTlvError::Truncated { offset };
+        let length = data.get(offset + 1..offset + 3).ok_or(truncated.clone())?;
+        let length = u16::from_le_bytes([length[0], length[1]]) as usize;
+        let value = data.get(offset + 3..offset + 3 + length).ok_or(truncated)?;
+        entries.push((tag, value));
+        offset += 3 + length;
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "esp")]
+pub use esp::ProvisioningStorage;
+
+#[cfg(feature = "esp")]
+mod esp {
+    use super::{parse_tlv, ParseTlvError};
+    use crate::storage::{
+        esp::{find_data_partition, map_partition, CreateStorageError},
+        StorageError,
+    };
+    use esp_idf_sys::{
+        esp_partition_mmap_handle_t, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+    };
+
+    /// A data partition mapped read-only, see [provisioning](super)
+    pub struct ProvisioningStorage {
+        data: &'static [u8],
+        /// Handle of the mapping. It is never released.
+        _mmap_handle: esp_partition_mmap_handle_t,
+    }
+
+    unsafe impl Sync for ProvisioningStorage {}
+    unsafe impl Send for ProvisioningStorage {}
+
+    impl ProvisioningStorage {
+        /// Find the data partition with the given label and map all of it
+        ///
+        /// The subtype of the partition does not matter. Nothing may write to the partition while it is
+        /// mapped.
+        pub fn open(label: &str) -> Result<Self, CreateStorageError> {
+            let partition =
+                find_data_partition(label, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY)?;
+            let size = unsafe { (*partition).size } as usize;
+            let (pointer, handle) = unsafe { map_partition(partition, 0, size) }
+                .map_err(|_| CreateStorageError::FailedToMmapSecrets)?;
+            Ok(Self {
+                data: unsafe { std::slice::from_raw_parts(pointer, size) },
+                _mmap_handle: handle,
+            })
+        }
+
+        /// Size of the partition in bytes
+        pub fn len(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        /// Check if the partition is empty
+        pub fn is_empty(&self) -> bool {
+            self.data.is_empty()
+        }
+
+        /// Read `length` bytes starting at `offset`
+        pub fn read(&self, offset: u32, length: u32) -> Result<&[u8], StorageError> {
+            let start = offset as usize;
+            if start > self.data.len() {
+                return Err(StorageError::AddressTooBig);
+            }
+            let end = start
+                .checked_add(length as usize)
+                .filter(|end| *end <= self.data.len())
+                .ok_or(StorageError::SizeTooBig)?;
+            Ok(&self.data[start..end])
+        }
+
+        /// Decode the entries starting at `offset`, see [parse_tlv]
+        pub fn tlv(&self, offset: u32) -> Result<Vec<(u8, &[u8])>, ParseTlvError> {
+            parse_tlv(self.data.get(offset as usize..).unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_parsed_until_the_end_tag() {
+        let data = [
+            0x01, 0x04, 0x00, b'r', b'u', b'd', b'e', // device id
+            0x02, 0x00, 0x00, // empty value
+            0xff, 0x12, 0x34, // erased flash
+        ];
+        assert_eq!(
+            parse_tlv(&data).unwrap(),
+            [(0x01, &b"rude"[..]), (0x02, &[][..])]
+        );
+        assert_eq!(parse_tlv(&[]).unwrap(), []);
+        assert_eq!(parse_tlv(&data[..10]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn truncated_entries_are_rejected() {
+        let data = [0x01, 0x01, 0x00, 0xaa, 0x02, 0x05, 0x00, 0xbb];
+        assert_eq!(
+            parse_tlv(&data),
+            Err(ParseTlvError::Truncated { offset: 4 })
+        );
+        assert_eq!(
+            parse_tlv(&data[..5]),
+            Err(ParseTlvError::Truncated { offset: 4 })
+        );
+    }
+}
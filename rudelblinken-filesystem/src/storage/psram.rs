@@ -0,0 +1,134 @@
+//! Storage implementation backed by external PSRAM
+//!
+//! [PsramStorage] is meant as a scratch area for staging large files before they are written to
+//! flash. An incoming program can be written to a filesystem on a [PsramStorage], its hash can be
+//! checked, and only then it is copied to the filesystem on the flash. An interrupted transfer never
+//! touches the flash.
+//!
+//! ```ignore
+//! let scratch: &'static PsramStorage<64> = Box::leak(Box::new(PsramStorage::new()?));
+//! let mut staging = Filesystem::new(scratch);
+//! staging.write_file("upload", &received, &hash)?;
+//! let upload = staging.read_file("upload").unwrap().upgrade()?;
+//! if upload.verify_hash() {
+//!     // The file only becomes visible on flash once it was written completely
+//!     flash_filesystem.write_file("main", &upload, upload.hash())?;
+//! }
+//! ```
+//!
+//! # Differences to flash
+//!
+//! - **Volatile**: The content and the metadata are lost on every reset. The storage starts out erased.
+//! - **Capacity**: The region is allocated twice as large as the storage to emulate the wraparound of
+//!   the double mapped flash, so a storage of `BLOCKS` blocks uses `2 * BLOCKS * 4096` bytes of PSRAM.
+//! - **No wear**: Erasing is only a memset, so erasing often does not hurt.
+//! - **Same write semantics**: Writes can only clear bits like on NOR flash, so code tested against
+//!   this storage behaves the same on flash.
+
+use super::{arena::RamArena, EraseStorageError, Storage, StorageError};
+use esp_idf_sys::{heap_caps_aligned_alloc, heap_caps_free, MALLOC_CAP_SPIRAM};
+use std::{collections::HashMap, ptr::NonNull, sync::Mutex};
+use thiserror::Error;
+
+/// An error while allocating a PSRAM storage
+#[derive(Error, Debug, Clone)]
+pub enum CreatePsramStorageError {
+    /// Not enough free PSRAM
+    #[error("Failed to allocate {size} bytes of PSRAM")]
+    AllocationFailed {
+        /// Number of bytes that were requested
+        size: usize,
+    },
+}
+
+/// A storage that keeps its content in PSRAM
+///
+/// `BLOCKS` is the number of 4096 byte blocks.
+pub struct PsramStorage<const BLOCKS: u32> {
+    arena: RamArena,
+    metadata: Mutex<HashMap<String, Box<[u8]>>>,
+}
+
+/// Free a region allocated by [PsramStorage::new]
+unsafe fn free_psram(buffer: NonNull<u8>) {
+    heap_caps_free(buffer.as_ptr() as _);
+}
+
+impl<const BLOCKS: u32> PsramStorage<BLOCKS> {
+    /// Size of the storage in bytes
+    pub const SIZE: u32 = BLOCKS * Self::BLOCK_SIZE;
+
+    /// Allocate an erased storage in PSRAM
+    pub fn new() -> Result<Self, CreatePsramStorageError> {
+        let size = Self::SIZE as usize * 2;
+        let buffer =
+            unsafe { heap_caps_aligned_alloc(Self::BLOCK_SIZE as usize, size, MALLOC_CAP_SPIRAM) };
+        let buffer = NonNull::new(buffer as *mut u8)
+            .ok_or(CreatePsramStorageError::AllocationFailed { size })?;
+        Ok(Self {
+            arena: unsafe { RamArena::from_buffer(buffer, Self::SIZE, free_psram) },
+            metadata: Default::default(),
+        })
+    }
+}
+
+impl<const BLOCKS: u32> Storage for PsramStorage<BLOCKS> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = 4096;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        if address >= Self::SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        if length > Self::SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        Ok(self.arena.read(address, length))
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        if address >= Self::SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        let length = u32::try_from(data.len()).map_err(|_| StorageError::SizeTooBig)?;
+        if length > Self::SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        self.arena.write(address, data);
+        Ok(())
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        if !address.is_multiple_of(Self::BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseAlongBlockBoundaries);
+        }
+        if !length.is_multiple_of(Self::BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseInBlockSizedChunks);
+        }
+        let end = address
+            .checked_add(length)
+            .ok_or(StorageError::SizeTooBig)?;
+        if end > Self::SIZE {
+            return Err(StorageError::SizeTooBig.into());
+        }
+        self.arena.erase(address, length);
+        Ok(())
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        self.metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to metadata"))?
+            .get(key)
+            .cloned()
+            .ok_or(std::io::ErrorKind::NotFound.into())
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to metadata"))?
+            .insert(key.into(), value.into());
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Retry transient errors of a storage
+//!
+//! Flash operations on the esp32 can time out while the radio is busy, and they usually succeed when
+//! they are tried again a little later. [RetryStorage] wraps another storage and retries writes and
+//! erases that fail with a transient error, see [StorageError::is_transient]. Other errors are
+//! returned immediately, so storages that never report transient errors are not affected.
+//!
+//! ```
+//! use rudelblinken_filesystem::storage::{retry::RetryStorage, simulated::SimulatedStorage};
+//! use rudelblinken_filesystem::Filesystem;
+//! use std::time::Duration;
+//!
+//! let storage: &'static RetryStorage<SimulatedStorage> = Box::leak(Box::new(
+//!     RetryStorage::new(SimulatedStorage::new())
+//!         .retries(5)
+//!         .delay(Duration::from_millis(1)),
+//! ));
+//! let mut filesystem = Filesystem::new(storage);
+//! filesystem.write_file("main", &[0; 100], &[0; 32]).unwrap();
+//! ```
+
+use super::{EraseStorageError, Storage, StorageError};
+use std::time::Duration;
+
+/// `esp_err_t` codes that are worth retrying
+///
+/// `ESP_ERR_TIMEOUT`, `ESP_ERR_NOT_FINISHED` and `ESP_ERR_FLASH_OP_TIMEOUT`. The values are spelled out,
+/// so errors can be classified without the `esp` feature.
+pub(crate) const TRANSIENT_ESP_ERRORS: &[i32] = &[0x107, 0x10c, 0x6002];
+
+/// Storage that retries writes and erases that failed with a transient error
+///
+/// The delay doubles after every retry. Reads and metadata operations are passed through unchanged.
+pub struct RetryStorage<S: Storage> {
+    storage: S,
+    retries: u32,
+    delay: Duration,
+}
+
+impl<S: Storage> RetryStorage<S> {
+    /// Wrap a storage. Operations are retried up to 3 times, starting with a delay of 2 ms.
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            retries: 3,
+            delay: Duration::from_millis(2),
+        }
+    }
+
+    /// Set how often a failed operation is retried before the last error is returned
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the delay before the first retry
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// The wrapped storage
+    pub fn inner(&self) -> &S {
+        &self.storage
+    }
+
+    /// Run `operation` until it succeeds, fails with an error that is not transient or runs out of retries
+    fn retry<E>(
+        &self,
+        name: &str,
+        transient: impl Fn(&E) -> bool,
+        operation: impl Fn() -> Result<(), E>,
+    ) -> Result<(), E>
+    where
+        E: std::fmt::Display,
+    {
+        let mut delay = self.delay;
+        let mut attempt = 0;
+        loop {
+            match operation() {
+                Err(error) if attempt < self.retries && transient(&error) => {
+                    attempt += 1;
+                    log::debug!(
+                        "Retrying {} in {:?} ({}/{}): {}",
+                        name,
+                        delay,
+                        attempt,
+                        self.retries,
+                        error
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<S: Storage> Storage for RetryStorage<S> {
+    const BLOCK_SIZE: u32 = S::BLOCK_SIZE;
+    const BLOCKS: u32 = S::BLOCKS;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        self.storage.read(address, length)
+    }
+
+    fn read_into(&self, address: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        self.storage.read_into(address, buf)
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.retry("write", StorageError::is_transient, || {
+            self.storage.write(address, data)
+        })
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        self.retry(
+            "erase",
+            |error| matches!(error, EraseStorageError::StorageError(error) if error.is_transient()),
+            || self.storage.erase(address, length),
+        )
+    }
+
+    fn erase_count(&self, block: u32) -> Option<u32> {
+        self.storage.erase_count(block)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.retry("flush", StorageError::is_transient, || self.storage.flush())
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        self.storage.read_metadata(key)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.storage.write_metadata(key, value)
+    }
+
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        self.storage.read_metadata_into(key, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::simulated::SimulatedStorage;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails the first writes and erases with the given error code
+    struct FlakyStorage {
+        storage: SimulatedStorage,
+        failures: AtomicU32,
+        code: i32,
+    }
+
+    impl FlakyStorage {
+        fn new(failures: u32, code: i32) -> Self {
+            Self {
+                storage: SimulatedStorage::new(),
+                failures: AtomicU32::new(failures),
+                code,
+            }
+        }
+
+        fn fail(&self) -> Result<(), StorageError> {
+            if self.failures.load(Ordering::SeqCst) == 0 {
+                return Ok(());
+            }
+            self.failures.fetch_sub(1, Ordering::SeqCst);
+            Err(StorageError::Esp {
+                code: self.code,
+                name: "ESP_ERR_TEST".into(),
+            })
+        }
+    }
+
+    impl Storage for FlakyStorage {
+        const BLOCK_SIZE: u32 = SimulatedStorage::BLOCK_SIZE;
+        const BLOCKS: u32 = SimulatedStorage::BLOCKS;
+
+        fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+            self.storage.read(address, length)
+        }
+
+        fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+            self.fail()?;
+            self.storage.write(address, data)
+        }
+
+        fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+            self.fail()?;
+            self.storage.erase(address, length)
+        }
+
+        fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+            self.storage.read_metadata(key)
+        }
+
+        fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+            self.storage.write_metadata(key, value)
+        }
+    }
+
+    fn retry_storage(failures: u32, code: i32) -> RetryStorage<FlakyStorage> {
+        RetryStorage::new(FlakyStorage::new(failures, code))
+            .retries(2)
+            .delay(Duration::ZERO)
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let storage = retry_storage(2, 0x107);
+        storage.write(0, &[0]).unwrap();
+        assert_eq!(storage.read(0, 1).unwrap(), &[0]);
+
+        let storage = retry_storage(2, 0x6002);
+        storage.erase(0, SimulatedStorage::BLOCK_SIZE).unwrap();
+    }
+
+    #[test]
+    fn the_last_error_is_returned_when_retries_run_out() {
+        let storage = retry_storage(3, 0x107);
+        let Err(StorageError::Esp { code, .. }) = storage.write(0, &[0]) else {
+            panic!("Expected the esp error");
+        };
+        assert_eq!(code, 0x107);
+        assert_eq!(storage.inner().failures.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        // ESP_ERR_INVALID_ARG
+        let storage = retry_storage(2, 0x102);
+        assert!(storage.erase(0, SimulatedStorage::BLOCK_SIZE).is_err());
+        assert_eq!(storage.inner().failures.load(Ordering::SeqCst), 1);
+    }
+}
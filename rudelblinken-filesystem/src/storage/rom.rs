@@ -0,0 +1,130 @@
+//! Filesystem images compiled into the firmware
+//!
+//! [RomStorage] serves a prebuilt image, usually from `include_bytes!`, as a storage that can only be
+//! read. Mount it with [Filesystem::mount_readonly] to ship factory defaults that are available even if
+//! the flash partition was wiped. Build the image on the host with
+//! [FilesystemImageBuilder](crate::image::FilesystemImageBuilder).
+//!
+//! [RomStorage::from_static] is a `const fn`, so the storage can be a `static`. A wrong image size is
+//! then reported when compiling:
+//!
+//! ```ignore
+//! static DEFAULTS: RomStorage<16, 4096> = RomStorage::from_static(include_bytes!("defaults.img"));
+//!
+//! let defaults = Filesystem::mount_readonly(&DEFAULTS);
+//! ```
+//!
+//! Writes and erases fail with [StorageError::ReadOnly]. There is no metadata, so the files are found
+//! by scanning the image from the first block, which is where the image builder places them.
+//!
+//! The image is not mapped twice like flash, so reads that wrap around the end of the image fail.
+//! Images from the image builder do not contain wrapping files. `include_bytes!` only aligns to a
+//! single byte. If files are executed in place, see
+//! [File::executable_slice](crate::file::File::executable_slice), wrap the bytes in a type with
+//! `#[repr(align(64))]`.
+
+use super::{EraseStorageError, Storage, StorageError};
+#[cfg(doc)]
+use crate::Filesystem;
+
+/// A read-only storage backed by a static image, see [rom](self)
+pub struct RomStorage<const BLOCKS: u32, const BLOCK_SIZE: u32> {
+    bytes: &'static [u8],
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> RomStorage<BLOCKS, BLOCK_SIZE> {
+    /// Use `bytes` as the content of the storage
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is not exactly `BLOCKS * BLOCK_SIZE` bytes long. In a `static`, this is a
+    /// compile error.
+    pub const fn from_static(bytes: &'static [u8]) -> Self {
+        assert!(
+            bytes.len() as u64 == BLOCKS as u64 * BLOCK_SIZE as u64,
+            "The image needs to be exactly BLOCKS * BLOCK_SIZE bytes long"
+        );
+        Self { bytes }
+    }
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Storage for RomStorage<BLOCKS, BLOCK_SIZE> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        let start = address as usize;
+        if start >= self.bytes.len() {
+            return Err(StorageError::AddressTooBig);
+        }
+        start
+            .checked_add(length as usize)
+            .and_then(|end| self.bytes.get(start..end))
+            .ok_or(StorageError::SizeTooBig)
+    }
+
+    fn write(&self, _address: u32, _data: &[u8]) -> Result<(), StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    fn erase(&self, _address: u32, _length: u32) -> Result<(), EraseStorageError> {
+        Err(StorageError::ReadOnly.into())
+    }
+
+    fn read_metadata(&self, _key: &str) -> std::io::Result<Box<[u8]>> {
+        Err(std::io::ErrorKind::NotFound.into())
+    }
+
+    fn write_metadata(&self, _key: &str, _value: &[u8]) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::ReadOnlyFilesystem,
+            StorageError::ReadOnly,
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod tests {
+    use super::*;
+    use crate::{image::FilesystemImageBuilder, Filesystem, FilesystemWriteError};
+
+    #[test]
+    fn images_can_be_mounted_from_static_bytes() {
+        let image = FilesystemImageBuilder::<16, 4096>::new()
+            .add_file("main", &[1; 5000])
+            .add_file("assets", &[2; 100])
+            .build()
+            .unwrap();
+        let storage: &'static RomStorage<16, 4096> =
+            Box::leak(Box::new(RomStorage::from_static(image.leak())));
+
+        let mut filesystem = Filesystem::mount_readonly(storage);
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 5000]);
+        assert!(main.verify_hash());
+        assert!(filesystem.read_file("assets").is_some());
+        assert!(matches!(
+            filesystem.write_file("new", &[3; 100], &[0; 32]),
+            Err(FilesystemWriteError::ReadOnly)
+        ));
+
+        assert!(matches!(
+            storage.write(0, &[0]),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.erase(0, 4096),
+            Err(EraseStorageError::StorageError(StorageError::ReadOnly))
+        ));
+        assert!(matches!(
+            storage.read(15 * 4096, 2 * 4096),
+            Err(StorageError::SizeTooBig)
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn images_need_to_match_the_geometry() {
+        RomStorage::<16, 4096>::from_static(&[0xff; 4096]);
+    }
+}
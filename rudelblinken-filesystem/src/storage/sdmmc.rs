@@ -0,0 +1,507 @@
+//! Storage implementation backed by an SD card
+//!
+//! [SdStorage] uses a raw region of sectors on a card accessed through [embedded_sdmmc::BlockDevice].
+//! The region starts with the data area (`BLOCKS * BLOCK_SIZE` bytes), followed by a small metadata
+//! area of [METADATA_SECTORS] sectors that replaces the NVS used by the flash backend.
+//!
+//! The metadata area is split into two slots. Every change writes all metadata to the slot that was not
+//! used last, together with a sequence number and a CRC-32. Opening the storage uses the valid slot with
+//! the highest sequence number. A write that is torn by a power loss leaves the other slot intact, so
+//! writing a metadata key is atomic like on the NVS: either the old or the new metadata is found.
+//!
+//! The card works with 512 byte sectors, so every filesystem block is made up of
+//! `BLOCK_SIZE / 512` consecutive sectors. Erasing a block writes all of its sectors in one request.
+//!
+//! # Differences to memory mapped flash
+//!
+//! A card can not be memory mapped, but [Storage::read] returns slices that borrow the storage. To
+//! make that possible the whole data area is mirrored in RAM when the storage is opened. This has some
+//! consequences:
+//!
+//! - **RAM usage**: The mirror needs `2 * BLOCKS * BLOCK_SIZE` bytes of RAM. Size `BLOCKS` accordingly.
+//! - **No zero-copy reads**: Reads are served from the RAM mirror, not from the card. The data is copied
+//!   once when opening, and opening gets slower the larger the region is.
+//! - **Writes go through**: Every write and erase is applied to the mirror and then written to the
+//!   affected sectors on the card before returning. Small writes cost a full sector write.
+//! - **Exclusive access**: Nothing else may modify the region while the storage is open, as the
+//!   mirror would not notice.
+
+use super::{arena::RamArena, EraseStorageError, Storage, StorageError};
+use crate::index::crc32;
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+use std::{collections::BTreeMap, sync::Mutex};
+use thiserror::Error;
+
+/// Number of sectors reserved for metadata after the data area
+pub const METADATA_SECTORS: u32 = 16;
+/// Number of sectors of each of the two metadata slots
+const SLOT_SECTORS: u32 = METADATA_SECTORS / 2;
+/// Marks an initialized metadata slot
+const METADATA_MAGIC: [u8; 4] = *b"rbmd";
+/// Length of the magic, sequence number and payload length at the start of a slot
+const SLOT_HEADER_LEN: usize = 12;
+/// Length of the CRC-32 after the payload of a slot
+const SLOT_CRC_LEN: usize = 4;
+
+/// An error while opening an SD card storage
+#[derive(Error, Debug, Clone)]
+pub enum CreateSdStorageError {
+    /// The block device reported an error
+    #[error("The block device reported an error: {0}")]
+    DeviceError(String),
+    /// The card is too small for the requested region
+    #[error("The region needs {required} sectors starting at sector {start}, but the card only has {available}")]
+    RegionTooLarge {
+        /// First sector of the region
+        start: u32,
+        /// Number of sectors required for data and metadata
+        required: u32,
+        /// Number of sectors on the card
+        available: u32,
+    },
+}
+
+/// A storage implementation that stores data in a raw region of an SD card
+///
+/// `BLOCKS` is the number of filesystem blocks in the region.
+pub struct SdStorage<D: BlockDevice, const BLOCKS: u32> {
+    device: Mutex<D>,
+    /// First sector of the region
+    start: u32,
+    /// RAM mirror of the data area
+    mirror: RamArena,
+    /// Metadata, written to the metadata area on every change
+    metadata: Mutex<MetadataArea>,
+}
+
+/// The metadata and the sequence number of the slot it was last written to
+#[derive(Default)]
+struct MetadataArea {
+    values: BTreeMap<String, Box<[u8]>>,
+    sequence: u32,
+}
+
+impl<D: BlockDevice, const BLOCKS: u32> SdStorage<D, BLOCKS> {
+    /// Number of card sectors per filesystem block
+    const SECTORS_PER_BLOCK: u32 = Self::BLOCK_SIZE / Block::LEN_U32;
+    /// Number of sectors in the data area
+    const DATA_SECTORS: u32 = BLOCKS * Self::SECTORS_PER_BLOCK;
+
+    /// Open the region starting at sector `start` and load it into RAM.
+    ///
+    /// The region needs `BLOCKS * BLOCK_SIZE / 512 + METADATA_SECTORS` sectors.
+    pub fn new(device: D, start: u32) -> Result<Self, CreateSdStorageError> {
+        let available = device
+            .num_blocks()
+            .map_err(|e| CreateSdStorageError::DeviceError(format!("{:?}", e)))?
+            .0;
+        let required = Self::DATA_SECTORS + METADATA_SECTORS;
+        if start
+            .checked_add(required)
+            .is_none_or(|end| end > available)
+        {
+            return Err(CreateSdStorageError::RegionTooLarge {
+                start,
+                required,
+                available,
+            });
+        }
+
+        let storage = Self {
+            device: Mutex::new(device),
+            start,
+            mirror: RamArena::new(BLOCKS * Self::BLOCK_SIZE),
+            metadata: Default::default(),
+        };
+
+        let mut sectors = vec![Block::new(); Self::SECTORS_PER_BLOCK as usize];
+        for block in 0..BLOCKS {
+            storage
+                .read_sectors(block * Self::SECTORS_PER_BLOCK, &mut sectors)
+                .map_err(|e| CreateSdStorageError::DeviceError(e.to_string()))?;
+            for (index, sector) in sectors.iter().enumerate() {
+                storage.mirror.load(
+                    block * Self::BLOCK_SIZE + index as u32 * Block::LEN_U32,
+                    &sector.contents,
+                );
+            }
+        }
+
+        let mut metadata_sectors = vec![Block::new(); METADATA_SECTORS as usize];
+        storage
+            .read_sectors(Self::DATA_SECTORS, &mut metadata_sectors)
+            .map_err(|e| CreateSdStorageError::DeviceError(e.to_string()))?;
+        let metadata_bytes: Vec<u8> = metadata_sectors
+            .iter()
+            .flat_map(|sector| sector.contents)
+            .collect();
+        let newest = metadata_bytes
+            .chunks(SLOT_SECTORS as usize * Block::LEN)
+            .filter_map(decode_slot)
+            .max_by_key(|area| area.sequence);
+        *storage.metadata.lock().unwrap() = newest.unwrap_or_default();
+
+        Ok(storage)
+    }
+
+    /// Read sectors relative to the start of the region
+    fn read_sectors(&self, sector: u32, sectors: &mut [Block]) -> Result<(), StorageError> {
+        self.device
+            .lock()
+            .map_err(|_| StorageError::Other("Failed to obtain lock to the block device".into()))?
+            .read(sectors, BlockIdx(self.start + sector), "rudelblinken")
+            .map_err(|e| StorageError::Other(format!("{:?}", e)))
+    }
+
+    /// Write sectors relative to the start of the region
+    fn write_sectors(&self, sector: u32, sectors: &[Block]) -> Result<(), StorageError> {
+        self.device
+            .lock()
+            .map_err(|_| StorageError::Other("Failed to obtain lock to the block device".into()))?
+            .write(sectors, BlockIdx(self.start + sector))
+            .map_err(|e| StorageError::Other(format!("{:?}", e)))
+    }
+
+    /// Write the mirrored content of the data sectors touched by a range back to the card
+    fn flush_range(&self, address: u32, length: u32) -> Result<(), StorageError> {
+        if length == 0 {
+            return Ok(());
+        }
+        let first_sector = address / Block::LEN_U32;
+        let last_sector = (address + length - 1) / Block::LEN_U32;
+        for sector in first_sector..=last_sector {
+            let sector = sector % Self::DATA_SECTORS;
+            let mut block = Block::new();
+            block
+                .contents
+                .copy_from_slice(self.mirror.read(sector * Block::LEN_U32, Block::LEN_U32));
+            self.write_sectors(sector, std::slice::from_ref(&block))?;
+        }
+        Ok(())
+    }
+
+    /// Write all metadata to the slot that was not used last
+    fn flush_metadata(&self, metadata: &MetadataArea) -> std::io::Result<()> {
+        let encoded = encode_slot(metadata)?;
+        let mut sectors = vec![Block::new(); SLOT_SECTORS as usize];
+        for (sector, chunk) in sectors.iter_mut().zip(encoded.chunks(Block::LEN)) {
+            sector.contents[..chunk.len()].copy_from_slice(chunk);
+        }
+        let slot = metadata.sequence % 2;
+        self.write_sectors(Self::DATA_SECTORS + slot * SLOT_SECTORS, &sectors)
+            .map_err(std::io::Error::other)
+    }
+}
+
+/// Encode a slot as `magic, sequence: u32, length: u32, metadata, crc32`
+///
+/// The CRC-32 covers everything before it.
+fn encode_slot(metadata: &MetadataArea) -> std::io::Result<Vec<u8>> {
+    let payload = encode_metadata(&metadata.values)?;
+    if SLOT_HEADER_LEN + payload.len() + SLOT_CRC_LEN > (SLOT_SECTORS * Block::LEN_U32) as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            "The metadata does not fit into the metadata area",
+        ));
+    }
+    let mut encoded = METADATA_MAGIC.to_vec();
+    encoded.extend_from_slice(&metadata.sequence.to_le_bytes());
+    encoded.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&payload);
+    encoded.extend_from_slice(&crc32(&encoded).to_le_bytes());
+    Ok(encoded)
+}
+
+/// Decode a slot written by [encode_slot]. Returns None for an uninitialized, torn or broken slot.
+fn decode_slot(bytes: &[u8]) -> Option<MetadataArea> {
+    let rest = bytes.strip_prefix(&METADATA_MAGIC)?;
+    let (sequence, rest) = rest.split_first_chunk::<4>()?;
+    let (length, rest) = rest.split_first_chunk::<4>()?;
+    let (payload, rest) = rest.split_at_checked(u32::from_le_bytes(*length) as usize)?;
+    let (crc, _) = rest.split_first_chunk::<4>()?;
+    let covered = &bytes[..SLOT_HEADER_LEN + payload.len()];
+    if crc32(covered) != u32::from_le_bytes(*crc) {
+        return None;
+    }
+    Some(MetadataArea {
+        values: decode_metadata(payload)?,
+        sequence: u32::from_le_bytes(*sequence),
+    })
+}
+
+/// Encode metadata as `count: u16, (key_length: u8, key, value_length: u16, value)*`
+///
+/// Fails with [std::io::ErrorKind::InvalidInput] if a key or a value is too long for its length field.
+fn encode_metadata(metadata: &BTreeMap<String, Box<[u8]>>) -> std::io::Result<Vec<u8>> {
+    let too_long = |what: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("The {what} is too long for the metadata area"),
+        )
+    };
+    let count = u16::try_from(metadata.len()).map_err(|_| too_long("number of keys"))?;
+    let mut encoded = count.to_le_bytes().to_vec();
+    for (key, value) in metadata {
+        let key_length = u8::try_from(key.len()).map_err(|_| too_long("key"))?;
+        let value_length = u16::try_from(value.len()).map_err(|_| too_long("value"))?;
+        encoded.push(key_length);
+        encoded.extend_from_slice(key.as_bytes());
+        encoded.extend_from_slice(&value_length.to_le_bytes());
+        encoded.extend_from_slice(value);
+    }
+    Ok(encoded)
+}
+
+/// Decode metadata written by [encode_metadata]. Returns None if it is broken.
+fn decode_metadata(bytes: &[u8]) -> Option<BTreeMap<String, Box<[u8]>>> {
+    let mut metadata = BTreeMap::new();
+    let (count, mut rest) = bytes.split_first_chunk::<2>()?;
+    for _ in 0..u16::from_le_bytes(*count) {
+        let (&key_length, after_length) = rest.split_first()?;
+        let (key, after_key) = after_length.split_at_checked(key_length as usize)?;
+        let (value_length, after_value_length) = after_key.split_first_chunk::<2>()?;
+        let (value, after_value) =
+            after_value_length.split_at_checked(u16::from_le_bytes(*value_length) as usize)?;
+        let key = std::str::from_utf8(key).ok()?;
+        metadata.insert(key.into(), value.into());
+        rest = after_value;
+    }
+    Some(metadata)
+}
+
+impl<D: BlockDevice, const BLOCKS: u32> Storage for SdStorage<D, BLOCKS> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = 4096;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        if address >= Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        if length > Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        Ok(self.mirror.read(address, length))
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        if address >= Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        let length = u32::try_from(data.len()).map_err(|_| StorageError::SizeTooBig)?;
+        if length > Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        self.mirror.write(address, data);
+        self.flush_range(address, length)
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        if length == 0 {
+            return Ok(());
+        }
+        if !address.is_multiple_of(Self::BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseAlongBlockBoundaries);
+        }
+        if !length.is_multiple_of(Self::BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseInBlockSizedChunks);
+        }
+        let end = address
+            .checked_add(length)
+            .ok_or(StorageError::SizeTooBig)?;
+        if end > Self::BLOCKS * Self::BLOCK_SIZE {
+            return Err(StorageError::SizeTooBig.into());
+        }
+        self.mirror.erase(address, length);
+        let erased = vec![
+            Block {
+                contents: [0xff; Block::LEN]
+            };
+            (length / Block::LEN_U32) as usize
+        ];
+        self.write_sectors(address / Block::LEN_U32, &erased)?;
+        Ok(())
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        self.metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to metadata"))?
+            .values
+            .get(key)
+            .cloned()
+            .ok_or(std::io::ErrorKind::NotFound.into())
+    }
+
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        let metadata = self
+            .metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to metadata"))?;
+        let value = metadata
+            .values
+            .get(key)
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        buf.clear();
+        buf.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        let mut metadata = self
+            .metadata
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to metadata"))?;
+        // Only keep the value if it was stored on the card
+        let mut updated = MetadataArea {
+            values: metadata.values.clone(),
+            sequence: metadata.sequence.wrapping_add(1),
+        };
+        updated.values.insert(key.into(), value.into());
+        self.flush_metadata(&updated)?;
+        *metadata = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Filesystem;
+    use embedded_sdmmc::BlockCount;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// A card in RAM that can lose power after a number of written sectors
+    #[derive(Clone)]
+    struct RamCard {
+        sectors: Arc<Mutex<Vec<Block>>>,
+        writes_left: Arc<AtomicUsize>,
+    }
+
+    impl RamCard {
+        fn new(sectors: usize) -> Self {
+            Self {
+                sectors: Arc::new(Mutex::new(vec![Block::new(); sectors])),
+                writes_left: Arc::new(AtomicUsize::new(usize::MAX)),
+            }
+        }
+    }
+
+    impl BlockDevice for RamCard {
+        type Error = &'static str;
+
+        fn read(
+            &self,
+            blocks: &mut [Block],
+            start: BlockIdx,
+            _reason: &str,
+        ) -> Result<(), Self::Error> {
+            let sectors = self.sectors.lock().unwrap();
+            for (index, block) in blocks.iter_mut().enumerate() {
+                *block = sectors[start.0 as usize + index].clone();
+            }
+            Ok(())
+        }
+
+        fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), Self::Error> {
+            let mut sectors = self.sectors.lock().unwrap();
+            for (index, block) in blocks.iter().enumerate() {
+                self.writes_left
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                        left.checked_sub(1)
+                    })
+                    .map_err(|_| "power lost")?;
+                sectors[start.0 as usize + index] = block.clone();
+            }
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+            Ok(BlockCount(self.sectors.lock().unwrap().len() as u32))
+        }
+    }
+
+    type TestStorage = SdStorage<RamCard, 4>;
+
+    /// A card with a region starting at sector 8
+    fn card() -> RamCard {
+        RamCard::new(8 + (4 * 8 + METADATA_SECTORS) as usize)
+    }
+
+    #[test]
+    fn metadata_round_trips_through_a_slot() {
+        let mut area = MetadataArea {
+            sequence: 7,
+            ..Default::default()
+        };
+        area.values.insert("first_block".into(), [3, 0].into());
+        area.values.insert("empty".into(), [].into());
+        let encoded = encode_slot(&area).unwrap();
+        let decoded = decode_slot(&encoded).unwrap();
+        assert_eq!(decoded.sequence, 7);
+        assert_eq!(decoded.values, area.values);
+
+        let mut torn = encoded.clone();
+        torn[SLOT_HEADER_LEN] ^= 1;
+        assert!(decode_slot(&torn).is_none());
+        assert!(decode_slot(&[0; 512]).is_none());
+    }
+
+    #[test]
+    fn oversized_metadata_is_rejected() {
+        let storage = TestStorage::new(card(), 8).unwrap();
+        let error = storage.write_metadata(&"k".repeat(256), &[1]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = storage.write_metadata("large", &[1; 70000]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Fits into the length field, but not into a slot
+        let error = storage.write_metadata("large", &[1; 5000]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+        assert!(storage.read_metadata("large").is_err());
+    }
+
+    #[test]
+    fn files_and_metadata_survive_a_remount() {
+        let card = card();
+        let storage = TestStorage::new(card.clone(), 8).unwrap();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("main", &[1; 5000], &[0u8; 32])
+            .unwrap();
+        filesystem.set_attr("main", "color", b"red").unwrap();
+        drop(filesystem);
+
+        let storage = TestStorage::new(card, 8).unwrap();
+        let filesystem = Filesystem::new(storage);
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 5000]);
+        assert_eq!(
+            filesystem.get_attr("main", "color").unwrap().as_deref(),
+            Some(&b"red"[..])
+        );
+    }
+
+    #[test]
+    fn a_torn_metadata_write_keeps_the_previous_metadata() {
+        let card = card();
+        let storage = TestStorage::new(card.clone(), 8).unwrap();
+        storage.write_metadata("key", b"old").unwrap();
+        storage.write_metadata("other", b"value").unwrap();
+
+        // Power is lost after the first sector of the next slot
+        card.writes_left.store(1, Ordering::SeqCst);
+        storage.write_metadata("key", &[2; 1000]).unwrap_err();
+        assert_eq!(&*storage.read_metadata("key").unwrap(), b"old");
+        drop(storage);
+
+        card.writes_left.store(usize::MAX, Ordering::SeqCst);
+        let storage = TestStorage::new(card, 8).unwrap();
+        assert_eq!(&*storage.read_metadata("key").unwrap(), b"old");
+        assert_eq!(&*storage.read_metadata("other").unwrap(), b"value");
+        storage.write_metadata("key", b"new").unwrap();
+        assert_eq!(&*storage.read_metadata("key").unwrap(), b"new");
+    }
+}
@@ -2,65 +2,149 @@
 
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use super::{EraseStorageError, Storage, StorageError};
+use super::{arena::RamArena, range_end, EraseStorageError, Storage, StorageError};
 
-#[derive(Debug)]
-#[repr(C, align(4096))]
-struct AlignedBuffer<const SIZE: usize>([u8; SIZE]);
+/// Counters of the operations on a [SimulatedStorage]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// Number of calls to [Storage::erase]
+    pub erase_calls: usize,
+    /// Number of blocks erased by all calls to [Storage::erase]
+    ///
+    /// Erases always cover whole blocks, so this is the wear caused by the calls, even if the data in
+    /// the erased blocks was shorter.
+    pub blocks_erased: usize,
+}
 
-#[derive(Debug)]
 /// A storage that is backed by a heap allocated buffer
 ///
 /// ```
 /// use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
 /// let storage = SimulatedStorage::new();
 /// ```
-pub struct SimulatedStorage {
-    pool: Box<AlignedBuffer<{ Self::SIZE as usize * 2 }>>,
-    pool_ptr: *mut [u8; Self::SIZE as usize * 2],
+pub type SimulatedStorage = SizedSimulatedStorage<16, 4096>;
+
+#[derive(Debug)]
+/// A [SimulatedStorage] with `BLOCKS` blocks of `BLOCK_SIZE` bytes
+///
+/// ```
+/// use rudelblinken_filesystem::storage::simulated::SizedSimulatedStorage;
+/// // Flash with 64 KiB erase blocks
+/// let storage = SizedSimulatedStorage::<4, 65536>::new();
+/// ```
+pub struct SizedSimulatedStorage<const BLOCKS: u32, const BLOCK_SIZE: u32> {
+    arena: RamArena,
     key_value: Arc<Mutex<HashMap<String, Box<[u8]>>>>,
+    /// Number of modifying operations until the power is cut. `usize::MAX` means no limit.
+    power_budget: AtomicUsize,
+    /// Maximum number of metadata keys. `usize::MAX` means no limit.
+    metadata_capacity: AtomicUsize,
+    stats: Mutex<StorageStats>,
+    /// Reject writes that need to set bits, see [SizedSimulatedStorage::set_strict]
+    strict: AtomicBool,
+    /// Number of erases of every block since the storage was created
+    erase_counts: Box<[AtomicU32]>,
+    /// Blocks that can not be erased anymore, see [SizedSimulatedStorage::wear_out]
+    worn_out: Box<[AtomicBool]>,
 }
 
-unsafe impl Send for SimulatedStorage {}
-unsafe impl Sync for SimulatedStorage {}
-
-impl Default for SimulatedStorage {
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Default
+    for SizedSimulatedStorage<BLOCKS, BLOCK_SIZE>
+{
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl SimulatedStorage {
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> SizedSimulatedStorage<BLOCKS, BLOCK_SIZE> {
     /// Size of the storage
-    pub const SIZE: u32 = Self::BLOCKS * Self::BLOCK_SIZE;
+    pub const SIZE: u32 = BLOCKS * BLOCK_SIZE;
 
     /// Create a new storage for testing purposes
-    pub fn new() -> SimulatedStorage {
-        let mut pool = Box::new(AlignedBuffer([0b11111111u8; Self::SIZE as usize * 2]));
-        SimulatedStorage {
-            pool_ptr: &mut (pool.0),
-            pool,
+    pub fn new() -> Self {
+        Self {
+            arena: RamArena::new(Self::SIZE),
             key_value: Default::default(),
+            power_budget: AtomicUsize::new(usize::MAX),
+            metadata_capacity: AtomicUsize::new(usize::MAX),
+            stats: Default::default(),
+            strict: AtomicBool::new(cfg!(debug_assertions)),
+            erase_counts: (0..BLOCKS).map(|_| AtomicU32::new(0)).collect(),
+            worn_out: (0..BLOCKS).map(|_| AtomicBool::new(false)).collect(),
         }
     }
-}
 
-/// Copies zeroes from src to dest and ignores ones in src.
-fn copy_zeroes_from_slice(dest: &mut [u8], src: &[u8]) {
-    let new_data: Vec<u8> = src
-        .iter()
-        .zip(dest.iter())
-        .map(|(src, dest)| src & dest)
-        .collect();
-    dest.copy_from_slice(&new_data);
+    /// Simulate a power loss after the given number of writes, erases and metadata writes
+    ///
+    /// All later modifying operations are silently dropped, but still report success. Use this to test
+    /// what a filesystem mounted from the storage looks like when power is lost at any point.
+    pub fn cut_power_after(&self, operations: usize) {
+        self.power_budget.store(operations, Ordering::SeqCst);
+    }
+
+    /// Undo [SizedSimulatedStorage::cut_power_after]
+    pub fn restore_power(&self) {
+        self.power_budget.store(usize::MAX, Ordering::SeqCst);
+    }
+
+    /// Limit the number of metadata keys, like a full NVS partition
+    ///
+    /// Writing a new key beyond the limit fails with [StorageError::metadata_full]. Existing keys can
+    /// still be overwritten.
+    pub fn set_metadata_capacity(&self, keys: usize) {
+        self.metadata_capacity.store(keys, Ordering::SeqCst);
+    }
+
+    /// Reject writes to bytes that were not erased
+    ///
+    /// Real flash silently stores the AND of the old and the new data in that case, which hides
+    /// allocator bugs. In strict mode, writes that would need to set a bit fail with
+    /// [StorageError::WriteOverDirty] and nothing is written. Clearing more bits of programmed bytes, like
+    /// setting a flag, is still allowed. Enabled by default in debug builds.
+    pub fn set_strict(&self, strict: bool) {
+        self.strict.store(strict, Ordering::SeqCst);
+    }
+
+    /// Make every erase that covers the given block fail, like on flash that is worn out
+    ///
+    /// The failing erase does not change any block.
+    pub fn wear_out(&self, block: u32) {
+        self.worn_out[block as usize].store(true, Ordering::SeqCst);
+    }
+
+    /// Counters of the operations since the storage was created or the last [SizedSimulatedStorage::reset_stats]
+    pub fn stats(&self) -> StorageStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Reset the counters returned by [SizedSimulatedStorage::stats]
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = StorageStats::default();
+    }
+
+    /// Consume one operation of the power budget. Returns false if the power is already gone.
+    fn has_power(&self) -> bool {
+        self.power_budget
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |budget| match budget {
+                usize::MAX => Some(usize::MAX),
+                0 => None,
+                budget => Some(budget - 1),
+            })
+            .is_ok()
+    }
 }
 
-impl Storage for SimulatedStorage {
-    const BLOCKS: u32 = 16;
-    const BLOCK_SIZE: u32 = 4096;
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Storage
+    for SizedSimulatedStorage<BLOCKS, BLOCK_SIZE>
+{
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
 
     fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
         if address >= Self::SIZE {
@@ -69,42 +153,26 @@ impl Storage for SimulatedStorage {
         if length >= Self::SIZE {
             return Err(StorageError::SizeTooBig);
         }
-        let static_slice = unsafe {
-            std::mem::transmute::<&[u8], &'static [u8]>(
-                &self.pool.0[address as usize..(address + length) as usize],
-            )
-        };
-
-        Ok(static_slice)
+        Ok(self.arena.read(address, length))
     }
 
     fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
         if address >= Self::SIZE {
             return Err(StorageError::AddressTooBig);
         }
-        if data.len() as u32 >= Self::SIZE {
+        let length = u32::try_from(data.len()).map_err(|_| StorageError::SizeTooBig)?;
+        if length >= Self::SIZE {
             return Err(StorageError::SizeTooBig);
         }
-        let pool = unsafe { &mut *self.pool_ptr };
-
-        copy_zeroes_from_slice(
-            &mut pool[address as usize..address as usize + data.len()],
-            data,
-        );
-        // The part of the data that is overlapping
-        let overlapping_length = (address + data.len() as u32).saturating_sub(Self::SIZE);
-        let nonoverlapping_length = data.len() as u32 - overlapping_length;
-
-        copy_zeroes_from_slice(
-            &mut pool[(Self::SIZE + address) as usize
-                ..((Self::SIZE + address) + nonoverlapping_length) as usize],
-            &data[0..nonoverlapping_length as usize],
-        );
-        if overlapping_length > 0 {
-            copy_zeroes_from_slice(
-                &mut pool[0..overlapping_length as usize],
-                &data[data.len() - (overlapping_length as usize)..data.len()],
-            );
+        if self.strict.load(Ordering::SeqCst) {
+            if let Some(offset) = self.arena.find_dirty(address, data) {
+                return Err(StorageError::WriteOverDirty {
+                    offset: (address + offset) % Self::SIZE,
+                });
+            }
+        }
+        if self.has_power() {
+            self.arena.write(address, data);
         }
         Ok(())
     }
@@ -113,20 +181,37 @@ impl Storage for SimulatedStorage {
         if address % Self::BLOCK_SIZE != 0 || length % Self::BLOCK_SIZE != 0 {
             return Err(EraseStorageError::SizeNotAMultipleOfPageSize);
         }
-        if (address + length) > Self::BLOCKS * Self::BLOCK_SIZE {
-            return Err(EraseStorageError::SizeNotAMultipleOfPageSize);
+        let end = range_end(address, length, Self::SIZE)?;
+        if let Some(block) = (address / BLOCK_SIZE..end / BLOCK_SIZE)
+            .find(|block| self.worn_out[*block as usize].load(Ordering::SeqCst))
+        {
+            return Err(StorageError::IoError(std::io::Error::other(format!(
+                "block {block} is worn out"
+            )))
+            .into());
         }
-        let pool = unsafe { &mut *self.pool_ptr };
-
-        let number_of_blocks = length.div_ceil(Self::BLOCK_SIZE);
-        for block in 0..number_of_blocks {
-            let base_address = address + block * Self::BLOCK_SIZE;
-            pool[base_address as usize..(base_address + Self::BLOCK_SIZE) as usize]
-                .copy_from_slice(&[0b11111111u8; Self::BLOCK_SIZE as usize]);
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.erase_calls += 1;
+            stats.blocks_erased += (length / BLOCK_SIZE) as usize;
+        }
+        if self.has_power() {
+            self.arena.erase(address, length);
+            for block in address / BLOCK_SIZE..end / BLOCK_SIZE {
+                self.erase_counts[block as usize].fetch_add(1, Ordering::Relaxed);
+            }
         }
         Ok(())
     }
 
+    fn erase_count(&self, block: u32) -> Option<u32> {
+        Some(
+            self.erase_counts
+                .get(block as usize)?
+                .load(Ordering::Relaxed),
+        )
+    }
+
     fn read_metadata(&self, key: &str) -> Result<Box<[u8]>, std::io::Error> {
         return self
             .key_value
@@ -137,10 +222,32 @@ impl Storage for SimulatedStorage {
     }
 
     fn write_metadata(&self, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
-        self.key_value
+        if !self.has_power() {
+            return Ok(());
+        }
+        let mut key_value = self
+            .key_value
             .lock()
-            .map_err(|_| std::io::Error::other("Failed to lock mutex"))?
-            .insert(key.into(), value.into());
+            .map_err(|_| std::io::Error::other("Failed to lock mutex"))?;
+        if !key_value.contains_key(key)
+            && key_value.len() >= self.metadata_capacity.load(Ordering::SeqCst)
+        {
+            return Err(StorageError::metadata_full());
+        }
+        key_value.insert(key.into(), value.into());
+        Ok(())
+    }
+
+    fn read_metadata_into(&self, key: &str, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        let key_value = self
+            .key_value
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to lock mutex"))?;
+        let value = key_value
+            .get(key)
+            .ok_or(std::io::Error::other("Failed to get a key for that value"))?;
+        buf.clear();
+        buf.extend_from_slice(value);
         Ok(())
     }
 }
@@ -160,3 +267,162 @@ pub(crate) fn get_test_storage() -> &'static SimulatedStorage {
     let backing_storage: &'static SimulatedStorage = unsafe { &*backing_storage_ptr };
     return backing_storage;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_near_the_end_of_the_address_space_fails() {
+        let storage = SimulatedStorage::new();
+        let result = storage.read(u32::MAX - 1, 10);
+        assert!(matches!(result, Err(StorageError::AddressTooBig)));
+    }
+
+    #[test]
+    fn write_near_the_end_of_the_address_space_fails() {
+        let storage = SimulatedStorage::new();
+        let result = storage.write(u32::MAX - 1, &[0; 10]);
+        assert!(matches!(result, Err(StorageError::AddressTooBig)));
+    }
+
+    #[test]
+    fn read_metadata_into_reuses_the_buffer() {
+        let storage = SimulatedStorage::new();
+        let large_value: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        storage.write_metadata("large", &large_value).unwrap();
+        storage.write_metadata("small", &[1, 2, 3]).unwrap();
+
+        let mut buffer = Vec::new();
+        storage.read_metadata_into("large", &mut buffer).unwrap();
+        assert_eq!(buffer, large_value);
+        let capacity = buffer.capacity();
+        storage.read_metadata_into("small", &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert_eq!(buffer.capacity(), capacity);
+        storage
+            .read_metadata_into("missing", &mut buffer)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn read_into_wraps_around_the_end() {
+        let storage = SimulatedStorage::new();
+        storage.write(SimulatedStorage::SIZE - 2, &[1, 2]).unwrap();
+        storage.write(0, &[3, 4]).unwrap();
+        let mut buffer = [0u8; 4];
+        storage
+            .read_into(SimulatedStorage::SIZE - 2, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn operations_after_a_power_cut_are_dropped() {
+        let storage = SimulatedStorage::new();
+        storage.cut_power_after(1);
+        storage.write(0, &[1]).unwrap();
+        storage.write(1, &[2]).unwrap();
+        storage.write_metadata("key", &[3]).unwrap();
+        assert_eq!(storage.read(0, 2).unwrap(), [1, 0xff]);
+        storage.read_metadata("key").unwrap_err();
+
+        storage.restore_power();
+        storage.write(1, &[2]).unwrap();
+        assert_eq!(storage.read(0, 2).unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn strict_mode_rejects_writes_over_data_that_was_not_erased() {
+        let storage = SimulatedStorage::new();
+        storage.set_strict(true);
+        storage.write(100, &[0x0f, 0xff]).unwrap();
+        // Clearing more bits is fine
+        storage.write(100, &[0x03]).unwrap();
+        assert!(matches!(
+            storage.write(99, &[0x00, 0x0c, 0x00]),
+            Err(StorageError::WriteOverDirty { offset: 100 })
+        ));
+        assert_eq!(storage.read(99, 3).unwrap(), [0xff, 0x03, 0xff]);
+
+        // Writes that wrap around report the address in the storage
+        storage.write(0, &[0x00]).unwrap();
+        assert!(matches!(
+            storage.write(SimulatedStorage::SIZE - 1, &[0x00, 0x01]),
+            Err(StorageError::WriteOverDirty { offset: 0 })
+        ));
+
+        storage.set_strict(false);
+        storage.write(100, &[0xf0]).unwrap();
+        assert_eq!(storage.read(100, 1).unwrap(), [0x00]);
+    }
+
+    #[test]
+    fn read_longer_than_the_storage_fails() {
+        let storage = SimulatedStorage::new();
+        let result = storage.read(4096, SimulatedStorage::SIZE);
+        assert!(matches!(result, Err(StorageError::SizeTooBig)));
+        let result = storage.read(4096, u32::MAX);
+        assert!(matches!(result, Err(StorageError::SizeTooBig)));
+    }
+
+    #[test]
+    fn write_longer_than_the_storage_fails() {
+        let storage = SimulatedStorage::new();
+        let data = vec![0; SimulatedStorage::SIZE as usize];
+        let result = storage.write(4096, &data);
+        assert!(matches!(result, Err(StorageError::SizeTooBig)));
+    }
+
+    #[test]
+    fn erase_past_the_end_of_the_storage_fails() {
+        let storage = SimulatedStorage::new();
+        let last_block = SimulatedStorage::SIZE - SimulatedStorage::BLOCK_SIZE;
+        let result = storage.erase(last_block, SimulatedStorage::BLOCK_SIZE * 2);
+        assert!(matches!(
+            result,
+            Err(EraseStorageError::StorageError(StorageError::SizeTooBig))
+        ));
+    }
+
+    #[test]
+    fn erase_with_an_overflowing_length_fails() {
+        let storage = SimulatedStorage::new();
+        let length = u32::MAX - (SimulatedStorage::BLOCK_SIZE - 1);
+        let result = storage.erase(SimulatedStorage::BLOCK_SIZE, length);
+        assert!(matches!(
+            result,
+            Err(EraseStorageError::StorageError(StorageError::SizeTooBig))
+        ));
+    }
+
+    #[test]
+    fn erasing_a_worn_out_block_fails() {
+        let storage = SimulatedStorage::new();
+        storage.write(SimulatedStorage::BLOCK_SIZE, &[0]).unwrap();
+        storage.wear_out(2);
+        let result = storage.erase(
+            SimulatedStorage::BLOCK_SIZE,
+            SimulatedStorage::BLOCK_SIZE * 2,
+        );
+        assert!(matches!(
+            result,
+            Err(EraseStorageError::StorageError(StorageError::IoError(_)))
+        ));
+        assert_eq!(storage.read(SimulatedStorage::BLOCK_SIZE, 1).unwrap(), [0]);
+        storage
+            .erase(SimulatedStorage::BLOCK_SIZE, SimulatedStorage::BLOCK_SIZE)
+            .unwrap();
+    }
+
+    #[test]
+    fn erase_with_overflowing_end_fails() {
+        let storage = SimulatedStorage::new();
+        let address = u32::MAX - (SimulatedStorage::BLOCK_SIZE - 1);
+        let result = storage.erase(address, SimulatedStorage::BLOCK_SIZE * 2);
+        assert!(matches!(
+            result,
+            Err(EraseStorageError::StorageError(StorageError::SizeTooBig))
+        ));
+    }
+}
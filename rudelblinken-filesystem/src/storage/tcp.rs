@@ -0,0 +1,546 @@
+//! Storage implementation that forwards to a server over TCP
+//!
+//! [TcpStorage] lets the firmware run against flash that is owned by another process, for example
+//! a server used by an integration test harness to snapshot and inspect the content. A reference
+//! server is in `examples/tcp_storage_server.rs`.
+//!
+//! # Protocol
+//!
+//! The client sends a request and waits for the response before sending the next one. Every
+//! message is a frame: a little endian `u32` with the length of the payload, followed by the
+//! payload. All integers are little endian.
+//!
+//! A request payload starts with an opcode:
+//!
+//! | Opcode | Request        | Fields                                               | Response payload               |
+//! |--------|----------------|------------------------------------------------------|--------------------------------|
+//! | `0x01` | Geometry       |                                                      | `blocks: u32, block_size: u32` |
+//! | `0x02` | Read           | `address: u32, length: u32`                          | the data                       |
+//! | `0x03` | Write          | `address: u32`, data until the end of the frame      | empty                          |
+//! | `0x04` | Erase          | `address: u32, length: u32`                          | empty                          |
+//! | `0x05` | Read metadata  | key until the end of the frame                       | the value                      |
+//! | `0x06` | Write metadata | `key_length: u8`, key, value until the end of frame  | empty                          |
+//!
+//! Reads and writes wrap around the end of the storage. Writes only clear bits, like on flash.
+//!
+//! A response payload starts with a [Status] byte. On success the rest is the payload from the table
+//! above, otherwise it is a UTF-8 error message.
+//!
+//! # Differences to memory mapped flash
+//!
+//! Like [SdStorage](super::sdmmc::SdStorage), the storage is mirrored in RAM, because [Storage::read]
+//! returns slices that borrow the storage. The mirror is loaded when connecting, and every write and
+//! erase is sent to the server before it is applied to the mirror. Nothing else may modify the
+//! storage on the server while the client is connected, as the mirror would not notice.
+
+use super::{arena::RamArena, EraseStorageError, Storage, StorageError};
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+};
+use thiserror::Error;
+
+/// Frames larger than this are rejected, so a broken peer can not make us allocate arbitrary amounts
+const MAX_FRAME_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// Read a length prefixed frame
+pub fn read_frame(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length);
+    if length > MAX_FRAME_LENGTH {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes is too large", length),
+        ));
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Write a length prefixed frame
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+    let length = u32::try_from(payload.len())
+        .ok()
+        .filter(|length| *length <= MAX_FRAME_LENGTH)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Frame too large"))?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// A request from the client to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Ask for the number and size of blocks
+    Geometry,
+    /// Read `length` bytes starting at `address`
+    Read {
+        /// Start of the range
+        address: u32,
+        /// Number of bytes
+        length: u32,
+    },
+    /// Write `data` starting at `address`
+    Write {
+        /// Start of the range
+        address: u32,
+        /// Data to write
+        data: Vec<u8>,
+    },
+    /// Erase `length` bytes starting at `address`, both aligned to blocks
+    Erase {
+        /// Start of the range
+        address: u32,
+        /// Number of bytes
+        length: u32,
+    },
+    /// Read a metadata key
+    ReadMetadata {
+        /// The key
+        key: String,
+    },
+    /// Write a metadata key
+    WriteMetadata {
+        /// The key, at most 255 bytes
+        key: String,
+        /// The new value
+        value: Vec<u8>,
+    },
+}
+
+impl Request {
+    /// Encode the request as frame payload
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Request::Geometry => payload.push(0x01),
+            Request::Read { address, length } => {
+                payload.push(0x02);
+                payload.extend_from_slice(&address.to_le_bytes());
+                payload.extend_from_slice(&length.to_le_bytes());
+            }
+            Request::Write { address, data } => {
+                payload.push(0x03);
+                payload.extend_from_slice(&address.to_le_bytes());
+                payload.extend_from_slice(data);
+            }
+            Request::Erase { address, length } => {
+                payload.push(0x04);
+                payload.extend_from_slice(&address.to_le_bytes());
+                payload.extend_from_slice(&length.to_le_bytes());
+            }
+            Request::ReadMetadata { key } => {
+                payload.push(0x05);
+                payload.extend_from_slice(key.as_bytes());
+            }
+            Request::WriteMetadata { key, value } => {
+                payload.push(0x06);
+                payload.push(key.len() as u8);
+                payload.extend_from_slice(key.as_bytes());
+                payload.extend_from_slice(value);
+            }
+        }
+        payload
+    }
+
+    /// Decode a request from a frame payload
+    pub fn decode(payload: &[u8]) -> std::io::Result<Self> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed request");
+        let u32_at = |offset: usize| -> std::io::Result<u32> {
+            payload
+                .get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(invalid)
+        };
+        let (opcode, rest) = payload.split_first().ok_or_else(invalid)?;
+        Ok(match opcode {
+            0x01 => Request::Geometry,
+            0x02 => Request::Read {
+                address: u32_at(1)?,
+                length: u32_at(5)?,
+            },
+            0x03 => Request::Write {
+                address: u32_at(1)?,
+                data: payload[5..].to_vec(),
+            },
+            0x04 => Request::Erase {
+                address: u32_at(1)?,
+                length: u32_at(5)?,
+            },
+            0x05 => Request::ReadMetadata {
+                key: String::from_utf8(rest.to_vec()).map_err(|_| invalid())?,
+            },
+            0x06 => {
+                let (key_length, rest) = rest.split_first().ok_or_else(invalid)?;
+                let (key, value) = rest
+                    .split_at_checked(*key_length as usize)
+                    .ok_or_else(invalid)?;
+                Request::WriteMetadata {
+                    key: String::from_utf8(key.to_vec()).map_err(|_| invalid())?,
+                    value: value.to_vec(),
+                }
+            }
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// Status byte at the start of every response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    /// The request succeeded
+    Ok = 0,
+    /// The request failed
+    Error = 1,
+    /// The metadata key does not exist
+    NotFound = 2,
+    /// There is no space left for the metadata value
+    MetadataFull = 3,
+}
+
+/// A response from the server to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// Whether the request succeeded
+    pub status: Status,
+    /// The requested data, or an error message
+    pub payload: Vec<u8>,
+}
+
+impl Response {
+    /// A successful response
+    pub fn ok(payload: Vec<u8>) -> Self {
+        Self {
+            status: Status::Ok,
+            payload,
+        }
+    }
+
+    /// A failed response
+    pub fn error(status: Status, message: &str) -> Self {
+        Self {
+            status,
+            payload: message.as_bytes().to_vec(),
+        }
+    }
+
+    /// Encode the response as frame payload
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(1 + self.payload.len());
+        payload.push(self.status as u8);
+        payload.extend_from_slice(&self.payload);
+        payload
+    }
+
+    /// Decode a response from a frame payload
+    pub fn decode(payload: &[u8]) -> std::io::Result<Self> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed response");
+        let (status, rest) = payload.split_first().ok_or_else(invalid)?;
+        let status = match status {
+            0 => Status::Ok,
+            1 => Status::Error,
+            2 => Status::NotFound,
+            3 => Status::MetadataFull,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            status,
+            payload: rest.to_vec(),
+        })
+    }
+}
+
+/// An error while connecting to a storage server
+#[derive(Error, Debug)]
+pub enum ConnectTcpStorageError {
+    /// The connection failed
+    #[error("Failed to talk to the storage server: {0}")]
+    Io(#[from] std::io::Error),
+    /// The server does not have the expected number or size of blocks
+    #[error("The server has {blocks} blocks of {block_size} bytes, which does not match the expected geometry")]
+    GeometryMismatch {
+        /// Number of blocks of the server
+        blocks: u32,
+        /// Block size of the server
+        block_size: u32,
+    },
+}
+
+/// A storage implementation that forwards to a server over TCP
+///
+/// `BLOCKS` and `BLOCK_SIZE` need to match the geometry of the server.
+pub struct TcpStorage<const BLOCKS: u32, const BLOCK_SIZE: u32> {
+    stream: Mutex<TcpStream>,
+    /// RAM mirror of the storage on the server
+    mirror: RamArena,
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> TcpStorage<BLOCKS, BLOCK_SIZE> {
+    /// Size of the storage
+    const SIZE: u32 = BLOCKS * BLOCK_SIZE;
+
+    /// Connect to a server and load its storage into RAM
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self, ConnectTcpStorageError> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let storage = Self {
+            stream: Mutex::new(stream),
+            mirror: RamArena::new(Self::SIZE),
+        };
+
+        let geometry = storage.request(&Request::Geometry)?;
+        let (Some(blocks), Some(block_size)) = (geometry.get(0..4), geometry.get(4..8)) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Malformed geometry response",
+            )
+            .into());
+        };
+        let blocks = u32::from_le_bytes(blocks.try_into().unwrap());
+        let block_size = u32::from_le_bytes(block_size.try_into().unwrap());
+        if blocks != BLOCKS || block_size != BLOCK_SIZE {
+            return Err(ConnectTcpStorageError::GeometryMismatch { blocks, block_size });
+        }
+
+        for block in 0..BLOCKS {
+            let address = block * BLOCK_SIZE;
+            let data = storage.request(&Request::Read {
+                address,
+                length: BLOCK_SIZE,
+            })?;
+            if data.len() != BLOCK_SIZE as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "The server returned the wrong amount of data",
+                )
+                .into());
+            }
+            storage.mirror.load(address, &data);
+        }
+
+        Ok(storage)
+    }
+
+    /// Send a request and wait for the response. Returns the payload of a successful response.
+    fn request(&self, request: &Request) -> std::io::Result<Vec<u8>> {
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| std::io::Error::other("Failed to obtain lock to the connection"))?;
+        write_frame(&mut *stream, &request.encode())?;
+        let response = Response::decode(&read_frame(&mut *stream)?)?;
+        let message = || String::from_utf8_lossy(&response.payload).into_owned();
+        match response.status {
+            Status::Ok => Ok(response.payload),
+            Status::Error => Err(std::io::Error::other(message())),
+            Status::NotFound => Err(std::io::Error::new(std::io::ErrorKind::NotFound, message())),
+            Status::MetadataFull => Err(StorageError::metadata_full()),
+        }
+    }
+}
+
+impl<const BLOCKS: u32, const BLOCK_SIZE: u32> Storage for TcpStorage<BLOCKS, BLOCK_SIZE> {
+    const BLOCKS: u32 = BLOCKS;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        if address >= Self::SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        if length > Self::SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        Ok(self.mirror.read(address, length))
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        if address >= Self::SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        if data.len() > Self::SIZE as usize {
+            return Err(StorageError::SizeTooBig);
+        }
+        self.request(&Request::Write {
+            address,
+            data: data.to_vec(),
+        })?;
+        self.mirror.write(address, data);
+        Ok(())
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        if !address.is_multiple_of(BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseAlongBlockBoundaries);
+        }
+        if !length.is_multiple_of(BLOCK_SIZE) {
+            return Err(EraseStorageError::CanOnlyEraseInBlockSizedChunks);
+        }
+        if address
+            .checked_add(length)
+            .is_none_or(|end| end > Self::SIZE)
+        {
+            return Err(StorageError::SizeTooBig.into());
+        }
+        self.request(&Request::Erase { address, length })
+            .map_err(StorageError::from)?;
+        self.mirror.erase(address, length);
+        Ok(())
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        Ok(self
+            .request(&Request::ReadMetadata { key: key.into() })?
+            .into_boxed_slice())
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        if key.len() > u8::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Metadata keys can be at most 255 bytes long",
+            ));
+        }
+        self.request(&Request::WriteMetadata {
+            key: key.into(),
+            value: value.to_vec(),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Filesystem;
+    use std::{collections::HashMap, net::TcpListener};
+
+    /// Serve `connections` connections after each other with a storage of 16 blocks of 4096 bytes.
+    /// Returns the content after the last client disconnected.
+    fn spawn_server(
+        connections: usize,
+    ) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut flash = vec![0xffu8; 16 * 4096];
+            let mut metadata = HashMap::new();
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                while let Ok(payload) = read_frame(&mut stream) {
+                    let response = match Request::decode(&payload).unwrap() {
+                        Request::Geometry => {
+                            Response::ok([16u32.to_le_bytes(), 4096u32.to_le_bytes()].concat())
+                        }
+                        Request::Read { address, length } => Response::ok(
+                            (address..address + length)
+                                .map(|address| flash[address as usize % flash.len()])
+                                .collect(),
+                        ),
+                        Request::Write { address, data } => {
+                            for (offset, byte) in data.iter().enumerate() {
+                                let index = (address as usize + offset) % flash.len();
+                                flash[index] &= byte;
+                            }
+                            Response::ok(Vec::new())
+                        }
+                        Request::Erase { address, length } => {
+                            flash[address as usize..(address + length) as usize].fill(0xff);
+                            Response::ok(Vec::new())
+                        }
+                        Request::ReadMetadata { key } => match metadata.get(&key) {
+                            Some(value) => Response::ok(Vec::clone(value)),
+                            None => Response::error(Status::NotFound, "No such key"),
+                        },
+                        Request::WriteMetadata { key, value } => {
+                            metadata.insert(key, value);
+                            Response::ok(Vec::new())
+                        }
+                    };
+                    write_frame(&mut stream, &response.encode()).unwrap();
+                }
+            }
+            flash
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn requests_survive_encoding() {
+        for request in [
+            Request::Geometry,
+            Request::Read {
+                address: 4096,
+                length: 12,
+            },
+            Request::Write {
+                address: 3,
+                data: vec![1, 2, 3],
+            },
+            Request::Erase {
+                address: 0,
+                length: 8192,
+            },
+            Request::ReadMetadata { key: "key".into() },
+            Request::WriteMetadata {
+                key: "key".into(),
+                value: vec![4, 5],
+            },
+        ] {
+            assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+        assert!(Request::decode(&[0x02, 1, 2]).is_err());
+        assert!(Request::decode(&[0x06, 10, b'a']).is_err());
+    }
+
+    /// Connect to the server and disconnect again when the filesystem is done
+    fn connect(address: std::net::SocketAddr) -> &'static TcpStorage<16, 4096> {
+        Box::leak(Box::new(TcpStorage::connect(address).unwrap()))
+    }
+
+    fn disconnect(storage: &TcpStorage<16, 4096>) {
+        let stream = storage.stream.lock().unwrap();
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+    }
+
+    #[test]
+    fn the_content_stays_on_the_server_between_connections() {
+        let (address, server) = spawn_server(2);
+        let storage = connect(address);
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 5000], &[0; 32]).unwrap();
+        drop(filesystem);
+        disconnect(storage);
+
+        let storage = connect(address);
+        let filesystem = Filesystem::new(storage);
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 5000]);
+        let error = storage.read_metadata("missing").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        drop(main);
+        drop(filesystem);
+        disconnect(storage);
+
+        let flash = server.join().unwrap();
+        assert!(flash.windows(5000).any(|window| window == [1; 5000]));
+    }
+
+    #[test]
+    fn a_server_with_a_different_geometry_is_rejected() {
+        let (address, server) = spawn_server(1);
+        let result = TcpStorage::<32, 4096>::connect(address);
+        assert!(matches!(
+            result,
+            Err(ConnectTcpStorageError::GeometryMismatch {
+                blocks: 16,
+                block_size: 4096
+            })
+        ));
+        drop(result);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+//! Map a flash region twice in a row
+//!
+//! Files can wrap around the end of the storage. To hand them out as a single slice, the storage is
+//! mapped twice into the address space, directly after each other. Reading past the end of the first
+//! copy continues at the start of the second copy, which is the start of the storage again.
+//!
+//! The ESP-IDF has no API to request a specific virtual address. This relies on how its MMU driver
+//! assigns addresses instead:
+//!
+//! - New mappings are placed at the lowest free virtual address that is large enough, so mappings
+//!   created one after another end up next to each other.
+//! - Requesting a range that is already mapped exactly like that returns the existing mapping instead
+//!   of creating a new one.
+//!
+//! So the region is first mapped as two parts, the first page and the remaining pages. They end up next
+//! to each other. Then the whole region is mapped at once. No existing mapping matches that range, so a
+//! new one is created directly after the first two. The result is checked after every step, because
+//! the assumptions break if something else maps flash at the same time or if the address space is
+//! fragmented.
+//!
+//! All mappings need to stay alive as long as the storage is used. The handles are returned for that
+//! purpose.
+
+use thiserror::Error;
+
+/// An error while mapping the region twice
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WrappingMapError {
+    /// One of the mappings failed
+    #[error("Failed to map the region")]
+    MapFailed,
+    /// The mappings did not end up next to each other
+    #[error("The mappings are not contiguous")]
+    NotContiguous,
+}
+
+/// Map a region of `length` bytes twice in a row.
+///
+/// `length` needs to be a multiple of `page_size`. `map(offset, length)` maps a part of the region and
+/// returns the virtual address and a handle of the mapping. Returns the address of the first copy and
+/// the handles of the three mappings.
+///
+/// A region of a single page can not be split into two parts, so mapping it again would only return
+/// the first mapping. This fails with [WrappingMapError::NotContiguous].
+pub(crate) fn map_wrapping<H>(
+    length: usize,
+    page_size: usize,
+    mut map: impl FnMut(usize, usize) -> Result<(usize, H), WrappingMapError>,
+) -> Result<(usize, [H; 3]), WrappingMapError> {
+    debug_assert!(length.is_multiple_of(page_size));
+    if length <= page_size {
+        return Err(WrappingMapError::NotContiguous);
+    }
+
+    let (first_address, first_handle) = map(0, page_size)?;
+    let (rest_address, rest_handle) = map(page_size, length - page_size)?;
+    if rest_address != first_address + page_size {
+        return Err(WrappingMapError::NotContiguous);
+    }
+    // Mapping the whole region in one step at the start would have returned the first mapping again
+    let (second_copy_address, second_copy_handle) = map(0, length)?;
+    if second_copy_address != first_address + length {
+        return Err(WrappingMapError::NotContiguous);
+    }
+    Ok((
+        first_address,
+        [first_handle, rest_handle, second_copy_handle],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 0x10000;
+
+    /// Behaves like the MMU driver: reuses identical mappings and places new ones after the last one
+    struct FakeMmu {
+        next_address: usize,
+        mappings: Vec<(usize, usize, usize)>,
+    }
+
+    impl FakeMmu {
+        fn new() -> Self {
+            Self {
+                next_address: 0x3c00_0000,
+                mappings: Vec::new(),
+            }
+        }
+
+        fn map(&mut self, offset: usize, length: usize) -> Result<(usize, u32), WrappingMapError> {
+            if let Some(index) = self
+                .mappings
+                .iter()
+                .position(|mapping| mapping.0 == offset && mapping.1 == length)
+            {
+                return Ok((self.mappings[index].2, index as u32 + 1));
+            }
+            let address = self.next_address;
+            self.next_address += length;
+            self.mappings.push((offset, length, address));
+            Ok((address, self.mappings.len() as u32))
+        }
+    }
+
+    #[test]
+    fn maps_the_region_twice_in_a_row() {
+        let mut mmu = FakeMmu::new();
+        let (address, handles) = map_wrapping(4 * PAGE_SIZE, PAGE_SIZE, |offset, length| {
+            mmu.map(offset, length)
+        })
+        .unwrap();
+        assert_eq!(address, 0x3c00_0000);
+        assert_eq!(handles, [1, 2, 3]);
+        assert_eq!(mmu.mappings[2], (0, 4 * PAGE_SIZE, address + 4 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn rejects_a_single_page() {
+        let mut mmu = FakeMmu::new();
+        let result = map_wrapping(PAGE_SIZE, PAGE_SIZE, |offset, length| {
+            mmu.map(offset, length)
+        });
+        assert_eq!(result, Err(WrappingMapError::NotContiguous));
+    }
+
+    #[test]
+    fn mapping_the_whole_region_first_does_not_work() {
+        let mut mmu = FakeMmu::new();
+        let (first, _) = mmu.map(0, 4 * PAGE_SIZE).unwrap();
+        let (second, _) = mmu.map(0, 4 * PAGE_SIZE).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn detects_a_gap_between_the_mappings() {
+        let mut mmu = FakeMmu::new();
+        let result = map_wrapping(4 * PAGE_SIZE, PAGE_SIZE, |offset, length| {
+            if offset == 0 && length == 4 * PAGE_SIZE {
+                // Something else mapped a page in between
+                mmu.next_address += PAGE_SIZE;
+            }
+            mmu.map(offset, length)
+        });
+        assert_eq!(result, Err(WrappingMapError::NotContiguous));
+    }
+
+    #[test]
+    fn forwards_errors_of_the_mapping() {
+        let result = map_wrapping::<u32>(4 * PAGE_SIZE, PAGE_SIZE, |_, _| {
+            Err(WrappingMapError::MapFailed)
+        });
+        assert_eq!(result, Err(WrappingMapError::MapFailed));
+    }
+}
@@ -0,0 +1,448 @@
+//! Transactions to create and delete several files at once
+//!
+//! Files written in a [FilesystemTransaction] are written to storage right away, but they are not marked
+//! as ready. Files that are not ready are erased during mount, so an interrupted transaction leaves no
+//! trace.
+//!
+//! On commit, the addresses of all new files and of all files that should be deleted are written to the
+//! write-ahead log, a single metadata key of the storage. Writing a metadata key is atomic, so this is
+//! the point where the transaction becomes durable. Afterwards the new files are marked as ready, the
+//! deleted files are marked for deletion and the log is cleared. If power is lost before the log is
+//! cleared, the next mount applies the log again before scanning the storage.
+//!
+//! Rewriting a single file, for example to truncate, append to or relocate it, uses the same log to
+//! switch from the old to the new version.
+
+use crate::{
+    file::{CommitFileContentError, DeleteFileContentError, File, FileState},
+    file_metadata::FileMetadata,
+    storage::{Storage, StorageError},
+    FilesystemDeleteError, FilesystemWriteError, IntoFileName, MAX_NAME_LEN,
+};
+use std::io::Write;
+use thiserror::Error;
+
+/// Metadata key of the write-ahead log
+const WAL_KEY: &str = "wal";
+
+/// Errors that can occur when committing a transaction
+///
+/// If committing fails after the log was written, the transaction is completed during the next mount.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemTransactionError {
+    /// Error while writing the write-ahead log
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// Error while marking a new file as ready
+    #[error(transparent)]
+    CommitFileContentError(#[from] CommitFileContentError),
+    /// Error while deleting a file
+    #[error(transparent)]
+    DeleteFileContentError(#[from] DeleteFileContentError),
+    /// Error while completing an interrupted transaction
+    #[error(transparent)]
+    StorageError(#[from] StorageError),
+}
+
+/// A set of file creations and deletions that are applied together
+///
+/// Created with [crate::Filesystem::transaction]. Dropping the transaction without committing it
+/// deletes the files written in it.
+///
+/// ```
+/// use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+/// use rudelblinken_filesystem::Filesystem;
+///
+/// let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+/// let mut filesystem = Filesystem::new(storage);
+/// filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+///
+/// let mut transaction = filesystem.transaction();
+/// transaction.delete_file("main").unwrap();
+/// transaction.write_file("main", &[2; 100], &[0; 32]).unwrap();
+/// transaction.write_file("assets", &[3; 100], &[0; 32]).unwrap();
+/// transaction.commit().unwrap();
+///
+/// let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+/// assert_eq!(main.as_ref(), [2; 100]);
+/// ```
+pub struct FilesystemTransaction<'a, T: Storage + 'static + Send + Sync> {
+    filesystem: &'a mut crate::Filesystem<T>,
+    /// Writers of the files written in this transaction, together with their address
+    writers: Vec<(u32, File<T, { FileState::Writer }>)>,
+    /// Address and name of the files deleted by this transaction
+    deletes: Vec<(u32, String)>,
+    committed: bool,
+}
+
+impl<'a, T: Storage + 'static + Send + Sync> FilesystemTransaction<'a, T> {
+    pub(crate) fn new(filesystem: &'a mut crate::Filesystem<T>) -> Self {
+        Self {
+            filesystem,
+            writers: Vec::new(),
+            deletes: Vec::new(),
+            committed: false,
+        }
+    }
+
+    /// Write a file as part of the transaction.
+    ///
+    /// To replace a file, record its deletion with [FilesystemTransaction::delete_file] first. No files
+    /// are deleted to make space, even if [crate::Filesystem::set_allow_evict_oldest] is enabled, as that
+    /// could not be undone.
+    pub fn write_file(
+        &mut self,
+        name: impl IntoFileName,
+        content: &[u8],
+        hash: &[u8; 32],
+    ) -> Result<(), FilesystemWriteError> {
+        let filesystem = &mut *self.filesystem;
+        if filesystem.read_only {
+            return Err(FilesystemWriteError::ReadOnly);
+        }
+        let name = name.into_file_name()?;
+        let name = name.as_str();
+        filesystem.cleanup_files();
+        let deletes = &self.deletes;
+        if filesystem.files.iter().any(|file| {
+            !file.deleted()
+                && !file.marked_for_deletion()
+                && file.name == name
+                && !deletes.iter().any(|(address, _)| *address == file.address)
+        }) {
+            return Err(FilesystemWriteError::NameAlreadyTaken);
+        }
+        filesystem.check_file_count()?;
+        let length = content.len() as u32;
+        let blocks = crate::Filesystem::<T>::blocks_for(length);
+        filesystem
+            .check_quotas(name, blocks)
+            .map_err(|prefix| FilesystemWriteError::QuotaExceeded { prefix })?;
+        if !filesystem.keeps_reservation(blocks)? {
+            return Err(filesystem.out_of_space(blocks).into());
+        }
+        let free_block = filesystem
+            .place_file(&filesystem.analyze_free_space()?, blocks as u16)
+            .ok_or_else(|| filesystem.out_of_space(blocks))?;
+
+        let address = free_block * T::BLOCK_SIZE;
+        let mut writer = filesystem.create_writer(name, length, hash, address)?;
+        writer.write_all(content)?;
+        self.writers.push((address, writer));
+        Ok(())
+    }
+
+    /// Delete a file as part of the transaction
+    pub fn delete_file(&mut self, name: &str) -> Result<(), FilesystemDeleteError> {
+        if self.filesystem.read_only {
+            return Err(FilesystemDeleteError::ReadOnly);
+        }
+        let index = self
+            .filesystem
+            .live_file_index(name)
+            .ok_or(FilesystemDeleteError::FileNotFound)?;
+        let address = self.filesystem.files[index].address;
+        if !self
+            .deletes
+            .iter()
+            .any(|(existing, _)| *existing == address)
+        {
+            self.deletes.push((address, name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Apply all changes of the transaction
+    pub fn commit(mut self) -> Result<(), FilesystemTransactionError> {
+        self.committed = true;
+        if self.writers.is_empty() && self.deletes.is_empty() {
+            return Ok(());
+        }
+        let storage = self.filesystem.storage;
+        let creates: Vec<u32> = self.writers.iter().map(|(address, _)| *address).collect();
+        storage.write_metadata(WAL_KEY, &encode(&creates, &self.deletes))?;
+
+        let mut created_names = Vec::new();
+        for (_, writer) in self.writers.drain(..) {
+            created_names.push(writer.name_str().to_string());
+            writer.commit()?;
+        }
+        let first_block = self.filesystem.get_first_block().unwrap_or(0) as u32;
+        let mut deleted_first_block = false;
+        for (address, _) in &self.deletes {
+            let Some(file) = self
+                .filesystem
+                .files
+                .iter()
+                .find(|file| file.address == *address && !file.deleted())
+            else {
+                continue;
+            };
+            file.mark_for_deletion()?;
+            deleted_first_block |= address / T::BLOCK_SIZE == first_block;
+        }
+        clear_log(storage)?;
+
+        for (_, name) in &self.deletes {
+            if !created_names.contains(name) {
+                self.filesystem.remove_attributes(name)?;
+            }
+        }
+        self.filesystem.cleanup_files();
+        if deleted_first_block {
+            self.filesystem
+                .set_first_block(self.filesystem.find_new_first_block())?;
+        }
+        Ok(())
+    }
+
+    /// Discard all changes of the transaction. Same as dropping it.
+    pub fn abort(self) {}
+}
+
+impl<T: Storage + 'static + Send + Sync> Drop for FilesystemTransaction<'_, T> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for (address, writer) in self.writers.drain(..) {
+            drop(writer);
+            if let Some(file) = self
+                .filesystem
+                .files
+                .iter()
+                .find(|file| file.address == address && !file.deleted())
+            {
+                if let Err(error) = file.mark_for_deletion() {
+                    log::warn!("Failed to delete file of aborted transaction: {}", error);
+                }
+            }
+        }
+        self.filesystem.cleanup_files();
+    }
+}
+
+/// Log that the file at `created` replaces the file at `deleted`, see [crate::Filesystem::rewrite]
+///
+/// Write this before the new file is marked as ready and clear it with [clear_log] after the old file
+/// was marked for deletion. If power is lost in between, the next mount completes the replacement.
+pub(crate) fn log_replacement<T: Storage>(
+    storage: &T,
+    created: u32,
+    deleted: (u32, &str),
+) -> std::io::Result<()> {
+    storage.write_metadata(
+        WAL_KEY,
+        &encode(&[created], &[(deleted.0, deleted.1.to_string())]),
+    )
+}
+
+/// Clear the log after all changes in it were applied
+pub(crate) fn clear_log<T: Storage>(storage: &T) -> std::io::Result<()> {
+    storage.write_metadata(WAL_KEY, &[])
+}
+
+/// Encode the log as `creates: u16, address: u32*, deletes: u16, (address: u32, name: [u8; MAX_NAME_LEN])*`
+fn encode(creates: &[u32], deletes: &[(u32, String)]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&(creates.len() as u16).to_le_bytes());
+    for address in creates {
+        encoded.extend_from_slice(&address.to_le_bytes());
+    }
+    encoded.extend_from_slice(&(deletes.len() as u16).to_le_bytes());
+    for (address, name) in deletes {
+        encoded.extend_from_slice(&address.to_le_bytes());
+        let mut name_bytes = [0u8; MAX_NAME_LEN];
+        let name_length = name.len().min(MAX_NAME_LEN);
+        name_bytes[..name_length].copy_from_slice(&name.as_bytes()[..name_length]);
+        encoded.extend_from_slice(&name_bytes);
+    }
+    encoded
+}
+
+/// Decode a log written by [encode]. Returns None for an empty or broken log.
+#[allow(clippy::type_complexity)]
+fn decode(bytes: &[u8]) -> Option<(Vec<u32>, Vec<(u32, [u8; MAX_NAME_LEN])>)> {
+    let (creates_count, mut rest) = bytes.split_first_chunk::<2>()?;
+    let mut creates = Vec::new();
+    for _ in 0..u16::from_le_bytes(*creates_count) {
+        let (address, after) = rest.split_first_chunk::<4>()?;
+        creates.push(u32::from_le_bytes(*address));
+        rest = after;
+    }
+    let (deletes_count, mut rest) = rest.split_first_chunk::<2>()?;
+    let mut deletes = Vec::new();
+    for _ in 0..u16::from_le_bytes(*deletes_count) {
+        let (address, after) = rest.split_first_chunk::<4>()?;
+        let (name, after) = after.split_first_chunk::<MAX_NAME_LEN>()?;
+        deletes.push((u32::from_le_bytes(*address), *name));
+        rest = after;
+    }
+    Some((creates, deletes))
+}
+
+/// Finish a transaction that was interrupted after its log was written.
+///
+/// Needs to be called before the storage is scanned, as files that are not ready get erased. Returns true
+/// if there was an interrupted transaction.
+pub(crate) fn replay<T: Storage>(storage: &T) -> Result<bool, FilesystemTransactionError> {
+    let Ok(log) = storage.read_metadata(WAL_KEY) else {
+        return Ok(false);
+    };
+    let Some((creates, deletes)) = decode(&log) else {
+        return Ok(false);
+    };
+    log::info!(
+        "Completing an interrupted transaction with {} new and {} deleted files",
+        creates.len(),
+        deletes.len()
+    );
+    for address in creates {
+        let Ok(metadata) = FileMetadata::from_storage(storage, address) else {
+            continue;
+        };
+        if metadata.ready() {
+            continue;
+        }
+        #[cfg(feature = "ecc")]
+        {
+            let content = storage.read(address + crate::HEADER_SIZE, metadata.length)?;
+            let (syndrome, parity) = crate::ecc::compute(content);
+            unsafe { metadata.set_ecc(storage, address, syndrome, parity)? };
+        }
+        unsafe { metadata.set_ready(storage, address)? };
+    }
+    for (address, name) in deletes {
+        let Ok(metadata) = FileMetadata::from_storage(storage, address) else {
+            continue;
+        };
+        // The file is erased during the scan
+        if metadata.name == name && metadata.ready() && !metadata.marked_for_deletion() {
+            unsafe { metadata.set_marked_for_deletion(storage, address)? };
+        }
+    }
+    clear_log(storage)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        storage::simulated::get_test_storage, Filesystem, FilesystemDeleteError,
+        FilesystemWriteError,
+    };
+
+    #[test]
+    fn committed_changes_are_visible_after_remount() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+        let mut transaction = filesystem.transaction();
+        transaction.delete_file("main").unwrap();
+        transaction.write_file("main", &[2; 100], &[0; 32]).unwrap();
+        transaction
+            .write_file("asset", &[3; 5000], &[0; 32])
+            .unwrap();
+        transaction.commit().unwrap();
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [2; 100]);
+        let asset = filesystem.read_file("asset").unwrap().upgrade().unwrap();
+        assert_eq!(asset.as_ref(), [3; 5000]);
+    }
+
+    #[test]
+    fn dropping_a_transaction_discards_its_files() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+        let mut transaction = filesystem.transaction();
+        transaction.delete_file("main").unwrap();
+        transaction
+            .write_file("asset", &[3; 5000], &[0; 32])
+            .unwrap();
+        transaction.abort();
+
+        assert!(filesystem.read_file("asset").is_none());
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), [1; 100]);
+        filesystem.write_file("asset", &[4; 100], &[0; 32]).unwrap();
+    }
+
+    #[test]
+    fn replacing_a_file_requires_deleting_it_first() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+        let mut transaction = filesystem.transaction();
+        assert!(matches!(
+            transaction.write_file("main", &[2; 100], &[0; 32]),
+            Err(FilesystemWriteError::NameAlreadyTaken)
+        ));
+        transaction
+            .write_file("asset", &[3; 100], &[0; 32])
+            .unwrap();
+        assert!(matches!(
+            transaction.write_file("asset", &[3; 100], &[0; 32]),
+            Err(FilesystemWriteError::NameAlreadyTaken)
+        ));
+        assert!(matches!(
+            transaction.delete_file("missing"),
+            Err(FilesystemDeleteError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn transactions_are_atomic_when_power_is_lost_during_commit() {
+        let mut saw_old_state = false;
+        let mut saw_new_state = false;
+        let mut saw_replay = false;
+        for operations in 0..64 {
+            let storage = get_test_storage();
+            let mut filesystem = Filesystem::new(storage);
+            filesystem.write_file("main", &[1; 100], &[0; 32]).unwrap();
+            let mut transaction = filesystem.transaction();
+            transaction.delete_file("main").unwrap();
+            transaction.write_file("main", &[2; 100], &[0; 32]).unwrap();
+            transaction
+                .write_file("asset", &[3; 5000], &[0; 32])
+                .unwrap();
+
+            storage.cut_power_after(operations);
+            let _ = transaction.commit();
+            // Nothing that happens after the power loss reaches the storage
+            storage.cut_power_after(0);
+            drop(filesystem);
+            storage.restore_power();
+
+            let (filesystem, report) = Filesystem::mount_with_report(storage);
+            saw_replay |= report.wal_replayed;
+            assert_eq!(
+                filesystem.mount_source() == crate::builder::MountSource::WalReplay,
+                report.wal_replayed
+            );
+            let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+            let asset = filesystem.read_file("asset");
+            if main.as_ref() == [1; 100] {
+                assert!(
+                    asset.is_none(),
+                    "Cut after {operations}: asset without main"
+                );
+                assert_eq!(filesystem.list_files().count(), 1);
+                saw_old_state = true;
+            } else {
+                assert_eq!(main.as_ref(), [2; 100]);
+                let asset = asset.unwrap().upgrade().unwrap();
+                assert_eq!(asset.as_ref(), [3; 5000]);
+                assert_eq!(filesystem.list_files().count(), 2);
+                saw_new_state = true;
+            }
+        }
+        assert!(saw_old_state);
+        assert!(saw_new_state);
+        assert!(saw_replay);
+    }
+}
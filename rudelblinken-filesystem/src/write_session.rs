@@ -0,0 +1,332 @@
+//! Streaming writes of files with a known size
+//!
+//! Programs received over BLE can be larger than the free RAM. [Filesystem::begin_write] allocates the
+//! blocks for the whole file up front and returns a [WriteSession]. The chunks are written to the
+//! storage as they arrive and hashed on the way, so nothing needs to be buffered.
+//!
+//! The blocks of a new file are always erased, because blocks are erased when the file that used them
+//! is deleted. Writing a chunk only programs the flash.
+//!
+//! The header of the file, with the declared length and the expected hash, is written before the first
+//! chunk. It marks the file as not ready. The file only becomes readable after [WriteSession::commit]
+//! checked that all bytes were written and that they match the expected hash, and then set the ready
+//! flag in the header. Dropping the session without committing deletes the file and erases its blocks.
+//! If power is lost during the transfer, mount finds the header that is not ready and erases the
+//! unfinished file.
+//!
+//! ```
+//! use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+//! use rudelblinken_filesystem::Filesystem;
+//!
+//! let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+//! let mut filesystem = Filesystem::new(storage);
+//! let content = [0x42; 6000];
+//! let hash: [u8; 32] = blake3::hash(&content).into();
+//!
+//! let mut session = filesystem.begin_write("main", 6000, &hash).unwrap();
+//! for chunk in content.chunks(512) {
+//!     session.write_chunk(chunk).unwrap();
+//! }
+//! session.commit().unwrap();
+//!
+//! let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+//! assert_eq!(main.as_ref(), content);
+//! ```
+
+#[cfg(doc)]
+use crate::Filesystem;
+use crate::{
+    file::{CommitFileContentError, File, FileState},
+    storage::Storage,
+};
+use std::io::Write;
+use thiserror::Error;
+
+/// Errors that can occur while streaming a file
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilesystemWriteSessionError {
+    /// Error while writing a chunk to the storage
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// The chunk does not fit into the length given when the session was started
+    #[error("The chunk does not fit, only {remaining} bytes are left")]
+    TooLong {
+        /// Number of bytes that can still be written
+        remaining: u32,
+    },
+    /// Not all bytes were written before committing
+    #[error("Only {got} of {expected} bytes were written")]
+    IncompleteWrite {
+        /// Length given when the session was started
+        expected: u32,
+        /// Number of bytes that were written
+        got: u32,
+    },
+    /// The written content does not match the expected hash
+    #[error("The written content does not match the expected hash")]
+    HashMismatch,
+    /// Error while marking the file as ready
+    #[error(transparent)]
+    CommitFileContentError(#[from] CommitFileContentError),
+}
+
+/// A file that is being written chunk by chunk, see [write_session](self)
+///
+/// The session does not borrow the filesystem, so the filesystem can be used while the chunks arrive.
+pub struct WriteSession<T: Storage + 'static + Send + Sync> {
+    /// Writer of the file. Only None after the session was committed.
+    writer: Option<File<T, { FileState::Writer }>>,
+    hasher: blake3::Hasher,
+    hash: [u8; 32],
+    written: u32,
+    length: u32,
+}
+
+impl<T: Storage + 'static + Send + Sync> WriteSession<T> {
+    pub(crate) fn new(writer: File<T, { FileState::Writer }>, hash: &[u8; 32]) -> Self {
+        Self {
+            length: writer.length_bytes(),
+            writer: Some(writer),
+            hasher: blake3::Hasher::new(),
+            hash: *hash,
+            written: 0,
+        }
+    }
+
+    /// Write the next chunk of the content
+    ///
+    /// Chunks are written in order. A chunk that would go past the length of the file is rejected
+    /// without writing any of it.
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), FilesystemWriteSessionError> {
+        let remaining = self.remaining();
+        if chunk.len() as u64 > remaining as u64 {
+            return Err(FilesystemWriteSessionError::TooLong { remaining });
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("the writer is only taken when committing");
+        writer.write_all(chunk)?;
+        self.hasher.update(chunk);
+        self.written += chunk.len() as u32;
+        Ok(())
+    }
+
+    /// Number of bytes that were written so far
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Number of bytes that still need to be written
+    pub fn remaining(&self) -> u32 {
+        self.length - self.written
+    }
+
+    /// Check the content and make the file readable
+    ///
+    /// Fails if not all bytes were written or if they do not match the hash given when the session was
+    /// started. The file is deleted in that case.
+    pub fn commit(mut self) -> Result<(), FilesystemWriteSessionError> {
+        if self.written != self.length {
+            return Err(FilesystemWriteSessionError::IncompleteWrite {
+                expected: self.length,
+                got: self.written,
+            });
+        }
+        if <[u8; 32]>::from(self.hasher.finalize()) != self.hash {
+            return Err(FilesystemWriteSessionError::HashMismatch);
+        }
+        let writer = self
+            .writer
+            .take()
+            .expect("the writer is only taken when committing");
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Discard the file. Same as dropping the session.
+    pub fn abort(self) {}
+}
+
+impl<T: Storage + 'static + Send + Sync> Drop for WriteSession<T> {
+    fn drop(&mut self) {
+        let Some(writer) = self.writer.take() else {
+            return;
+        };
+        // The blocks are erased when the writer is dropped
+        if let Err(error) = writer.mark_for_deletion() {
+            log::warn!("Failed to delete the file of an aborted write: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_metadata::FileMetadata;
+    use crate::storage::simulated::{get_test_storage, SimulatedStorage};
+    use crate::Filesystem;
+
+    fn hash(content: &[u8]) -> [u8; 32] {
+        blake3::hash(content).into()
+    }
+
+    #[test]
+    fn files_are_readable_after_the_last_chunk_was_committed() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+
+        let mut session = filesystem
+            .begin_write("main", content.len() as u32, &hash(&content))
+            .unwrap();
+        // The blocks are reserved before the first chunk arrives
+        assert!(filesystem.free_space() < 13 * SimulatedStorage::BLOCK_SIZE);
+        for chunk in content.chunks(1000) {
+            session.write_chunk(chunk).unwrap();
+            assert!(filesystem.read_file("main").is_none());
+        }
+        assert!(matches!(
+            session.write_chunk(&[0]),
+            Err(FilesystemWriteSessionError::TooLong { remaining: 0 })
+        ));
+        session.commit().unwrap();
+
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), content);
+        assert!(main.verify_hash());
+    }
+
+    #[test]
+    fn unfinished_or_corrupted_writes_free_their_blocks() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let free_space = filesystem.free_space();
+        let content = [0x42; 5000];
+
+        let mut session = filesystem
+            .begin_write("main", 5000, &hash(&content))
+            .unwrap();
+        session.write_chunk(&content[..4000]).unwrap();
+        assert!(matches!(
+            session.commit(),
+            Err(FilesystemWriteSessionError::IncompleteWrite {
+                expected: 5000,
+                got: 4000
+            })
+        ));
+        assert!(filesystem.read_file("main").is_none());
+        assert_eq!(filesystem.free_space(), free_space);
+
+        let mut session = filesystem
+            .begin_write("main", 5000, &hash(&content))
+            .unwrap();
+        session.write_chunk(&[0x43; 5000]).unwrap();
+        assert!(matches!(
+            session.commit(),
+            Err(FilesystemWriteSessionError::HashMismatch)
+        ));
+        assert!(filesystem.read_file("main").is_none());
+
+        let session = filesystem
+            .begin_write("main", 5000, &hash(&content))
+            .unwrap();
+        session.abort();
+        assert_eq!(filesystem.free_space(), free_space);
+        // The blocks were erased, so the whole storage can be written again
+        filesystem
+            .write_file("big", &[0; 14 * 4096], &[0; 32])
+            .unwrap();
+    }
+
+    #[test]
+    fn chunks_past_the_declared_length_are_rejected() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let content = [0x42; 5000];
+
+        let mut session = filesystem
+            .begin_write("main", 5000, &hash(&content))
+            .unwrap();
+        session.write_chunk(&content[..4000]).unwrap();
+        assert!(matches!(
+            session.write_chunk(&[0x42; 2000]),
+            Err(FilesystemWriteSessionError::TooLong { remaining: 1000 })
+        ));
+        // Nothing of the rejected chunk was written
+        assert_eq!(session.written(), 4000);
+        session.write_chunk(&content[4000..]).unwrap();
+        session.commit().unwrap();
+
+        let main = filesystem.read_file("main").unwrap().upgrade().unwrap();
+        assert_eq!(main.as_ref(), content);
+    }
+
+    #[test]
+    fn abandoned_writes_are_gone_after_a_remount() {
+        let storage = get_test_storage();
+        let mut filesystem = Filesystem::new(storage);
+        let free_space = filesystem.free_space();
+        let content = [0x42; 5000];
+
+        let mut session = filesystem
+            .begin_write("main", 5000, &hash(&content))
+            .unwrap();
+        session.write_chunk(&content[..4000]).unwrap();
+        // Like losing power during the transfer, the session never gets to clean up
+        std::mem::forget(session);
+        drop(filesystem);
+
+        let mut filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("main").is_none());
+        assert_eq!(filesystem.list_files().count(), 0);
+        assert_eq!(filesystem.free_space(), free_space);
+        filesystem.write_file("main", &content, &[0; 32]).unwrap();
+    }
+
+    #[test]
+    fn power_loss_during_a_session_never_leaves_a_partial_file() {
+        let content: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut completed = false;
+        for budget in 0..30 {
+            let storage = get_test_storage();
+            let mut filesystem = Filesystem::new(storage);
+            let free_space = filesystem.free_space();
+
+            storage.cut_power_after(budget);
+            // Writing the header fails if the power is already gone, as it is read back
+            if let Ok(mut session) = filesystem.begin_write("main", 5000, &hash(&content)) {
+                if budget > 0 {
+                    // The header marks the file as not ready before any content is written
+                    let address = filesystem.files[0].address;
+                    let header = FileMetadata::from_storage(storage, address).unwrap();
+                    assert!(!header.ready());
+                }
+                for chunk in content.chunks(1000) {
+                    session.write_chunk(chunk).unwrap();
+                }
+                session.commit().unwrap();
+            }
+            drop(filesystem);
+            storage.restore_power();
+
+            let mut filesystem = Filesystem::new(storage);
+            match filesystem.read_file("main") {
+                Some(file) => {
+                    assert_eq!(file.upgrade().unwrap().as_ref(), content);
+                    completed = true;
+                }
+                None => {
+                    assert_eq!(filesystem.list_files().count(), 0);
+                    assert_eq!(filesystem.free_space(), free_space);
+                    // The blocks of the unfinished file are erased again
+                    filesystem
+                        .write_file("big", &[0; 14 * 4096], &[0; 32])
+                        .unwrap();
+                }
+            }
+        }
+        assert!(completed);
+    }
+}
@@ -2,13 +2,14 @@
 use crate::config::main_program;
 use crate::storage::get_filesystem;
 use crate::{storage::FlashStorage, wasm_service::wasm_host::WasmHost};
-use rudelblinken_filesystem::file::{File, FileState};
+use rudelblinken_filesystem::{
+    file::{File, FileState},
+    lock::RecoverPoisoned,
+};
 use std::time::Duration;
 
 /// The delay between attempts to load the main program
 const LOAD_MAIN_PROGRAM_RETRY_DELAY: Duration = Duration::from_millis(200);
-/// The max number of attempts to acquire the filesystem lock before returning the default program
-const MAX_MAIN_PROGRAM_FS_LOCK_ATTEMPTS: usize = 50;
 /// The max number of attempts to read the main program before deleting it and returning the default program
 const MAX_MAIN_PROGRAM_UPGRADE_ATTEMPTS: usize = 5;
 
@@ -36,7 +37,6 @@ impl AsRef<[u8]> for WasmProgram {
 
 /// Load the main program or return the default program
 pub fn load_main_program(host: &mut WasmHost) -> WasmProgram {
-    let mut fs_lock_attempts_left = MAX_MAIN_PROGRAM_FS_LOCK_ATTEMPTS;
     let mut upgrade_attempts_left = MAX_MAIN_PROGRAM_UPGRADE_ATTEMPTS;
     loop {
         std::thread::sleep(LOAD_MAIN_PROGRAM_RETRY_DELAY);
@@ -50,17 +50,7 @@ pub fn load_main_program(host: &mut WasmHost) -> WasmProgram {
         };
 
         let filesystem = get_filesystem().unwrap();
-        let Ok(filesystem_reader) = filesystem.read() else {
-            // This will change,
-            fs_lock_attempts_left = fs_lock_attempts_left.saturating_sub(1);
-            if fs_lock_attempts_left == 0 {
-                // If we can't acquire the lock, we can't read the file
-                // We can't continue without the main program, so we use the default program
-                tracing::warn!("Failed to acquire filesystem lock");
-                return WasmProgram::Default;
-            }
-            continue;
-        };
+        let filesystem_reader = filesystem.read_recovering();
         let Some(file) = filesystem_reader.read_file_by_hash(&current_main_program) else {
             // If the main program does not exist on the filesystem, we can remove the reference to it
             main_program::set(&None);
@@ -1,6 +1,9 @@
 use crate::storage::{get_filesystem, CreateStorageError, FlashStorage};
 use incomplete_file::{IncompleteFile, ReceiveChunkError, VerifyFileError};
-use rudelblinken_filesystem::file::{FileState, UpgradeFileError};
+use rudelblinken_filesystem::{
+    file::{FileState, UpgradeFileError},
+    lock::RecoverPoisoned,
+};
 use thiserror::Error;
 use upload_request::UploadRequest;
 mod incomplete_file;
@@ -34,6 +37,9 @@ pub enum FileUploadError {
     WrongNumberOfChecksums { expected: u32, got: u32 },
     #[error(transparent)]
     SetupFilesystemError(#[from] CreateStorageError),
+    // Not returned anymore, as the filesystem lock recovers from poisoning. Kept so the codes of the
+    // following variants stay the same.
+    #[allow(dead_code)]
     #[error("Failed to lock filesystem")]
     LockFilesystemError,
     #[error("Failed to create file: FilesystemWriteError: {0}")]
@@ -53,9 +59,7 @@ impl FileUploadService {
         unsafe { esp_idf_sys::esp_fill_random(bytes.as_mut_ptr() as *mut core::ffi::c_void, 4) };
         let random_name = format!("fw-{}", u32::from_le_bytes(bytes));
         let writer = {
-            let mut filesystem_writer = get_filesystem()?
-                .write()
-                .map_err(|_| FileUploadError::LockFilesystemError)?;
+            let mut filesystem_writer = get_filesystem()?.write_recovering();
             filesystem_writer
                 .get_file_writer(&random_name, upload_request.file_size, &upload_request.hash)
                 .map_err(|error| FileUploadError::FailedToCreateFile(format!("{}", error)))?
@@ -103,7 +107,7 @@ impl FileUploadService {
             let incomplete_file = maybe_current_upload
                 .take()
                 .ok_or(FileUploadError::NoUploadActive)?;
-            incomplete_file.into_file(&get_filesystem().unwrap().read().unwrap())?;
+            incomplete_file.into_file(&get_filesystem().unwrap().read_recovering())?;
         }
         Ok(())
     }
@@ -117,7 +121,7 @@ impl FileUploadService {
         let filesystem_reader: std::sync::RwLockReadGuard<
             '_,
             rudelblinken_filesystem::Filesystem<FlashStorage>,
-        > = filesystem.read().unwrap();
+        > = filesystem.read_recovering();
         filesystem_reader.read_file_by_hash(hash)
     }
 
@@ -5,12 +5,12 @@ use std::{
 
 use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
 use esp_idf_sys::{
-    esp_err_to_name, esp_partition_erase_range, esp_partition_find, esp_partition_get,
+    esp_err_t, esp_err_to_name, esp_partition_erase_range, esp_partition_find, esp_partition_get,
     esp_partition_mmap, esp_partition_mmap_memory_t_ESP_PARTITION_MMAP_DATA, esp_partition_next,
     esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
     esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_UNDEFINED,
     esp_partition_type_t_ESP_PARTITION_TYPE_ANY, esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
-    esp_partition_write_raw, ESP_OK,
+    esp_partition_write_raw, ESP_ERR_NVS_NOT_ENOUGH_SPACE, ESP_OK,
 };
 use rudelblinken_filesystem::{
     storage::{EraseStorageError, Storage, StorageError},
@@ -75,8 +75,10 @@ pub enum CreateStorageError {
 }
 
 impl FlashStorage {
-    pub fn new() -> Result<FlashStorage, CreateStorageError> {
-        // TODO: Make sure that there is only one flash storage instance.
+    /// Map the storage partition and open the NVS namespace
+    ///
+    /// Only [get_filesystem] calls this, so there is never more than one instance.
+    fn new() -> Result<FlashStorage, CreateStorageError> {
         let mut label: Vec<i8> = String::from("storage")
             .bytes()
             .into_iter()
@@ -277,18 +279,32 @@ impl Storage for FlashStorage {
             .lock()
             .map_err(|_| std::io::Error::other("Failed to obtain lock to nvs"))?
             .set_raw(key, value)
-            .map_err(|_| std::io::Error::other("Failed to write value to nvs"))?;
+            .map_err(|error| {
+                if error.code() == ESP_ERR_NVS_NOT_ENOUGH_SPACE as esp_err_t {
+                    return StorageError::metadata_full();
+                }
+                std::io::Error::other("Failed to write value to nvs")
+            })?;
         return Ok(());
     }
 }
 
-static STORAGE_SINGLETON: OnceLock<FlashStorage> = OnceLock::new();
 static FILESYSTEM_SINGLETON: OnceLock<RwLock<Filesystem<FlashStorage>>> = OnceLock::new();
 
+/// Get the global filesystem
+///
+/// The filesystem is mounted on the first call. `get_or_try_init` runs the initialization on one
+/// thread only, concurrent callers block until it is done and then get the same filesystem. If it
+/// fails, the error is returned to that caller and the next call tries again.
+///
+/// Lock it with [RecoverPoisoned](rudelblinken_filesystem::lock::RecoverPoisoned), so a panic in one
+/// task does not make the filesystem unusable for all others.
 pub fn get_filesystem() -> Result<&'static RwLock<Filesystem<FlashStorage>>, CreateStorageError> {
     FILESYSTEM_SINGLETON.get_or_try_init(|| {
-        let storage = STORAGE_SINGLETON.get_or_try_init(|| FlashStorage::new())?;
-        Ok(RwLock::new(Filesystem::new(storage)))
+        let mut filesystem = Filesystem::new(FlashStorage::new()?);
+        // Uploaded programs replace old unimportant ones when the flash is full
+        filesystem.set_allow_evict_oldest(true);
+        Ok(RwLock::new(filesystem))
     })
 }
 